@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_torrent_client::core_models::entities::ExtendedHandshake;
+use rust_torrent_client::torrent_parser::parse_torrent_bytes;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = parse_torrent_bytes(data);
+    let _ = ExtendedHandshake::from_bytes(data);
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_torrent_client::p2p::conn::receive_handshake;
+
+fuzz_target!(|data: &[u8]| {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let mut stream = data;
+    let _ = runtime.block_on(receive_handshake(&mut stream));
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_torrent_client::core_models::entities::Message;
+
+fuzz_target!(|data: &[u8]| {
+    // whatever decodes has to encode back to the same bytes, without the length prefix
+    if let Ok(message) = Message::deserialize(data.to_vec()) {
+        assert_eq!(&message.serialize()[4..], data);
+    }
+});
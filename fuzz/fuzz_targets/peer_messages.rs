@@ -0,0 +1,36 @@
+#![no_main]
+
+use std::sync::Arc;
+use libfuzzer_sys::fuzz_target;
+use tokio::sync::Mutex;
+use rust_torrent_client::core_models::entities::{Bitfield, Message};
+use rust_torrent_client::file_provider::{FileProv, TempFileProv};
+use rust_torrent_client::mocks::generate_mock_layout;
+use rust_torrent_client::p2p::handlers::handle;
+use rust_torrent_client::p2p::models::{P2PEvent, P2PState};
+use rust_torrent_client::piece_picker::{PiecePicker, RarestPiecePicker};
+
+// Feeds decoded messages to the handlers of a transfer, which have to refuse the ones that do not
+// fit the torrent instead of panicking. Each message is preceded by a one byte length.
+fuzz_target!(|data: &[u8]| {
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let layout = generate_mock_layout(10, 2, 1);
+    let mut state = P2PState::new(0, Bitfield::init(layout.pieces), layout.clone());
+    state.peer_is_choked = false;
+    state.peer_is_interested = true;
+    let picker: Arc<Mutex<dyn PiecePicker>> = Arc::new(Mutex::new(RarestPiecePicker::init(layout.clone())));
+    let mut fp: Box<dyn FileProv> = Box::new(TempFileProv::new(layout));
+
+    let mut remaining = data;
+    while let Some((&len, rest)) = remaining.split_first() {
+        let (bytes, rest) = rest.split_at((len as usize).min(rest.len()));
+        remaining = rest;
+        let Ok(message) = Message::deserialize(bytes.to_vec()) else {
+            continue;
+        };
+        let event = P2PEvent::PeerMessageReceived(Ok(message));
+        if runtime.block_on(handle(event, &mut state, &mut fp, &picker)).is_err() {
+            return;
+        }
+    }
+});
@@ -0,0 +1,160 @@
+use std::net::Ipv4Addr;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use crate::coordinator::stats::TorrentStatus;
+use crate::core_models::entities::Peer;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AlertCategory {
+    Piece,
+    Peer,
+    Tracker,
+    Status,
+    Storage,
+}
+
+impl AlertCategory {
+    pub const ALL: [AlertCategory; 5] = [
+        AlertCategory::Piece, AlertCategory::Peer, AlertCategory::Tracker, AlertCategory::Status, AlertCategory::Storage,
+    ];
+}
+
+// Notable things that happened to a torrent of the session, identified by its info hash
+#[derive(Clone, Debug, PartialEq)]
+pub enum Alert {
+    PieceFinished { info_hash: Vec<u8>, piece_idx: usize },
+    HashFailed { info_hash: Vec<u8>, piece_idx: usize },
+    PeerConnected { info_hash: Vec<u8>, peer: Peer },
+    PeerDisconnected { info_hash: Vec<u8>, peer: Peer },
+    // the peer sent corrupt data and was disconnected
+    PeerBanned { info_hash: Vec<u8>, ip: Ipv4Addr },
+    TrackerReply { info_hash: Vec<u8>, peers: usize },
+    TrackerError { info_hash: Vec<u8>, reason: String },
+    TorrentStateChanged { info_hash: Vec<u8>, status: TorrentStatus },
+    StorageError { info_hash: Vec<u8>, reason: String },
+    TorrentFinished { info_hash: Vec<u8> },
+}
+
+impl Alert {
+    pub fn category(&self) -> AlertCategory {
+        return match self {
+            Alert::PieceFinished { .. } | Alert::HashFailed { .. } | Alert::TorrentFinished { .. } => AlertCategory::Piece,
+            Alert::PeerConnected { .. } | Alert::PeerDisconnected { .. } | Alert::PeerBanned { .. } => AlertCategory::Peer,
+            Alert::TrackerReply { .. } | Alert::TrackerError { .. } => AlertCategory::Tracker,
+            Alert::TorrentStateChanged { .. } => AlertCategory::Status,
+            Alert::StorageError { .. } => AlertCategory::Storage,
+        };
+    }
+
+    pub fn info_hash(&self) -> &Vec<u8> {
+        return match self {
+            Alert::PieceFinished { info_hash, .. }
+            | Alert::HashFailed { info_hash, .. }
+            | Alert::PeerConnected { info_hash, .. }
+            | Alert::PeerDisconnected { info_hash, .. }
+            | Alert::PeerBanned { info_hash, .. }
+            | Alert::TrackerReply { info_hash, .. }
+            | Alert::TrackerError { info_hash, .. }
+            | Alert::TorrentStateChanged { info_hash, .. }
+            | Alert::StorageError { info_hash, .. }
+            | Alert::TorrentFinished { info_hash } => info_hash,
+        };
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum AlertError {
+    // the subscriber fell behind and this many of the oldest alerts were dropped; receiving again
+    // continues with the oldest alert still buffered
+    Dropped(u64),
+    Closed,
+}
+
+// Publishes alerts to any number of subscribers; the alerts are buffered for each subscriber up to
+// the capacity, past which the oldest ones are dropped
+#[derive(Clone)]
+pub struct Alerts {
+    tx: broadcast::Sender<Alert>,
+}
+
+impl Alerts {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity.max(1));
+        return Alerts { tx };
+    }
+
+    pub fn send(&self, alert: Alert) {
+        // nobody listening is fine
+        let _ = self.tx.send(alert);
+    }
+
+    pub fn subscribe(&self, categories: &[AlertCategory]) -> AlertStream {
+        return AlertStream { rx: self.tx.subscribe(), categories: categories.to_vec() };
+    }
+}
+
+pub struct AlertStream {
+    rx: broadcast::Receiver<Alert>,
+    categories: Vec<AlertCategory>,
+}
+
+impl AlertStream {
+    // Waits for the next alert of the subscribed categories. Alerts of other categories count
+    // towards the buffer as well, so they can cause drops too.
+    pub async fn recv(&mut self) -> Result<Alert, AlertError> {
+        loop {
+            match self.rx.recv().await {
+                Ok(alert) if self.categories.contains(&alert.category()) => return Ok(alert),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(dropped)) => return Err(AlertError::Dropped(dropped)),
+                Err(RecvError::Closed) => return Err(AlertError::Closed),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::alerts::{Alert, AlertCategory, AlertError, Alerts};
+    use crate::coordinator::stats::TorrentStatus;
+
+    fn piece_finished(piece_idx: usize) -> Alert {
+        return Alert::PieceFinished { info_hash: vec![1; 20], piece_idx };
+    }
+
+    #[tokio::test]
+    async fn test_alerts_filtered_by_category() {
+        let alerts = Alerts::new(8);
+        let mut stream = alerts.subscribe(&[AlertCategory::Status]);
+
+        alerts.send(piece_finished(0));
+        alerts.send(Alert::TorrentStateChanged { info_hash: vec![1; 20], status: TorrentStatus::Paused });
+
+        let alert = stream.recv().await.unwrap();
+        assert_eq!(alert, Alert::TorrentStateChanged { info_hash: vec![1; 20], status: TorrentStatus::Paused });
+    }
+
+    #[tokio::test]
+    async fn test_oldest_alerts_dropped_when_buffer_full() {
+        let alerts = Alerts::new(2);
+        let mut stream = alerts.subscribe(&AlertCategory::ALL);
+
+        for piece_idx in 0..5 {
+            alerts.send(piece_finished(piece_idx));
+        }
+
+        assert_eq!(stream.recv().await, Err(AlertError::Dropped(3)));
+        assert_eq!(stream.recv().await, Ok(piece_finished(3)));
+        assert_eq!(stream.recv().await, Ok(piece_finished(4)));
+    }
+
+    #[tokio::test]
+    async fn test_stream_closed_with_publisher() {
+        let alerts = Alerts::new(2);
+        let mut stream = alerts.subscribe(&AlertCategory::ALL);
+
+        drop(alerts);
+
+        assert_eq!(stream.recv().await, Err(AlertError::Closed));
+    }
+}
@@ -1,153 +1,459 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use rand::prelude::IteratorRandom;
-use crate::choke::models::{ChokeEvent, PeerState};
+use std::time::{Duration, Instant};
+use rand::seq::SliceRandom;
+use crate::choke::models::{ChokeEvent, ChokeState, PeerState};
 use crate::core_models::events::InternalEvent;
 
-const MAX_CONCURRENTLY_UNCHOKED_PEERS: usize = 4;
+// while seeding, peers unchoked within this period keep their slots
+const SEED_RECENTLY_UNCHOKED_SECS: u64 = 20;
+// peers connected within the last three optimistic unchoke rounds count as new
+const NEW_PEER_PERIOD_SECS: u64 = 90;
+const NEW_PEER_OPTIMISTIC_UNCHOKE_WEIGHT: u32 = 3;
 
-pub fn handle(event: ChokeEvent, peers: &mut HashMap<usize, PeerState>) -> Vec<InternalEvent> {
+// Events about a peer may still arrive after it was unregistered, in which case they are ignored
+pub fn handle(event: ChokeEvent, state: &mut ChokeState) -> Vec<InternalEvent> {
     return match event {
         ChokeEvent::UnchokePeers => {
-            unchoke_peers(peers)
+            unchoke_peers(state)
         }
         ChokeEvent::OptimisticUnchoke => {
-            optimistic_unchoke(peers)
+            optimistic_unchoke(state)
         }
         ChokeEvent::ClientInterestedInPeer(idx, interested) => {
-            peers.get_mut(&idx).unwrap().client_interested_in_peer = interested;
+            if let Some(peer) = state.peers.get_mut(&idx) {
+                peer.client_interested_in_peer = interested;
+            }
             vec![]
         }
         ChokeEvent::PeerInterestedInClient(idx, interested) => {
-            peers.get_mut(&idx).unwrap().peer_interested_in_client = interested;
+            if let Some(peer) = state.peers.get_mut(&idx) {
+                peer.peer_interested_in_client = interested;
+            }
             vec![]
         }
-        ChokeEvent::BlockDownloadedFromPeer(idx) => {
-            peers.get_mut(&idx).unwrap().block_downloaded();
+        ChokeEvent::BlockDownloadedFromPeer(idx, bytes) => {
+            if let Some(peer) = state.peers.get_mut(&idx) {
+                peer.block_downloaded(bytes);
+            }
+            vec![]
+        }
+        ChokeEvent::BlockUploadedToPeer(idx, bytes) => {
+            if let Some(peer) = state.peers.get_mut(&idx) {
+                peer.block_uploaded(bytes);
+            }
+            vec![]
+        }
+        ChokeEvent::PeerConnected(idx) => {
+            // peers which connected to the client are not known upfront
+            let peer = state.peers.entry(idx).or_insert_with(|| PeerState::new(idx));
+            peer.connected_at = Some(Instant::now());
+            vec![]
+        }
+        ChokeEvent::PeerSnubbed(idx, snubbed) => {
+            if let Some(peer) = state.peers.get_mut(&idx) {
+                peer.is_snubbed = snubbed;
+            }
+            vec![]
+        }
+        ChokeEvent::SeedingStateChanged(is_seeding) => {
+            state.is_seeding = is_seeding;
             vec![]
         }
         ChokeEvent::UnregisterPeer(idx) => {
-            peers.remove(&idx);
+            state.peers.remove(&idx);
+            state.regular_unchokes.retain(|peer_idx| *peer_idx != idx);
+            if state.optimistic_unchoke == Some(idx) {
+                state.optimistic_unchoke = None;
+            }
             vec![]
         }
     };
 }
 
-fn unchoke_peers(peers: &mut HashMap<usize, PeerState>) -> Vec<InternalEvent> {
-    let mut output_events: Vec<InternalEvent> = vec![];
+fn unchoke_peers(state: &mut ChokeState) -> Vec<InternalEvent> {
+    let ranked_peers = if state.is_seeding {
+        rank_for_seeding(&mut state.peers)
+    } else {
+        rank_by_download_rate(&mut state.peers)
+    };
+
+    let now = Instant::now();
+    let total_upload_rate: f64 = state.peers.values_mut()
+        .map(|peer| peer.upload_rate.bytes_per_sec_at(now))
+        .sum();
+    let saturated = ranked_peers.len() > state.upload_slots.count;
+    state.upload_slots.adjust(total_upload_rate, state.regular_unchokes.len(), saturated);
 
-    let currently_unchoked: Vec<usize> = peers.iter()
-        .filter(|(_idx, peer)| !peer.peer_choked_by_client)
-        .map(|(idx, _peer)| *idx)
+    state.regular_unchokes = ranked_peers.into_iter()
+        .take(state.upload_slots.count)
         .collect();
 
-    let mut sorted_peers: Vec<&PeerState> = peers.values()
-        .filter(|peer| peer.client_interested_in_peer)
+    return assign_unchoke_slots(state);
+}
+
+// peers we are interested in, ranked by the rate at which they upload to us
+fn rank_by_download_rate(peers: &mut HashMap<usize, PeerState>) -> Vec<usize> {
+    let now = Instant::now();
+    // snubbed peers are not reciprocated
+    let mut candidates: Vec<(usize, f64)> = peers.values_mut()
+        .filter(|peer| peer.client_interested_in_peer && !peer.is_snubbed)
+        .map(|peer| (peer.idx, peer.download_rate.bytes_per_sec_at(now)))
         .collect();
-    sorted_peers.sort_by(|a, b| b.downloaded_blocks.cmp(&a.downloaded_blocks));
+    candidates.sort_by(|(_, a_rate), (_, b_rate)| b_rate.total_cmp(a_rate));
 
-    let top_peers: Vec<usize> = sorted_peers.iter()
-        .take(MAX_CONCURRENTLY_UNCHOKED_PEERS)
-        .map(|peer| peer.idx)
+    return candidates.iter()
+        .map(|(idx, _)| *idx)
         .collect();
+}
 
-    for unchoked in currently_unchoked.iter() {
-        if !top_peers.contains(&unchoked) {
-            output_events.push(InternalEvent::ChokePeer(*unchoked));
+// Nothing is downloaded while seeding, so peers are ranked by the upload rate to them instead. Recently unchoked
+// peers keep their slots, which lets the optimistic unchokes rotate the slots among all interested peers.
+fn rank_for_seeding(peers: &mut HashMap<usize, PeerState>) -> Vec<usize> {
+    let now = Instant::now();
+    let recently_unchoked_period = Duration::from_secs(SEED_RECENTLY_UNCHOKED_SECS);
+
+    // (peer idx, recently unchoked at, upload rate)
+    let mut candidates: Vec<(usize, Option<Instant>, f64)> = peers.values_mut()
+        .filter(|peer| peer.peer_interested_in_client)
+        .map(|peer| {
+            let recently_unchoked_at = peer.unchoked_at
+                .filter(|at| now.saturating_duration_since(*at) < recently_unchoked_period);
+            (peer.idx, recently_unchoked_at, peer.upload_rate.bytes_per_sec_at(now))
+        })
+        .collect();
+    candidates.sort_by(|(_, a_unchoked_at, a_rate), (_, b_unchoked_at, b_rate)| {
+        return match (a_unchoked_at, b_unchoked_at) {
+            (Some(a), Some(b)) => b.cmp(a),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => b_rate.total_cmp(a_rate),
+        };
+    });
+
+    return candidates.iter()
+        .map(|(idx, _, _)| *idx)
+        .collect();
+}
+
+// Replaces the optimistically unchoked peer, which gets choked again unless it has earned a regular slot
+fn optimistic_unchoke(state: &mut ChokeState) -> Vec<InternalEvent> {
+    let now = Instant::now();
+    let candidates: Vec<(usize, u32)> = state.peers.values()
+        .filter(|peer| peer.is_optimistic_candidate())
+        .map(|peer| (peer.idx, optimistic_unchoke_weight(peer, now)))
+        .collect();
+
+    return match candidates.choose_weighted(&mut rand::thread_rng(), |(_idx, weight)| *weight) {
+        Ok((idx, _weight)) => {
+            state.optimistic_unchoke = Some(*idx);
+            assign_unchoke_slots(state)
         }
-    }
+        Err(_) => vec![],
+    };
+}
+
+// newly connected peers have nothing to offer yet, so they are more likely to get the optimistic unchoke
+fn optimistic_unchoke_weight(peer: &PeerState, now: Instant) -> u32 {
+    let is_new = peer.connected_at
+        .is_some_and(|at| now.saturating_duration_since(at) < Duration::from_secs(NEW_PEER_PERIOD_SECS));
+    return if is_new { NEW_PEER_OPTIMISTIC_UNCHOKE_WEIGHT } else { 1 };
+}
 
-    for top_peer in top_peers.iter() {
-        if !currently_unchoked.contains(&top_peer) {
-            output_events.push(InternalEvent::UnchokePeer(*top_peer));
+// chokes the unchoked peers which hold neither a regular nor the optimistic slot and unchokes the ones which do
+fn assign_unchoke_slots(state: &mut ChokeState) -> Vec<InternalEvent> {
+    let now = Instant::now();
+    let mut output_events: Vec<InternalEvent> = vec![];
+    for peer in state.peers.values_mut() {
+        let is_selected = state.regular_unchokes.contains(&peer.idx) || state.optimistic_unchoke == Some(peer.idx);
+        if is_selected && peer.peer_choked_by_client {
+            peer.peer_choked_by_client = false;
+            peer.unchoked_at = Some(now);
+            output_events.push(InternalEvent::UnchokePeer(peer.idx));
+        } else if !is_selected && !peer.peer_choked_by_client {
+            peer.peer_choked_by_client = true;
+            peer.unchoked_at = None;
+            output_events.push(InternalEvent::ChokePeer(peer.idx));
         }
     }
 
-    peers.into_iter().for_each(|(_idx, peer)| peer.downloaded_blocks = 0);
-
     return output_events;
 }
 
-fn optimistic_unchoke(peers: &mut HashMap<usize, PeerState>) -> Vec<InternalEvent> {
-    return peers.iter()
-        .filter(|(_idx, peer)| peer.is_unchokeable())
-        .choose(&mut rand::thread_rng())
-        .map_or_else(|| vec![], |peer| vec![InternalEvent::UnchokePeer(*peer.0)]);
-}
-
 #[cfg(test)]
 mod tests {
-    use std::collections::HashMap;
-    use crate::choke::handler::handle;
-    use crate::choke::models::{ChokeEvent, PeerState};
+    use std::time::{Duration, Instant};
+    use crate::choke::handler::{handle, optimistic_unchoke_weight};
+    use crate::choke::models::{ChokeEvent, ChokeState};
     use crate::core_models::events::InternalEvent;
 
     #[test]
     fn test_handle_client_interested_in_peer() {
-        let mut peers = init_peers(3);
-        assert!(!peers.get(&1).unwrap().client_interested_in_peer);
-        handle(ChokeEvent::ClientInterestedInPeer(1, true), &mut peers);
-        assert!(peers.get(&1).unwrap().client_interested_in_peer);
+        let mut state = init_state(3);
+        assert!(!state.peers.get(&1).unwrap().client_interested_in_peer);
+        handle(ChokeEvent::ClientInterestedInPeer(1, true), &mut state);
+        assert!(state.peers.get(&1).unwrap().client_interested_in_peer);
     }
 
     #[test]
     fn test_handle_peer_interested_in_client() {
-        let mut peers = init_peers(3);
-        assert!(!peers.get(&1).unwrap().peer_interested_in_client);
-        handle(ChokeEvent::PeerInterestedInClient(1, true), &mut peers);
-        assert!(peers.get(&1).unwrap().peer_interested_in_client);
+        let mut state = init_state(3);
+        assert!(!state.peers.get(&1).unwrap().peer_interested_in_client);
+        handle(ChokeEvent::PeerInterestedInClient(1, true), &mut state);
+        assert!(state.peers.get(&1).unwrap().peer_interested_in_client);
     }
 
     #[test]
     fn test_handle_block_downloaded_from_peer() {
-        let mut peers = init_peers(3);
-        assert_eq!(peers.get(&1).unwrap().downloaded_blocks, 0);
-        handle(ChokeEvent::BlockDownloadedFromPeer(1), &mut peers);
-        assert_eq!(peers.get(&1).unwrap().downloaded_blocks, 1);
+        let mut state = init_state(3);
+        handle(ChokeEvent::BlockDownloadedFromPeer(1, 16384), &mut state);
+        assert_eq!(state.peers.get_mut(&1).unwrap().download_rate.bytes_per_sec(), 16384.0);
+    }
+
+    #[test]
+    fn test_handle_peer_snubbed() {
+        let mut state = init_state(3);
+        handle(ChokeEvent::PeerSnubbed(1, true), &mut state);
+        assert!(state.peers.get(&1).unwrap().is_snubbed);
+        handle(ChokeEvent::PeerSnubbed(1, false), &mut state);
+        assert!(!state.peers.get(&1).unwrap().is_snubbed);
+    }
+
+    #[test]
+    fn test_events_of_unregistered_peer_ignored() {
+        let mut state = init_state(3);
+        handle(ChokeEvent::UnregisterPeer(1), &mut state);
+
+        let events = handle(ChokeEvent::BlockDownloadedFromPeer(1, 16384), &mut state);
+        handle(ChokeEvent::PeerSnubbed(1, true), &mut state);
+
+        assert!(events.is_empty());
+        assert!(!state.peers.contains_key(&1));
+    }
+
+    #[test]
+    fn test_snubbed_peers_not_unchoked() {
+        let mut state = init_state(2);
+        state.peers.iter_mut().for_each(|(_idx, peer)| peer.client_interested_in_peer = true);
+        state.peers.get_mut(&0).unwrap().block_downloaded(10 * 16384);
+        state.peers.get_mut(&0).unwrap().is_snubbed = true;
+
+        let result = handle(ChokeEvent::UnchokePeers, &mut state);
+
+        assert_eq!(result, vec![InternalEvent::UnchokePeer(1)]);
     }
 
     #[test]
     fn test_handle_optimistic_unchoke() {
-        let mut peers = init_peers(3);
-        peers.get_mut(&0).unwrap().peer_choked_by_client = false;
-        peers.get_mut(&1).unwrap().peer_choked_by_client = false;
-        peers.get_mut(&2).unwrap().client_interested_in_peer = true;
-        peers.get_mut(&2).unwrap().peer_interested_in_client = true;
+        let mut state = init_state(3);
+        state.peers.get_mut(&0).unwrap().peer_choked_by_client = false;
+        state.peers.get_mut(&1).unwrap().peer_choked_by_client = false;
+        state.peers.get_mut(&2).unwrap().client_interested_in_peer = true;
+        state.peers.get_mut(&2).unwrap().peer_interested_in_client = true;
+        state.regular_unchokes = vec![0, 1];
 
-        let result = handle(ChokeEvent::OptimisticUnchoke, &mut peers);
+        let result = handle(ChokeEvent::OptimisticUnchoke, &mut state);
 
         assert_eq!(result.len(), 1);
         assert_eq!(result[0], InternalEvent::UnchokePeer(2));
+        assert_eq!(state.optimistic_unchoke, Some(2));
+    }
+
+    #[test]
+    fn test_optimistic_unchoke_rotates() {
+        let mut state = init_state(3);
+        state.peers.iter_mut().for_each(|(_idx, peer)| {
+            peer.client_interested_in_peer = true;
+            peer.peer_interested_in_client = true;
+        });
+        state.peers.get_mut(&0).unwrap().peer_choked_by_client = false;
+        state.optimistic_unchoke = Some(0);
+        state.peers.get_mut(&2).unwrap().peer_choked_by_client = false;
+        state.regular_unchokes = vec![2];
+
+        let result = handle(ChokeEvent::OptimisticUnchoke, &mut state);
+
+        assert_eq!(result.len(), 2);
+        assert!(result.contains(&InternalEvent::ChokePeer(0)));
+        assert!(result.contains(&InternalEvent::UnchokePeer(1)));
+        assert_eq!(state.optimistic_unchoke, Some(1));
+    }
+
+    #[test]
+    fn test_optimistic_unchoke_survives_unchoke_round() {
+        let mut state = init_state(2);
+        state.peers.iter_mut().for_each(|(_idx, peer)| peer.client_interested_in_peer = true);
+        state.peers.get_mut(&0).unwrap().is_snubbed = true;
+        state.peers.get_mut(&0).unwrap().peer_choked_by_client = false;
+        state.optimistic_unchoke = Some(0);
+
+        let result = handle(ChokeEvent::UnchokePeers, &mut state);
+
+        assert_eq!(result, vec![InternalEvent::UnchokePeer(1)]);
+        assert!(!state.peers.get(&0).unwrap().peer_choked_by_client);
+    }
+
+    #[test]
+    fn test_new_peers_weigh_more_in_optimistic_unchoke() {
+        let mut state = init_state(2);
+        handle(ChokeEvent::PeerConnected(0), &mut state);
+        state.peers.get_mut(&1).unwrap().connected_at = Some(Instant::now() - Duration::from_secs(600));
+
+        let now = Instant::now();
+        assert_eq!(optimistic_unchoke_weight(state.peers.get(&0).unwrap(), now), 3);
+        assert_eq!(optimistic_unchoke_weight(state.peers.get(&1).unwrap(), now), 1);
+    }
+
+    #[test]
+    fn test_upload_slot_opened_with_spare_capacity() {
+        let mut state = ChokeState::new(6, Some(1_000_000));
+        state.is_seeding = true;
+        state.peers.iter_mut().for_each(|(idx, peer)| {
+            peer.peer_interested_in_client = true;
+            peer.block_uploaded(idx * 16384);
+        });
+
+        let result = handle(ChokeEvent::UnchokePeers, &mut state);
+
+        assert_eq!(state.upload_slots.count, 5);
+        assert_eq!(result.len(), 5);
+        assert!(!result.contains(&InternalEvent::UnchokePeer(0)));
+    }
+
+    #[test]
+    fn test_unregister_optimistically_unchoked_peer() {
+        let mut state = init_state(2);
+        state.optimistic_unchoke = Some(1);
+        state.regular_unchokes = vec![0, 1];
+
+        handle(ChokeEvent::UnregisterPeer(1), &mut state);
+
+        assert_eq!(state.optimistic_unchoke, None);
+        assert_eq!(state.regular_unchokes, vec![0]);
     }
 
     #[test]
     fn test_handle_unchoke_peers() {
-        let mut peers = init_peers(10);
-        peers.iter_mut().for_each(|(_idx, peer)| {
+        let mut state = init_state(10);
+        state.peers.iter_mut().for_each(|(_idx, peer)| {
             peer.client_interested_in_peer = true;
         });
-        peers.get_mut(&0).unwrap().peer_choked_by_client = false;
-        peers.get_mut(&1).unwrap().peer_choked_by_client = false;
-        peers.get_mut(&2).unwrap().peer_choked_by_client = false;
-        peers.get_mut(&3).unwrap().peer_choked_by_client = false;
+        state.peers.get_mut(&0).unwrap().peer_choked_by_client = false;
+        state.peers.get_mut(&1).unwrap().peer_choked_by_client = false;
+        state.peers.get_mut(&2).unwrap().peer_choked_by_client = false;
+        state.peers.get_mut(&3).unwrap().peer_choked_by_client = false;
 
-        peers.get_mut(&0).unwrap().downloaded_blocks = 10;
-        peers.get_mut(&1).unwrap().downloaded_blocks = 10;
-        peers.get_mut(&4).unwrap().downloaded_blocks = 10;
-        peers.get_mut(&5).unwrap().downloaded_blocks = 10;
+        state.peers.get_mut(&0).unwrap().block_downloaded(10 * 16384);
+        state.peers.get_mut(&1).unwrap().block_downloaded(10 * 16384);
+        state.peers.get_mut(&4).unwrap().block_downloaded(10 * 16384);
+        state.peers.get_mut(&5).unwrap().block_downloaded(10 * 16384);
 
-        let result = handle(ChokeEvent::UnchokePeers, &mut peers);
+        let result = handle(ChokeEvent::UnchokePeers, &mut state);
 
         assert_eq!(result.len(), 4);
         assert!(result.contains(&InternalEvent::ChokePeer(2)));
         assert!(result.contains(&InternalEvent::ChokePeer(3)));
         assert!(result.contains(&InternalEvent::UnchokePeer(4)));
         assert!(result.contains(&InternalEvent::UnchokePeer(5)));
-        assert!(peers.iter().all(|(_idx, peer)| peer.downloaded_blocks == 0));
     }
 
-    fn init_peers(count: usize) -> HashMap<usize, PeerState> {
-        return (0..count).into_iter()
-            .map(|idx| (idx, PeerState::new(idx)))
-            .collect();
+    #[test]
+    fn test_handle_block_uploaded_to_peer() {
+        let mut state = init_state(3);
+        handle(ChokeEvent::BlockUploadedToPeer(1, 16384), &mut state);
+        assert!(state.peers.get_mut(&1).unwrap().upload_rate.bytes_per_sec() > 0.0);
+    }
+
+    #[test]
+    fn test_handle_seeding_state_changed() {
+        let mut state = init_state(3);
+        handle(ChokeEvent::SeedingStateChanged(true), &mut state);
+        assert!(state.is_seeding);
+    }
+
+    #[test]
+    fn test_unchoked_peers_are_tracked() {
+        let mut state = init_state(2);
+        state.peers.iter_mut().for_each(|(_idx, peer)| peer.client_interested_in_peer = true);
+
+        let result = handle(ChokeEvent::UnchokePeers, &mut state);
+        assert_eq!(result.len(), 2);
+        assert!(state.peers.values().all(|peer| !peer.peer_choked_by_client && peer.unchoked_at.is_some()));
+
+        let result = handle(ChokeEvent::UnchokePeers, &mut state);
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    fn test_seed_unchoke_ranks_by_upload_rate() {
+        // the upload is at capacity, so no slots get opened
+        let mut state = ChokeState::new(6, Some(100_000));
+        state.is_seeding = true;
+        state.peers.iter_mut().for_each(|(idx, peer)| {
+            peer.peer_interested_in_client = true;
+            peer.block_uploaded(idx * 16384);
+        });
+
+        let result = handle(ChokeEvent::UnchokePeers, &mut state);
+
+        assert_eq!(result.len(), 4);
+        for idx in 2..6 {
+            assert!(result.contains(&InternalEvent::UnchokePeer(idx)));
+        }
+    }
+
+    #[test]
+    fn test_seed_unchoke_favors_recently_unchoked_peers() {
+        // the upload is at capacity, so no slots get opened
+        let mut state = ChokeState::new(6, Some(100_000));
+        state.is_seeding = true;
+        state.peers.iter_mut().for_each(|(idx, peer)| {
+            peer.peer_interested_in_client = true;
+            peer.block_uploaded(idx * 16384);
+        });
+        let recently_unchoked = state.peers.get_mut(&0).unwrap();
+        recently_unchoked.peer_choked_by_client = false;
+        recently_unchoked.unchoked_at = Some(Instant::now());
+        let long_unchoked = state.peers.get_mut(&1).unwrap();
+        long_unchoked.peer_choked_by_client = false;
+        long_unchoked.unchoked_at = Some(Instant::now() - Duration::from_secs(60));
+
+        let result = handle(ChokeEvent::UnchokePeers, &mut state);
+
+        assert!(!state.peers.get(&0).unwrap().peer_choked_by_client);
+        assert!(result.contains(&InternalEvent::ChokePeer(1)));
+        assert!(result.contains(&InternalEvent::UnchokePeer(5)));
+        assert!(result.contains(&InternalEvent::UnchokePeer(4)));
+        assert!(result.contains(&InternalEvent::UnchokePeer(3)));
+    }
+
+    #[test]
+    fn test_seed_optimistic_unchoke_ignores_client_interest() {
+        let mut state = init_state(1);
+        state.is_seeding = true;
+        state.peers.get_mut(&0).unwrap().peer_interested_in_client = true;
+
+        let result = handle(ChokeEvent::OptimisticUnchoke, &mut state);
+
+        assert_eq!(result, vec![InternalEvent::UnchokePeer(0)]);
+    }
+
+    #[test]
+    fn test_leech_optimistic_unchoke_ignores_client_interest() {
+        let mut state = init_state(1);
+        handle(ChokeEvent::PeerConnected(0), &mut state);
+        handle(ChokeEvent::PeerInterestedInClient(0, true), &mut state);
+
+        let result = handle(ChokeEvent::OptimisticUnchoke, &mut state);
+        assert_eq!(result, vec![InternalEvent::UnchokePeer(0)]);
+
+        // the peer only keeps a regular slot once we are interested in it
+        state.optimistic_unchoke = None;
+        let result = handle(ChokeEvent::UnchokePeers, &mut state);
+        assert_eq!(result, vec![InternalEvent::ChokePeer(0)]);
+    }
+
+    fn init_state(count: usize) -> ChokeState {
+        return ChokeState::new(count, None);
     }
 }
\ No newline at end of file
@@ -1,19 +1,112 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use crate::config::DEFAULT_MAX_UPLOAD_SLOTS;
+use crate::transfer_rate::TransferRate;
+
+const TRANSFER_RATE_WINDOW_SECS: u64 = 20;
+const INITIAL_UPLOAD_SLOTS: usize = 4;
+const MIN_UPLOAD_SLOTS: usize = 2;
+// the upload has headroom while it stays below this share of the capacity
+const UPLOAD_HEADROOM_RATIO: f64 = 0.9;
+// without a known capacity, slots keep opening while each one grows the total upload by this ratio
+const UPLOAD_GROWTH_RATIO: f64 = 1.1;
+// a slot is closed when the throughput per slot falls below this share of the previous round's
+const PER_SLOT_RATE_DROP_RATIO: f64 = 0.75;
+
 pub enum ChokeEvent {
     UnchokePeers,
     OptimisticUnchoke,
     ClientInterestedInPeer(usize, bool),
     PeerInterestedInClient(usize, bool),
-    BlockDownloadedFromPeer(usize),
+    BlockDownloadedFromPeer(usize, usize),
+    BlockUploadedToPeer(usize, usize),
+    PeerConnected(usize),
+    PeerSnubbed(usize, bool),
+    SeedingStateChanged(bool),
     UnregisterPeer(usize),
 }
 
-#[derive(Debug, Eq, Hash, PartialEq)]
+#[derive(Debug, PartialEq)]
+pub struct ChokeState {
+    pub peers: HashMap<usize, PeerState>,
+    pub is_seeding: bool,
+    pub regular_unchokes: Vec<usize>,
+    pub optimistic_unchoke: Option<usize>,
+    pub upload_slots: UploadSlots,
+}
+
+impl ChokeState {
+    pub fn new(peer_transfers_count: usize, upload_capacity: Option<u64>) -> Self {
+        return ChokeState {
+            peers: (0..peer_transfers_count).map(|idx| (idx, PeerState::new(idx))).collect(),
+            is_seeding: false,
+            regular_unchokes: vec![],
+            optimistic_unchoke: None,
+            upload_slots: UploadSlots::new(upload_capacity),
+        };
+    }
+
+    pub fn with_max_upload_slots(mut self, max_slots: usize) -> Self {
+        self.upload_slots.max_count = max_slots;
+        self.upload_slots.count = self.upload_slots.count.min(max_slots);
+        return self;
+    }
+}
+
+// Number of regular unchoke slots, adjusted every unchoke round from the measured upload throughput.
+// Without a configured upload capacity, slots keep opening as long as each new one grows the total upload.
+#[derive(Debug, PartialEq)]
+pub struct UploadSlots {
+    pub count: usize,
+    max_count: usize,
+    capacity: Option<f64>,
+    previous_total_rate: f64,
+    previous_per_slot_rate: f64,
+}
+
+impl UploadSlots {
+    pub fn new(capacity: Option<u64>) -> Self {
+        return UploadSlots {
+            count: INITIAL_UPLOAD_SLOTS,
+            max_count: DEFAULT_MAX_UPLOAD_SLOTS,
+            capacity: capacity.map(|capacity| capacity as f64),
+            previous_total_rate: 0.0,
+            previous_per_slot_rate: 0.0,
+        };
+    }
+
+    // `saturated` tells whether more peers compete for the slots than there are slots
+    pub fn adjust(&mut self, total_rate: f64, unchoked_peers: usize, saturated: bool) {
+        let per_slot_rate = if unchoked_peers == 0 { 0.0 } else { total_rate / unchoked_peers as f64 };
+        let has_headroom = match self.capacity {
+            Some(capacity) => total_rate < capacity * UPLOAD_HEADROOM_RATIO,
+            None => total_rate > self.previous_total_rate * UPLOAD_GROWTH_RATIO,
+        };
+        let per_slot_rate_dropped = per_slot_rate < self.previous_per_slot_rate * PER_SLOT_RATE_DROP_RATIO
+            && total_rate <= self.previous_total_rate;
+
+        if saturated && has_headroom && self.count < self.max_count {
+            self.count += 1;
+        } else if per_slot_rate_dropped && self.count > MIN_UPLOAD_SLOTS {
+            self.count -= 1;
+        }
+
+        self.previous_total_rate = total_rate;
+        self.previous_per_slot_rate = per_slot_rate;
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
 pub struct PeerState {
     pub idx: usize,
     pub peer_choked_by_client: bool,
     pub client_interested_in_peer: bool,
     pub peer_interested_in_client: bool,
-    pub downloaded_blocks: usize,
+    pub download_rate: TransferRate,
+    pub upload_rate: TransferRate,
+    pub unchoked_at: Option<Instant>,
+    pub connected_at: Option<Instant>,
+    pub is_snubbed: bool,
 }
 
 impl PeerState {
@@ -23,16 +116,78 @@ impl PeerState {
             peer_choked_by_client: true,
             client_interested_in_peer: false,
             peer_interested_in_client: false,
-            downloaded_blocks: 0,
+            download_rate: TransferRate::new(Duration::from_secs(TRANSFER_RATE_WINDOW_SECS)),
+            upload_rate: TransferRate::new(Duration::from_secs(TRANSFER_RATE_WINDOW_SECS)),
+            unchoked_at: None,
+            connected_at: None,
+            is_snubbed: false,
         };
     }
 
-    pub fn is_unchokeable(&self) -> bool {
-        return self.peer_choked_by_client && self.peer_interested_in_client && self.client_interested_in_peer;
+    // an optimistic unchoke lets peers we are not interested in yet earn a regular slot, e.g. new peers with no pieces
+    pub fn is_optimistic_candidate(&self) -> bool {
+        return self.peer_choked_by_client && self.peer_interested_in_client;
+    }
+
+    pub fn block_downloaded(&mut self, bytes: usize) {
+        self.download_rate.record(bytes);
+    }
+
+    pub fn block_uploaded(&mut self, bytes: usize) {
+        self.upload_rate.record(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::choke::models::{INITIAL_UPLOAD_SLOTS, MIN_UPLOAD_SLOTS, UploadSlots};
+
+    #[test]
+    fn test_slot_opened_when_saturated_with_headroom() {
+        let mut slots = UploadSlots::new(Some(100_000));
+        slots.adjust(50_000.0, INITIAL_UPLOAD_SLOTS, true);
+        assert_eq!(slots.count, INITIAL_UPLOAD_SLOTS + 1);
+    }
+
+    #[test]
+    fn test_slot_kept_without_headroom() {
+        let mut slots = UploadSlots::new(Some(100_000));
+        slots.adjust(95_000.0, INITIAL_UPLOAD_SLOTS, true);
+        assert_eq!(slots.count, INITIAL_UPLOAD_SLOTS);
+    }
+
+    #[test]
+    fn test_slot_kept_when_not_saturated() {
+        let mut slots = UploadSlots::new(Some(100_000));
+        slots.adjust(10_000.0, 2, false);
+        assert_eq!(slots.count, INITIAL_UPLOAD_SLOTS);
+    }
+
+    #[test]
+    fn test_slots_open_while_upload_grows_without_capacity() {
+        let mut slots = UploadSlots::new(None);
+        slots.adjust(40_000.0, INITIAL_UPLOAD_SLOTS, true);
+        assert_eq!(slots.count, INITIAL_UPLOAD_SLOTS + 1);
+        slots.adjust(50_000.0, INITIAL_UPLOAD_SLOTS + 1, true);
+        assert_eq!(slots.count, INITIAL_UPLOAD_SLOTS + 2);
+        slots.adjust(50_000.0, INITIAL_UPLOAD_SLOTS + 2, true);
+        assert_eq!(slots.count, INITIAL_UPLOAD_SLOTS + 2);
+    }
+
+    #[test]
+    fn test_slot_closed_when_per_slot_rate_drops() {
+        let mut slots = UploadSlots::new(Some(100_000));
+        slots.adjust(95_000.0, INITIAL_UPLOAD_SLOTS, false);
+        slots.adjust(40_000.0, INITIAL_UPLOAD_SLOTS, false);
+        assert_eq!(slots.count, INITIAL_UPLOAD_SLOTS - 1);
     }
 
-    pub fn block_downloaded(&mut self) {
-        self.downloaded_blocks += 1;
+    #[test]
+    fn test_slots_never_below_minimum() {
+        let mut slots = UploadSlots::new(Some(100_000));
+        for rate in [90_000.0, 40_000.0, 10_000.0, 2_000.0, 100.0] {
+            slots.adjust(rate, slots.count, false);
+        }
+        assert_eq!(slots.count, MIN_UPLOAD_SLOTS);
     }
-    
 }
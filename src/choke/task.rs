@@ -1,22 +1,20 @@
-use std::collections::HashMap;
 use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::task::JoinHandle;
 use tokio::time;
 use crate::choke::handler;
-use crate::choke::models::{ChokeEvent, PeerState};
+use crate::choke::models::{ChokeEvent, ChokeState};
+use crate::config::Config;
 use crate::core_models::events::InternalEvent;
 
-const CHANGE_UNCHOKED_PEERS_INTERVAL_SECS: u64 = 10;
-const OPTIMISTIC_UNCHOKE_INTERVAL_SECS: u64 = 30;
-
-pub fn spawn(output_tx: Sender<InternalEvent>, peer_transfers_count: usize)
+pub fn spawn(output_tx: Sender<InternalEvent>, peer_transfers_count: usize, config: &Config)
                -> (JoinHandle<()>, Sender<ChokeEvent>) {
-    let (tx_to_self, rx) = mpsc::channel::<ChokeEvent>(1024);
+    let (tx_to_self, rx) = mpsc::channel::<ChokeEvent>(config.channel_capacity);
     let tx_to_self_clone = tx_to_self.clone();
+    let config = config.clone();
     let handle = tokio::spawn(async move {
-        return run(output_tx, tx_to_self_clone, rx, peer_transfers_count).await;
+        return run(output_tx, tx_to_self_clone, rx, peer_transfers_count, config).await;
     });
 
     return (handle, tx_to_self);
@@ -25,34 +23,42 @@ pub fn spawn(output_tx: Sender<InternalEvent>, peer_transfers_count: usize)
 async fn run(output_tx: Sender<InternalEvent>,
              tx_to_self: Sender<ChokeEvent>,
              mut rx: Receiver<ChokeEvent>,
-             peer_transfers_count: usize) {
-    let mut peers: HashMap<usize, PeerState> = (0..peer_transfers_count).into_iter()
-        .map(|idx| (idx, PeerState::new(idx)))
-        .collect();
+             peer_transfers_count: usize,
+             config: Config) {
+    // a global upload limit caps the capacity when none is configured
+    let upload_capacity = config.upload_capacity_bytes_per_sec.or(config.rate_limits.upload_bytes_per_sec);
+    let mut state = ChokeState::new(peer_transfers_count, upload_capacity)
+        .with_max_upload_slots(config.max_upload_slots);
 
-    tokio::spawn(unchoke_peers_scheduler(tx_to_self.clone()));
-    tokio::spawn(optimistic_unchoke_scheduler(tx_to_self));
+    tokio::spawn(unchoke_peers_scheduler(tx_to_self.clone(), config.unchoke_interval_secs));
+    tokio::spawn(optimistic_unchoke_scheduler(tx_to_self, config.optimistic_unchoke_interval_secs));
 
     while let Some(event) = rx.recv().await {
-        let internal_events = handler::handle(event, &mut peers);
+        let internal_events = handler::handle(event, &mut state);
         for event in internal_events {
-            output_tx.send(event).await.unwrap();
+            if output_tx.send(event).await.is_err() {
+                return;
+            }
         }
     }
 }
 
-async fn unchoke_peers_scheduler(tx: Sender<ChokeEvent>) {
-    let mut interval = time::interval(Duration::from_secs(CHANGE_UNCHOKED_PEERS_INTERVAL_SECS));
+async fn unchoke_peers_scheduler(tx: Sender<ChokeEvent>, interval_secs: u64) {
+    let mut interval = time::interval(Duration::from_secs(interval_secs));
     loop {
         interval.tick().await;
-        tx.send(ChokeEvent::UnchokePeers).await.unwrap();
+        if tx.send(ChokeEvent::UnchokePeers).await.is_err() {
+            break;
+        }
     }
 }
 
-async fn optimistic_unchoke_scheduler(tx: Sender<ChokeEvent>) {
-    let mut interval = time::interval(Duration::from_secs(OPTIMISTIC_UNCHOKE_INTERVAL_SECS));
+async fn optimistic_unchoke_scheduler(tx: Sender<ChokeEvent>, interval_secs: u64) {
+    let mut interval = time::interval(Duration::from_secs(interval_secs));
     loop {
         interval.tick().await;
-        tx.send(ChokeEvent::OptimisticUnchoke).await.unwrap();
+        if tx.send(ChokeEvent::OptimisticUnchoke).await.is_err() {
+            break;
+        }
     }
 }
\ No newline at end of file
@@ -1,34 +1,319 @@
-use log::LevelFilter;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use log::{LevelFilter, warn};
 use rand::distributions::Alphanumeric;
 use rand::Rng;
+use crate::p2p::peer_client::ClientFilter;
+use crate::rate_limiter::RateLimits;
 
-const CLIENT_ID_PREFIX: &str = "XX0001x";
+// Azureus style: client code `RC` and version 0.1.0.0, decoded by `PeerClient::from_peer_id`
+const CLIENT_ID_PREFIX: &str = "-RC0100-";
+const CLIENT_ID_LEN: usize = 20;
 pub const BLOCK_SIZE_BYTES: usize = 16384;
+pub const DEFAULT_MAX_QUEUE_DEPTH: usize = 500;
+pub const DEFAULT_MAX_UPLOAD_SLOTS: usize = 50;
+// environment variables overriding settings are named by this prefix and the upper case setting name
+pub const ENV_PREFIX: &str = "TORRENT_CLIENT_";
+// `--` flags the command line handles itself, any other flag has to be a setting
+pub const COMMAND_LINE_FLAGS: [&str; 6] = ["config", "daemon", "no-tui", "sequential", "file-priority", "range-priority"];
 
-#[derive(Clone)]
+#[derive(Debug, Eq, PartialEq)]
+pub enum ConfigError {
+    FileNotRead(String),
+    FileNotParsed(String),
+    UnknownSetting(String),
+    InvalidValue(String, String),
+    Invalid(String),
+}
+
+#[derive(Clone, Debug)]
 pub struct Config {
     pub listening_port: u16,
     pub client_id: String,
+    pub log_level: LevelFilter,
+    pub rate_limits: RateLimits,
+    pub peer_rate_limits: RateLimits,
+    pub upload_capacity_bytes_per_sec: Option<u64>,
+    pub max_connections: usize,
+    // directory the downloaded files are written to
+    pub download_dir: PathBuf,
+    // port of the control API, which only listens on the loopback interface
+    pub rpc_port: u16,
+    // alerts buffered for each subscriber before the oldest ones are dropped
+    pub alert_buffer_size: usize,
+    // port metrics are served on for scraping, on all interfaces; not served when none
+    pub metrics_port: Option<u16>,
+    // upper bound of the requests kept outstanding with a single peer
+    pub max_queue_depth: usize,
+    pub max_upload_slots: usize,
+    pub unchoke_interval_secs: u64,
+    pub optimistic_unchoke_interval_secs: u64,
+    pub keep_alive_interval_secs: u64,
+    // applies to connecting to peers as well as to receiving the handshake of incoming connections
+    pub connect_timeout_secs: u64,
+    // number of peers asked from the tracker
+    pub numwant: usize,
+    // capacity of the channels between the tasks of a torrent
+    pub channel_capacity: usize,
+    // capacity of the channel of every p2p task, which receives all the messages of its peer
+    pub peer_channel_capacity: usize,
+    // peers are refused by the client software they run, as far as it is known
+    pub client_filter: ClientFilter,
+    // blocklist of the IP ranges peers are refused from, reloaded whenever the file changes
+    pub ip_filter_path: Option<PathBuf>,
+    // time given to each step of stopping a transfer: flushing the storage and the stopped announce
+    pub shutdown_timeout_secs: u64,
+    // whether completed torrents keep uploading to their peers until they are stopped
+    pub seed: bool,
 }
 
-impl Config {
-    pub fn init() -> Self {
-        env_logger::Builder::new()
-            .filter_level(LevelFilter::Info)
-            .target(env_logger::Target::Stderr).init();
+impl Default for Config {
+    fn default() -> Self {
         return Config {
             listening_port: 42000,
-            client_id: Config::generate_client_id(),
+            client_id: Config::generate_client_id(CLIENT_ID_PREFIX),
+            log_level: LevelFilter::Info,
+            rate_limits: RateLimits::default(),
+            peer_rate_limits: RateLimits::default(),
+            upload_capacity_bytes_per_sec: None,
+            max_connections: 200,
+            download_dir: PathBuf::from("."),
+            rpc_port: 42001,
+            alert_buffer_size: 1024,
+            metrics_port: None,
+            max_queue_depth: DEFAULT_MAX_QUEUE_DEPTH,
+            max_upload_slots: DEFAULT_MAX_UPLOAD_SLOTS,
+            unchoke_interval_secs: 10,
+            optimistic_unchoke_interval_secs: 30,
+            keep_alive_interval_secs: 50,
+            connect_timeout_secs: 10,
+            numwant: 300,
+            channel_capacity: 1024,
+            peer_channel_capacity: 8192,
+            client_filter: ClientFilter::default(),
+            ip_filter_path: None,
+            shutdown_timeout_secs: 5,
+            seed: true,
         };
     }
+}
+
+impl Config {
+    // Layers the settings of the TOML file, then the environment variables, then the `--<setting>=<value>`
+    // args over the defaults. Args without the `--` prefix and the command line flags are left to the caller.
+    // Other environments may share the prefix, so unknown variables are only logged, while unknown flags are
+    // most likely typos and rejected.
+    pub fn load(file: Option<&Path>,
+                env: impl IntoIterator<Item=(String, String)>,
+                args: &[String]) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        if let Some(file) = file {
+            let contents = std::fs::read_to_string(file)
+                .map_err(|err| ConfigError::FileNotRead(format!("{}: {}", file.display(), err)))?;
+            config.apply_toml(&contents)?;
+        }
+        for (name, value) in env {
+            let Some(key) = name.strip_prefix(ENV_PREFIX) else { continue; };
+            match config.set(&key.to_lowercase(), &value) {
+                Err(ConfigError::UnknownSetting(key)) => warn!("Ignoring unknown setting {} of variable {}", key, name),
+                result => result?,
+            }
+        }
+        for arg in args {
+            let Some(arg) = arg.strip_prefix("--") else { continue; };
+            let (flag, value) = arg.split_once('=').unwrap_or((arg, ""));
+            if !COMMAND_LINE_FLAGS.contains(&flag) {
+                config.set(&flag.replace('-', "_"), value)?;
+            }
+        }
+
+        config.validate()?;
+        return Ok(config);
+    }
+
+    pub fn apply_toml(&mut self, contents: &str) -> Result<(), ConfigError> {
+        let table: toml::Table = contents.parse().map_err(|err: toml::de::Error| ConfigError::FileNotParsed(err.to_string()))?;
+        for (key, value) in table {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(value) => value.to_string(),
+                toml::Value::Boolean(value) => value.to_string(),
+                value => return Err(ConfigError::InvalidValue(key, value.to_string())),
+            };
+            self.set(&key, &value)?;
+        }
+
+        return Ok(());
+    }
+
+    // Sets a single setting from its textual value; rate limits are given in KiB/s, 0 meaning no limit
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        let invalid = || ConfigError::InvalidValue(key.to_string(), value.to_string());
+        match key {
+            "listening_port" => self.listening_port = parse(value).ok_or_else(invalid)?,
+            "client_id_prefix" => self.client_id = Config::generate_client_id(value),
+            "log_level" => self.log_level = parse(value).ok_or_else(invalid)?,
+            "download_limit" => self.rate_limits.download_bytes_per_sec = parse_kib(value).ok_or_else(invalid)?,
+            "upload_limit" => self.rate_limits.upload_bytes_per_sec = parse_kib(value).ok_or_else(invalid)?,
+            "peer_download_limit" => self.peer_rate_limits.download_bytes_per_sec = parse_kib(value).ok_or_else(invalid)?,
+            "peer_upload_limit" => self.peer_rate_limits.upload_bytes_per_sec = parse_kib(value).ok_or_else(invalid)?,
+            "upload_capacity" => self.upload_capacity_bytes_per_sec = parse_kib(value).ok_or_else(invalid)?,
+            "max_connections" => self.max_connections = parse(value).ok_or_else(invalid)?,
+            "download_dir" => self.download_dir = PathBuf::from(value),
+            "rpc_port" => self.rpc_port = parse(value).ok_or_else(invalid)?,
+            "alert_buffer_size" => self.alert_buffer_size = parse(value).ok_or_else(invalid)?,
+            "metrics_port" => self.metrics_port = Some(parse(value).ok_or_else(invalid)?),
+            "max_queue_depth" => self.max_queue_depth = parse(value).ok_or_else(invalid)?,
+            "max_upload_slots" => self.max_upload_slots = parse(value).ok_or_else(invalid)?,
+            "unchoke_interval_secs" => self.unchoke_interval_secs = parse(value).ok_or_else(invalid)?,
+            "optimistic_unchoke_interval_secs" => self.optimistic_unchoke_interval_secs = parse(value).ok_or_else(invalid)?,
+            "keep_alive_interval_secs" => self.keep_alive_interval_secs = parse(value).ok_or_else(invalid)?,
+            "connect_timeout_secs" => self.connect_timeout_secs = parse(value).ok_or_else(invalid)?,
+            "numwant" => self.numwant = parse(value).ok_or_else(invalid)?,
+            "channel_capacity" => self.channel_capacity = parse(value).ok_or_else(invalid)?,
+            "peer_channel_capacity" => self.peer_channel_capacity = parse(value).ok_or_else(invalid)?,
+            "blocked_clients" => self.client_filter.blocked = ClientFilter::parse_rules(value).map_err(|_| invalid())?,
+            "allowed_clients" => self.client_filter.allowed = ClientFilter::parse_rules(value).map_err(|_| invalid())?,
+            "ip_filter" => self.ip_filter_path = Some(PathBuf::from(value)),
+            "shutdown_timeout_secs" => self.shutdown_timeout_secs = parse(value).ok_or_else(invalid)?,
+            "seed" => self.seed = parse(value).ok_or_else(invalid)?,
+            _ => return Err(ConfigError::UnknownSetting(key.to_string())),
+        }
+
+        return Ok(());
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let positive = [
+            ("max_connections", self.max_connections as u64),
+            ("alert_buffer_size", self.alert_buffer_size as u64),
+            ("max_queue_depth", self.max_queue_depth as u64),
+            ("unchoke_interval_secs", self.unchoke_interval_secs),
+            ("optimistic_unchoke_interval_secs", self.optimistic_unchoke_interval_secs),
+            ("keep_alive_interval_secs", self.keep_alive_interval_secs),
+            ("connect_timeout_secs", self.connect_timeout_secs),
+            ("channel_capacity", self.channel_capacity as u64),
+            ("peer_channel_capacity", self.peer_channel_capacity as u64),
+        ];
+        if let Some((key, _)) = positive.iter().find(|(_, value)| *value == 0) {
+            return Err(ConfigError::Invalid(format!("{} must be greater than 0", key)));
+        }
+        if self.client_id.len() != CLIENT_ID_LEN || !self.client_id.is_ascii() {
+            return Err(ConfigError::Invalid(format!("client id prefix must be ASCII and shorter than {} characters", CLIENT_ID_LEN)));
+        }
+        if self.max_upload_slots < 2 {
+            return Err(ConfigError::Invalid("max_upload_slots must be at least 2".to_string()));
+        }
+        if self.listening_port != 0 && (Some(self.listening_port) == self.metrics_port || self.listening_port == self.rpc_port) {
+            return Err(ConfigError::Invalid("listening_port is taken by the rpc or metrics port".to_string()));
+        }
+
+        return Ok(());
+    }
 
-    fn generate_client_id() -> String {
+    fn generate_client_id(prefix: &str) -> String {
         let suffix: String = rand::thread_rng()
             .sample_iter(Alphanumeric)
-            .take(20 - CLIENT_ID_PREFIX.len())
+            .take(CLIENT_ID_LEN.saturating_sub(prefix.len()))
             .map(char::from)
             .collect();
 
-        return format!("{}{}", CLIENT_ID_PREFIX, suffix);
+        return format!("{}{}", prefix, suffix);
+    }
+}
+
+fn parse<T: FromStr>(value: &str) -> Option<T> {
+    return value.trim().parse().ok();
+}
+
+fn parse_kib(value: &str) -> Option<Option<u64>> {
+    let kib_per_sec: u64 = parse(value)?;
+    return Some(if kib_per_sec == 0 { None } else { Some(kib_per_sec * 1024) });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use log::LevelFilter;
+    use crate::config::{Config, ConfigError};
+
+    #[test]
+    fn test_layers_override_in_order() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(&mut file, b"listening_port = 6881\nnumwant = 50\ndownload_limit = 100\nseed = false\n").unwrap();
+        let env = vec![
+            ("TORRENT_CLIENT_NUMWANT".to_string(), "80".to_string()),
+            ("TORRENT_CLIENT_LOG_LEVEL".to_string(), "debug".to_string()),
+            ("PATH".to_string(), "/usr/bin".to_string()),
+        ];
+        let args = vec!["--log-level=warn".to_string(), "--sequential".to_string(), "--download-dir=/tmp/out".to_string()];
+
+        let config = Config::load(Some(file.path()), env, &args).unwrap();
+
+        assert_eq!(config.listening_port, 6881);
+        assert_eq!(config.numwant, 80);
+        assert_eq!(config.log_level, LevelFilter::Warn);
+        assert_eq!(config.download_dir, PathBuf::from("/tmp/out"));
+        assert_eq!(config.rate_limits.download_bytes_per_sec, Some(100 * 1024));
+        assert!(!config.seed);
+    }
+
+    #[test]
+    fn test_unknown_setting_in_file_rejected() {
+        let mut config = Config::default();
+
+        assert_eq!(config.apply_toml("max_peers = 3"), Err(ConfigError::UnknownSetting("max_peers".to_string())));
+        assert!(matches!(config.apply_toml("numwant = "), Err(ConfigError::FileNotParsed(_))));
+    }
+
+    #[test]
+    fn test_invalid_values_rejected() {
+        let env = vec![("TORRENT_CLIENT_LISTENING_PORT".to_string(), "70000".to_string())];
+        let zero_interval = vec!["--unchoke-interval-secs=0".to_string()];
+        let long_prefix = vec!["--client-id-prefix=-XX0001-abcdefghijklmn".to_string()];
+
+        assert!(matches!(Config::load(None, env, &[]), Err(ConfigError::InvalidValue(_, _))));
+        assert!(matches!(Config::load(None, vec![], &zero_interval), Err(ConfigError::Invalid(_))));
+        assert!(matches!(Config::load(None, vec![], &long_prefix), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_unknown_env_variable_ignored() {
+        let env = vec![
+            ("TORRENT_CLIENT_MAX_PEERS".to_string(), "3".to_string()),
+            ("TORRENT_CLIENT_NUMWANT".to_string(), "80".to_string()),
+        ];
+
+        let config = Config::load(None, env, &[]).unwrap();
+
+        assert_eq!(config.numwant, 80);
+    }
+
+    #[test]
+    fn test_unknown_flag_rejected() {
+        let args = vec!["--daemon".to_string(), "--file-priority=0:skip".to_string(), "--dowload-dir=/tmp/out".to_string()];
+        let switch = vec!["--sequentail".to_string()];
+
+        assert_eq!(Config::load(None, vec![], &args).unwrap_err(), ConfigError::UnknownSetting("dowload_dir".to_string()));
+        assert_eq!(Config::load(None, vec![], &switch).unwrap_err(), ConfigError::UnknownSetting("sequentail".to_string()));
+    }
+
+    #[test]
+    fn test_client_rules_parsed() {
+        let mut config = Config::default();
+
+        config.apply_toml("blocked_clients = \"Xunlei, qBittorrent -4.1.9\"").unwrap();
+
+        assert_eq!(config.client_filter.blocked.len(), 2);
+        assert!(config.client_filter.allowed.is_empty());
+        assert!(matches!(config.set("allowed_clients", "Transmission 4.a"), Err(ConfigError::InvalidValue(_, _))));
+    }
+
+    #[test]
+    fn test_client_id_generated_from_prefix() {
+        let config = Config::load(None, vec![], &["--client-id-prefix=-AB1234-".to_string()]).unwrap();
+
+        assert!(config.client_id.starts_with("-AB1234-"));
+        assert_eq!(config.client_id.len(), 20);
     }
 }
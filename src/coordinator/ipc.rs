@@ -1,107 +1,582 @@
 use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+use std::time::Duration;
+use log::warn;
 use tokio::sync::mpsc::{Sender, Receiver};
+use prometheus::IntGauge;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio::time::{timeout_at, Instant};
+use crate::alerts::{Alert, Alerts};
 use crate::choke::models::ChokeEvent;
-use crate::core_models::entities::DataBlock;
+use crate::coordinator::stats::{PeerStats, TorrentStats, TorrentStatus};
+use crate::core_models::entities::{Bitfield, DataBlock, Peer};
+use crate::coordinator::task::TransferError;
 use crate::core_models::events::InternalEvent;
-use crate::p2p::models::P2PEvent;
+use crate::dependency_provider::TransferDeps;
+use crate::metrics::{GaugeGuard, PAYLOAD};
+use crate::p2p;
+use crate::p2p::conn::IncomingConnection;
+use crate::p2p::models::{P2PError, P2PEvent};
+use crate::rpc::json_rpc::encode_info_hash;
 use crate::tracker::task::TrackerEvent;
 
-struct PeerTransfer {
+// How often the depths of the queues of the transfer are sampled for the metrics
+const QUEUE_DEPTH_SAMPLE_INTERVAL_SECS: u64 = 1;
+
+pub struct PeerTransfer {
+    peer: Peer,
+    handle: JoinHandle<Result<(), P2PError>>,
     tx: Sender<P2PEvent>,
     is_connected: bool,
 }
 
 impl PeerTransfer {
-    fn new(tx: Sender<P2PEvent>) -> Self {
+    pub fn new(peer: Peer, handle: JoinHandle<Result<(), P2PError>>, tx: Sender<P2PEvent>) -> Self {
         return PeerTransfer {
+            peer,
+            handle,
             tx,
             is_connected: false,
         };
     }
 }
 
+// Senders to the other tasks of the transfer
+pub struct TaskChannels {
+    pub choke_tx: Sender<ChokeEvent>,
+    pub data_collector_tx: Sender<(Ipv4Addr, DataBlock)>,
+    pub tracker_tx: Sender<TrackerEvent>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum TransferOutcome {
+    Completed,
+    Stopped,
+}
 
-pub async fn broadcast_events(pieces_count: usize,
+pub async fn broadcast_events(deps: Arc<dyn TransferDeps>,
                               mut rx: Receiver<InternalEvent>,
-                              choke_tx: Sender<ChokeEvent>,
-                              data_collector_tx: Sender<DataBlock>,
-                              p2p_tx: Vec<(usize, Sender<P2PEvent>)>,
-                              tracker_tx: Sender<TrackerEvent>,
-) {
-    let mut p2p_transfers: HashMap<usize, PeerTransfer> = p2p_tx.into_iter()
-        .map(|(idx, tx)| (idx, PeerTransfer::new(tx)))
-        .collect();
-
-    let mut stored_pieces = 0;
-    let mut connected_peers = 0;
-
-    while let Some(event) = rx.recv().await {
-        match event {
-            InternalEvent::BlockDownloaded(transfer_idx, block) => {
-                choke_tx.send(ChokeEvent::BlockDownloadedFromPeer(transfer_idx)).await.unwrap();
-                tracker_tx.send(TrackerEvent::Downloaded(block.data.len() as u64)).await.unwrap();
-                data_collector_tx.send(block).await.unwrap();
-            }
-            InternalEvent::BlockStored(block) => {
-                for peer in p2p_transfers.values().filter(|peer| peer.is_connected) {
-                    let _ = peer.tx.send(P2PEvent::BlockStored(block.clone())).await;
+                              mut incoming_rx: Receiver<IncomingConnection>,
+                              tasks: TaskChannels,
+                              p2p_transfers: Vec<(usize, PeerTransfer)>,
+                              mut client_bitfield: Bitfield,
+) -> Result<TransferOutcome, TransferError> {
+    let TaskChannels { choke_tx, data_collector_tx, tracker_tx } = tasks;
+    let pieces_count = deps.torrent_layout().pieces;
+    let seed = deps.client_config().seed;
+    let stats_tx = deps.stats_tx();
+    let alerts = deps.alerts();
+    let info_hash = deps.info_hash();
+    let metrics = deps.metrics();
+    let picker = deps.piece_picker();
+    let banned_peers = deps.banned_peers();
+    let mut endgame_guard: Option<GaugeGuard> = None;
+    // a torrent which starts out complete seeds right away, without announcing its completion
+    let mut is_seeding = (0..pieces_count).all(|piece_idx| client_bitfield.has_piece(piece_idx));
+    // all the wanted pieces are stored; with pieces skipped, this happens before the torrent can seed
+    let mut is_finished = is_seeding;
+    if is_seeding {
+        if !seed {
+            set_status(&stats_tx, &alerts, &info_hash, TorrentStatus::Completed);
+            close_transfers(p2p_transfers.into_iter().map(|(_, transfer)| transfer), deps.as_ref()).await;
+            return Ok(TransferOutcome::Completed);
+        }
+        send_to_task(&choke_tx, ChokeEvent::SeedingStateChanged(true), "choke").await?;
+    }
+
+    // names may be shared between torrents, info hashes are not
+    let torrent_label = encode_info_hash(&info_hash);
+    let queue_depths = QueueDepths {
+        coordinator: metrics.queue_depth.with_label_values(&[&torrent_label, "coordinator"]),
+        choke: metrics.queue_depth.with_label_values(&[&torrent_label, "choke"]),
+        data_collector: metrics.queue_depth.with_label_values(&[&torrent_label, "data_collector"]),
+        tracker: metrics.queue_depth.with_label_values(&[&torrent_label, "tracker"]),
+    };
+    // sampled on a timer, so that the depths stay current while no event arrives
+    let mut queue_depth_interval = time::interval(Duration::from_secs(QUEUE_DEPTH_SAMPLE_INTERVAL_SECS));
+    let mut next_transfer_idx = p2p_transfers.len();
+    let mut p2p_transfers: HashMap<usize, PeerTransfer> = p2p_transfers.into_iter().collect();
+    let mut is_paused = false;
+
+    set_status(&stats_tx, &alerts, &info_hash, active_status(is_finished));
+    stats_tx.send_modify(|stats| {
+        stats.peers = p2p_transfers.iter()
+            .map(|(idx, transfer)| (*idx, PeerStats::new(transfer.peer.clone())))
+            .collect();
+    });
+
+    // the channels of the other tasks only close when they failed, which ends the transfer
+    let result = async {
+        loop {
+            let event = tokio::select! {
+                event = rx.recv() => event,
+                _ = queue_depth_interval.tick() => {
+                    queue_depths.coordinator.set(rx.len() as i64);
+                    queue_depths.choke.set(queued_events(&choke_tx));
+                    queue_depths.data_collector.set(queued_events(&data_collector_tx));
+                    queue_depths.tracker.set(queued_events(&tracker_tx));
+                    continue;
                 }
-            }
-            InternalEvent::DownloadComplete => {
-                tracker_tx.send(TrackerEvent::CompletedAnnounce).await.unwrap();
-                break;
-            }
-            InternalEvent::PieceStored(piece_idx) => {
-                for (_, peer) in p2p_transfers.iter() {
-                    let _ = peer.tx.send(P2PEvent::PieceStored(piece_idx)).await;
+                Some(connection) = incoming_rx.recv() => {
+                    if banned_peers.is_banned(&connection.peer.ip) {
+                        continue;
+                    }
+                    let peer = connection.peer.clone();
+                    let (handle, tx) = p2p::task::spawn_incoming(
+                        connection, next_transfer_idx, client_bitfield.clone(), deps.clone(),
+                    );
+                    if is_paused {
+                        let _ = tx.send(P2PEvent::Pause).await;
+                    }
+                    stats_tx.send_modify(|stats| { stats.peers.insert(next_transfer_idx, PeerStats::new(peer.clone())); });
+                    p2p_transfers.insert(next_transfer_idx, PeerTransfer::new(peer, handle, tx));
+                    next_transfer_idx += 1;
+                    continue;
                 }
-                stored_pieces += 1;
-                print_transfer_state(connected_peers, stored_pieces, pieces_count);
-            }
-            InternalEvent::P2PTransferTerminated(transfer_idx) => {
-                let transfer = p2p_transfers.remove(&transfer_idx);
-                choke_tx.send(ChokeEvent::UnregisterPeer(transfer_idx)).await.unwrap();
-                if let Some(p2p_transfer) = transfer {
-                    if p2p_transfer.is_connected {
-                        connected_peers -= 1;
-                        print_transfer_state(connected_peers, stored_pieces, pieces_count);
+            };
+            let Some(event) = event else { return Ok(TransferOutcome::Stopped); };
+
+            match event {
+                InternalEvent::BlockDownloaded(transfer_idx, block) => {
+                    metrics.downloaded_bytes.with_label_values(&[PAYLOAD]).inc_by(block.data.len() as u64);
+                    stats_tx.send_modify(|stats| stats.block_downloaded(transfer_idx, block.piece_idx, block.data.len()));
+                    send_to_task(&choke_tx, ChokeEvent::BlockDownloadedFromPeer(transfer_idx, block.data.len()), "choke").await?;
+                    send_to_task(&tracker_tx, TrackerEvent::Downloaded(block.data.len() as u64), "tracker").await?;
+                    // blocks are attributed to the address of their sender, which outlives the transfer
+                    let Some(sender) = p2p_transfers.get(&transfer_idx).map(|transfer| transfer.peer.ip) else {
+                        continue;
+                    };
+                    send_to_task(&data_collector_tx, (sender, block), "data_collector").await?;
+                }
+                InternalEvent::BlockStored(block) => {
+                    let in_endgame = picker.lock().await.is_in_endgame();
+                    if in_endgame != endgame_guard.is_some() {
+                        endgame_guard = in_endgame.then(|| GaugeGuard::raise(&metrics.torrents_in_endgame));
+                    }
+                    for peer in p2p_transfers.values().filter(|peer| peer.is_connected) {
+                        let _ = peer.tx.send(P2PEvent::BlockStored(block.clone())).await;
                     }
                 }
-            }
-            InternalEvent::ChokePeer(transfer_idx) => {
-                match p2p_transfers.get(&transfer_idx) {
-                    None => {}
-                    Some(peer) => { let _ = peer.tx.send(P2PEvent::ChokePeer).await; }
+                event @ (InternalEvent::DownloadComplete | InternalEvent::PrioritiesChanged) => {
+                    if event == InternalEvent::PrioritiesChanged {
+                        for peer in p2p_transfers.values() {
+                            let _ = peer.tx.send(P2PEvent::PrioritiesChanged).await;
+                        }
+                    }
+                    if !picker.lock().await.is_selection_complete() {
+                        // skipped pieces are wanted again, so the torrent downloads once more
+                        if is_finished {
+                            is_finished = false;
+                            if !is_paused {
+                                set_status(&stats_tx, &alerts, &info_hash, TorrentStatus::Downloading);
+                            }
+                        }
+                        continue;
+                    }
+                    if is_finished {
+                        continue;
+                    }
+                    is_finished = true;
+                    // the data collector only checks for completion once a piece is stored, and alerts about it itself
+                    if event == InternalEvent::PrioritiesChanged {
+                        alerts.send(Alert::TorrentFinished { info_hash: info_hash.clone() });
+                    }
+                    if !seed {
+                        return Ok(TransferOutcome::Completed);
+                    }
+                    if !is_paused {
+                        set_status(&stats_tx, &alerts, &info_hash, TorrentStatus::Seeding);
+                    }
                 }
-            }
-            InternalEvent::UnchokePeer(transfer_idx) => {
-                match p2p_transfers.get(&transfer_idx) {
-                    None => {}
-                    Some(peer) => { let _ = peer.tx.send(P2PEvent::UnchokePeer).await; }
+                InternalEvent::PieceStored(piece_idx) => {
+                    client_bitfield.piece_acquired(piece_idx);
+                    for (_, peer) in p2p_transfers.iter() {
+                        let _ = peer.tx.send(P2PEvent::PieceStored(piece_idx)).await;
+                    }
+                    // the completion is only announced, and the choker only seeds, once every piece is held
+                    if !is_seeding && (0..pieces_count).all(|piece_idx| client_bitfield.has_piece(piece_idx)) {
+                        is_seeding = true;
+                        send_to_task(&choke_tx, ChokeEvent::SeedingStateChanged(true), "choke").await?;
+                        send_to_task(&tracker_tx, TrackerEvent::CompletedAnnounce, "tracker").await?;
+                    }
                 }
-            }
-            InternalEvent::ClientInterestedInPeer(idx, interested) => {
-                choke_tx.send(ChokeEvent::ClientInterestedInPeer(idx, interested)).await.unwrap()
-            }
-            InternalEvent::PeerInterestedInClient(idx, interested) => {
-                choke_tx.send(ChokeEvent::PeerInterestedInClient(idx, interested)).await.unwrap()
-            }
-            InternalEvent::PeerConnectionEstablished(idx) => {
-                match p2p_transfers.get_mut(&idx) {
-                    None => {}
-                    Some(peer) => { peer.is_connected = true; }
+                InternalEvent::P2PTransferTerminated(transfer_idx) => {
+                    if let Some(transfer) = p2p_transfers.remove(&transfer_idx).filter(|transfer| transfer.is_connected) {
+                        alerts.send(Alert::PeerDisconnected { info_hash: info_hash.clone(), peer: transfer.peer });
+                    }
+                    send_to_task(&choke_tx, ChokeEvent::UnregisterPeer(transfer_idx), "choke").await?;
+                    stats_tx.send_modify(|stats| { stats.peers.remove(&transfer_idx); });
+                }
+                InternalEvent::ChokePeer(transfer_idx) => {
+                    match p2p_transfers.get(&transfer_idx) {
+                        None => {}
+                        Some(peer) => { let _ = peer.tx.send(P2PEvent::ChokePeer).await; }
+                    }
+                }
+                InternalEvent::UnchokePeer(transfer_idx) => {
+                    match p2p_transfers.get(&transfer_idx) {
+                        None => {}
+                        Some(peer) => { let _ = peer.tx.send(P2PEvent::UnchokePeer).await; }
+                    }
+                }
+                InternalEvent::ClientInterestedInPeer(idx, interested) => {
+                    send_to_task(&choke_tx, ChokeEvent::ClientInterestedInPeer(idx, interested), "choke").await?;
+                }
+                InternalEvent::PeerInterestedInClient(idx, interested) => {
+                    send_to_task(&choke_tx, ChokeEvent::PeerInterestedInClient(idx, interested), "choke").await?;
+                }
+                InternalEvent::PeerSnubbed(idx, snubbed) => {
+                    send_to_task(&choke_tx, ChokeEvent::PeerSnubbed(idx, snubbed), "choke").await?;
+                }
+                InternalEvent::PeerStatusChanged(idx, status) => {
+                    stats_tx.send_modify(|stats| {
+                        if let Some(peer) = stats.peers.get_mut(&idx) {
+                            peer.status = status;
+                        }
+                    });
+                }
+                InternalEvent::PeerConnectionEstablished(idx) => {
+                    match p2p_transfers.get_mut(&idx) {
+                        None => {}
+                        Some(peer) => {
+                            peer.is_connected = true;
+                            alerts.send(Alert::PeerConnected { info_hash: info_hash.clone(), peer: peer.peer.clone() });
+                        }
+                    }
+                    send_to_task(&choke_tx, ChokeEvent::PeerConnected(idx), "choke").await?;
+                    stats_tx.send_modify(|stats| {
+                        if let Some(peer) = stats.peers.get_mut(&idx) {
+                            peer.is_connected = true;
+                        }
+                    });
+                }
+                InternalEvent::BlockUploaded(transfer_idx, size) => {
+                    metrics.uploaded_bytes.with_label_values(&[PAYLOAD]).inc_by(size as u64);
+                    stats_tx.send_modify(|stats| stats.block_uploaded(transfer_idx, size));
+                    send_to_task(&choke_tx, ChokeEvent::BlockUploadedToPeer(transfer_idx, size), "choke").await?;
+                    send_to_task(&tracker_tx, TrackerEvent::Uploaded(size as u64), "tracker").await?;
+                }
+                InternalEvent::PauseTransfer | InternalEvent::ResumeTransfer => {
+                    is_paused = event == InternalEvent::PauseTransfer;
+                    let p2p_event = if is_paused { P2PEvent::Pause } else { P2PEvent::Resume };
+                    for peer in p2p_transfers.values() {
+                        let _ = peer.tx.send(p2p_event.clone()).await;
+                    }
+                    let status = if is_paused { TorrentStatus::Paused } else { active_status(is_finished) };
+                    set_status(&stats_tx, &alerts, &info_hash, status);
+                }
+                InternalEvent::StorageFailed(reason) => {
+                    warn!("Transfer paused due to a storage error: {}", reason);
+                    alerts.send(Alert::StorageError { info_hash: info_hash.clone(), reason });
+                    is_paused = true;
+                    for peer in p2p_transfers.values() {
+                        let _ = peer.tx.send(P2PEvent::Pause).await;
+                    }
+                    set_status(&stats_tx, &alerts, &info_hash, TorrentStatus::Paused);
+                }
+                InternalEvent::BanPeer(ip) => {
+                    warn!("Banned peer {} for sending corrupt data", ip);
+                    // the data collector banned the address already; any connection from it is dropped
+                    for transfer in p2p_transfers.values().filter(|transfer| transfer.peer.ip == ip) {
+                        let _ = transfer.tx.send(P2PEvent::Ban).await;
+                    }
+                    alerts.send(Alert::PeerBanned { info_hash: info_hash.clone(), ip });
+                }
+                InternalEvent::StopTransfer => {
+                    // best effort, the tracker task may have ended already
+                    let _ = tracker_tx.send(TrackerEvent::StoppedAnnounce).await;
+                    return Ok(TransferOutcome::Stopped);
                 }
-                connected_peers += 1;
-                print_transfer_state(connected_peers, stored_pieces, pieces_count);
-            }
-            InternalEvent::BlockUploaded(size) => {
-                tracker_tx.send(TrackerEvent::Uploaded(size as u64)).await.unwrap();
             }
         }
+    }.await;
+
+    // the peer transfers may be waiting for room in the queue, which is no longer read
+    drop(rx);
+    close_transfers(p2p_transfers.into_values(), deps.as_ref()).await;
+    for channel in ["coordinator", "choke", "data_collector", "tracker"] {
+        let _ = metrics.queue_depth.remove_label_values(&[&torrent_label, channel]);
+    }
+    // a failed transfer gets its status from the session
+    if let Ok(outcome) = &result {
+        let status = match outcome {
+            TransferOutcome::Completed => TorrentStatus::Completed,
+            TransferOutcome::Stopped => TorrentStatus::Stopped,
+        };
+        set_status(&stats_tx, &alerts, &info_hash, status);
+    }
+
+    return result;
+}
+
+// Asks the peer transfers to end, so that the messages queued for the peers are still sent before
+// the connections close; the transfers which do not end within the shutdown timeout are aborted
+async fn close_transfers(transfers: impl Iterator<Item = PeerTransfer>, deps: &dyn TransferDeps) {
+    let deadline = Instant::now() + Duration::from_secs(deps.client_config().shutdown_timeout_secs);
+    let transfers: Vec<PeerTransfer> = transfers.collect();
+    for transfer in &transfers {
+        let _ = timeout_at(deadline, transfer.tx.send(P2PEvent::Close)).await;
+    }
+    for mut transfer in transfers {
+        if timeout_at(deadline, &mut transfer.handle).await.is_err() {
+            transfer.handle.abort();
+        }
     }
 }
 
-fn print_transfer_state(connected_peers: usize, stored_pieces: usize, total_pieces: usize) {
-    let progress = format!("{:.1}", (stored_pieces as f64 / total_pieces as f64) * 100.0);
-    print!("\rProgress: {}%({}/{} pieces) | Connected Peers: {}", progress, stored_pieces, total_pieces, connected_peers);
+fn active_status(is_finished: bool) -> TorrentStatus {
+    return if is_finished { TorrentStatus::Seeding } else { TorrentStatus::Downloading };
+}
+
+async fn send_to_task<T>(tx: &Sender<T>, event: T, task: &'static str) -> Result<(), TransferError> {
+    return tx.send(event).await.map_err(|_| TransferError::TaskStopped(task));
+}
+
+struct QueueDepths {
+    coordinator: IntGauge,
+    choke: IntGauge,
+    data_collector: IntGauge,
+    tracker: IntGauge,
+}
+
+fn queued_events<T>(tx: &Sender<T>) -> i64 {
+    return (tx.max_capacity() - tx.capacity()) as i64;
+}
+
+// Publishes the new status of the torrent, alerting about it when it differs from the previous one
+pub fn set_status(stats_tx: &watch::Sender<TorrentStats>, alerts: &Alerts, info_hash: &[u8], status: TorrentStatus) {
+    let changed = stats_tx.send_if_modified(|stats| {
+        if stats.status == status {
+            return false;
+        }
+        stats.status = status.clone();
+        return true;
+    });
+    if changed {
+        alerts.send(Alert::TorrentStateChanged { info_hash: info_hash.to_vec(), status });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::net::Ipv4Addr;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tokio::sync::mpsc;
+    use tokio::sync::mpsc::{Receiver, Sender};
+    use tokio::task::JoinHandle;
+    use crate::choke;
+    use crate::config::Config;
+    use crate::coordinator::ipc::{broadcast_events, PeerTransfer, TaskChannels, TransferOutcome};
+    use crate::coordinator::stats::TorrentStatus;
+    use crate::coordinator::task::TransferError;
+    use crate::core_models::entities::{Bitfield, DataBlock, Peer, PeerSource};
+    use crate::core_models::events::InternalEvent;
+    use crate::dependency_provider::TransferDeps;
+    use crate::mocks::{MockDepsProvider, MockTorrent};
+    use crate::p2p::models::P2PEvent;
+    use crate::rpc::json_rpc::encode_info_hash;
+    use crate::selection::Priority;
+    use crate::tracker;
+    use crate::tracker::client::{MockTrackerClient, TrackerRequestEvent, TrackerResponse};
+
+    // A coordinator along with a running choke task, whose peers only receive what the client sends them
+    struct Transfer {
+        deps: Arc<dyn TransferDeps>,
+        tx: Sender<InternalEvent>,
+        peer_rxs: Vec<Receiver<P2PEvent>>,
+        // the events of the announces made by the tracker task
+        announces: Arc<Mutex<Vec<&'static str>>>,
+        _data_collector_rx: Receiver<(Ipv4Addr, DataBlock)>,
+        coordinator: JoinHandle<Result<TransferOutcome, TransferError>>,
+    }
+
+    fn start_transfer(num_of_peers: usize, config: Config) -> Transfer {
+        let (tx, rx) = mpsc::channel(1024);
+        let deps = MockDepsProvider::new(MockTorrent::generate(2, 1, 1), tx.clone()).with_config(config);
+        let deps: Arc<dyn TransferDeps> = Arc::new(deps);
+        let (_choke_handle, choke_tx) = choke::task::spawn(tx.clone(), num_of_peers, &deps.client_config());
+        let (data_collector_tx, data_collector_rx) = mpsc::channel(64);
+        let (announces, tracker_client) = recording_tracker_client();
+        let (_tracker_handle, tracker_tx) = tracker::task::spawn(tracker_client, 1000, deps.clone());
+        let (_incoming_tx, incoming_rx) = mpsc::channel(1);
+        let mut peer_rxs = vec![];
+        let mut p2p_transfers = vec![];
+        for idx in 0..num_of_peers {
+            let (peer_tx, peer_rx) = mpsc::channel(64);
+            let peer = Peer { ip: Ipv4Addr::new(10, 0, 0, idx as u8), port: 6881, source: PeerSource::Tracker, peer_id: None };
+            p2p_transfers.push((idx, PeerTransfer::new(peer, tokio::spawn(std::future::pending()), peer_tx)));
+            peer_rxs.push(peer_rx);
+        }
+        let tasks = TaskChannels { choke_tx, data_collector_tx, tracker_tx };
+        let coordinator = tokio::spawn(broadcast_events(deps.clone(), rx, incoming_rx, tasks, p2p_transfers, Bitfield::init(2)));
+
+        return Transfer { deps, tx, peer_rxs, announces, _data_collector_rx: data_collector_rx, coordinator };
+    }
+
+    fn recording_tracker_client() -> (Arc<Mutex<Vec<&'static str>>>, Box<MockTrackerClient>) {
+        let announces = Arc::new(Mutex::new(vec![]));
+        let recorded = announces.clone();
+        let mut client = MockTrackerClient::new();
+        client.expect_announce().returning(move |event| {
+            recorded.lock().unwrap().push(match event {
+                TrackerRequestEvent::Started => "started",
+                TrackerRequestEvent::Regular(..) => "regular",
+                TrackerRequestEvent::Completed(..) => "completed",
+                TrackerRequestEvent::Stopped(..) => "stopped",
+            });
+            return Box::pin(async { Ok(TrackerResponse { complete: 0, incomplete: 0, interval: 1000, peers: vec![], tracker_id: None }) });
+        });
+        return (announces, Box::new(client));
+    }
+
+    // stores every piece of the torrent, as the data collector does
+    async fn store_all_pieces(transfer: &Transfer) {
+        for piece_idx in 0..transfer.deps.torrent_layout().pieces {
+            transfer.deps.piece_picker().lock().await.remove_piece(piece_idx);
+            transfer.tx.send(InternalEvent::PieceStored(piece_idx)).await.unwrap();
+        }
+    }
+
+    // the peers are interested in the client, which has no interest in any of them
+    async fn peers_interested(transfer: &Transfer) {
+        for idx in 0..transfer.peer_rxs.len() {
+            transfer.tx.send(InternalEvent::PeerConnectionEstablished(idx)).await.unwrap();
+            transfer.tx.send(InternalEvent::PeerInterestedInClient(idx, true)).await.unwrap();
+        }
+    }
+
+    // number of distinct peers unchoked, once at least `count` were or a few unchoke rounds passed
+    async fn wait_for_unchokes(peer_rxs: &mut [Receiver<P2PEvent>], count: usize) -> usize {
+        let mut unchoked = HashSet::new();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while unchoked.len() < count && Instant::now() < deadline {
+            for (idx, rx) in peer_rxs.iter_mut().enumerate() {
+                while let Ok(event) = rx.try_recv() {
+                    if matches!(event, P2PEvent::UnchokePeer) {
+                        unchoked.insert(idx);
+                    }
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        return unchoked.len();
+    }
+
+    fn config() -> Config {
+        // the peers of the tests never end on their own, so they are aborted right away
+        return Config {
+            unchoke_interval_secs: 1,
+            optimistic_unchoke_interval_secs: 1000,
+            shutdown_timeout_secs: 0,
+            ..Config::default()
+        };
+    }
+
+    #[tokio::test]
+    async fn test_completed_torrent_keeps_seeding() {
+        let mut transfer = start_transfer(1, config());
+        peers_interested(&transfer).await;
+
+        store_all_pieces(&transfer).await;
+        transfer.tx.send(InternalEvent::DownloadComplete).await.unwrap();
+        transfer.tx.send(InternalEvent::BlockUploaded(0, 1024)).await.unwrap();
+
+        // a leecher only unchokes the peers it is interested in
+        assert_eq!(wait_for_unchokes(&mut transfer.peer_rxs, 1).await, 1);
+        assert_eq!(transfer.deps.stats_tx().borrow().status, TorrentStatus::Seeding);
+        assert!(!transfer.coordinator.is_finished());
+        transfer.tx.send(InternalEvent::StopTransfer).await.unwrap();
+        assert_eq!(transfer.coordinator.await.unwrap().unwrap(), TransferOutcome::Stopped);
+        // the tracker task outlives the completed announce, and is told when the transfer stops
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(*transfer.announces.lock().unwrap(), vec!["completed", "stopped"]);
+    }
+
+    #[tokio::test]
+    async fn test_upload_slots_opened_while_seeding_with_spare_capacity() {
+        let config = Config { upload_capacity_bytes_per_sec: Some(1024 * 1024), ..config() };
+        let mut transfer = start_transfer(8, config);
+        peers_interested(&transfer).await;
+
+        store_all_pieces(&transfer).await;
+        transfer.tx.send(InternalEvent::DownloadComplete).await.unwrap();
+
+        // more than the initial slots and the optimistic unchoke
+        assert_eq!(wait_for_unchokes(&mut transfer.peer_rxs, 6).await, 6);
+        transfer.coordinator.abort();
+    }
+
+    #[tokio::test]
+    async fn test_transfer_ends_on_completion_without_seeding() {
+        let transfer = start_transfer(1, Config { seed: false, ..config() });
+
+        store_all_pieces(&transfer).await;
+        transfer.tx.send(InternalEvent::DownloadComplete).await.unwrap();
+
+        assert_eq!(transfer.coordinator.await.unwrap().unwrap(), TransferOutcome::Completed);
+        assert_eq!(transfer.deps.stats_tx().borrow().status, TorrentStatus::Completed);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_completes_once_nothing_is_wanted() {
+        let transfer = start_transfer(1, Config { seed: false, ..config() });
+        transfer.tx.send(InternalEvent::PrioritiesChanged).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!transfer.coordinator.is_finished());
+
+        let pieces = transfer.deps.torrent_layout().pieces;
+        transfer.deps.piece_picker().lock().await.set_piece_priorities(vec![Priority::Skip; pieces]).unwrap();
+        transfer.tx.send(InternalEvent::PrioritiesChanged).await.unwrap();
+
+        assert_eq!(transfer.coordinator.await.unwrap().unwrap(), TransferOutcome::Completed);
+    }
+
+    #[tokio::test]
+    async fn test_queue_depths_sampled_by_info_hash() {
+        let transfer = start_transfer(1, config());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let label = format!("info_hash=\"{}\"", encode_info_hash(&transfer.deps.info_hash()));
+        let encoded = transfer.deps.metrics().encode();
+
+        assert!(encoded.lines().any(|line| line.contains("ipc_queue_depth") && line.contains(&label)));
+        transfer.coordinator.abort();
+    }
+
+    #[tokio::test]
+    async fn test_finished_selection_neither_announced_nor_seeded_until_every_piece_held() {
+        let mut transfer = start_transfer(1, config());
+        let picker = transfer.deps.piece_picker();
+        picker.lock().await.set_piece_priorities(vec![Priority::Normal, Priority::Skip]).unwrap();
+        picker.lock().await.remove_piece(0);
+        transfer.tx.send(InternalEvent::PieceStored(0)).await.unwrap();
+        transfer.tx.send(InternalEvent::DownloadComplete).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(transfer.deps.stats_tx().borrow().status, TorrentStatus::Seeding);
+
+        // the skipped piece is wanted again
+        picker.lock().await.set_piece_priorities(vec![Priority::Normal, Priority::Normal]).unwrap();
+        transfer.tx.send(InternalEvent::PrioritiesChanged).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(transfer.deps.stats_tx().borrow().status, TorrentStatus::Downloading);
+        let mut priorities_changed = false;
+        while let Ok(event) = transfer.peer_rxs[0].try_recv() {
+            priorities_changed |= matches!(event, P2PEvent::PrioritiesChanged);
+        }
+        assert!(priorities_changed);
+        assert!(transfer.announces.lock().unwrap().is_empty());
+
+        picker.lock().await.remove_piece(1);
+        transfer.tx.send(InternalEvent::PieceStored(1)).await.unwrap();
+        transfer.tx.send(InternalEvent::DownloadComplete).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(transfer.deps.stats_tx().borrow().status, TorrentStatus::Seeding);
+        assert_eq!(*transfer.announces.lock().unwrap(), vec!["completed"]);
+        transfer.coordinator.abort();
+    }
 }
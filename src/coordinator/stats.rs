@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+use crate::core_models::entities::{Peer, PeerStatus, TorrentLayout};
+use crate::tracker::client::TrackerResponse;
+use crate::transfer_rate::TransferRate;
+
+const STATS_RATE_WINDOW_SECS: u64 = 10;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TorrentStatus {
+    Starting,
+    Downloading,
+    // the download is complete and the torrent keeps uploading
+    Seeding,
+    Paused,
+    Completed,
+    Stopped,
+    Failed(String),
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PieceState {
+    Missing,
+    Downloading,
+    Stored,
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub enum TrackerStatus {
+    #[default]
+    NotContacted,
+    Working,
+    Failed(String),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct TrackerStats {
+    pub status: TrackerStatus,
+    pub last_announce_at: Option<Instant>,
+    pub interval: Duration,
+    pub seeders: u16,
+    pub leechers: u16,
+    // peers returned by the last announce
+    pub peers: usize,
+}
+
+impl TrackerStats {
+    pub fn announce_succeeded(&mut self, response: &TrackerResponse) {
+        self.status = TrackerStatus::Working;
+        self.last_announce_at = Some(Instant::now());
+        self.interval = Duration::from_secs(response.interval);
+        self.seeders = response.complete;
+        self.leechers = response.incomplete;
+        self.peers = response.peers.len();
+    }
+
+    pub fn announce_failed(&mut self, reason: String) {
+        self.status = TrackerStatus::Failed(reason);
+        self.last_announce_at = Some(Instant::now());
+    }
+}
+
+// State of a torrent transfer, published by the coordinator as its events come in
+#[derive(Clone, Debug)]
+pub struct TorrentStats {
+    // assigned by the session in the order the torrents are added
+    pub id: usize,
+    pub name: String,
+    pub info_hash: Vec<u8>,
+    pub total_length: usize,
+    pub piece_length: usize,
+    pub pieces: usize,
+    pub stored_pieces: usize,
+    pub piece_states: Vec<PieceState>,
+    pub downloaded_bytes: u64,
+    pub uploaded_bytes: u64,
+    pub download_rate: TransferRate,
+    pub upload_rate: TransferRate,
+    pub status: TorrentStatus,
+    pub tracker: TrackerStats,
+    pub peers: BTreeMap<usize, PeerStats>,
+}
+
+impl TorrentStats {
+    pub fn new(name: String, info_hash: Vec<u8>, layout: &TorrentLayout) -> Self {
+        return TorrentStats {
+            id: 0,
+            name,
+            info_hash,
+            total_length: layout.output_file_length,
+            piece_length: layout.head_pieces_length,
+            pieces: layout.pieces,
+            stored_pieces: 0,
+            piece_states: vec![PieceState::Missing; layout.pieces],
+            downloaded_bytes: 0,
+            uploaded_bytes: 0,
+            download_rate: new_rate(),
+            upload_rate: new_rate(),
+            status: TorrentStatus::Starting,
+            tracker: TrackerStats::default(),
+            peers: BTreeMap::new(),
+        };
+    }
+
+    pub fn progress(&self) -> f64 {
+        return if self.pieces == 0 { 1.0 } else { self.stored_pieces as f64 / self.pieces as f64 };
+    }
+
+    pub fn connected_peers(&self) -> usize {
+        return self.peers.values().filter(|peer| peer.is_connected).count();
+    }
+
+    // only stored pieces count, the last one may be shorter than the others
+    pub fn remaining_bytes(&self) -> usize {
+        return self.total_length.saturating_sub(self.stored_pieces * self.piece_length);
+    }
+
+    // estimated time until the download completes at the current download rate
+    pub fn eta(&mut self) -> Option<Duration> {
+        let remaining_bytes = self.remaining_bytes();
+        if remaining_bytes == 0 {
+            return Some(Duration::ZERO);
+        }
+        let rate = self.download_rate.bytes_per_sec();
+        if rate < 1.0 {
+            return None;
+        }
+        return Some(Duration::from_secs_f64(remaining_bytes as f64 / rate));
+    }
+
+    pub fn piece_stored(&mut self, piece_idx: usize) {
+        if let Some(state) = self.piece_states.get_mut(piece_idx) {
+            *state = PieceState::Stored;
+        }
+        self.stored_pieces += 1;
+    }
+
+    pub fn block_downloaded(&mut self, transfer_idx: usize, piece_idx: usize, bytes: usize) {
+        if let Some(state @ PieceState::Missing) = self.piece_states.get_mut(piece_idx) {
+            *state = PieceState::Downloading;
+        }
+        self.downloaded_bytes += bytes as u64;
+        self.download_rate.record(bytes);
+        if let Some(peer) = self.peers.get_mut(&transfer_idx) {
+            peer.downloaded_bytes += bytes as u64;
+            peer.download_rate.record(bytes);
+        }
+    }
+
+    pub fn block_uploaded(&mut self, transfer_idx: usize, bytes: usize) {
+        self.uploaded_bytes += bytes as u64;
+        self.upload_rate.record(bytes);
+        if let Some(peer) = self.peers.get_mut(&transfer_idx) {
+            peer.uploaded_bytes += bytes as u64;
+            peer.upload_rate.record(bytes);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PeerStats {
+    pub peer: Peer,
+    pub is_connected: bool,
+    pub status: PeerStatus,
+    pub downloaded_bytes: u64,
+    pub uploaded_bytes: u64,
+    pub download_rate: TransferRate,
+    pub upload_rate: TransferRate,
+}
+
+impl PeerStats {
+    pub fn new(peer: Peer) -> Self {
+        return PeerStats {
+            peer,
+            is_connected: false,
+            status: PeerStatus::default(),
+            downloaded_bytes: 0,
+            uploaded_bytes: 0,
+            download_rate: new_rate(),
+            upload_rate: new_rate(),
+        };
+    }
+}
+
+fn new_rate() -> TransferRate {
+    return TransferRate::new(Duration::from_secs(STATS_RATE_WINDOW_SECS));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use crate::coordinator::stats::{PeerStats, PieceState, TorrentStats};
+    use crate::core_models::entities::{Peer, PeerSource};
+    use crate::mocks::MockTorrent;
+
+    #[test]
+    fn test_transferred_bytes_accounted_to_peer() {
+        let layout = MockTorrent::generate(2, 2, 1).layout;
+        let mut stats = TorrentStats::new("mock".to_string(), vec![1; 20], &layout);
+        let peer = Peer { ip: Ipv4Addr::LOCALHOST, port: 6881, source: PeerSource::Tracker, peer_id: None };
+        stats.peers.insert(3, PeerStats::new(peer));
+
+        stats.block_downloaded(3, 0, 100);
+        stats.block_uploaded(3, 50);
+        stats.block_downloaded(4, 0, 10);
+
+        assert_eq!(stats.downloaded_bytes, 110);
+        assert_eq!(stats.uploaded_bytes, 50);
+        assert_eq!(stats.peers[&3].downloaded_bytes, 100);
+        assert_eq!(stats.peers[&3].uploaded_bytes, 50);
+    }
+
+    #[test]
+    fn test_piece_states_follow_transfer() {
+        let layout = MockTorrent::generate(3, 2, 1).layout;
+        let mut stats = TorrentStats::new("mock".to_string(), vec![1; 20], &layout);
+
+        stats.block_downloaded(0, 1, 100);
+        stats.block_downloaded(0, 2, 100);
+        stats.piece_stored(2);
+        stats.block_downloaded(0, 2, 100);
+
+        assert_eq!(stats.piece_states, vec![PieceState::Missing, PieceState::Downloading, PieceState::Stored]);
+        assert_eq!(stats.stored_pieces, 1);
+    }
+}
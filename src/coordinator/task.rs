@@ -1,65 +1,273 @@
+use std::io;
 use std::sync::Arc;
-use log::{error, info};
-use tokio::sync::mpsc::{Receiver, Sender};
-use tokio::task::JoinHandle;
+use std::time::Duration;
+use log::{error, info, warn};
+use tokio::sync::mpsc::Receiver;
+use tokio::time::timeout;
 use crate::coordinator::ipc;
+use crate::coordinator::ipc::{PeerTransfer, TaskChannels, TransferOutcome};
 use crate::core_models::entities::{Bitfield, Peer};
 use crate::core_models::events::InternalEvent;
 use crate::{choke, data_collector, tracker};
 use crate::dependency_provider::TransferDeps;
-use crate::p2p::models::{P2PEvent, P2PError};
+use crate::p2p::conn::IncomingConnection;
 use crate::p2p::task;
+use crate::resume::{resume_file_path, ResumeData};
 use crate::tracker::client::{TrackerClient, TrackerRequestEvent, TrackerResponse};
 
 #[derive(Debug)]
 pub enum TransferError {
     TrackerCallFailed(String),
+    // a task of the transfer ended unexpectedly, named after the task
+    TaskStopped(&'static str),
 }
 
-pub async fn run(deps: Arc<dyn TransferDeps>, rx: Receiver<InternalEvent>) -> Result<(), TransferError> {
+pub async fn run(deps: Arc<dyn TransferDeps>,
+                 rx: Receiver<InternalEvent>,
+                 incoming_rx: Receiver<IncomingConnection>) -> Result<(), TransferError> {
     info!("Starting transfer at... {}", chrono::prelude::Utc::now());
 
     let tracker_client = deps.tracker_client();
-    let layout = deps.torrent_layout();
-    let client_bitfield = Bitfield::init(layout.pieces);
+    let client_bitfield = restore_stored_pieces(deps.as_ref()).await;
+
+    let tracker_resp = call_initial_announce(tracker_client.as_ref(), deps.as_ref()).await?;
+    let peers = filter_blocked_peers(&deps, filter_peers_by_source(&deps, tracker_resp.peers));
 
-    let tracker_resp = call_initial_announce(&tracker_client).await?;
+    let (data_collector_handle, data_collector_tx) = data_collector::spawn(deps.clone());
+    let p2p_transfers = spawn_p2p_tasks(deps.clone(), client_bitfield.clone(), peers);
+    let (choke_handle, choke_tx) = choke::task::spawn(deps.output_tx().clone(), p2p_transfers.len(), &deps.client_config());
+    let (tracker_handle, tracker_tx) = tracker::task::spawn(tracker_client, tracker_resp.interval, deps.clone());
 
-    let (_data_collector_handle, data_collector_tx) = data_collector::spawn(deps.clone());
-    let (_p2p_handles, p2p_tx) = spawn_p2p_tasks(deps.clone(), client_bitfield.clone(), tracker_resp.peers);
-    let (_choke_handle, choke_tx) = choke::task::spawn(deps.output_tx().clone(), p2p_tx.len());
-    let (tracker_handle, tracker_tx) = tracker::task::spawn(tracker_client, tracker_resp.interval);
+    let tasks = TaskChannels { choke_tx, data_collector_tx, tracker_tx };
+    let outcome = ipc::broadcast_events(deps.clone(), rx, incoming_rx, tasks, p2p_transfers, client_bitfield).await;
+    choke_handle.abort();
+    let outcome = match outcome {
+        Ok(outcome) => outcome,
+        Err(err) => {
+            data_collector_handle.abort();
+            tracker_handle.abort();
+            error!("Transfer failed at... {}: {:?}", chrono::prelude::Utc::now(), err);
+            return Err(err);
+        }
+    };
 
-    ipc::broadcast_events(layout.pieces, rx, choke_tx, data_collector_tx, p2p_tx, tracker_tx).await;
-    let _ = tracker_handle.await;
+    // the peers are disconnected by now; the blocks already received are still written and the
+    // tracker is told, within the shutdown timeout
+    let shutdown_timeout = Duration::from_secs(deps.client_config().shutdown_timeout_secs);
+    if timeout(shutdown_timeout, data_collector_handle).await.is_err() {
+        warn!("Storage not flushed within {:?}", shutdown_timeout);
+    }
+    let tracker_abort_handle = tracker_handle.abort_handle();
+    if timeout(shutdown_timeout, tracker_handle).await.is_err() {
+        tracker_abort_handle.abort();
+        warn!("Tracker not announced to within {:?}", shutdown_timeout);
+    }
+    save_resume_data(deps.as_ref());
 
-    info!("Transfer completed at... {}", chrono::prelude::Utc::now());
+    match outcome {
+        TransferOutcome::Completed => info!("Transfer completed at... {}", chrono::prelude::Utc::now()),
+        TransferOutcome::Stopped => info!("Transfer stopped at... {}", chrono::prelude::Utc::now()),
+    }
 
     return Ok(());
 }
 
-async fn call_initial_announce(client: &Box<dyn TrackerClient>) -> Result<TrackerResponse, TransferError> {
-    return match client.announce(TrackerRequestEvent::Started).await {
+// Takes up the pieces recorded in the resume data of an earlier transfer, once they are verified
+// against their hashes; the others are downloaded again
+async fn restore_stored_pieces(deps: &dyn TransferDeps) -> Bitfield {
+    let layout = deps.torrent_layout();
+    let mut client_bitfield = Bitfield::init(layout.pieces);
+    let path = resume_file_path(&layout.output_file_path);
+    let resume_data = match ResumeData::load(&path) {
+        Ok(resume_data) => resume_data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return client_bitfield,
+        Err(err) => {
+            warn!("Resume data not loaded from {}: {}", path.display(), err);
+            return client_bitfield;
+        }
+    };
+    if resume_data.info_hash != deps.info_hash() || resume_data.pieces.len() != client_bitfield.content.len() {
+        warn!("Resume data at {} does not belong to the torrent", path.display());
+        return client_bitfield;
+    }
+
+    let saved_pieces = Bitfield::new(resume_data.pieces);
+    let hashes = deps.piece_hashes();
+    let picker = deps.piece_picker();
+    let stats_tx = deps.stats_tx();
+    let mut file_prov = deps.file_provider();
+    if let Err(err) = file_prov.open_read_only_instance().await {
+        warn!("Stored pieces not verified: {:?}", err);
+        return client_bitfield;
+    }
+    for piece_idx in (0..layout.pieces).filter(|piece_idx| saved_pieces.has_piece(*piece_idx)) {
+        match file_prov.read_piece(piece_idx).await {
+            Ok(piece) if !data_collector::piece_corrupt(&piece, &hashes[piece_idx]) => {
+                client_bitfield.piece_acquired(piece_idx);
+                picker.lock().await.remove_piece(piece_idx);
+                stats_tx.send_modify(|stats| stats.piece_stored(piece_idx));
+            }
+            Ok(_) => warn!("Stored piece {} is corrupt", piece_idx),
+            Err(err) => warn!("Stored piece {} not read: {:?}", piece_idx, err),
+        }
+    }
+    stats_tx.send_modify(|stats| {
+        stats.downloaded_bytes = resume_data.downloaded;
+        stats.uploaded_bytes = resume_data.uploaded;
+    });
+    info!("Restored {} stored pieces out of {}", client_bitfield.to_available_pieces_vec().len(), layout.pieces);
+
+    return client_bitfield;
+}
+
+fn save_resume_data(deps: &dyn TransferDeps) {
+    let path = resume_file_path(&deps.torrent_layout().output_file_path);
+    let resume_data = ResumeData::from_stats(&deps.stats_tx().borrow());
+    if let Err(err) = resume_data.save(&path) {
+        warn!("Resume data not saved to {}: {}", path.display(), err);
+    }
+}
+
+async fn call_initial_announce(client: &dyn TrackerClient, deps: &dyn TransferDeps) -> Result<TrackerResponse, TransferError> {
+    return match tracker::task::announce(client, TrackerRequestEvent::Started, deps).await {
         Ok(resp) => Ok(resp),
         Err(err) => {
             error!("Initial announce failed {:?}", err);
-            return Err(TransferError::TrackerCallFailed(err.to_string()));
+            return Err(TransferError::TrackerCallFailed(err));
         }
     };
 }
 
+fn filter_peers_by_source(deps: &Arc<dyn TransferDeps>, peers: Vec<Peer>) -> Vec<Peer> {
+    let policy = deps.discovery_policy();
+    let (allowed, refused): (Vec<Peer>, Vec<Peer>) = peers.into_iter()
+        .partition(|peer| policy.allows(peer.source));
+    if !refused.is_empty() {
+        warn!("Refused {} peers learned from sources disabled for this torrent", refused.len());
+    }
+
+    return allowed;
+}
+
+// Drops the peers refused by the IP filter, whatever source they were learned from, and the ones
+// banned by other torrents of the session
+fn filter_blocked_peers(deps: &Arc<dyn TransferDeps>, peers: Vec<Peer>) -> Vec<Peer> {
+    let ip_filter = deps.ip_filter();
+    let banned_peers = deps.banned_peers();
+    let metrics = deps.metrics();
+    return peers.into_iter()
+        .filter(|peer| {
+            if ip_filter.is_blocked(&peer.ip) {
+                metrics.blocked_peers.with_label_values(&[peer.source.label()]).inc();
+                return false;
+            }
+            return !banned_peers.is_banned(&peer.ip);
+        })
+        .collect();
+}
+
 fn spawn_p2p_tasks(deps: Arc<dyn TransferDeps>, client_bitfield: Bitfield, peers: Vec<Peer>)
-                   -> (Vec<(usize, JoinHandle<Result<(), P2PError>>)>,
-                       Vec<(usize, Sender<P2PEvent>)>) {
-    let mut p2p_handles: Vec<(usize, JoinHandle<Result<(), P2PError>>)> = vec![];
-    let mut p2p_tx: Vec<(usize, Sender<P2PEvent>)> = vec![];
+                   -> Vec<(usize, PeerTransfer)> {
+    let mut p2p_transfers: Vec<(usize, PeerTransfer)> = vec![];
     for (transfer_idx, peer) in peers.into_iter().enumerate() {
         let (handle, tx) = task::spawn(
-            peer, transfer_idx, client_bitfield.clone(), deps.clone(),
+            peer.clone(), transfer_idx, client_bitfield.clone(), deps.clone(),
         );
-        p2p_handles.push((transfer_idx, handle));
-        p2p_tx.push((transfer_idx, tx));
+        p2p_transfers.push((transfer_idx, PeerTransfer::new(peer, handle, tx)));
+    }
+
+    return p2p_transfers;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+    use std::collections::HashSet;
+    use crate::coordinator::stats::PieceState;
+    use crate::coordinator::task::{filter_blocked_peers, restore_stored_pieces};
+    use crate::core_models::entities::{Bitfield, Peer, PeerSource};
+    use crate::dependency_provider::TransferDeps;
+    use crate::ip_filter::Blocklist;
+    use crate::mocks::{MockDepsProvider, MockTorrent};
+    use crate::resume::{resume_file_path, ResumeData};
+
+    fn peer(ip: Ipv4Addr, source: PeerSource) -> Peer {
+        return Peer { ip, port: 6881, source, peer_id: None };
+    }
+
+    #[tokio::test]
+    async fn test_blocked_peers_filtered_and_counted() {
+        let (tx, _rx) = mpsc::channel(1);
+        let deps: Arc<dyn TransferDeps> = Arc::new(MockDepsProvider::new(MockTorrent::generate(1, 1, 1), tx));
+        deps.ip_filter().replace(Blocklist::parse("10.0.0.0/8\n"));
+        deps.banned_peers().ban(Ipv4Addr::new(192, 168, 0, 2));
+        let peers = vec![
+            peer(Ipv4Addr::new(10, 0, 0, 1), PeerSource::Tracker),
+            peer(Ipv4Addr::new(10, 0, 0, 2), PeerSource::Pex),
+            peer(Ipv4Addr::new(192, 168, 0, 1), PeerSource::Tracker),
+            peer(Ipv4Addr::new(192, 168, 0, 2), PeerSource::Tracker),
+        ];
+
+        let peers = filter_blocked_peers(&deps, peers);
+
+        assert_eq!(peers, vec![peer(Ipv4Addr::new(192, 168, 0, 1), PeerSource::Tracker)]);
+        assert_eq!(deps.metrics().blocked_peers.with_label_values(&["tracker"]).get(), 1);
+        assert_eq!(deps.metrics().blocked_peers.with_label_values(&["pex"]).get(), 1);
+    }
+
+    // stores the first two pieces, the second one corrupt, and records both in the resume data
+    async fn store_pieces(torrent: &MockTorrent, deps: &Arc<dyn TransferDeps>, info_hash: Vec<u8>) {
+        let mut file_prov = deps.file_provider();
+        file_prov.open_read_write_instance().await.unwrap();
+        file_prov.write(0, 0, &torrent.pieces_data[0]).await.unwrap();
+        file_prov.write(1, 0, &vec![0xff; torrent.pieces_data[1].len()]).await.unwrap();
+        let mut pieces = Bitfield::init(torrent.layout.pieces);
+        pieces.piece_acquired(0);
+        pieces.piece_acquired(1);
+        let resume_data = ResumeData { info_hash, pieces: pieces.content, downloaded: 2048, uploaded: 1024 };
+        resume_data.save(&resume_file_path(&deps.torrent_layout().output_file_path)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_verified_pieces_restored_from_resume_data() {
+        let (tx, _rx) = mpsc::channel(1);
+        let torrent = MockTorrent::generate(3, 1, 1);
+        let deps: Arc<dyn TransferDeps> = Arc::new(MockDepsProvider::new(torrent.clone(), tx));
+        store_pieces(&torrent, &deps, deps.info_hash()).await;
+
+        let client_bitfield = restore_stored_pieces(deps.as_ref()).await;
+
+        assert_eq!(client_bitfield.to_available_pieces_vec(), vec![0]);
+        let stats = deps.stats_tx().borrow().clone();
+        assert_eq!(stats.piece_states, vec![PieceState::Stored, PieceState::Missing, PieceState::Missing]);
+        assert_eq!((stats.downloaded_bytes, stats.uploaded_bytes), (2048, 1024));
+        // the corrupt piece is downloaded again, while the restored one is never picked
+        let mut peer = Bitfield::init(3);
+        (0..3).for_each(|piece_idx| peer.piece_acquired(piece_idx));
+        let mut requested = HashSet::new();
+        loop {
+            let blocks = deps.piece_picker().lock().await.pick(&peer, &requested, 1);
+            if blocks.is_empty() {
+                break;
+            }
+            requested.extend(blocks);
+        }
+        let picked: HashSet<usize> = requested.iter().map(|block| block.piece_idx).collect();
+        assert_eq!(picked, HashSet::from([1, 2]));
     }
 
-    return (p2p_handles, p2p_tx);
+    #[tokio::test]
+    async fn test_resume_data_of_other_torrent_ignored() {
+        let (tx, _rx) = mpsc::channel(1);
+        let torrent = MockTorrent::generate(3, 1, 1);
+        let deps: Arc<dyn TransferDeps> = Arc::new(MockDepsProvider::new(torrent.clone(), tx));
+        store_pieces(&torrent, &deps, vec![9; 20]).await;
+
+        let client_bitfield = restore_stored_pieces(deps.as_ref()).await;
+
+        assert!(client_bitfield.to_available_pieces_vec().is_empty());
+        assert_eq!(deps.stats_tx().borrow().stored_pieces, 0);
+    }
 }
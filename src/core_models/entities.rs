@@ -1,18 +1,73 @@
 use serde_derive::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
+use std::collections::BTreeMap;
 use std::net::Ipv4Addr;
 use crate::config;
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq)]
 pub struct Peer {
     pub ip: Ipv4Addr,
     pub port: u16,
+    #[serde(skip)]
+    pub source: PeerSource,
+    // only known when the tracker gives it
+    #[serde(skip)]
+    pub peer_id: Option<Vec<u8>>,
+}
+
+// Where the client learned about a peer from
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum PeerSource {
+    #[default]
+    Tracker,
+    Incoming,
+    Dht,
+    Pex,
+    Lsd,
+}
+
+impl PeerSource {
+    // name of the source in metric labels
+    pub fn label(&self) -> &'static str {
+        return match self {
+            PeerSource::Tracker => "tracker",
+            PeerSource::Incoming => "incoming",
+            PeerSource::Dht => "dht",
+            PeerSource::Pex => "pex",
+            PeerSource::Lsd => "lsd",
+        };
+    }
+}
+
+// Peer discovery mechanisms enabled for a torrent
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct DiscoveryPolicy {
+    pub dht: bool,
+    pub pex: bool,
+    pub lsd: bool,
+}
+
+impl DiscoveryPolicy {
+    // BEP 27: private torrents must only use peers returned by the metainfo's trackers
+    pub fn for_torrent(torrent: &Torrent) -> Self {
+        let public = !torrent.is_private();
+        return DiscoveryPolicy { dht: public, pex: public, lsd: public };
+    }
+
+    pub fn allows(&self, source: PeerSource) -> bool {
+        return match source {
+            PeerSource::Tracker | PeerSource::Incoming => true,
+            PeerSource::Dht => self.dht,
+            PeerSource::Pex => self.pex,
+            PeerSource::Lsd => self.lsd,
+        };
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct File {
-    length: u64,
-    path: Vec<String>,
+    pub length: u64,
+    pub path: Vec<String>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -31,7 +86,7 @@ pub struct Info {
     pub private: Option<u8>,
 }
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Torrent {
     pub info: Info,
     #[serde(default)]
@@ -50,9 +105,19 @@ pub struct Torrent {
     #[serde(default)]
     pub encoding: Option<String>,
     #[serde(default)]
+    #[serde(skip_serializing)]
     pub info_hash: Vec<u8>,
     #[serde(skip)]
     pub piece_hashes: Vec<Vec<u8>>,
+    // the info dictionary as it was read, which the info hash is computed from and which is saved back
+    #[serde(skip)]
+    pub info_bytes: Vec<u8>,
+}
+
+impl Torrent {
+    pub fn is_private(&self) -> bool {
+        return self.info.private == Some(1);
+    }
 }
 
 #[derive(Clone, Default, Debug, Eq, Hash, PartialEq)]
@@ -89,7 +154,7 @@ impl DataBlock {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct TorrentLayout {
     pub pieces: usize,
     pub head_pieces_length: usize,
@@ -154,6 +219,12 @@ impl TorrentLayout {
         };
     }
 
+    // whether the block lies within one of the pieces of the torrent
+    pub fn contains_block(&self, block: &Block) -> bool {
+        return block.piece_idx < self.pieces
+            && block.offset.checked_add(block.length).is_some_and(|end| end <= self.piece_length(block.piece_idx));
+    }
+
     pub fn block_length(&self, piece_idx: usize, block_idx: usize) -> usize {
         return if block_idx == self.blocks_in_piece(piece_idx) - 1 {
             self.last_block_length_for_piece(piece_idx)
@@ -176,47 +247,108 @@ pub enum Message {
     Piece(DataBlock),
     Cancel(Block),
     Port(usize),
+    // BEP 10 extension message: (extended message id, bencoded payload)
+    Extended(u8, Vec<u8>),
+}
+
+// Id of the extension handshake among the extended messages
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+// BEP 10 extension handshake
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ExtendedHandshake {
+    // supported extensions, mapped to the extended message id used for them
+    #[serde(default)]
+    pub m: BTreeMap<String, i64>,
+    // client name and version
+    #[serde(default)]
+    pub v: Option<String>,
+    // number of outstanding requests the client supports
+    #[serde(default)]
+    pub reqq: Option<usize>,
+    // length of the bencoded info dictionary, for peers supporting ut_metadata (BEP 9)
+    #[serde(default)]
+    pub metadata_size: Option<usize>,
+}
+
+impl ExtendedHandshake {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        return serde_bencode::de::from_bytes::<ExtendedHandshake>(bytes).ok();
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        return serde_bencode::ser::to_bytes(self).unwrap_or_default();
+    }
+}
+
+// Choking and interest state of a transfer with a peer, as reported by its p2p task
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct PeerStatus {
+    // client name and version, decoded from the peer id or the extension handshake
+    pub client: Option<String>,
+    pub client_is_choked: bool,
+    pub peer_is_choked: bool,
+    pub client_is_interested: bool,
+    pub peer_is_interested: bool,
+    pub is_snubbed: bool,
+    pub outstanding_requests: usize,
+}
+
+// Reasons a message from a peer could not be decoded
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MessageError {
+    UnknownId(u8),
+    // the payload, including the message id, is too short or too long for the id
+    InvalidLength { id: u8, len: usize },
+    // the message refers to a piece the torrent does not have
+    PieceOutOfRange(usize),
+    // a `PIECE` message whose data does not fit in its piece
+    BlockOutOfRange { piece_idx: usize, offset: usize, len: usize },
+    // the bitfield length does not match the number of pieces, or its spare bits are set
+    InvalidBitfield,
 }
 
 impl Message {
-    pub fn deserialize(bytes: Vec<u8>) -> Option<Self> {
-        if bytes.is_empty() {
-            return Some(Message::KeepAlive);
-        }
+    // Decodes a message without its length prefix, checking the payload length of each message id
+    pub fn deserialize(bytes: Vec<u8>) -> Result<Self, MessageError> {
+        let Some(&id) = bytes.first() else {
+            return Ok(Message::KeepAlive);
+        };
 
-        match bytes[0] {
-            0 => Some(Message::Choke),
-            1 => Some(Message::Unchoke),
-            2 => Some(Message::Interested),
-            3 => Some(Message::NotInterested),
-            4 => {
-                let piece = Self::usize_from_be_bytes(bytes[1..].to_vec());
-                return Some(Message::Have(piece));
-            }
-            5 => Some(Message::Bitfield(bytes[1..].to_vec())),
-            6 => {
-                let piece_idx = Self::usize_from_be_bytes(bytes[1..5].to_vec());
-                let offset = Self::usize_from_be_bytes(bytes[5..9].to_vec());
-                let length = Self::usize_from_be_bytes(bytes[9..13].to_vec());
-                return Some(Message::Request(Block::new(piece_idx, offset, length)));
-            }
-            7 => {
+        return match id {
+            0 if bytes.len() == 1 => Ok(Message::Choke),
+            1 if bytes.len() == 1 => Ok(Message::Unchoke),
+            2 if bytes.len() == 1 => Ok(Message::Interested),
+            3 if bytes.len() == 1 => Ok(Message::NotInterested),
+            4 if bytes.len() == 5 => Ok(Message::Have(Self::usize_from_be_bytes(bytes[1..5].to_vec()))),
+            5 => Ok(Message::Bitfield(bytes[1..].to_vec())),
+            6 if bytes.len() == 13 => Ok(Message::Request(Self::block_from_be_bytes(&bytes[1..]))),
+            7 if bytes.len() >= 9 => {
                 let index = Self::usize_from_be_bytes(bytes[1..5].to_vec());
                 let begin = Self::usize_from_be_bytes(bytes[5..9].to_vec());
-                return Some(Message::Piece(DataBlock::new(index, begin, bytes[9..].to_vec())));
-            }
-            8 => {
-                let piece_idx = Self::usize_from_be_bytes(bytes[1..5].to_vec());
-                let offset = Self::usize_from_be_bytes(bytes[5..9].to_vec());
-                let length = Self::usize_from_be_bytes(bytes[9..13].to_vec());
-                return Some(Message::Cancel(Block::new(piece_idx, offset, length)));
+                Ok(Message::Piece(DataBlock::new(index, begin, bytes[9..].to_vec())))
             }
-            9 => {
-                let port = Self::usize_from_be_bytes(bytes[1..].to_vec());
-                return Some(Message::Port(port));
-            }
-            _ => None
-        }
+            8 if bytes.len() == 13 => Ok(Message::Cancel(Self::block_from_be_bytes(&bytes[1..]))),
+            9 if bytes.len() == 3 => Ok(Message::Port(Self::usize_from_be_bytes(bytes[1..3].to_vec()))),
+            20 if bytes.len() >= 2 => Ok(Message::Extended(bytes[1], bytes[2..].to_vec())),
+            0..=9 | 20 => Err(MessageError::InvalidLength { id, len: bytes.len() }),
+            _ => Err(MessageError::UnknownId(id)),
+        };
+    }
+
+    // Checks the piece indices, blocks and bitfields the message refers to against the layout of the
+    // torrent; `REQUEST` messages are checked when they are served
+    pub fn validate(&self, layout: &TorrentLayout) -> Result<(), MessageError> {
+        return match self {
+            Message::Have(piece_idx) if *piece_idx >= layout.pieces => Err(MessageError::PieceOutOfRange(*piece_idx)),
+            Message::Bitfield(bytes) if !bitfield_fits(bytes, layout.pieces) => Err(MessageError::InvalidBitfield),
+            Message::Piece(data_block) if !layout.contains_block(&data_block.to_block()) => Err(MessageError::BlockOutOfRange {
+                piece_idx: data_block.piece_idx,
+                offset: data_block.offset,
+                len: data_block.data.len(),
+            }),
+            _ => Ok(()),
+        };
     }
 
     pub fn serialize(&self) -> Vec<u8> {
@@ -255,7 +387,12 @@ impl Message {
             }
             Message::Port(port) => {
                 bytes.push(9);
-                bytes.append(&mut Self::usize_to_four_be_bytes(*port));
+                bytes.extend((*port as u16).to_be_bytes());
+            }
+            Message::Extended(extended_id, payload) => {
+                bytes.push(20);
+                bytes.push(*extended_id);
+                bytes.extend(payload.iter());
             }
         }
 
@@ -265,6 +402,28 @@ impl Message {
         return message;
     }
 
+    // bytes of the serialized message which are not block data
+    pub fn overhead_len(&self) -> usize {
+        return match self {
+            Message::Piece(data_block) => self.serialized_len() - data_block.data.len(),
+            _ => self.serialized_len(),
+        };
+    }
+
+    fn serialized_len(&self) -> usize {
+        let payload_len = match self {
+            Message::KeepAlive => 0,
+            Message::Choke | Message::Unchoke | Message::Interested | Message::NotInterested => 1,
+            Message::Have(_) => 5,
+            Message::Port(_) => 3,
+            Message::Bitfield(bitfield) => 1 + bitfield.len(),
+            Message::Request(_) | Message::Cancel(_) => 13,
+            Message::Piece(data_block) => 9 + data_block.data.len(),
+            Message::Extended(_, payload) => 2 + payload.len(),
+        };
+        return 4 + payload_len;
+    }
+
     pub fn is_interested(&self) -> bool {
         return match self {
             Message::Interested => true,
@@ -293,6 +452,14 @@ impl Message {
         };
     }
 
+    // piece index, offset and length of a REQUEST or CANCEL message
+    fn block_from_be_bytes(bytes: &[u8]) -> Block {
+        let piece_idx = Self::usize_from_be_bytes(bytes[0..4].to_vec());
+        let offset = Self::usize_from_be_bytes(bytes[4..8].to_vec());
+        let length = Self::usize_from_be_bytes(bytes[8..12].to_vec());
+        return Block::new(piece_idx, offset, length);
+    }
+
     fn usize_from_be_bytes(bytes: Vec<u8>) -> usize {
         return bytes.into_iter()
             .rev().enumerate()
@@ -356,7 +523,7 @@ impl Bitfield {
     pub fn to_available_pieces_vec(&self) -> Vec<usize> {
         let mut pieces_available = Vec::new();
         for (byte_idx, byte) in self.content.iter().enumerate() {
-            for bit_idx in 0..7 {
+            for bit_idx in 0..8 {
                 let mask = 1 << (7 - bit_idx);
                 let bit_value = byte & mask;
                 if bit_value != 0 {
@@ -369,10 +536,45 @@ impl Bitfield {
     }
 }
 
+// whether the bitfield has a bit for each piece, and none set past the last piece
+fn bitfield_fits(bytes: &[u8], num_of_pieces: usize) -> bool {
+    if bytes.len() != num_of_pieces.div_ceil(8) {
+        return false;
+    }
+    let spare_bits = bytes.len() * 8 - num_of_pieces;
+    let spare_bits_mask = ((1u16 << spare_bits) - 1) as u8;
+    return bytes.last().is_none_or(|last| last & spare_bits_mask == 0);
+}
 
 #[cfg(test)]
 mod tests {
-    use crate::core_models::entities::{Bitfield, Block, DataBlock, Message};
+    use crate::config;
+    use crate::core_models::entities::{Bitfield, Block, DataBlock, DiscoveryPolicy, ExtendedHandshake, Message, MessageError, PeerSource};
+    use crate::mocks::generate_mock_layout;
+    use crate::torrent_parser::parse_torrent;
+
+    #[test]
+    pub fn discovery_policy_for_private_torrent_test() {
+        let mut torrent = parse_torrent("test_resources/debian-12.0.0-amd64-netinst.iso.torrent").unwrap();
+        torrent.info.private = Some(1);
+        let policy = DiscoveryPolicy::for_torrent(&torrent);
+
+        assert!(policy.allows(PeerSource::Tracker));
+        assert!(policy.allows(PeerSource::Incoming));
+        assert!(!policy.allows(PeerSource::Dht));
+        assert!(!policy.allows(PeerSource::Pex));
+        assert!(!policy.allows(PeerSource::Lsd));
+    }
+
+    #[test]
+    pub fn discovery_policy_for_public_torrent_test() {
+        let torrent = parse_torrent("test_resources/debian-12.0.0-amd64-netinst.iso.torrent").unwrap();
+        let policy = DiscoveryPolicy::for_torrent(&torrent);
+
+        assert!(policy.allows(PeerSource::Dht));
+        assert!(policy.allows(PeerSource::Pex));
+        assert!(policy.allows(PeerSource::Lsd));
+    }
 
     #[test]
     pub fn bitfield_initialization_test() {
@@ -432,6 +634,27 @@ mod tests {
         bitfield.piece_acquired(11);
         pieces_available.push(11);
         assert_eq!(bitfield.to_available_pieces_vec(), pieces_available);
+
+        // the last bit of each byte
+        bitfield.piece_acquired(7);
+        assert_eq!(bitfield.to_available_pieces_vec(), vec![5, 7, 11]);
+    }
+
+    #[test]
+    fn messages_validated_against_layout_test() {
+        let layout = generate_mock_layout(10, 2, 1);
+
+        assert_eq!(Message::Have(9).validate(&layout), Ok(()));
+        assert_eq!(Message::Have(10).validate(&layout), Err(MessageError::PieceOutOfRange(10)));
+        assert_eq!(Message::Bitfield(vec![0xff, 0b1100_0000]).validate(&layout), Ok(()));
+        assert_eq!(Message::Bitfield(vec![0xff]).validate(&layout), Err(MessageError::InvalidBitfield));
+        assert_eq!(Message::Bitfield(vec![0xff, 0, 0]).validate(&layout), Err(MessageError::InvalidBitfield));
+        assert_eq!(Message::Bitfield(vec![0xff, 0b1110_0000]).validate(&layout), Err(MessageError::InvalidBitfield));
+        assert_eq!(Message::Piece(DataBlock::new(9, 0, vec![0; config::BLOCK_SIZE_BYTES])).validate(&layout), Ok(()));
+        assert_eq!(Message::Piece(DataBlock::new(9, 1, vec![0; config::BLOCK_SIZE_BYTES])).validate(&layout),
+                   Err(MessageError::BlockOutOfRange { piece_idx: 9, offset: 1, len: config::BLOCK_SIZE_BYTES }));
+        assert_eq!(Message::Piece(DataBlock::new(10, 0, vec![0])).validate(&layout),
+                   Err(MessageError::BlockOutOfRange { piece_idx: 10, offset: 0, len: 1 }));
     }
 
     #[test]
@@ -445,7 +668,7 @@ mod tests {
     fn deserialize_choke_test() {
         let serialized_bytes = vec![0];
         let deserialized_message = Message::deserialize(serialized_bytes.clone());
-        assert_eq!(deserialized_message, Some(Message::Choke));
+        assert_eq!(deserialized_message, Ok(Message::Choke));
     }
 
     #[test]
@@ -459,7 +682,7 @@ mod tests {
     fn deserialize_unchoke_test() {
         let serialized_bytes = vec![1];
         let message = Message::deserialize(serialized_bytes.clone());
-        assert_eq!(message, Some(Message::Unchoke));
+        assert_eq!(message, Ok(Message::Unchoke));
     }
 
     #[test]
@@ -473,7 +696,7 @@ mod tests {
     fn deserialize_interested_test() {
         let serialized_bytes = vec![2];
         let deserialized_message = Message::deserialize(serialized_bytes.clone());
-        assert_eq!(deserialized_message, Some(Message::Interested));
+        assert_eq!(deserialized_message, Ok(Message::Interested));
     }
 
     #[test]
@@ -487,7 +710,7 @@ mod tests {
     fn deserialize_not_interested_test() {
         let serialized_bytes = vec![3];
         let deserialized_message = Message::deserialize(serialized_bytes.clone());
-        assert_eq!(deserialized_message, Some(Message::NotInterested));
+        assert_eq!(deserialized_message, Ok(Message::NotInterested));
     }
 
     #[test]
@@ -502,7 +725,7 @@ mod tests {
         let index = 42;
         let serialized_bytes = vec![4, 0, 0, 0, 42];
         let deserialized_message = Message::deserialize(serialized_bytes.clone());
-        assert_eq!(deserialized_message, Some(Message::Have(index)));
+        assert_eq!(deserialized_message, Ok(Message::Have(index)));
     }
 
     #[test]
@@ -521,7 +744,7 @@ mod tests {
         bitfield.piece_acquired(2);
         let serialized_bytes = vec![5u8, 32, 0];
         let deserialized_message = Message::deserialize(serialized_bytes.clone());
-        assert_eq!(deserialized_message, Some(Message::Bitfield(bitfield.content.clone())));
+        assert_eq!(deserialized_message, Ok(Message::Bitfield(bitfield.content.clone())));
     }
 
     #[test]
@@ -538,7 +761,7 @@ mod tests {
         let block = Block::new(1, 2, 3);
         let bytes: Vec<u8> = vec![6, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
         let deserialized_message = Message::deserialize(bytes);
-        assert_eq!(deserialized_message, Some(Message::Request(block)));
+        assert_eq!(deserialized_message, Ok(Message::Request(block)));
     }
 
     #[test]
@@ -556,7 +779,7 @@ mod tests {
         let mut expected_bytes = vec![7, 0, 0, 0, 1, 0, 0, 0, 2];
         expected_bytes.extend(data_block.data.iter());
         let deserialized_message = Message::deserialize(expected_bytes.clone());
-        assert_eq!(deserialized_message, Some(Message::Piece(data_block)));
+        assert_eq!(deserialized_message, Ok(Message::Piece(data_block)));
     }
 
     #[test]
@@ -573,6 +796,56 @@ mod tests {
         let block = Block::new(1, 2, 3); // Example block parameters.
         let expected_bytes: Vec<u8> = vec![8, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3];
         let deserialized_message = Message::deserialize(expected_bytes.clone());
-        assert_eq!(deserialized_message, Some(Message::Cancel(block)));
+        assert_eq!(deserialized_message, Ok(Message::Cancel(block)));
+    }
+
+    #[test]
+    fn serialize_extended_test() {
+        let message = Message::Extended(0, vec![100, 101]);
+        let serialized_bytes = message.serialize();
+        assert_eq!(serialized_bytes, vec![0, 0, 0, 4, 20, 0, 100, 101]);
+    }
+
+    #[test]
+    fn deserialize_extended_test() {
+        let deserialized_message = Message::deserialize(vec![20, 3, 100]);
+        assert_eq!(deserialized_message, Ok(Message::Extended(3, vec![100])));
+    }
+
+    #[test]
+    fn deserialize_truncated_messages_test() {
+        for bytes in [vec![4, 0, 0], vec![6, 0, 0, 0, 1, 0, 0, 0, 2], vec![7, 0, 0, 0, 1], vec![8], vec![20], vec![0, 1]] {
+            let id = bytes[0];
+            let len = bytes.len();
+            assert_eq!(Message::deserialize(bytes), Err(MessageError::InvalidLength { id, len }));
+        }
+        assert_eq!(Message::deserialize(vec![42, 0]), Err(MessageError::UnknownId(42)));
+    }
+
+    #[test]
+    fn port_round_trip_test() {
+        let serialized_bytes = Message::Port(6881).serialize();
+        assert_eq!(serialized_bytes, vec![0, 0, 0, 3, 9, 0x1A, 0xE1]);
+        assert_eq!(Message::deserialize(serialized_bytes[4..].to_vec()), Ok(Message::Port(6881)));
+    }
+
+    #[test]
+    fn extended_handshake_test() {
+        let bytes = b"d1:md11:ut_metadatai3ee4:reqqi500e1:v13:qBittorrent 4e".to_vec();
+        let handshake = ExtendedHandshake::from_bytes(&bytes).unwrap();
+        assert_eq!(handshake.reqq, Some(500));
+        assert_eq!(handshake.v, Some("qBittorrent 4".to_string()));
+        assert_eq!(handshake.m.get("ut_metadata"), Some(&3));
+        assert_eq!(ExtendedHandshake::from_bytes(&handshake.to_bytes()), Some(handshake));
+    }
+
+    #[test]
+    fn overhead_len_test() {
+        let piece = Message::Piece(DataBlock::new(1, 0, vec![7; 100]));
+        assert_eq!(piece.overhead_len(), piece.serialize().len() - 100);
+        let have = Message::Have(42);
+        assert_eq!(have.overhead_len(), have.serialize().len());
+        let bitfield = Message::Bitfield(vec![255, 128]);
+        assert_eq!(bitfield.overhead_len(), bitfield.serialize().len());
     }
 }
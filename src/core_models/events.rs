@@ -1,11 +1,12 @@
-use crate::core_models::entities::{Block, DataBlock};
+use std::net::Ipv4Addr;
+use crate::core_models::entities::{Block, DataBlock, PeerStatus};
 
 pub type TransferIdx = usize;
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum InternalEvent {
     BlockDownloaded(usize, DataBlock),
-    BlockUploaded(usize),
+    BlockUploaded(usize, usize),
     BlockStored(Block),
     ChokePeer(usize),
     DownloadComplete,
@@ -15,6 +16,17 @@ pub enum InternalEvent {
     ClientInterestedInPeer(usize, bool),
     PeerInterestedInClient(usize, bool),
     PeerConnectionEstablished(usize),
+    PeerSnubbed(usize, bool),
+    PeerStatusChanged(usize, PeerStatus),
+    PauseTransfer,
+    ResumeTransfer,
+    StopTransfer,
+    // reading or writing the torrent data failed, so the transfer is paused
+    StorageFailed(String),
+    // the peer at the address sent corrupt data and was banned
+    BanPeer(Ipv4Addr),
+    // the selection of wanted pieces changed, which may leave nothing to download
+    PrioritiesChanged,
 }
 
 impl InternalEvent {
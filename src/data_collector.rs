@@ -1,17 +1,22 @@
 use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
 use std::sync::Arc;
 use log::{info, warn};
 use sha1::{Digest, Sha1};
 use tokio::sync::{mpsc};
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::task::JoinHandle;
+use crate::alerts::Alert;
+use crate::coordinator::stats::PieceState;
 use crate::dependency_provider::TransferDeps;
 use crate::core_models::entities::{Block, DataBlock, TorrentLayout};
 use crate::core_models::events::InternalEvent;
-use crate::file_provider::{FileProv};
+use crate::file_provider::{FileProv, StorageError};
+use crate::metrics::{DUPLICATE, HASH_FAILURE};
+use crate::smart_ban::{SmartBan, StoredBlock};
 
-pub fn spawn(deps: Arc<dyn TransferDeps>) -> (JoinHandle<()>, Sender<DataBlock>) {
-    let (tx_to_self, rx) = mpsc::channel::<DataBlock>(1024);
+pub fn spawn(deps: Arc<dyn TransferDeps>) -> (JoinHandle<()>, Sender<(Ipv4Addr, DataBlock)>) {
+    let (tx_to_self, rx) = mpsc::channel::<(Ipv4Addr, DataBlock)>(deps.client_config().channel_capacity);
     let handle = tokio::spawn(async move {
         run(deps.clone(), rx).await;
     });
@@ -19,67 +24,154 @@ pub fn spawn(deps: Arc<dyn TransferDeps>) -> (JoinHandle<()>, Sender<DataBlock>)
     return (handle, tx_to_self);
 }
 
-async fn run(deps: Arc<dyn TransferDeps>, mut rx: Receiver<DataBlock>) {
+async fn run(deps: Arc<dyn TransferDeps>, mut rx: Receiver<(Ipv4Addr, DataBlock)>) {
     let tx = deps.output_tx();
     let layout = deps.torrent_layout();
     let hashes = deps.piece_hashes();
     let mut file_prov = deps.file_provider();
     let picker = deps.piece_picker();
+    let alerts = deps.alerts();
+    let info_hash = deps.info_hash();
+    let metrics = deps.metrics();
+    let banned_peers = deps.banned_peers();
+    let stats_tx = deps.stats_tx();
 
-    file_prov.open_read_write_instance().await;
-    let mut acquired_pieces = 0;
-    let mut written_data: HashMap<usize, HashSet<Block>> = (0..layout.pieces).into_iter()
-        .map(|piece_idx| (piece_idx, HashSet::new()))
+    let mut is_file_open = false;
+    // pieces restored from the resume data are already stored
+    let mut acquired_pieces: HashSet<usize> = deps.stats_tx().borrow().piece_states.iter()
+        .enumerate()
+        .filter(|(_, state)| **state == PieceState::Stored)
+        .map(|(piece_idx, _)| piece_idx)
         .collect();
+    // the stored blocks of each piece, along with the addresses of the peers which sent them
+    let mut written_data: HashMap<usize, HashMap<Block, Ipv4Addr>> = (0..layout.pieces).into_iter()
+        .map(|piece_idx| (piece_idx, HashMap::new()))
+        .collect();
+    let mut smart_ban = SmartBan::default();
 
-    while let Some(data_block) = rx.recv().await {
-        let blocks = written_data.get_mut(&data_block.piece_idx).unwrap();
-        if !blocks.insert(data_block.to_block()) {
+    // once the transfer stops, the coordinator no longer listens, but the blocks it already sent are
+    // still stored before the channel closes
+    while let Some((sender, data_block)) = rx.recv().await {
+        let block = data_block.to_block();
+        let Some(blocks) = written_data.get_mut(&data_block.piece_idx) else {
+            warn!("Received a block of a piece which does not exist -> {}", data_block.piece_idx);
+            continue;
+        };
+        if blocks.contains_key(&block) || acquired_pieces.contains(&data_block.piece_idx) {
+            metrics.wasted_bytes.with_label_values(&[DUPLICATE]).inc_by(data_block.data.len() as u64);
+            continue;
+        }
+        if let Err(err) = store(&mut file_prov, &mut is_file_open, &data_block).await {
+            // the block is picked again once the transfer is resumed
+            picker.lock().await.unpick_blocks(&[block]);
+            let _ = tx.send(InternalEvent::StorageFailed(format!("{:?}", err))).await;
             continue;
         }
-        file_prov.write(data_block.piece_idx, data_block.offset, &data_block.data).await;
+        blocks.insert(block.clone(), sender);
 
         if piece_incomplete(data_block.piece_idx, &layout, blocks.len()) {
             {
                 let mut picker = picker.lock().await;
-                picker.remove_block(&data_block.to_block());
+                picker.remove_block(&block);
+            }
+            let _ = tx.send(InternalEvent::BlockStored(block)).await;
+            continue;
+        }
+
+        let piece = match file_prov.read_piece(data_block.piece_idx).await {
+            Ok(piece) => piece,
+            Err(err) => {
+                // the piece could not be verified, so it is downloaded again
+                {
+                    let mut picker = picker.lock().await;
+                    picker.reinsert_piece(data_block.piece_idx);
+                }
+                written_data.insert(data_block.piece_idx, HashMap::new());
+                let _ = tx.send(InternalEvent::StorageFailed(format!("{:?}", err))).await;
+                continue;
             }
-            tx.send(InternalEvent::BlockStored(data_block.to_block())).await.unwrap();
-        } else if piece_corrupt(data_block.piece_idx, &mut file_prov, &hashes).await {
+        };
+        let stored_blocks = stored_blocks(blocks, &piece);
+        let is_corrupt = piece_corrupt(&piece, &hashes[data_block.piece_idx]);
+        let to_ban = if is_corrupt {
+            smart_ban.piece_failed(data_block.piece_idx, &stored_blocks)
+        } else {
+            smart_ban.piece_passed(data_block.piece_idx, &stored_blocks)
+        };
+
+        if is_corrupt {
             warn!("Piece corrupt -> {}", data_block.piece_idx);
             {
                 let mut picker = picker.lock().await;
                 picker.reinsert_piece(data_block.piece_idx);
             }
-            written_data.insert(data_block.piece_idx, HashSet::new());
+            written_data.insert(data_block.piece_idx, HashMap::new());
+            metrics.hash_failures.inc();
+            metrics.wasted_bytes.with_label_values(&[HASH_FAILURE]).inc_by(layout.piece_length(data_block.piece_idx) as u64);
+            alerts.send(Alert::HashFailed { info_hash: info_hash.clone(), piece_idx: data_block.piece_idx });
         } else {
-            acquired_pieces += 1;
+            acquired_pieces.insert(data_block.piece_idx);
             {
                 let mut picker = picker.lock().await;
-                picker.remove_block(&data_block.to_block());
+                picker.remove_piece(data_block.piece_idx);
             }
             let piece_idx = data_block.piece_idx.clone();
-            tx.send(InternalEvent::BlockStored(data_block.to_block())).await.unwrap();
-            tx.send(InternalEvent::PieceStored(piece_idx)).await.unwrap();
-            info!("Piece complete -> {}, {} out of {}", data_block.piece_idx, acquired_pieces, layout.pieces);
+            // recorded here rather than by the coordinator, so that pieces stored while draining make it into
+            // the resume data
+            stats_tx.send_modify(|stats| stats.piece_stored(piece_idx));
+            let _ = tx.send(InternalEvent::BlockStored(block)).await;
+            let _ = tx.send(InternalEvent::PieceStored(piece_idx)).await;
+            metrics.pieces_stored.inc();
+            alerts.send(Alert::PieceFinished { info_hash: info_hash.clone(), piece_idx });
+            info!("Piece complete -> {}, {} out of {}", data_block.piece_idx, acquired_pieces.len(), layout.pieces);
+        }
+        for ip in to_ban {
+            warn!("Peer {} sent corrupt data for piece {}", ip, data_block.piece_idx);
+            // banned right away, as the peer may have disconnected already
+            banned_peers.ban(ip);
+            let _ = tx.send(InternalEvent::BanPeer(ip)).await;
         }
-        if acquired_pieces == layout.pieces {
-            tx.send(InternalEvent::DownloadComplete).await.unwrap();
-            break;
+        // the collector keeps running, as skipped pieces may be wanted later on
+        if picker.lock().await.is_selection_complete() {
+            alerts.send(Alert::TorrentFinished { info_hash: info_hash.clone() });
+            let _ = tx.send(InternalEvent::DownloadComplete).await;
         }
     }
+
+    if is_file_open {
+        if let Err(err) = file_prov.flush().await {
+            warn!("Stored data not flushed: {:?}", err);
+        }
+    }
+}
+
+// Writes the block, opening the file first if it is not open yet; after a failure to open it, the
+// next block tries again, since the problem may have been fixed before the transfer was resumed
+async fn store(file_prov: &mut Box<dyn FileProv>, is_file_open: &mut bool, data_block: &DataBlock) -> Result<(), StorageError> {
+    if !*is_file_open {
+        file_prov.open_read_write_instance().await?;
+        *is_file_open = true;
+    }
+
+    return file_prov.write(data_block.piece_idx, data_block.offset, &data_block.data).await;
 }
 
 fn piece_incomplete(piece_idx: usize, layout: &TorrentLayout, stored_blocks_in_piece: usize) -> bool {
     return stored_blocks_in_piece < layout.blocks_in_piece(piece_idx);
 }
 
-async fn piece_corrupt(piece_idx: usize, file: &mut Box<dyn FileProv>, hashes: &Vec<Vec<u8>>) -> bool {
-    // compute piece hash
-    let piece = file.read_piece(piece_idx).await;
+// the blocks of a complete piece, with their data sliced out of the piece as read back from the file
+fn stored_blocks<'a>(blocks: &'a HashMap<Block, Ipv4Addr>, piece: &'a [u8]) -> Vec<StoredBlock<'a>> {
+    return blocks.iter()
+        .filter_map(|(block, sender)| {
+            let data = piece.get(block.offset..block.offset + block.length)?;
+            return Some(StoredBlock { block, sender: *sender, data });
+        })
+        .collect();
+}
+
+pub fn piece_corrupt(piece: &[u8], piece_hash: &[u8]) -> bool {
     let mut hasher = Sha1::new();
     hasher.update(piece);
-    let piece_hash = hasher.finalize().into_iter().collect::<Vec<u8>>();
-
-    return piece_hash.cmp(&hashes[piece_idx]).is_ne();
-}
\ No newline at end of file
+    return hasher.finalize().as_slice() != piece_hash;
+}
@@ -1,60 +1,130 @@
 use std::sync::Arc;
 use tokio::sync::mpsc::Sender;
-use tokio::sync::Mutex;
+use tokio::sync::watch;
+use tokio::sync::{Mutex, Semaphore};
+use crate::alerts::Alerts;
 use crate::config::Config;
-use crate::core_models::entities::{Torrent, TorrentLayout};
+use crate::coordinator::stats::TorrentStats;
+use crate::core_models::entities::{DiscoveryPolicy, Torrent, TorrentLayout};
 use crate::core_models::events::InternalEvent;
 use crate::file_provider::{FileProv, TokioFileProv};
+use crate::ip_filter::IpFilter;
+use crate::metrics::Metrics;
 use crate::p2p::conn::{PeerConnector, TCPPeerConnector};
-use crate::piece_picker::{PiecePicker, RarestPiecePicker};
+use crate::p2p::models::{BannedPeers, ConnectedPeers};
+use crate::piece_picker::{PickingMode, PiecePicker, RarestPiecePicker};
+use crate::rate_limiter::RateLimiter;
 use crate::tracker::client::{TorrentTrackerClient, TrackerClient};
 
 pub trait TransferDeps: Send + Sync {
+    fn alerts(&self) -> Alerts;
     fn announce_url(&self) -> String;
+    fn banned_peers(&self) -> BannedPeers;
     fn client_config(&self) -> Config;
+    fn connection_budget(&self) -> Arc<Semaphore>;
+    fn connected_peers(&self) -> ConnectedPeers;
+    fn discovery_policy(&self) -> DiscoveryPolicy;
     fn file_provider(&self) -> Box<dyn FileProv>;
     fn info_hash(&self) -> Vec<u8>;
+    fn ip_filter(&self) -> IpFilter;
+    fn metrics(&self) -> Arc<Metrics>;
     fn output_tx(&self) -> Sender<InternalEvent>;
     fn peer_connector(&self) -> Box<dyn PeerConnector>;
     fn piece_hashes(&self) -> Vec<Vec<u8>>;
     fn piece_picker(&self) -> Arc<Mutex<dyn PiecePicker>>;
+    fn rate_limiter(&self) -> Arc<RateLimiter>;
+    fn stats_tx(&self) -> watch::Sender<TorrentStats>;
     fn torrent_layout(&self) -> TorrentLayout;
     fn tracker_client(&self) -> Box<dyn TrackerClient>;
 }
 
+// Resources shared by all the torrents of a session
+#[derive(Clone)]
+pub struct SessionResources {
+    pub rate_limiter: Arc<RateLimiter>,
+    // limits the number of peer connections across all torrents
+    pub connection_budget: Arc<Semaphore>,
+    pub alerts: Alerts,
+    pub metrics: Arc<Metrics>,
+    pub banned_peers: BannedPeers,
+    pub ip_filter: IpFilter,
+}
+
+impl SessionResources {
+    pub fn new(config: &Config) -> Self {
+        return SessionResources {
+            rate_limiter: Arc::new(RateLimiter::new(config.rate_limits)),
+            connection_budget: Arc::new(Semaphore::new(config.max_connections)),
+            alerts: Alerts::new(config.alert_buffer_size),
+            metrics: Arc::new(Metrics::new()),
+            banned_peers: BannedPeers::default(),
+            ip_filter: IpFilter::default(),
+        };
+    }
+}
+
 pub struct DependencyProvider {
     client_config: Config,
+    resources: SessionResources,
     torrent: Torrent,
     layout: TorrentLayout,
     tx_to_coordinator: Sender<InternalEvent>,
     piece_picker: Arc<Mutex<dyn PiecePicker>>,
+    stats_tx: watch::Sender<TorrentStats>,
+    connected_peers: ConnectedPeers,
 }
 
 impl DependencyProvider {
     pub fn init(client_config: Config,
+                resources: SessionResources,
                 torrent: Torrent, layout: TorrentLayout,
+                picking_mode: PickingMode,
                 tx_to_coordinator: Sender<InternalEvent>) -> Self {
-        let picker = RarestPiecePicker::init(layout.clone());
+        let picker = RarestPiecePicker::init(layout.clone()).with_mode(picking_mode);
+        let (stats_tx, _) = watch::channel(TorrentStats::new(torrent.info.name.clone(), torrent.info_hash.clone(), &layout));
 
         return DependencyProvider {
             client_config,
+            resources,
             torrent,
             layout,
             tx_to_coordinator,
             piece_picker: Arc::new(Mutex::new(picker)),
+            stats_tx,
+            connected_peers: ConnectedPeers::default(),
         };
     }
 }
 
 impl TransferDeps for DependencyProvider {
+    fn alerts(&self) -> Alerts {
+        return self.resources.alerts.clone();
+    }
+
     fn announce_url(&self) -> String {
         return self.torrent.announce.clone();
     }
 
+    fn banned_peers(&self) -> BannedPeers {
+        return self.resources.banned_peers.clone();
+    }
+
     fn client_config(&self) -> Config {
         return self.client_config.clone();
     }
 
+    fn connection_budget(&self) -> Arc<Semaphore> {
+        return self.resources.connection_budget.clone();
+    }
+
+    fn connected_peers(&self) -> ConnectedPeers {
+        return self.connected_peers.clone();
+    }
+
+    fn discovery_policy(&self) -> DiscoveryPolicy {
+        return DiscoveryPolicy::for_torrent(&self.torrent);
+    }
+
     fn file_provider(&self) -> Box<dyn FileProv> {
         return Box::new(TokioFileProv::new(self.layout.clone()));
     }
@@ -63,6 +133,14 @@ impl TransferDeps for DependencyProvider {
         return self.torrent.info_hash.clone();
     }
 
+    fn ip_filter(&self) -> IpFilter {
+        return self.resources.ip_filter.clone();
+    }
+
+    fn metrics(&self) -> Arc<Metrics> {
+        return self.resources.metrics.clone();
+    }
+
     fn output_tx(&self) -> Sender<InternalEvent> {
         return self.tx_to_coordinator.clone();
     }
@@ -79,6 +157,14 @@ impl TransferDeps for DependencyProvider {
         return self.piece_picker.clone();
     }
 
+    fn rate_limiter(&self) -> Arc<RateLimiter> {
+        return self.resources.rate_limiter.clone();
+    }
+
+    fn stats_tx(&self) -> watch::Sender<TorrentStats> {
+        return self.stats_tx.clone();
+    }
+
     fn torrent_layout(&self) -> TorrentLayout {
         return self.layout.clone();
     }
@@ -4,14 +4,25 @@ use std::io::{Read, Seek, SeekFrom, Write};
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use crate::core_models::entities::{Block, TorrentLayout};
 
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StorageError {
+    FileNotOpened(String),
+    // reading or writing before the file was opened
+    FileNotOpen,
+    ReadFailed(String),
+    WriteFailed(String),
+}
+
 #[automock]
 #[async_trait]
 pub trait FileProv: Send {
-    async fn open_read_write_instance(&mut self);
-    async fn open_read_only_instance(&mut self);
-    async fn read_block(&mut self, block: &Block) -> Vec<u8>;
-    async fn read_piece(&mut self, piece_idx: usize) -> Vec<u8>;
-    async fn write(&mut self, piece_idx: usize, offset_in_piece: usize, data: &Vec<u8>);
+    async fn open_read_write_instance(&mut self) -> Result<(), StorageError>;
+    async fn open_read_only_instance(&mut self) -> Result<(), StorageError>;
+    async fn read_block(&mut self, block: &Block) -> Result<Vec<u8>, StorageError>;
+    async fn read_piece(&mut self, piece_idx: usize) -> Result<Vec<u8>, StorageError>;
+    async fn write(&mut self, piece_idx: usize, offset_in_piece: usize, data: &Vec<u8>) -> Result<(), StorageError>;
+    // makes sure the writes so far reached the disk
+    async fn flush(&mut self) -> Result<(), StorageError>;
 }
 
 pub struct TokioFileProv {
@@ -27,46 +38,66 @@ impl TokioFileProv {
 
 #[async_trait]
 impl FileProv for TokioFileProv {
-    async fn open_read_write_instance(&mut self) {
+    async fn open_read_write_instance(&mut self) -> Result<(), StorageError> {
         let file = tokio::fs::OpenOptions::new()
             .read(true)
             .write(true)
             .open(self.layout.output_file_path.as_str())
             .await
-            .unwrap();
+            .map_err(|err| StorageError::FileNotOpened(err.to_string()))?;
         self.file = Some(file);
+        return Ok(());
     }
 
-    async fn open_read_only_instance(&mut self) {
+    async fn open_read_only_instance(&mut self) -> Result<(), StorageError> {
         let file = tokio::fs::OpenOptions::new()
             .read(true)
             .open(self.layout.output_file_path.as_str())
             .await
-            .unwrap();
+            .map_err(|err| StorageError::FileNotOpened(err.to_string()))?;
         self.file = Some(file);
+        return Ok(());
     }
 
-    async fn read_block(&mut self, block: &Block) -> Vec<u8> {
+    async fn read_block(&mut self, block: &Block) -> Result<Vec<u8>, StorageError> {
         let offset = block.piece_idx * self.layout.head_pieces_length + block.offset;
-        let mut piece_buff = vec![0u8; block.length];
-        self.file.as_mut().unwrap().seek(SeekFrom::Start(offset as u64)).await.unwrap();
-        self.file.as_mut().unwrap().read_exact(&mut piece_buff).await.unwrap();
-        return piece_buff;
+        return self.read_at(offset, block.length).await;
     }
 
-    async fn read_piece(&mut self, piece_idx: usize) -> Vec<u8> {
+    async fn read_piece(&mut self, piece_idx: usize) -> Result<Vec<u8>, StorageError> {
         let offset = piece_idx * self.layout.head_pieces_length;
-        let length = self.layout.piece_length(piece_idx);
-        let mut piece_buff = vec![0u8; length];
-        self.file.as_mut().unwrap().seek(SeekFrom::Start(offset as u64)).await.unwrap();
-        self.file.as_mut().unwrap().read_exact(&mut piece_buff).await.unwrap();
-        return piece_buff;
+        return self.read_at(offset, self.layout.piece_length(piece_idx)).await;
     }
 
-    async fn write(&mut self, piece_idx: usize, offset_in_piece: usize, data: &Vec<u8>) {
+    async fn write(&mut self, piece_idx: usize, offset_in_piece: usize, data: &Vec<u8>) -> Result<(), StorageError> {
         let offset = piece_idx * self.layout.head_pieces_length + offset_in_piece;
-        self.file.as_mut().unwrap().seek(SeekFrom::Start(offset as u64)).await.unwrap();
-        self.file.as_mut().unwrap().write_all(&*data).await.unwrap();
+        let file = self.file.as_mut().ok_or(StorageError::FileNotOpen)?;
+        file.seek(SeekFrom::Start(offset as u64)).await
+            .map_err(|err| StorageError::WriteFailed(err.to_string()))?;
+        file.write_all(data).await
+            .map_err(|err| StorageError::WriteFailed(err.to_string()))?;
+        return Ok(());
+    }
+
+    async fn flush(&mut self) -> Result<(), StorageError> {
+        let file = self.file.as_mut().ok_or(StorageError::FileNotOpen)?;
+        file.flush().await
+            .map_err(|err| StorageError::WriteFailed(err.to_string()))?;
+        file.sync_data().await
+            .map_err(|err| StorageError::WriteFailed(err.to_string()))?;
+        return Ok(());
+    }
+}
+
+impl TokioFileProv {
+    async fn read_at(&mut self, offset: usize, length: usize) -> Result<Vec<u8>, StorageError> {
+        let file = self.file.as_mut().ok_or(StorageError::FileNotOpen)?;
+        let mut buffer = vec![0u8; length];
+        file.seek(SeekFrom::Start(offset as u64)).await
+            .map_err(|err| StorageError::ReadFailed(err.to_string()))?;
+        file.read_exact(&mut buffer).await
+            .map_err(|err| StorageError::ReadFailed(err.to_string()))?;
+        return Ok(buffer);
     }
 }
 
@@ -83,43 +114,63 @@ impl TempFileProv {
 
 #[async_trait]
 impl FileProv for TempFileProv {
-    async fn open_read_write_instance(&mut self) {
+    async fn open_read_write_instance(&mut self) -> Result<(), StorageError> {
         let file = std::fs::OpenOptions::new()
             .read(true)
             .write(true)
             .open(self.layout.output_file_path.as_str())
-            .unwrap();
+            .map_err(|err| StorageError::FileNotOpened(err.to_string()))?;
         self.file = Some(file);
+        return Ok(());
     }
 
-    async fn open_read_only_instance(&mut self) {
+    async fn open_read_only_instance(&mut self) -> Result<(), StorageError> {
         let file = std::fs::OpenOptions::new()
             .read(true)
             .open(self.layout.output_file_path.as_str())
-            .unwrap();
+            .map_err(|err| StorageError::FileNotOpened(err.to_string()))?;
         self.file = Some(file);
+        return Ok(());
     }
 
-    async fn read_block(&mut self, block: &Block) -> Vec<u8> {
+    async fn read_block(&mut self, block: &Block) -> Result<Vec<u8>, StorageError> {
         let offset = block.piece_idx * self.layout.head_pieces_length + block.offset;
-        let mut piece_buff = vec![0u8; block.length];
-        self.file.as_mut().unwrap().seek(SeekFrom::Start(offset as u64)).unwrap();
-        self.file.as_mut().unwrap().read_exact(&mut piece_buff).unwrap();
-        return piece_buff;
+        return self.read_at(offset, block.length);
     }
 
-    async fn read_piece(&mut self, piece_idx: usize) -> Vec<u8> {
+    async fn read_piece(&mut self, piece_idx: usize) -> Result<Vec<u8>, StorageError> {
         let offset = piece_idx * self.layout.head_pieces_length;
-        let length = self.layout.piece_length(piece_idx);
-        let mut piece_buff = vec![0u8; length];
-        self.file.as_mut().unwrap().seek(SeekFrom::Start(offset as u64)).unwrap();
-        self.file.as_mut().unwrap().read_exact(&mut piece_buff).unwrap();
-        return piece_buff;
+        return self.read_at(offset, self.layout.piece_length(piece_idx));
     }
 
-    async fn write(&mut self, piece_idx: usize, offset_in_piece: usize, data: &Vec<u8>) {
+    async fn write(&mut self, piece_idx: usize, offset_in_piece: usize, data: &Vec<u8>) -> Result<(), StorageError> {
         let offset = piece_idx * self.layout.head_pieces_length + offset_in_piece;
-        self.file.as_mut().unwrap().seek(SeekFrom::Start(offset as u64)).unwrap();
-        self.file.as_mut().unwrap().write_all(&*data).unwrap();
+        let file = self.file.as_mut().ok_or(StorageError::FileNotOpen)?;
+        file.seek(SeekFrom::Start(offset as u64))
+            .map_err(|err| StorageError::WriteFailed(err.to_string()))?;
+        file.write_all(data)
+            .map_err(|err| StorageError::WriteFailed(err.to_string()))?;
+        return Ok(());
+    }
+
+    async fn flush(&mut self) -> Result<(), StorageError> {
+        let file = self.file.as_mut().ok_or(StorageError::FileNotOpen)?;
+        file.flush()
+            .map_err(|err| StorageError::WriteFailed(err.to_string()))?;
+        file.sync_data()
+            .map_err(|err| StorageError::WriteFailed(err.to_string()))?;
+        return Ok(());
+    }
+}
+
+impl TempFileProv {
+    fn read_at(&mut self, offset: usize, length: usize) -> Result<Vec<u8>, StorageError> {
+        let file = self.file.as_mut().ok_or(StorageError::FileNotOpen)?;
+        let mut buffer = vec![0u8; length];
+        file.seek(SeekFrom::Start(offset as u64))
+            .map_err(|err| StorageError::ReadFailed(err.to_string()))?;
+        file.read_exact(&mut buffer)
+            .map_err(|err| StorageError::ReadFailed(err.to_string()))?;
+        return Ok(buffer);
     }
 }
@@ -0,0 +1,244 @@
+use std::io;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use log::{info, warn};
+use tokio::task::JoinHandle;
+
+// eMule access levels below this block the range, the ones above allow it
+const EMULE_BLOCKING_LEVEL: u32 = 128;
+
+// Blocked IPv4 address ranges, sorted and merged
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Blocklist {
+    ranges: Vec<(u32, u32)>,
+}
+
+impl Blocklist {
+    // Parses the lines of an eMule `ipfilter.dat`, a P2P plaintext list, or a list of CIDR blocks
+    // and single addresses; the formats can be mixed. Lines which can not be parsed, including the
+    // IPv6 ones, are skipped.
+    pub fn parse(contents: &str) -> Self {
+        let mut ranges = Vec::new();
+        let mut skipped_lines = 0;
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+                continue;
+            }
+            match parse_line(line) {
+                Some(Some(range)) => ranges.push(range),
+                Some(None) => {}
+                None => skipped_lines += 1,
+            }
+        }
+        if skipped_lines > 0 {
+            warn!("Skipped {} blocklist lines which could not be parsed", skipped_lines);
+        }
+
+        return Blocklist { ranges: merge(ranges) };
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = std::fs::read(path)?;
+        return Ok(Blocklist::parse(&String::from_utf8_lossy(&contents)));
+    }
+
+    pub fn contains(&self, ip: &Ipv4Addr) -> bool {
+        let ip = u32::from(*ip);
+        let next_range = self.ranges.partition_point(|(start, _)| *start <= ip);
+        return next_range > 0 && self.ranges[next_range - 1].1 >= ip;
+    }
+
+    // number of disjoint ranges blocked
+    pub fn len(&self) -> usize {
+        return self.ranges.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.ranges.is_empty();
+    }
+}
+
+// The blocklist applied to the peers of all the torrents of a session, which can be swapped while
+// the session runs
+#[derive(Clone, Default)]
+pub struct IpFilter {
+    blocklist: Arc<RwLock<Blocklist>>,
+}
+
+impl IpFilter {
+    pub fn new(blocklist: Blocklist) -> Self {
+        return IpFilter { blocklist: Arc::new(RwLock::new(blocklist)) };
+    }
+
+    pub fn is_blocked(&self, ip: &Ipv4Addr) -> bool {
+        return self.blocklist.read().unwrap().contains(ip);
+    }
+
+    pub fn replace(&self, blocklist: Blocklist) {
+        *self.blocklist.write().unwrap() = blocklist;
+    }
+}
+
+// Loads the blocklist into the filter, then keeps reloading it whenever its file changes. A file
+// which can not be read later on leaves the previous blocklist in place.
+pub async fn load_and_watch(ip_filter: IpFilter, path: PathBuf, check_interval: Duration) -> io::Result<JoinHandle<()>> {
+    // taken before loading, so that a change made in the meantime is loaded again
+    let version = file_version(&path).await;
+    let blocklist = load_blocking(path.clone()).await?;
+    info!("Loaded blocklist {} with {} ranges", path.display(), blocklist.len());
+    ip_filter.replace(blocklist);
+
+    return Ok(tokio::spawn(watch(ip_filter, path, check_interval, version)));
+}
+
+async fn watch(ip_filter: IpFilter, path: PathBuf, check_interval: Duration, mut last_version: Option<(SystemTime, u64)>) {
+    let mut interval = tokio::time::interval(check_interval);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let version = file_version(&path).await;
+        if version.is_none() || version == last_version {
+            continue;
+        }
+        match load_blocking(path.clone()).await {
+            Ok(blocklist) => {
+                info!("Reloaded blocklist {} with {} ranges", path.display(), blocklist.len());
+                ip_filter.replace(blocklist);
+                last_version = version;
+            }
+            Err(err) => warn!("Blocklist {} not reloaded: {}", path.display(), err),
+        }
+    }
+}
+
+// changes to the file are told by its modification time, along with its length in case the
+// modification time is too coarse
+async fn file_version(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = tokio::fs::metadata(path).await.ok()?;
+    return Some((metadata.modified().ok()?, metadata.len()));
+}
+
+// reading and parsing a large blocklist would hold up the other tasks of the runtime
+async fn load_blocking(path: PathBuf) -> io::Result<Blocklist> {
+    return tokio::task::spawn_blocking(move || Blocklist::load(&path))
+        .await
+        .unwrap_or_else(|err| Err(io::Error::other(err)));
+}
+
+// the range blocked by the line; none for eMule ranges whose access level allows them
+fn parse_line(line: &str) -> Option<Option<(u32, u32)>> {
+    if let Some(range) = parse_emule_line(line) {
+        return Some(range);
+    }
+    // P2P plaintext: `Some Org:1.2.3.0-1.2.3.255`
+    if let Some(range) = line.rsplit_once(':').and_then(|(_, range)| parse_range(range)) {
+        return Some(Some(range));
+    }
+    // CIDR: `1.2.3.0/24`
+    if let Some((ip, prefix_len)) = line.split_once('/') {
+        let ip = parse_ipv4(ip)?;
+        let prefix_len = prefix_len.trim().parse::<u32>().ok().filter(|len| *len <= 32)?;
+        let mask = u32::MAX.checked_shl(32 - prefix_len).unwrap_or(0);
+        return Some(Some((ip & mask, ip | !mask)));
+    }
+
+    return parse_range(line).map(Some);
+}
+
+// eMule: `001.009.096.105 - 001.009.096.105 , 000 , Some Org`
+fn parse_emule_line(line: &str) -> Option<Option<(u32, u32)>> {
+    let mut fields = line.split(',');
+    let range = parse_range(fields.next()?)?;
+    let level = fields.next()?.trim().parse::<u32>().ok()?;
+    return Some((level < EMULE_BLOCKING_LEVEL).then_some(range));
+}
+
+// `<first>-<last>` or a single address
+fn parse_range(range: &str) -> Option<(u32, u32)> {
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (parse_ipv4(start)?, parse_ipv4(end)?),
+        None => (parse_ipv4(range)?, parse_ipv4(range)?),
+    };
+    return (start <= end).then_some((start, end));
+}
+
+// blocklists often pad the octets with zeros, which the standard parser refuses
+fn parse_ipv4(ip: &str) -> Option<u32> {
+    let octets: Vec<u8> = ip.trim().split('.')
+        .map(|octet| octet.parse::<u8>().ok())
+        .collect::<Option<Vec<u8>>>()?;
+    let octets: [u8; 4] = octets.try_into().ok()?;
+    return Some(u32::from(Ipv4Addr::from(octets)));
+}
+
+fn merge(mut ranges: Vec<(u32, u32)>) -> Vec<(u32, u32)> {
+    ranges.sort();
+    let mut merged: Vec<(u32, u32)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start as u64 <= last.1 as u64 + 1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    return merged;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+    use crate::ip_filter::{load_and_watch, Blocklist, IpFilter};
+
+    #[test]
+    fn test_blocklist_formats_parsed() {
+        let blocklist = Blocklist::parse("\
+            # comment\n\
+            001.009.096.000 - 001.009.096.255 , 000 , Some Org\n\
+            002.000.000.000 - 002.255.255.255 , 200 , Allowed Org\n\
+            Other Org, Inc: with colon:3.3.3.0-3.3.3.10\n\
+            10.0.0.0/8\n\
+            4.4.4.4\n\
+            2001:db8::/32\n\
+            not an address\n");
+
+        assert!(blocklist.contains(&Ipv4Addr::new(1, 9, 96, 105)));
+        assert!(!blocklist.contains(&Ipv4Addr::new(2, 1, 1, 1)));
+        assert!(blocklist.contains(&Ipv4Addr::new(3, 3, 3, 10)) && !blocklist.contains(&Ipv4Addr::new(3, 3, 3, 11)));
+        assert!(blocklist.contains(&Ipv4Addr::new(10, 255, 0, 1)) && !blocklist.contains(&Ipv4Addr::new(11, 0, 0, 0)));
+        assert!(blocklist.contains(&Ipv4Addr::new(4, 4, 4, 4)) && !blocklist.contains(&Ipv4Addr::new(4, 4, 4, 5)));
+        assert_eq!(blocklist.len(), 4);
+    }
+
+    #[test]
+    fn test_overlapping_ranges_merged() {
+        let blocklist = Blocklist::parse("1.0.0.0-1.0.0.10\n1.0.0.5-1.0.0.20\n1.0.0.21\n0.0.0.0/0\n");
+
+        assert_eq!(blocklist.len(), 1);
+        assert!(blocklist.contains(&Ipv4Addr::new(255, 255, 255, 255)));
+        assert!(Blocklist::default().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_blocklist_reloaded_when_file_changes() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "1.1.1.1\n").unwrap();
+        let ip_filter = IpFilter::default();
+        let handle = load_and_watch(ip_filter.clone(), file.path().to_path_buf(), Duration::from_millis(10)).await.unwrap();
+        assert!(ip_filter.is_blocked(&Ipv4Addr::new(1, 1, 1, 1)));
+
+        std::fs::write(file.path(), "2.2.2.0/24\n").unwrap();
+        for _ in 0..200 {
+            if !ip_filter.is_blocked(&Ipv4Addr::new(1, 1, 1, 1)) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        handle.abort();
+
+        assert!(!ip_filter.is_blocked(&Ipv4Addr::new(1, 1, 1, 1)));
+        assert!(ip_filter.is_blocked(&Ipv4Addr::new(2, 2, 2, 2)));
+    }
+}
@@ -6,6 +6,7 @@ pub mod choke {
 
 pub mod coordinator {
     pub mod ipc;
+    pub mod stats;
     pub mod task;
 }
 
@@ -18,21 +19,44 @@ pub mod p2p {
     pub mod conn;
     pub mod handlers;
     pub mod models;
+    pub mod peer_client;
     pub mod task;
 }
 
+pub mod rpc {
+    pub mod json_rpc;
+    pub mod server;
+    pub mod transmission;
+}
+
 pub mod tracker {
     pub mod client;
     pub mod task;
 }
 
+pub mod tui {
+    pub mod app;
+    pub mod logger;
+    pub mod view;
+}
+
+pub mod alerts;
 pub mod config;
 pub mod data_collector;
 pub mod dependency_provider;
 pub mod file_provider;
+pub mod ip_filter;
+pub mod metadata;
+pub mod metrics;
 pub mod mocks;
 pub mod piece_picker;
+pub mod rate_limiter;
+pub mod resume;
+pub mod selection;
+pub mod session;
+pub mod smart_ban;
 pub mod torrent_parser;
+pub mod transfer_rate;
 
 
 
@@ -1,43 +1,211 @@
-use std::fs::OpenOptions;
+use std::io::IsTerminal;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::ops::Range;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::sync::mpsc;
+use std::time::Duration;
+use log::error;
 use rust_torrent_client::{torrent_parser};
-use rust_torrent_client::config::Config;
-use rust_torrent_client::dependency_provider::DependencyProvider;
-use rust_torrent_client::core_models::entities::{TorrentLayout};
+use rust_torrent_client::config::{Config, ENV_PREFIX};
+use rust_torrent_client::core_models::entities::{Torrent, TorrentLayout};
+use rust_torrent_client::metrics;
+use rust_torrent_client::piece_picker::{DEFAULT_SEQUENTIAL_WINDOW, PickingMode};
+use rust_torrent_client::rpc::server;
+use rust_torrent_client::selection::{file_byte_ranges, piece_priorities, Priority};
+use rust_torrent_client::session::Session;
+use rust_torrent_client::tui;
+
+const PROGRESS_INTERVAL_SECS: u64 = 5;
 
 #[tokio::main]
 async fn main() {
-    // Retrieve .torrent file path arg
+    // Retrieve .torrent file path args
     let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 {
-        eprintln!("Usage: {} <path-to-torrent-file>", args[0]);
+    let (flags, torrent_file_paths): (Vec<String>, Vec<String>) = args.iter().skip(1)
+        .cloned()
+        .partition(|arg| arg.starts_with("--"));
+    let is_daemon = flags.iter().any(|arg| arg == "--daemon");
+    if torrent_file_paths.is_empty() && !is_daemon {
+        eprintln!("Usage: {} <path-to-torrent-file>... [--daemon] [--config=<toml-file>] [--no-tui] [--sequential] \
+                   [--file-priority=<file-idx>:<priority>]... [--range-priority=<start>-<end>:<priority>]... \
+                   [--<setting>=<value>]...\n\
+                   Settings are read from the defaults, then the config file, then {}<SETTING> environment \
+                   variables, then the flags, e.g. --download-dir=<dir>, --rpc-port=<port>, --metrics-port=<port>, \
+                   --download-limit=<KiB/s>, --upload-limit=<KiB/s>, --peer-download-limit=<KiB/s>, \
+                   --peer-upload-limit=<KiB/s>, --upload-capacity=<KiB/s>, --seed=<true|false>, --log-level=<level>", args[0], ENV_PREFIX);
         std::process::exit(1);
     }
-    let torrent_file_path = &args[1];
+    let picking_mode = if flags.iter().any(|arg| arg == "--sequential") {
+        PickingMode::Sequential { window: DEFAULT_SEQUENTIAL_WINDOW }
+    } else {
+        PickingMode::RarestFirst
+    };
 
     // initialize client
-    let config = Config::init();
+    let config_file = flags.iter().find_map(|arg| arg.strip_prefix("--config=")).map(PathBuf::from);
+    let config = match Config::load(config_file.as_deref(), std::env::vars(), &flags) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Invalid configuration: {:?}", err);
+            std::process::exit(1);
+        }
+    };
+
+    // the interface takes over the terminal, log lines are then shown in its log pane
+    let is_tui = !is_daemon && !flags.iter().any(|arg| arg == "--no-tui") && std::io::stdout().is_terminal();
+    let logs = if is_tui { tui::logger::init(config.log_level).ok() } else { None };
+
+    if !is_tui {
+        env_logger::Builder::new()
+            .filter_level(config.log_level)
+            .target(env_logger::Target::Stderr).init();
+    }
 
-    // parse metadata and prepare output files
-    let torrent = torrent_parser::parse_torrent(torrent_file_path).unwrap();
-    let layout = TorrentLayout::from_torrent(&torrent);
+    let rpc_port = config.rpc_port;
+    let metrics_port = config.metrics_port;
+    let session = match Session::start(config).await {
+        Ok(session) => session,
+        Err(err) => {
+            eprintln!("Failed to start session: {:?}", err);
+            std::process::exit(1);
+        }
+    };
 
-    create_output_files(&layout);
+    // parse metadata and start the transfers
+    for torrent_file_path in torrent_file_paths.iter() {
+        let torrent = torrent_parser::parse_torrent(torrent_file_path).unwrap();
+        // the layout can only be built for single file torrents
+        if torrent.info.length.is_none() {
+            eprintln!("Failed to add {}: only single file torrents are supported", torrent_file_path);
+            continue;
+        }
+        let layout = TorrentLayout::from_torrent(&torrent);
+        let priorities = match parse_priorities(&flags, &torrent, torrent_file_paths.len()) {
+            Ok(ranges) => piece_priorities(&layout, &ranges),
+            Err(err) => {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        };
+        if let Err(err) = session.add_torrent(torrent, picking_mode, priorities).await {
+            eprintln!("Failed to add {}: {:?}", torrent_file_path, err);
+        }
+    }
 
-    // prepare shared dependencies
-    let (coordinator_tx, coordinator_rx) = mpsc::channel(1024);
-    let deps = DependencyProvider::init(config, torrent, layout, coordinator_tx);
+    if let Some(metrics_port) = metrics_port {
+        let metrics_addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, metrics_port));
+        let metrics = session.metrics();
+        tokio::spawn(async move {
+            if let Err(err) = metrics::serve(metrics_addr, metrics).await {
+                error!("Metrics server failed: {}", err);
+            }
+        });
+    }
 
-    let _ = rust_torrent_client::coordinator::task::run(Arc::new(deps), coordinator_rx).await;
+    let session = Arc::new(session);
+    if let Some(logs) = logs {
+        tokio::select! {
+            result = tui::app::run(session.clone(), logs) => {
+                if let Err(err) = result {
+                    eprintln!("Terminal interface failed: {}", err);
+                    std::process::exit(1);
+                }
+            }
+            _ = shutdown_signal() => {}
+        }
+        shut_down(&session).await;
+        return;
+    }
+    if !is_daemon {
+        tokio::select! {
+            _ = session.wait() => return,
+            _ = print_progress(&session) => {}
+            _ = shutdown_signal() => {}
+        }
+        shut_down(&session).await;
+        return;
+    }
+
+    // in daemon mode the session keeps running and is controlled over the RPC API until interrupted
+    let rpc_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, rpc_port));
+    let rpc_handle = tokio::spawn(server::serve(rpc_addr, session.clone()));
+    tokio::select! {
+        result = rpc_handle => {
+            if let Ok(Err(err)) = result {
+                eprintln!("RPC server failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+        _ = shutdown_signal() => {}
+    }
+    shut_down(&session).await;
 }
 
-fn create_output_files(layout: &TorrentLayout) {
-    let file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .truncate(true)
-        .open(layout.output_file_path.as_str())
-        .unwrap();
-    file.set_len(layout.output_file_length as u64).unwrap();
+// resolves on Ctrl-C, or on SIGTERM on unix
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let Ok(mut terminate) = signal(SignalKind::terminate()) else {
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+// stops the torrents gracefully, unless another signal asks to exit right away
+async fn shut_down(session: &Session) {
+    eprintln!("Shutting down, interrupt again to exit immediately");
+    tokio::select! {
+        _ = session.shutdown() => {}
+        _ = shutdown_signal() => std::process::exit(130),
+    }
+}
+
+// prints the progress of every torrent now and then, when the output is not a terminal
+async fn print_progress(session: &Session) {
+    let mut interval = tokio::time::interval(Duration::from_secs(PROGRESS_INTERVAL_SECS));
+    loop {
+        interval.tick().await;
+        for stats in session.torrents().await {
+            println!("{}: {:.1}% ({}/{} pieces) | Connected Peers: {}",
+                     stats.name, stats.progress() * 100.0, stats.stored_pieces, stats.pieces, stats.connected_peers());
+        }
+    }
+}
+
+// parses `--file-priority` and `--range-priority` args into priorities for byte ranges of the torrent,
+// which are only accepted when transferring a single torrent
+fn parse_priorities(args: &[String], torrent: &Torrent, torrents_count: usize)
+                    -> Result<Vec<(Range<usize>, Priority)>, String> {
+    let files = file_byte_ranges(&torrent.info);
+    let mut ranges = Vec::new();
+    for arg in args {
+        if torrents_count > 1 && (arg.starts_with("--file-priority=") || arg.starts_with("--range-priority=")) {
+            return Err("priorities can only be set when transferring a single torrent".to_string());
+        }
+        if let Some(spec) = arg.strip_prefix("--file-priority=") {
+            let (file_idx, priority) = spec.split_once(':').ok_or(format!("invalid file priority {}", spec))?;
+            let file_idx: usize = file_idx.parse().map_err(|_| format!("invalid file index {}", file_idx))?;
+            if file_idx != 0 {
+                return Err(format!("torrent has no file {}, only single file torrents are supported", file_idx));
+            }
+            let file = files.get(file_idx).ok_or(format!("torrent has no file {}", file_idx))?;
+            ranges.push((file.clone(), priority.parse()?));
+        } else if let Some(spec) = arg.strip_prefix("--range-priority=") {
+            let (range, priority) = spec.split_once(':').ok_or(format!("invalid range priority {}", spec))?;
+            let (start, end) = range.split_once('-').ok_or(format!("invalid byte range {}", range))?;
+            let start: usize = start.parse().map_err(|_| format!("invalid range start {}", start))?;
+            let end: usize = end.parse().map_err(|_| format!("invalid range end {}", end))?;
+            ranges.push((start..end, priority.parse()?));
+        }
+    }
+
+    return Ok(ranges);
 }
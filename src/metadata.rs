@@ -0,0 +1,324 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use form_urlencoded::parse;
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+use crate::config;
+use crate::config::Config;
+use crate::core_models::entities::{Peer, EXTENDED_HANDSHAKE_ID, ExtendedHandshake, Message};
+use crate::p2p::conn::{EstablishedConnection, PeerConnector, TCPPeerConnector};
+use crate::p2p::models::P2PError;
+use crate::torrent_parser::bencoded_len;
+use crate::tracker::client::{TorrentTrackerClient, TrackerClient, TrackerRequestEvent};
+
+// Name of the BEP 9 extension, and the extended message id peers send its messages to the client with
+pub const UT_METADATA: &str = "ut_metadata";
+pub const UT_METADATA_ID: u8 = 1;
+// The metadata is exchanged in pieces of 16 KiB, only the last one may be shorter
+const METADATA_PIECE_LEN: usize = config::BLOCK_SIZE_BYTES;
+// Larger metadata is refused, so that a peer cannot make the client allocate arbitrary amounts of memory
+const MAX_METADATA_LEN: usize = 8 * 1024 * 1024;
+// Peers asked for the metadata at the same time, and how long each of them gets to send it all
+const MAX_METADATA_PEERS: usize = 8;
+const METADATA_PEER_TIMEOUT_SECS: u64 = 30;
+
+const REQUEST: i64 = 0;
+const DATA: i64 = 1;
+const REJECT: i64 = 2;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum MetadataError {
+    InvalidMagnetLink(String),
+    // the magnet link names no tracker, and the client has no other way of finding peers
+    NoTrackers,
+    TrackerCallFailed(String),
+    // none of the peers sent metadata matching the info hash
+    NotFetched,
+}
+
+// A magnet link identifies a torrent by its info hash; the rest of the metainfo is fetched from peers
+#[derive(Debug, Eq, PartialEq)]
+pub struct MagnetLink {
+    pub info_hash: Vec<u8>,
+    pub name: Option<String>,
+    pub trackers: Vec<String>,
+}
+
+impl MagnetLink {
+    pub fn parse(link: &str) -> Result<Self, MetadataError> {
+        let invalid = |reason: &str| MetadataError::InvalidMagnetLink(reason.to_string());
+        let query = link.strip_prefix("magnet:?").ok_or_else(|| invalid("not a magnet link"))?;
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = vec![];
+        for (key, value) in parse(query.as_bytes()) {
+            match key.as_ref() {
+                "xt" => {
+                    let hash = value.strip_prefix("urn:btih:").ok_or_else(|| invalid("not a BitTorrent info hash"))?;
+                    info_hash = Some(decode_info_hash(hash).ok_or_else(|| invalid("malformed info hash"))?);
+                }
+                "dn" => name = Some(value.to_string()),
+                "tr" => trackers.push(value.to_string()),
+                _ => {}
+            }
+        }
+
+        return Ok(MagnetLink { info_hash: info_hash.ok_or_else(|| invalid("missing info hash"))?, name, trackers });
+    }
+}
+
+// The info hash is either hex encoded, in 40 characters, or base32 encoded, in 32
+fn decode_info_hash(hash: &str) -> Option<Vec<u8>> {
+    return match hash.len() {
+        40 => (0..40).step_by(2)
+            .map(|idx| u8::from_str_radix(hash.get(idx..idx + 2)?, 16).ok())
+            .collect(),
+        32 => {
+            let mut bytes = Vec::with_capacity(20);
+            let (mut buffer, mut bits) = (0u64, 0);
+            for char in hash.chars() {
+                let value = "ABCDEFGHIJKLMNOPQRSTUVWXYZ234567".find(char.to_ascii_uppercase())? as u64;
+                buffer = (buffer << 5) | value;
+                bits += 5;
+                if bits >= 8 {
+                    bits -= 8;
+                    bytes.push((buffer >> bits) as u8);
+                }
+            }
+            Some(bytes)
+        }
+        _ => None,
+    };
+}
+
+// Dictionary heading every ut_metadata message; `DATA` messages carry the piece right after it
+#[derive(Debug, Deserialize, Serialize)]
+struct MetadataMessage {
+    msg_type: i64,
+    piece: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+// Asks the trackers of the magnet link for peers of the torrent
+pub async fn announce(magnet: &MagnetLink, config: &Config) -> Result<Vec<Peer>, MetadataError> {
+    if magnet.trackers.is_empty() {
+        return Err(MetadataError::NoTrackers);
+    }
+
+    let mut peers: Vec<Peer> = vec![];
+    let mut last_error = None;
+    for announce_url in &magnet.trackers {
+        let client = TorrentTrackerClient {
+            announce_url: announce_url.clone(),
+            client_config: config.clone(),
+            info_hash: magnet.info_hash.clone(),
+        };
+        match client.announce(TrackerRequestEvent::Started).await {
+            Ok(response) => {
+                for peer in response.peers {
+                    if !peers.contains(&peer) {
+                        peers.push(peer);
+                    }
+                }
+            }
+            Err(err) => last_error = Some(err.to_string()),
+        }
+    }
+    if peers.is_empty() {
+        if let Some(err) = last_error {
+            return Err(MetadataError::TrackerCallFailed(err));
+        }
+    }
+
+    return Ok(peers);
+}
+
+// Fetches the bencoded info dictionary from the peers, a few of them at a time; the first one which
+// sends metadata matching the info hash wins
+pub async fn fetch_from_peers(peers: Vec<Peer>, info_hash: &[u8], config: &Config) -> Result<Vec<u8>, MetadataError> {
+    let mut peers = peers.into_iter();
+    let mut fetches = JoinSet::new();
+    let spawn_fetch = |fetches: &mut JoinSet<Result<Vec<u8>, P2PError>>, peer: Peer| {
+        let (info_hash, client_id) = (info_hash.to_vec(), config.client_id.clone());
+        let connect_timeout = Duration::from_secs(config.connect_timeout_secs);
+        fetches.spawn(async move {
+            let connection = timeout(connect_timeout, TCPPeerConnector {}.connect_to(peer, info_hash.clone(), client_id)).await
+                .map_err(|_| P2PError::TCPConnectionNotEstablished)??;
+            return timeout(Duration::from_secs(METADATA_PEER_TIMEOUT_SECS), fetch_from_peer(connection, &info_hash)).await
+                .map_err(|_| P2PError::SocketClosed)?;
+        });
+    };
+    peers.by_ref().take(MAX_METADATA_PEERS).for_each(|peer| spawn_fetch(&mut fetches, peer));
+
+    while let Some(result) = fetches.join_next().await {
+        match result {
+            Ok(Ok(metadata)) => {
+                info!("Fetched {} bytes of metadata", metadata.len());
+                return Ok(metadata);
+            }
+            Ok(Err(err)) => warn!("Metadata not fetched from peer: {:?}", err),
+            Err(err) => warn!("Metadata fetch ended unexpectedly: {}", err),
+        }
+        if let Some(peer) = peers.next() {
+            spawn_fetch(&mut fetches, peer);
+        }
+    }
+
+    return Err(MetadataError::NotFetched);
+}
+
+// BEP 9: after the extension handshakes, the metadata is requested piece by piece and checked against
+// the info hash once complete
+pub async fn fetch_from_peer(connection: EstablishedConnection, info_hash: &[u8]) -> Result<Vec<u8>, P2PError> {
+    let EstablishedConnection { mut receiver, mut sender, .. } = connection;
+    let client_handshake = ExtendedHandshake {
+        m: BTreeMap::from([(UT_METADATA.to_string(), UT_METADATA_ID as i64)]),
+        v: Some(format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))),
+        ..Default::default()
+    };
+    sender.send(Message::Extended(EXTENDED_HANDSHAKE_ID, client_handshake.to_bytes())).await?;
+
+    let peer_handshake = loop {
+        if let Message::Extended(EXTENDED_HANDSHAKE_ID, payload) = receiver.receive().await? {
+            break ExtendedHandshake::from_bytes(&payload).ok_or(P2PError::HandshakeFailed)?;
+        }
+    };
+    let peer_metadata_id = peer_handshake.m.get(UT_METADATA)
+        .and_then(|id| u8::try_from(*id).ok())
+        .filter(|id| *id != EXTENDED_HANDSHAKE_ID)
+        .ok_or(P2PError::HandshakeFailed)?;
+    let metadata_len = peer_handshake.metadata_size
+        .filter(|len| (1..=MAX_METADATA_LEN).contains(len))
+        .ok_or(P2PError::HandshakeFailed)?;
+
+    let mut metadata = Vec::with_capacity(metadata_len);
+    for piece in 0..metadata_len.div_ceil(METADATA_PIECE_LEN) {
+        let request = MetadataMessage { msg_type: REQUEST, piece, total_size: None };
+        sender.send(Message::Extended(peer_metadata_id, serde_bencode::to_bytes(&request).unwrap_or_default())).await?;
+        let expected_len = METADATA_PIECE_LEN.min(metadata_len - metadata.len());
+        loop {
+            let Message::Extended(UT_METADATA_ID, payload) = receiver.receive().await? else {
+                continue;
+            };
+            let (message, data) = split_metadata_message(&payload).ok_or(P2PError::IO("malformed metadata message".to_string()))?;
+            if message.piece != piece {
+                continue;
+            }
+            if message.msg_type == REJECT || message.msg_type != DATA || data.len() != expected_len {
+                return Err(P2PError::IO(format!("metadata piece {} not sent", piece)));
+            }
+            metadata.extend_from_slice(data);
+            break;
+        }
+    }
+
+    if Sha1::digest(&metadata).as_slice() != info_hash {
+        return Err(P2PError::InfoHashMismatch);
+    }
+    return Ok(metadata);
+}
+
+// Splits a ut_metadata message into its dictionary and the piece following it
+fn split_metadata_message(payload: &[u8]) -> Option<(MetadataMessage, &[u8])> {
+    let dict_len = bencoded_len(payload)?;
+    let message = serde_bencode::from_bytes::<MetadataMessage>(&payload[..dict_len]).ok()?;
+    return Some((message, &payload[dict_len..]));
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use sha1::{Digest, Sha1};
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::core_models::entities::{EXTENDED_HANDSHAKE_ID, ExtendedHandshake, Message};
+    use crate::metadata::{fetch_from_peer, MagnetLink, MetadataError, MetadataMessage, UT_METADATA, UT_METADATA_ID, DATA, REJECT};
+    use crate::p2p::conn::{split_connection, EstablishedConnection};
+    use crate::p2p::models::P2PError;
+
+    const PEER_METADATA_ID: u8 = 3;
+
+    // a peer serving the metadata, answering the requests for the pieces in `rejected` with a reject
+    async fn serve_metadata(metadata: Vec<u8>, rejected: Vec<usize>) -> EstablishedConnection {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client_stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (peer_stream, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+            let (mut receiver, mut sender) = split_connection(peer_stream);
+            let handshake = ExtendedHandshake {
+                m: BTreeMap::from([(UT_METADATA.to_string(), PEER_METADATA_ID as i64)]),
+                metadata_size: Some(metadata.len()),
+                ..Default::default()
+            };
+            sender.send(Message::Bitfield(vec![0])).await.unwrap();
+            sender.send(Message::Extended(EXTENDED_HANDSHAKE_ID, handshake.to_bytes())).await.unwrap();
+            while let Ok(message) = receiver.receive().await {
+                let Message::Extended(PEER_METADATA_ID, payload) = message else { continue; };
+                let request: MetadataMessage = serde_bencode::from_bytes(&payload).unwrap();
+                let msg_type = if rejected.contains(&request.piece) { REJECT } else { DATA };
+                let response = MetadataMessage { msg_type, piece: request.piece, total_size: Some(metadata.len()) };
+                let mut payload = serde_bencode::to_bytes(&response).unwrap();
+                if msg_type == DATA {
+                    payload.extend(metadata.chunks(16384).nth(request.piece).unwrap());
+                }
+                sender.send(Message::Extended(UT_METADATA_ID, payload)).await.unwrap();
+            }
+        });
+
+        let (receiver, sender) = split_connection(client_stream);
+        return EstablishedConnection { peer_id: vec![1; 20], receiver, sender };
+    }
+
+    fn metadata() -> (Vec<u8>, Vec<u8>) {
+        let mut metadata = b"d6:lengthi1024e4:name4:test12:piece lengthi16384e6:pieces40000:".to_vec();
+        metadata.extend(vec![7u8; 40000]);
+        metadata.push(b'e');
+        let info_hash = Sha1::digest(&metadata).to_vec();
+        return (metadata, info_hash);
+    }
+
+    #[test]
+    fn test_magnet_link_parsed() {
+        let link = "magnet:?xt=urn:btih:bc26c6bc83d0ca1a7bf9875df1ffc3fed81ff555&dn=ubuntu&tr=https%3A%2F%2Ftorrent.ubuntu.com%2Fannounce";
+        let base32_link = "magnet:?xt=urn:btih:XQTMNPED2DFBU67ZQ5O7D76D73MB75KV";
+
+        let magnet = MagnetLink::parse(link).unwrap();
+
+        assert_eq!(magnet.info_hash, vec![
+            0xbc, 0x26, 0xc6, 0xbc, 0x83, 0xd0, 0xca, 0x1a, 0x7b, 0xf9, 0x87, 0x5d, 0xf1, 0xff, 0xc3, 0xfe, 0xd8, 0x1f, 0xf5, 0x55,
+        ]);
+        assert_eq!(magnet.name, Some("ubuntu".to_string()));
+        assert_eq!(magnet.trackers, vec!["https://torrent.ubuntu.com/announce".to_string()]);
+        assert_eq!(MagnetLink::parse(base32_link).unwrap().info_hash, magnet.info_hash);
+        assert!(matches!(MagnetLink::parse("magnet:?xt=urn:btih:00"), Err(MetadataError::InvalidMagnetLink(_))));
+        assert!(matches!(MagnetLink::parse("magnet:?dn=ubuntu"), Err(MetadataError::InvalidMagnetLink(_))));
+        assert!(matches!(MagnetLink::parse("http://example.com"), Err(MetadataError::InvalidMagnetLink(_))));
+    }
+
+    #[tokio::test]
+    async fn test_metadata_fetched_from_peer() {
+        let (metadata, info_hash) = metadata();
+        let connection = serve_metadata(metadata.clone(), vec![]).await;
+
+        assert_eq!(fetch_from_peer(connection, &info_hash).await, Ok(metadata));
+    }
+
+    #[tokio::test]
+    async fn test_metadata_not_matching_info_hash_refused() {
+        let (metadata, _) = metadata();
+        let connection = serve_metadata(metadata, vec![]).await;
+
+        assert_eq!(fetch_from_peer(connection, &[1; 20]).await, Err(P2PError::InfoHashMismatch));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_metadata_request_ends_fetch() {
+        let (metadata, info_hash) = metadata();
+        let connection = serve_metadata(metadata, vec![1]).await;
+
+        assert!(fetch_from_peer(connection, &info_hash).await.is_err());
+    }
+}
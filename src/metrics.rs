@@ -0,0 +1,175 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use prometheus::{Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+
+pub const METRICS_PATH: &str = "/metrics";
+const NAMESPACE: &str = "torrent_client";
+
+// Label values of the byte counters
+pub const PAYLOAD: &str = "payload";
+pub const OVERHEAD: &str = "overhead";
+pub const DUPLICATE: &str = "duplicate";
+pub const HASH_FAILURE: &str = "hash_failure";
+
+// Metrics of all the torrents of a session, in the Prometheus text format
+pub struct Metrics {
+    registry: Registry,
+    // labelled by kind: payload or protocol overhead
+    pub downloaded_bytes: IntCounterVec,
+    pub uploaded_bytes: IntCounterVec,
+    // labelled by reason: duplicate blocks or pieces which failed the hash check
+    pub wasted_bytes: IntCounterVec,
+    pub connected_peers: IntGauge,
+    // peers being connected to, before the handshake completed
+    pub half_open_peers: IntGauge,
+    // peers refused by the IP filter, labelled by where they were learned from
+    pub blocked_peers: IntCounterVec,
+    pub torrents_in_endgame: IntGauge,
+    pub pieces_stored: IntCounter,
+    pub hash_failures: IntCounter,
+    pub tracker_announce_seconds: Histogram,
+    pub tracker_announce_errors: IntCounter,
+    // events waiting in the channels of the coordinator, labelled by info hash and channel
+    pub queue_depth: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some(NAMESPACE.to_string()), None).unwrap();
+        let metrics = Metrics {
+            downloaded_bytes: IntCounterVec::new(
+                Opts::new("downloaded_bytes_total", "Bytes received from peers"), &["kind"],
+            ).unwrap(),
+            uploaded_bytes: IntCounterVec::new(
+                Opts::new("uploaded_bytes_total", "Bytes sent to peers"), &["kind"],
+            ).unwrap(),
+            wasted_bytes: IntCounterVec::new(
+                Opts::new("wasted_bytes_total", "Downloaded bytes which were thrown away"), &["reason"],
+            ).unwrap(),
+            connected_peers: IntGauge::new("connected_peers", "Peers with an established connection").unwrap(),
+            half_open_peers: IntGauge::new("half_open_peers", "Peers still being connected to").unwrap(),
+            blocked_peers: IntCounterVec::new(
+                Opts::new("blocked_peers_total", "Peers refused by the IP filter"), &["source"],
+            ).unwrap(),
+            torrents_in_endgame: IntGauge::new("torrents_in_endgame", "Torrents whose piece picker is in endgame").unwrap(),
+            pieces_stored: IntCounter::new("pieces_stored_total", "Pieces which passed the hash check").unwrap(),
+            hash_failures: IntCounter::new("hash_failures_total", "Pieces which failed the hash check").unwrap(),
+            tracker_announce_seconds: Histogram::with_opts(
+                HistogramOpts::new("tracker_announce_duration_seconds", "Time taken by tracker announces")
+                    .buckets(vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]),
+            ).unwrap(),
+            tracker_announce_errors: IntCounter::new("tracker_announce_errors_total", "Failed tracker announces").unwrap(),
+            queue_depth: IntGaugeVec::new(
+                Opts::new("ipc_queue_depth", "Events waiting in the channels of the coordinator"), &["info_hash", "channel"],
+            ).unwrap(),
+            registry,
+        };
+
+        metrics.registry.register(Box::new(metrics.downloaded_bytes.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.uploaded_bytes.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.wasted_bytes.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.connected_peers.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.half_open_peers.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.blocked_peers.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.torrents_in_endgame.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.pieces_stored.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.hash_failures.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.tracker_announce_seconds.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.tracker_announce_errors.clone())).unwrap();
+        metrics.registry.register(Box::new(metrics.queue_depth.clone())).unwrap();
+
+        return metrics;
+    }
+
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).unwrap();
+        return String::from_utf8(buffer).unwrap();
+    }
+}
+
+// Keeps a gauge raised for as long as it lives, which also covers tasks being aborted
+pub struct GaugeGuard(IntGauge);
+
+impl GaugeGuard {
+    pub fn raise(gauge: &IntGauge) -> Self {
+        gauge.inc();
+        return GaugeGuard(gauge.clone());
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        return Metrics::new();
+    }
+}
+
+// Serves the metrics for scraping
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_conn| {
+        let metrics = metrics.clone();
+        async move {
+            return Ok::<_, Infallible>(service_fn(move |request| {
+                let metrics = metrics.clone();
+                async move { return Ok::<_, Infallible>(handle_request(request, &metrics)); }
+            }));
+        }
+    });
+
+    return Server::bind(&addr).serve(make_service).await;
+}
+
+fn handle_request(request: Request<Body>, metrics: &Metrics) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, METRICS_PATH) => {
+            *response.body_mut() = Body::from(metrics.encode());
+            response.headers_mut().insert(CONTENT_TYPE, TextEncoder::new().format_type().parse().unwrap());
+        }
+        (_, METRICS_PATH) => *response.status_mut() = StatusCode::METHOD_NOT_ALLOWED,
+        _ => *response.status_mut() = StatusCode::NOT_FOUND,
+    }
+
+    return response;
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Body, Request, StatusCode};
+    use crate::metrics::{handle_request, Metrics, PAYLOAD};
+
+    #[test]
+    fn test_metrics_encoded_in_text_format() {
+        let metrics = Metrics::new();
+        metrics.downloaded_bytes.with_label_values(&[PAYLOAD]).inc_by(16384);
+        metrics.connected_peers.inc();
+
+        let text = metrics.encode();
+
+        assert!(text.contains("torrent_client_downloaded_bytes_total{kind=\"payload\"} 16384"));
+        assert!(text.contains("torrent_client_connected_peers 1"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_served() {
+        let metrics = Metrics::new();
+        metrics.pieces_stored.inc();
+
+        let response = handle_request(Request::get("/metrics").body(Body::empty()).unwrap(), &metrics);
+        let not_found = handle_request(Request::get("/").body(Body::empty()).unwrap(), &metrics);
+
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert!(String::from_utf8(body.to_vec()).unwrap().contains("torrent_client_pieces_stored_total 1"));
+        assert_eq!(not_found.status(), StatusCode::NOT_FOUND);
+    }
+}
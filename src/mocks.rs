@@ -3,14 +3,21 @@ use async_trait::async_trait;
 use sha1::{Digest, Sha1};
 use tempfile::TempDir;
 use tokio::sync::mpsc::Sender;
-use tokio::sync::Mutex;
+use tokio::sync::watch;
+use tokio::sync::{Mutex, Semaphore};
+use crate::alerts::Alerts;
 use crate::config;
 use crate::config::Config;
+use crate::coordinator::stats::TorrentStats;
+use crate::rate_limiter::{RateLimiter, RateLimits};
 use crate::dependency_provider::TransferDeps;
-use crate::core_models::entities::{Block, DataBlock, TorrentLayout};
+use crate::core_models::entities::{Block, DataBlock, DiscoveryPolicy, TorrentLayout};
 use crate::core_models::events::InternalEvent;
 use crate::file_provider::{FileProv, TempFileProv};
+use crate::ip_filter::IpFilter;
+use crate::metrics::Metrics;
 use crate::p2p::conn::PeerConnector;
+use crate::p2p::models::{BannedPeers, ConnectedPeers};
 use crate::piece_picker::{PiecePicker, RarestPiecePicker};
 use crate::tracker::client::TrackerClient;
 
@@ -91,6 +98,13 @@ pub struct MockDepsProvider {
     piece_picker: Arc<Mutex<dyn PiecePicker>>,
     mock_torrent: MockTorrent,
     output_tx: Sender<InternalEvent>,
+    stats_tx: watch::Sender<TorrentStats>,
+    alerts: Alerts,
+    metrics: Arc<Metrics>,
+    connected_peers: ConnectedPeers,
+    banned_peers: BannedPeers,
+    ip_filter: IpFilter,
+    config: Config,
 }
 
 impl MockDepsProvider {
@@ -104,21 +118,63 @@ impl MockDepsProvider {
         mock_torrent.layout.output_file_path = file_path;
 
         let piece_picker = Arc::new(Mutex::new(RarestPiecePicker::init(mock_torrent.layout.clone())));
-        return MockDepsProvider { _output_temp_dir: output_temp_dir, piece_picker, mock_torrent, output_tx };
+        let (stats_tx, _) = watch::channel(TorrentStats::new("mock".to_string(), vec![1; 20], &mock_torrent.layout));
+        let alerts = Alerts::new(64);
+        return MockDepsProvider {
+            _output_temp_dir: output_temp_dir,
+            piece_picker,
+            mock_torrent,
+            output_tx,
+            stats_tx,
+            alerts,
+            metrics: Arc::new(Metrics::new()),
+            connected_peers: ConnectedPeers::default(),
+            banned_peers: BannedPeers::default(),
+            ip_filter: IpFilter::default(),
+            config: Config {
+                listening_port: 1483,
+                client_id: "toThe3toThe6toThe9".to_string(),
+                rpc_port: 1484,
+                alert_buffer_size: 64,
+                ..Config::default()
+            },
+        };
+    }
+
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        return self;
     }
 }
 
 #[async_trait]
 impl TransferDeps for MockDepsProvider {
+    fn alerts(&self) -> Alerts {
+        return self.alerts.clone();
+    }
+
     fn announce_url(&self) -> String {
         return "announce".to_string();
     }
 
+    fn banned_peers(&self) -> BannedPeers {
+        return self.banned_peers.clone();
+    }
+
     fn client_config(&self) -> Config {
-        return Config {
-            listening_port: 1483,
-            client_id: "toThe3toThe6toThe9".to_string(),
-        };
+        return self.config.clone();
+    }
+
+    fn connection_budget(&self) -> Arc<Semaphore> {
+        return Arc::new(Semaphore::new(Semaphore::MAX_PERMITS));
+    }
+
+    fn connected_peers(&self) -> ConnectedPeers {
+        return self.connected_peers.clone();
+    }
+
+    fn discovery_policy(&self) -> DiscoveryPolicy {
+        return DiscoveryPolicy { dht: true, pex: true, lsd: true };
     }
 
     fn file_provider(&self) -> Box<dyn FileProv> {
@@ -129,6 +185,14 @@ impl TransferDeps for MockDepsProvider {
         return vec![1, 0, 0, 0, 1, 0, 1];
     }
 
+    fn ip_filter(&self) -> IpFilter {
+        return self.ip_filter.clone();
+    }
+
+    fn metrics(&self) -> Arc<Metrics> {
+        return self.metrics.clone();
+    }
+
     fn output_tx(&self) -> Sender<InternalEvent> {
         return self.output_tx.clone();
     }
@@ -146,6 +210,14 @@ impl TransferDeps for MockDepsProvider {
         return self.piece_picker.clone();
     }
 
+    fn rate_limiter(&self) -> Arc<RateLimiter> {
+        return Arc::new(RateLimiter::new(RateLimits::default()));
+    }
+
+    fn stats_tx(&self) -> watch::Sender<TorrentStats> {
+        return self.stats_tx.clone();
+    }
+
     fn torrent_layout(&self) -> TorrentLayout {
         return self.mock_torrent.layout.clone();
     }
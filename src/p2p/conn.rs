@@ -3,14 +3,24 @@ use async_trait::async_trait;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
 use mockall::automock;
 use tokio::io;
+use crate::config;
 use crate::core_models::entities::{Message, Peer};
 use crate::p2p::models::P2PError;
 
 const PROTOCOL: &'static str = "BitTorrent protocol";
+pub const HANDSHAKE_LEN: usize = 49 + PROTOCOL.len();
+// Reserved handshake bit signaling support for the extension protocol (BEP 10)
+const EXTENSION_PROTOCOL_BYTE: usize = 5;
+const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+// Room for the message id and the fields preceding the block data, or the dictionary of an extension
+// message carrying a block sized payload
+const MESSAGE_HEADER_ALLOWANCE: usize = 1024;
 
 #[async_trait]
 pub trait PeerReceiver: Send {
     async fn receive(&mut self) -> Result<Message, P2PError>;
+    // Messages whose length prefix exceeds the limit are refused before they are read
+    fn set_max_message_len(&mut self, max_message_len: usize);
 }
 
 #[async_trait]
@@ -20,6 +30,13 @@ pub trait PeerSender: Send {
 
 pub struct PeerReadConn {
     stream: ReadHalf<TcpStream>,
+    max_message_len: usize,
+}
+
+impl PeerReadConn {
+    fn new(stream: ReadHalf<TcpStream>) -> Self {
+        return PeerReadConn { stream, max_message_len: max_message_len(0) };
+    }
 }
 
 #[async_trait]
@@ -27,12 +44,24 @@ impl PeerReceiver for PeerReadConn {
     async fn receive(&mut self) -> Result<Message, P2PError> {
         let len = read_from_stream(&mut self.stream, 4).await?;
         let len = usize_from_be_bytes(len);
+        // the length comes from the peer, so nothing is allocated for it before it is checked
+        if len > self.max_message_len {
+            return Err(P2PError::MessageTooLong(len));
+        }
         let message = read_from_stream(&mut self.stream, len).await?;
-        return match Message::deserialize(message) {
-            Some(msg) => Ok(msg),
-            None => Err(P2PError::UnknownMessageReceived)
-        };
+        return Message::deserialize(message).map_err(P2PError::MalformedMessage);
     }
+
+    fn set_max_message_len(&mut self, max_message_len: usize) {
+        self.max_message_len = max_message_len;
+    }
+}
+
+// Largest message a peer may send for a torrent: a PIECE message carrying a block, an extension
+// message of about the same size, or the BITFIELD of all the pieces
+pub fn max_message_len(num_of_pieces: usize) -> usize {
+    let bitfield_len = 1 + num_of_pieces.div_ceil(8);
+    return (config::BLOCK_SIZE_BYTES + MESSAGE_HEADER_ALLOWANCE).max(bitfield_len);
 }
 
 pub struct PeerWriteConn {
@@ -52,26 +81,81 @@ impl PeerSender for PeerWriteConn {
 #[async_trait]
 #[automock]
 pub trait PeerConnector: Send + Sync {
-    async fn connect_to(&self, peer: Peer, info_hash: Vec<u8>, client_id: String) -> Result<(Box<dyn PeerReceiver>, Box<dyn PeerSender>), P2PError>;
+    async fn connect_to(&self, peer: Peer, info_hash: Vec<u8>, client_id: String) -> Result<EstablishedConnection, P2PError>;
 }
 
 pub struct TCPPeerConnector {}
 
 #[async_trait]
 impl PeerConnector for TCPPeerConnector {
-    async fn connect_to(&self, peer: Peer, info_hash: Vec<u8>, client_id: String) -> Result<(Box<dyn PeerReceiver>, Box<dyn PeerSender>), P2PError> {
+    async fn connect_to(&self, peer: Peer, info_hash: Vec<u8>, client_id: String) -> Result<EstablishedConnection, P2PError> {
         let mut tcp_stream = establish_tcp_connection(&peer).await?;
         send_handshake(&mut tcp_stream, &info_hash, &client_id).await?;
-        receive_handshake(&mut tcp_stream).await?;
+        let handshake = receive_handshake(&mut tcp_stream).await?;
+        handshake.validate(&info_hash, &client_id, peer.peer_id.as_ref())?;
+        let (receiver, sender) = split_connection(tcp_stream);
+
+        return Ok(EstablishedConnection { peer_id: handshake.peer_id, receiver, sender });
+    }
+}
+
+// A connection to a peer, with the handshakes exchanged
+pub struct EstablishedConnection {
+    pub peer_id: Vec<u8>,
+    pub receiver: Box<dyn PeerReceiver>,
+    pub sender: Box<dyn PeerSender>,
+}
+
+// A connection a peer opened to the client, with the handshake already exchanged
+pub struct IncomingConnection {
+    pub peer: Peer,
+    pub peer_id: Vec<u8>,
+    pub receiver: Box<dyn PeerReceiver>,
+    pub sender: Box<dyn PeerSender>,
+}
+
+// Answers the handshake of a peer which connected to the client
+pub async fn accept_connection(mut stream: TcpStream, peer: Peer, handshake: Handshake, client_id: &String)
+                               -> Result<IncomingConnection, P2PError> {
+    send_handshake(&mut stream, &handshake.info_hash, client_id).await?;
+    let (receiver, sender) = split_connection(stream);
+
+    return Ok(IncomingConnection { peer, peer_id: handshake.peer_id, receiver, sender });
+}
 
-        let (read_stream, write_stream) = io::split(tcp_stream);
-        let receiver = Box::new(PeerReadConn { stream: read_stream });
-        let sender = Box::new(PeerWriteConn { stream: write_stream });
+#[derive(Debug, Eq, PartialEq)]
+pub struct Handshake {
+    pub reserved: [u8; 8],
+    pub info_hash: Vec<u8>,
+    pub peer_id: Vec<u8>,
+}
 
-        return Ok((receiver, sender));
+impl Handshake {
+    // Checks the handshake belongs to the torrent and does not come from the client itself; the peer
+    // id is checked against the one the tracker gave, when it gave one
+    pub fn validate(&self, info_hash: &[u8], client_id: &str, expected_peer_id: Option<&Vec<u8>>) -> Result<(), P2PError> {
+        if self.info_hash != info_hash {
+            return Err(P2PError::InfoHashMismatch);
+        }
+        if self.peer_id == client_id.as_bytes() {
+            return Err(P2PError::ConnectedToSelf);
+        }
+        if expected_peer_id.is_some_and(|peer_id| *peer_id != self.peer_id) {
+            return Err(P2PError::PeerIdMismatch);
+        }
+
+        return Ok(());
     }
 }
 
+pub fn split_connection(stream: TcpStream) -> (Box<dyn PeerReceiver>, Box<dyn PeerSender>) {
+    let (read_stream, write_stream) = io::split(stream);
+    let receiver = Box::new(PeerReadConn::new(read_stream));
+    let sender = Box::new(PeerWriteConn { stream: write_stream });
+
+    return (receiver, sender);
+}
+
 async fn establish_tcp_connection(peer: &Peer) -> Result<TcpStream, P2PError> {
     return match TcpStream::connect((peer.ip, peer.port)).await {
         Ok(stream) => Ok(stream),
@@ -107,13 +191,15 @@ async fn read_from_stream<T: AsyncRead + Unpin>(stream: &mut T, mut num_of_bytes
 }
 
 async fn send_handshake(stream: &mut TcpStream, info_hash: &Vec<u8>, client_id: &String) -> Result<(), P2PError> {
-    let mut handshake: Vec<u8> = Vec::with_capacity(49 + PROTOCOL.len());
+    let mut handshake: Vec<u8> = Vec::with_capacity(HANDSHAKE_LEN);
     //pstrlen
     handshake.push(PROTOCOL.len() as u8);
     //pstr
     handshake.extend(PROTOCOL.bytes());
     //reserved bytes
-    handshake.extend(vec![0; 8].into_iter());
+    let mut reserved = [0u8; 8];
+    reserved[EXTENSION_PROTOCOL_BYTE] |= EXTENSION_PROTOCOL_BIT;
+    handshake.extend(reserved);
     //info hash of desired torrent
     handshake.extend(info_hash);
     //client id
@@ -125,39 +211,18 @@ async fn send_handshake(stream: &mut TcpStream, info_hash: &Vec<u8>, client_id:
     };
 }
 
-async fn receive_handshake(stream: &mut TcpStream) -> Result<(), P2PError> {
-    //todo: check all props in handshake!
-    let pstrlen = match read_from_stream(stream, 1).await {
-        Ok(len) => len,
-        Err(_) => {
-            return Err(P2PError::HandshakeFailed);
-        }
-    };
-    let _pstr = match read_from_stream(stream, pstrlen[0] as usize).await {
-        Ok(pstr) => pstr,
-        Err(_) => {
-            return Err(P2PError::HandshakeFailed);
-        }
-    };
-    let _reserved = match read_from_stream(stream, 8).await {
-        Ok(res) => res,
-        Err(_) => {
-            return Err(P2PError::HandshakeFailed);
-        }
-    };
-    let _info_hash = match read_from_stream(stream, 20).await {
-        Ok(hash) => hash.iter().map(|byte| format!("{:02x}", byte)).collect::<String>(),
-        Err(_) => {
-            return Err(P2PError::HandshakeFailed);
-        }
-    };
-    let _peer_id = match read_from_stream(stream, 20).await {
-        Ok(id) => id.iter().map(|byte| format!("{:02x}", byte)).collect::<String>(),
-        Err(_) => {
-            return Err(P2PError::HandshakeFailed);
-        }
-    };
-    return Ok(());
+// Reads the handshake of a peer, which has to speak the BitTorrent protocol
+pub async fn receive_handshake<T: AsyncRead + Unpin>(stream: &mut T) -> Result<Handshake, P2PError> {
+    let pstrlen = read_from_stream(stream, 1).await.map_err(|_| P2PError::HandshakeFailed)?;
+    let pstr = read_from_stream(stream, pstrlen[0] as usize).await.map_err(|_| P2PError::HandshakeFailed)?;
+    if pstr != PROTOCOL.as_bytes() {
+        return Err(P2PError::UnsupportedProtocol);
+    }
+    let reserved = read_from_stream(stream, 8).await.map_err(|_| P2PError::HandshakeFailed)?;
+    let info_hash = read_from_stream(stream, 20).await.map_err(|_| P2PError::HandshakeFailed)?;
+    let peer_id = read_from_stream(stream, 20).await.map_err(|_| P2PError::HandshakeFailed)?;
+
+    return Ok(Handshake { reserved: reserved.try_into().unwrap(), info_hash, peer_id });
 }
 
 fn usize_from_be_bytes(bytes: Vec<u8>) -> usize {
@@ -172,7 +237,52 @@ mod tests {
     use tokio::io::AsyncWriteExt;
     use tokio::net::{TcpListener, TcpStream};
     use crate::core_models::entities::{DataBlock, Message};
-    use crate::p2p::conn::{PeerReadConn, PeerReceiver};
+    use crate::config;
+    use crate::p2p::conn::{max_message_len, receive_handshake, Handshake, PeerReadConn, PeerReceiver, PROTOCOL};
+    use crate::p2p::models::P2PError;
+
+    const CLIENT_ID: &str = "-XX0001-abcdefghijkl";
+
+    fn handshake_bytes(protocol: &str, info_hash: &[u8], peer_id: &[u8]) -> Vec<u8> {
+        let mut bytes = vec![protocol.len() as u8];
+        bytes.extend(protocol.bytes());
+        bytes.extend([0u8; 8]);
+        bytes.extend(info_hash);
+        bytes.extend(peer_id);
+        return bytes;
+    }
+
+    fn handshake(info_hash: &[u8], peer_id: &[u8]) -> Handshake {
+        return Handshake { reserved: [0; 8], info_hash: info_hash.to_vec(), peer_id: peer_id.to_vec() };
+    }
+
+    #[tokio::test]
+    async fn test_receive_handshake() {
+        let bytes = handshake_bytes(PROTOCOL, &[7; 20], &[1; 20]);
+
+        let received = receive_handshake(&mut &bytes[..]).await;
+
+        assert_eq!(received, Ok(handshake(&[7; 20], &[1; 20])));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_of_other_protocol_rejected() {
+        let bytes = handshake_bytes("BitTorrent protocoL", &[7; 20], &[1; 20]);
+
+        let received = receive_handshake(&mut &bytes[..]).await;
+
+        assert_eq!(received, Err(P2PError::UnsupportedProtocol));
+    }
+
+    #[test]
+    fn test_handshake_validated() {
+        let peer_id = vec![1; 20];
+
+        assert_eq!(handshake(&[7; 20], &peer_id).validate(&[7; 20], CLIENT_ID, Some(&peer_id)), Ok(()));
+        assert_eq!(handshake(&[8; 20], &peer_id).validate(&[7; 20], CLIENT_ID, None), Err(P2PError::InfoHashMismatch));
+        assert_eq!(handshake(&[7; 20], CLIENT_ID.as_bytes()).validate(&[7; 20], CLIENT_ID, None), Err(P2PError::ConnectedToSelf));
+        assert_eq!(handshake(&[7; 20], &peer_id).validate(&[7; 20], CLIENT_ID, Some(&vec![2; 20])), Err(P2PError::PeerIdMismatch));
+    }
 
     #[tokio::test]
     async fn test_receive_message() {
@@ -182,7 +292,7 @@ mod tests {
         let task = tokio::spawn(async move {
             let (stream, _) = listener.accept().await.unwrap();
             let (read_half, _) = tokio::io::split(stream);
-            let mut receiver = PeerReadConn { stream: read_half };
+            let mut receiver = PeerReadConn::new(read_half);
             return receiver.receive().await.unwrap();
         });
 
@@ -193,5 +303,25 @@ mod tests {
         let received_message = task.await.unwrap();
         assert_eq!(received_message, Message::Piece(DataBlock::new(0, 0, vec![0])));
     }
-}
 
+    #[tokio::test]
+    async fn test_oversized_message_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+
+        let task = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read_half, _) = tokio::io::split(stream);
+            let mut receiver = PeerReadConn::new(read_half);
+            receiver.set_max_message_len(max_message_len(8));
+            return receiver.receive().await;
+        });
+
+        let mut client_stream = TcpStream::connect(&local_addr).await.unwrap();
+        client_stream.write_all(&[0xFF, 0xFF, 0xFF, 0xFF, 5]).await.unwrap();
+
+        assert_eq!(task.await.unwrap(), Err(P2PError::MessageTooLong(u32::MAX as usize)));
+        assert!(max_message_len(8) > 9 + config::BLOCK_SIZE_BYTES);
+        assert_eq!(max_message_len(1_000_000), 1 + 125_000);
+    }
+}
@@ -1,14 +1,35 @@
+use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use log::warn;
 use tokio::sync::Mutex;
 use crate::config;
-use crate::core_models::entities::{Bitfield, DataBlock, Message};
+use crate::core_models::entities::{Bitfield, Block, DataBlock, EXTENDED_HANDSHAKE_ID, ExtendedHandshake, Message};
 use crate::core_models::events::InternalEvent;
 use crate::file_provider::FileProv;
 use crate::p2p::models::{P2PError, P2PEvent, P2PState};
+use crate::p2p::peer_client::PeerClient;
 use crate::piece_picker::{PiecePicker};
 
-const MAX_ONGOING_REQUESTS: usize = 10;
+// Number of outstanding requests used before the peer's download rate and round trip time are known
+const INITIAL_QUEUE_DEPTH: usize = 10;
+// Bounds for the number of outstanding requests; the upper bound is also advertised to peers as `reqq`
+const MIN_QUEUE_DEPTH: usize = 2;
+// Outstanding requests should cover this many round trips at the peer's download rate, and at least
+// `MIN_REQUEST_QUEUE_TIME` worth of data
+const QUEUE_DEPTH_ROUND_TRIPS: f64 = 2.0;
+const MIN_REQUEST_QUEUE_TIME: Duration = Duration::from_secs(1);
+// Snubbed peers are only asked for one block at a time
+const SNUBBED_MAX_ONGOING_REQUESTS: usize = 1;
+// A peer which sends no block for this long, while having blocks requested, is considered snubbed
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+// Bounds for the request timeout, which otherwise adapts to how fast the peer responds
+const MIN_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+// How many times the peer's average response time a request may take before timing out
+const REQUEST_TIMEOUT_FACTOR: u32 = 4;
+// Weight of the latest sample in the peer's average response time
+const RESPONSE_TIME_SMOOTHING: f64 = 0.2;
 
 pub struct HandlerResult {
     pub internal_events: Vec<InternalEvent>,
@@ -36,24 +57,60 @@ pub async fn handle(event: P2PEvent, state: &mut P2PState, fp: &mut Box<dyn File
 
     match event {
         P2PEvent::BlockStored(block) => {
-            if state.ongoing_requests.remove(&block) {
+            // in endgame, the same block is requested from multiple peers
+            if state.ongoing_requests.remove(&block).is_some() {
+                if state.ongoing_requests.is_empty() {
+                    state.awaiting_blocks_since = None;
+                }
                 result.msg(Message::Cancel(block));
+                pick_blocks(state, &mut result, picker).await;
             }
         }
         P2PEvent::PieceStored(piece_idx) => {
             state.client_bitfield.piece_acquired(piece_idx);
-            update_clients_interested_status(state, &mut result);
+            update_clients_interested_status(state, &mut result, picker).await;
         }
         P2PEvent::SendKeepAlive => {
             result.msg(Message::KeepAlive);
         }
+        P2PEvent::CheckRequestTimeouts => {
+            release_timed_out_requests(state, &mut result, picker).await;
+            pick_blocks(state, &mut result, picker).await;
+        }
         P2PEvent::ChokePeer => {
             state.peer_is_choked = true;
             result.msg(Message::Choke);
         }
         P2PEvent::UnchokePeer => {
-            state.peer_is_choked = false;
-            result.msg(Message::Unchoke);
+            if !state.is_paused {
+                state.peer_is_choked = false;
+                result.msg(Message::Unchoke);
+            }
+        }
+        P2PEvent::Pause => {
+            state.is_paused = true;
+            release_all_requests(state, picker).await;
+            update_clients_interested_status(state, &mut result, picker).await;
+            if !state.peer_is_choked {
+                state.peer_is_choked = true;
+                result.msg(Message::Choke);
+            }
+        }
+        P2PEvent::Resume => {
+            state.is_paused = false;
+            update_clients_interested_status(state, &mut result, picker).await;
+            pick_blocks(state, &mut result, picker).await;
+        }
+        P2PEvent::Ban => {
+            return Err(P2PError::PeerBanned);
+        }
+        P2PEvent::PrioritiesChanged => {
+            update_clients_interested_status(state, &mut result, picker).await;
+            pick_blocks(state, &mut result, picker).await;
+        }
+        P2PEvent::Close => {
+            state.is_closing = true;
+            release_all_requests(state, picker).await;
         }
         P2PEvent::PeerMessageReceived(message) => {
             return handle_peer_message(message, state, fp, picker).await;
@@ -67,10 +124,13 @@ async fn handle_peer_message(message: Result<Message, P2PError>, state: &mut P2P
                              -> Result<HandlerResult, P2PError> {
     let mut result = HandlerResult::new();
     let message = message?;
+    message.validate(&state.layout).map_err(P2PError::MalformedMessage)?;
     match message {
         Message::KeepAlive => {}
         Message::Choke => {
+            // a choking peer discards the requests it did not serve yet
             state.client_is_choked = true;
+            release_all_requests(state, picker).await;
         }
         Message::Unchoke => {
             state.client_is_choked = false;
@@ -90,7 +150,7 @@ async fn handle_peer_message(message: Result<Message, P2PError>, state: &mut P2P
                 picker.increase_availability_for_piece(piece_idx);
             }
             state.peer_bitfield.piece_acquired(piece_idx);
-            update_clients_interested_status(state, &mut result);
+            update_clients_interested_status(state, &mut result, picker).await;
             pick_blocks(state, &mut result, &picker).await;
         }
         Message::Bitfield(bitfield_vec) => {
@@ -99,27 +159,38 @@ async fn handle_peer_message(message: Result<Message, P2PError>, state: &mut P2P
                 let mut picker = picker.lock().await;
                 picker.increase_availability_for_pieces(state.peer_bitfield.to_available_pieces_vec());
             }
-            update_clients_interested_status(state, &mut result);
+            update_clients_interested_status(state, &mut result, picker).await;
             pick_blocks(state, &mut result, &picker).await;
         }
         Message::Request(block) => {
-            if block.length > config::BLOCK_SIZE_BYTES {
-                warn!("Received a REQUEST message with a length exceeding 16kb!");
+            if block.length > config::BLOCK_SIZE_BYTES || !state.layout.contains_block(&block) {
+                warn!("Peer of transfer {} requested an invalid block {:?}", state.transfer_idx, block);
+                return Err(P2PError::InvalidRequest(block));
             } else if state.peer_is_choked || !state.peer_is_interested {
                 warn!("Received a bad REQUEST message: peer choked: {}, interested: {}", state.peer_is_choked, state.peer_is_interested);
             } else if !state.client_bitfield.has_piece(block.piece_idx) {
                 warn!("Received a REQUEST message for a piece {} which is not currently owned! ", block.piece_idx);
             } else {
-                let data = fp.read_block(&block).await;
-                result.event(InternalEvent::BlockUploaded(data.len()));
-                result.msg(Message::Piece(DataBlock::new(block.piece_idx, block.offset, data)));
+                match fp.read_block(&block).await {
+                    Ok(data) => {
+                        result.event(InternalEvent::BlockUploaded(state.transfer_idx, data.len()));
+                        result.msg(Message::Piece(DataBlock::new(block.piece_idx, block.offset, data)));
+                    }
+                    Err(err) => {
+                        warn!("Could not read block {:?} requested by the peer: {:?}", block, err);
+                        result.event(InternalEvent::StorageFailed(format!("{:?}", err)));
+                    }
+                }
             }
         }
         Message::Piece(data_block) => {
             let block = data_block.to_block();
-            state.ongoing_requests.remove(&block);
+            state.download_rate.record(block.length);
+            if let Some(requested_at) = state.ongoing_requests.remove(&block) {
+                block_received(state, &mut result, requested_at.elapsed());
+            }
             result.event(InternalEvent::BlockDownloaded(state.transfer_idx, data_block));
-            update_clients_interested_status(state, &mut result);
+            update_clients_interested_status(state, &mut result, picker).await;
             pick_blocks(state, &mut result, &picker).await;
         }
         Message::Cancel(_) => {
@@ -127,13 +198,28 @@ async fn handle_peer_message(message: Result<Message, P2PError>, state: &mut P2P
             // needs to be done here
         }
         Message::Port(_) => {}
+        Message::Extended(EXTENDED_HANDSHAKE_ID, payload) => {
+            handle_extended_handshake(&payload, state, &mut result)?;
+            pick_blocks(state, &mut result, picker).await;
+        }
+        Message::Extended(_, _) => {}
     };
 
     return Ok(result);
 }
 
-fn update_clients_interested_status(state: &mut P2PState, result: &mut HandlerResult) {
-    let peer_has_needed_data = state.peer_bitfield.has_any_missing_pieces_from(&state.client_bitfield);
+// the client is interested in peers having pieces it misses, as long as the pieces are wanted
+async fn update_clients_interested_status(state: &mut P2PState, result: &mut HandlerResult, picker: &Arc<Mutex<dyn PiecePicker>>) {
+    let peer_has_needed_data = !state.is_paused
+        && state.peer_bitfield.has_any_missing_pieces_from(&state.client_bitfield)
+        && {
+            let picker = picker.lock().await;
+            (0..state.layout.pieces).any(|piece_idx| {
+                state.peer_bitfield.has_piece(piece_idx)
+                    && !state.client_bitfield.has_piece(piece_idx)
+                    && picker.is_piece_wanted(piece_idx)
+            })
+        };
     if peer_has_needed_data && !state.client_is_interested {
         state.client_is_interested = true;
         result.msg(Message::Interested);
@@ -146,38 +232,150 @@ fn update_clients_interested_status(state: &mut P2PState, result: &mut HandlerRe
 }
 
 async fn pick_blocks(state: &mut P2PState, result: &mut HandlerResult, picker: &Arc<Mutex<dyn PiecePicker>>) {
-    let blocks_to_request = MAX_ONGOING_REQUESTS - state.ongoing_requests.len();
-    if blocks_to_request < 3 || state.client_is_choked || !state.client_is_interested {
+    let max_requests = queue_depth(state);
+    let blocks_to_request = max_requests.saturating_sub(state.ongoing_requests.len());
+    // refill the queue in batches, instead of requesting one block for every block received
+    if blocks_to_request < (max_requests / 4).max(1) || state.client_is_choked || !state.client_is_interested {
         return;
     }
     let blocks = {
+        let requested: HashSet<Block> = state.ongoing_requests.keys().cloned().collect();
         let mut picker = picker.lock().await;
-        picker.pick(&state.peer_bitfield, blocks_to_request)
+        picker.pick(&state.peer_bitfield, &requested, blocks_to_request)
     };
-    state.ongoing_requests.extend(blocks.clone().into_iter());
+    if blocks.is_empty() {
+        return;
+    }
+
+    let now = Instant::now();
+    state.awaiting_blocks_since.get_or_insert(now);
+    state.ongoing_requests.extend(blocks.iter().map(|block| (block.clone(), now)));
     blocks.into_iter().for_each(|block| result.msg(Message::Request(block)));
 }
 
+// number of requests to keep outstanding, so that the link to the peer stays saturated
+fn queue_depth(state: &mut P2PState) -> usize {
+    if state.is_snubbed {
+        return SNUBBED_MAX_ONGOING_REQUESTS;
+    }
+    let max_depth = state.peer_max_requests.unwrap_or(state.max_queue_depth).clamp(1, state.max_queue_depth);
+    let round_trip_time = match state.min_request_response_time {
+        None => return INITIAL_QUEUE_DEPTH.min(max_depth),
+        Some(rtt) => rtt,
+    };
+
+    let queue_time = round_trip_time.mul_f64(QUEUE_DEPTH_ROUND_TRIPS).max(MIN_REQUEST_QUEUE_TIME);
+    let bytes_in_flight = state.download_rate.bytes_per_sec() * queue_time.as_secs_f64();
+    let depth = (bytes_in_flight / config::BLOCK_SIZE_BYTES as f64).ceil() as usize;
+
+    return depth.max(MIN_QUEUE_DEPTH).min(max_depth);
+}
+
+fn handle_extended_handshake(payload: &[u8], state: &mut P2PState, result: &mut HandlerResult) -> Result<(), P2PError> {
+    let handshake = match ExtendedHandshake::from_bytes(payload) {
+        Some(handshake) => handshake,
+        None => {
+            warn!("Received a malformed extension handshake from peer of transfer {}", state.transfer_idx);
+            return Ok(());
+        }
+    };
+    state.peer_max_requests = handshake.reqq;
+    // the version the peer reports itself is more precise than the one encoded in its peer id
+    if let Some(v) = handshake.v {
+        state.client_identified(PeerClient::from_extension_version(&v))?;
+    }
+
+    // peers only send the extension handshake when the client advertised support for it
+    if !state.extended_handshake_sent {
+        state.extended_handshake_sent = true;
+        let client_handshake = ExtendedHandshake {
+            m: Default::default(),
+            v: Some(format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))),
+            reqq: Some(state.max_queue_depth),
+            ..Default::default()
+        };
+        result.msg(Message::Extended(EXTENDED_HANDSHAKE_ID, client_handshake.to_bytes()));
+    }
+    return Ok(());
+}
+
+fn block_received(state: &mut P2PState, result: &mut HandlerResult, response_time: Duration) {
+    state.avg_request_response_time = Some(match state.avg_request_response_time {
+        None => response_time,
+        Some(avg) => avg.mul_f64(1.0 - RESPONSE_TIME_SMOOTHING) + response_time.mul_f64(RESPONSE_TIME_SMOOTHING),
+    });
+    state.min_request_response_time = Some(state.min_request_response_time.map_or(response_time, |min| min.min(response_time)));
+    // the wait for the remaining requests starts over, so a peer which stops sending is still noticed
+    state.awaiting_blocks_since = (!state.ongoing_requests.is_empty()).then(Instant::now);
+    if state.is_snubbed {
+        state.is_snubbed = false;
+        result.event(InternalEvent::PeerSnubbed(state.transfer_idx, false));
+    }
+}
+
+pub async fn release_all_requests(state: &mut P2PState, picker: &Arc<Mutex<dyn PiecePicker>>) {
+    let blocks: Vec<Block> = state.ongoing_requests.drain().map(|(block, _requested_at)| block).collect();
+    state.awaiting_blocks_since = None;
+    if !blocks.is_empty() {
+        picker.lock().await.unpick_blocks(&blocks);
+    }
+}
+
+fn request_timeout(state: &P2PState) -> Duration {
+    return state.avg_request_response_time
+        .map_or(MAX_REQUEST_TIMEOUT, |avg| (avg * REQUEST_TIMEOUT_FACTOR).clamp(MIN_REQUEST_TIMEOUT, MAX_REQUEST_TIMEOUT));
+}
+
+// cancels requests the peer did not respond to in time and gives their blocks back to the picker;
+// also marks the peer as snubbed if it did not send anything for a while
+async fn release_timed_out_requests(state: &mut P2PState, result: &mut HandlerResult, picker: &Arc<Mutex<dyn PiecePicker>>) {
+    let timeout = request_timeout(state);
+    let timed_out: Vec<Block> = state.ongoing_requests.iter()
+        .filter(|(_block, requested_at)| requested_at.elapsed() >= timeout)
+        .map(|(block, _requested_at)| block.clone())
+        .collect();
+
+    if !timed_out.is_empty() {
+        timed_out.iter().for_each(|block| {
+            state.ongoing_requests.remove(block);
+            result.msg(Message::Cancel(block.clone()));
+        });
+        picker.lock().await.unpick_blocks(&timed_out);
+    }
+
+    let stalled = state.awaiting_blocks_since.is_some_and(|since| since.elapsed() >= SNUB_TIMEOUT);
+    if stalled && !state.is_snubbed {
+        warn!("Peer of transfer {} is snubbing the client", state.transfer_idx);
+        state.is_snubbed = true;
+        result.event(InternalEvent::PeerSnubbed(state.transfer_idx, true));
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::collections::HashSet;
+    use std::collections::HashMap;
     use std::sync::{Arc};
+    use std::time::{Duration, Instant};
     use tokio::sync::Mutex;
     use crate::config;
-    use crate::core_models::entities::{Bitfield, Block, DataBlock, Message};
-    use crate::file_provider::{FileProv, MockFileProv};
-    use crate::p2p::handlers::{handle, HandlerResult, pick_blocks, update_clients_interested_status};
-    use crate::p2p::models::{P2PEvent, P2PState};
-    use crate::piece_picker::{MockPiecePicker, PiecePicker};
+    use crate::core_models::entities::{Bitfield, Block, DataBlock, ExtendedHandshake, Message, MessageError};
+    use crate::file_provider::{FileProv, MockFileProv, StorageError};
+    use crate::mocks::generate_mock_layout;
+    use crate::core_models::events::InternalEvent;
+    use crate::p2p::handlers::{handle, HandlerResult, pick_blocks, queue_depth, update_clients_interested_status, INITIAL_QUEUE_DEPTH, MAX_REQUEST_TIMEOUT, MIN_QUEUE_DEPTH, SNUB_TIMEOUT};
+    use crate::p2p::models::{P2PError, P2PEvent, P2PState};
+    use crate::p2p::peer_client::ClientFilter;
+    use crate::piece_picker::{MockPiecePicker, PiecePicker, RarestPiecePicker};
 
-    #[test]
-    fn client_interested_status_update_when_uninterested_and_peer_has_needed_data_test() {
+    #[tokio::test]
+    async fn client_interested_status_update_when_uninterested_and_peer_has_needed_data_test() {
         let mut result = HandlerResult::new();
-        let mut state = P2PState::new(0, Bitfield::init(5), 5);
+        let (picker, _) = prepare_mocks();
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
         state.client_is_interested = false;
         state.peer_bitfield.piece_acquired(0);
 
-        update_clients_interested_status(&mut state, &mut result);
+        update_clients_interested_status(&mut state, &mut result, &picker).await;
 
         assert!(state.client_is_interested);
         assert_eq!(result.internal_events.len(), 1);
@@ -185,40 +383,43 @@ mod tests {
         assert!(result.messages_for_peer[0].is_interested())
     }
 
-    #[test]
-    fn client_interested_status_update_when_uninterested_and_peer_has_no_needed_data_test() {
+    #[tokio::test]
+    async fn client_interested_status_update_when_uninterested_and_peer_has_no_needed_data_test() {
         let mut result = HandlerResult::new();
-        let mut state = P2PState::new(0, Bitfield::init(5), 5);
+        let (picker, _) = prepare_mocks();
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
         state.client_is_interested = false;
 
-        update_clients_interested_status(&mut state, &mut result);
+        update_clients_interested_status(&mut state, &mut result, &picker).await;
 
         assert!(!state.client_is_interested);
         assert!(result.internal_events.is_empty());
         assert!(result.messages_for_peer.is_empty());
     }
 
-    #[test]
-    fn client_interested_status_update_when_interested_and_peer_has_needed_data_test() {
+    #[tokio::test]
+    async fn client_interested_status_update_when_interested_and_peer_has_needed_data_test() {
         let mut result = HandlerResult::new();
-        let mut state = P2PState::new(0, Bitfield::init(5), 5);
+        let (picker, _) = prepare_mocks();
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
         state.client_is_interested = true;
         state.peer_bitfield.piece_acquired(0);
 
-        update_clients_interested_status(&mut state, &mut result);
+        update_clients_interested_status(&mut state, &mut result, &picker).await;
 
         assert!(state.client_is_interested);
         assert!(result.internal_events.is_empty());
         assert!(result.messages_for_peer.is_empty());
     }
 
-    #[test]
-    fn client_interested_status_update_when_interested_and_peer_has_no_needed_data_test() {
+    #[tokio::test]
+    async fn client_interested_status_update_when_interested_and_peer_has_no_needed_data_test() {
         let mut result = HandlerResult::new();
-        let mut state = P2PState::new(0, Bitfield::init(5), 5);
+        let (picker, _) = prepare_mocks();
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
         state.client_is_interested = true;
 
-        update_clients_interested_status(&mut state, &mut result);
+        update_clients_interested_status(&mut state, &mut result, &picker).await;
 
         assert!(!state.client_is_interested);
         assert_eq!(result.internal_events.len(), 1);
@@ -226,18 +427,33 @@ mod tests {
         assert!(result.messages_for_peer[0].is_not_interested());
     }
 
+    #[tokio::test]
+    async fn client_not_interested_in_peer_with_only_skipped_pieces_test() {
+        let mut result = HandlerResult::new();
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
+        state.peer_bitfield.piece_acquired(0);
+        let mut picker = MockPiecePicker::new();
+        picker.expect_is_piece_wanted().returning(|piece_idx| piece_idx != 0);
+        let picker: Arc<Mutex<dyn PiecePicker>> = Arc::new(Mutex::new(picker));
+
+        update_clients_interested_status(&mut state, &mut result, &picker).await;
+
+        assert!(!state.client_is_interested);
+        assert!(result.messages_for_peer.is_empty());
+    }
+
     #[tokio::test]
     async fn pick_blocks_test() {
         let mut result = HandlerResult::new();
-        let mut state = P2PState::new(0, Bitfield::init(5), 5);
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
         state.client_is_interested = true;
         state.client_is_choked = false;
-        state.ongoing_requests = HashSet::new();
+        state.ongoing_requests = HashMap::new();
 
         let picked_blocks = vec![Block::new(0, 0, 0)];
         let picked = picked_blocks.clone();
         let mut picker = MockPiecePicker::new();
-        picker.expect_pick().returning(move |_, _| picked_blocks.clone());
+        picker.expect_pick().returning(move |_, _, _| picked_blocks.clone());
         let picker = Arc::new(Mutex::new(picker));
 
         pick_blocks(&mut state, &mut result, &(picker as Arc<Mutex<dyn PiecePicker>>)).await;
@@ -251,7 +467,7 @@ mod tests {
     #[tokio::test]
     async fn pick_blocks_when_client_choked_test() {
         let mut result = HandlerResult::new();
-        let mut state = P2PState::new(0, Bitfield::init(5), 5);
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
         state.client_is_choked = true;
         state.client_is_interested = true;
         let initial_state = state.clone();
@@ -267,7 +483,7 @@ mod tests {
     #[tokio::test]
     async fn pick_blocks_when_client_not_interested_test() {
         let mut result = HandlerResult::new();
-        let mut state = P2PState::new(0, Bitfield::init(5), 5);
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
         state.client_is_choked = false;
         state.client_is_interested = false;
         let initial_state = state.clone();
@@ -282,7 +498,7 @@ mod tests {
 
     #[tokio::test]
     async fn handle_choke_message_test() {
-        let mut state = P2PState::new(0, Bitfield::init(5), 5);
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
         let (picker, mut fp) = prepare_mocks();
         state.client_is_choked = false;
 
@@ -294,7 +510,7 @@ mod tests {
 
     #[tokio::test]
     async fn handle_unchoke_message_test() {
-        let mut state = P2PState::new(0, Bitfield::init(5), 5);
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
         let (picker, mut fp) = prepare_mocks();
         state.client_is_choked = true;
 
@@ -306,7 +522,7 @@ mod tests {
 
     #[tokio::test]
     async fn handle_interested_message_test() {
-        let mut state = P2PState::new(0, Bitfield::init(5), 5);
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
         let (picker, mut fp) = prepare_mocks();
         state.peer_is_interested = false;
 
@@ -318,7 +534,7 @@ mod tests {
 
     #[tokio::test]
     async fn handle_not_interested_message_test() {
-        let mut state = P2PState::new(0, Bitfield::init(5), 5);
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
         let (picker, mut fp) = prepare_mocks();
         state.peer_is_interested = true;
 
@@ -330,7 +546,7 @@ mod tests {
 
     #[tokio::test]
     async fn handle_have_message_test() {
-        let mut state = P2PState::new(0, Bitfield::init(5), 5);
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
         let (picker, mut fp) = prepare_mocks();
 
         let msg = P2PEvent::PeerMessageReceived(Ok(Message::Have(2)));
@@ -341,18 +557,40 @@ mod tests {
 
     #[tokio::test]
     async fn handle_bitfield_message_test() {
-        let mut state = P2PState::new(0, Bitfield::init(5), 5);
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
         let (picker, mut fp) = prepare_mocks();
 
-        let msg = P2PEvent::PeerMessageReceived(Ok(Message::Bitfield(vec![1])));
+        let msg = P2PEvent::PeerMessageReceived(Ok(Message::Bitfield(vec![0b1000_0000])));
         let _result = handle(msg, &mut state, &mut fp, &picker).await;
 
-        assert_eq!(state.peer_bitfield.content, vec![1]);
+        assert_eq!(state.peer_bitfield.content, vec![0b1000_0000]);
+    }
+
+    #[tokio::test]
+    async fn messages_outside_of_torrent_refused_test() {
+        let messages = [
+            (Message::Have(5), MessageError::PieceOutOfRange(5)),
+            (Message::Bitfield(vec![0, 0]), MessageError::InvalidBitfield),
+            (Message::Bitfield(vec![]), MessageError::InvalidBitfield),
+            (Message::Bitfield(vec![0b0000_0100]), MessageError::InvalidBitfield),
+            (Message::Piece(DataBlock::new(7, 0, vec![0])), MessageError::BlockOutOfRange { piece_idx: 7, offset: 0, len: 1 }),
+        ];
+        for (message, error) in messages {
+            // a real picker, as the mocked one would not panic on unknown pieces
+            let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
+            let picker: Arc<Mutex<dyn PiecePicker>> = Arc::new(Mutex::new(RarestPiecePicker::init(state.layout.clone())));
+            let (_, mut fp) = prepare_mocks();
+
+            let result = handle(P2PEvent::PeerMessageReceived(Ok(message)), &mut state, &mut fp, &picker).await;
+
+            assert_eq!(result.err(), Some(P2PError::MalformedMessage(error)));
+            assert_eq!(state.peer_bitfield, Bitfield::init(5));
+        }
     }
 
     #[tokio::test]
     async fn handle_request_message_test() {
-        let mut state = P2PState::new(0, Bitfield::init(5), 5);
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
         state.peer_is_choked = false;
         state.peer_is_interested = true;
         state.client_bitfield.piece_acquired(0);
@@ -364,13 +602,48 @@ mod tests {
         assert!(result.messages_for_peer.iter().any(|msg| msg.is_piece()));
     }
 
+    #[tokio::test]
+    async fn request_outside_of_torrent_refused_test() {
+        // the last piece is a single block long
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 1));
+        state.peer_is_choked = false;
+        state.peer_is_interested = true;
+        state.client_bitfield.piece_acquired(4);
+        let (picker, mut fp) = prepare_mocks();
+
+        for block in [Block::new(4, config::BLOCK_SIZE_BYTES, config::BLOCK_SIZE_BYTES), Block::new(5, 0, 1), Block::new(4, usize::MAX, 2)] {
+            let msg = P2PEvent::PeerMessageReceived(Ok(Message::Request(block.clone())));
+            let result = handle(msg, &mut state, &mut fp, &picker).await;
+
+            assert_eq!(result.err(), Some(P2PError::InvalidRequest(block)));
+        }
+    }
+
+    #[tokio::test]
+    async fn request_failing_to_read_reports_storage_failure_test() {
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
+        state.peer_is_choked = false;
+        state.peer_is_interested = true;
+        state.client_bitfield.piece_acquired(0);
+        let (picker, _) = prepare_mocks();
+        let mut fp = MockFileProv::new();
+        fp.expect_read_block().returning(|_| Err(StorageError::ReadFailed("disk gone".to_string())));
+        let mut fp: Box<dyn FileProv> = Box::new(fp);
+
+        let msg = P2PEvent::PeerMessageReceived(Ok(Message::Request(Block::new(0, 0, config::BLOCK_SIZE_BYTES))));
+        let result = handle(msg, &mut state, &mut fp, &picker).await.unwrap();
+
+        assert!(result.messages_for_peer.is_empty());
+        assert!(matches!(result.internal_events[..], [InternalEvent::StorageFailed(_)]));
+    }
+
     #[tokio::test]
     async fn handle_piece_message_test() {
-        let mut state = P2PState::new(0, Bitfield::init(5), 5);
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
         state.peer_is_choked = false;
         state.peer_is_interested = true;
         state.client_bitfield.piece_acquired(0);
-        state.ongoing_requests.insert(Block::new(0, 0, 0));
+        state.ongoing_requests.insert(Block::new(0, 0, 0), Instant::now());
         let (picker, mut fp) = prepare_mocks();
 
         let msg = P2PEvent::PeerMessageReceived(Ok(Message::Piece(DataBlock::new(0, 0, vec![]))));
@@ -380,14 +653,211 @@ mod tests {
         assert!(result.internal_events.iter().any(|msg| msg.is_block_downloaded()));
     }
 
+    #[tokio::test]
+    async fn check_request_timeouts_releases_timed_out_requests_test() {
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
+        let timed_out = Block::new(0, 0, config::BLOCK_SIZE_BYTES);
+        let recent = Block::new(1, 0, config::BLOCK_SIZE_BYTES);
+        state.ongoing_requests.insert(timed_out.clone(), Instant::now() - MAX_REQUEST_TIMEOUT);
+        state.ongoing_requests.insert(recent.clone(), Instant::now());
+        let mut picker = MockPiecePicker::new();
+        let expected = timed_out.clone();
+        picker.expect_unpick_blocks()
+            .withf(move |blocks| blocks == [expected.clone()])
+            .times(1)
+            .returning(|_| ());
+        let picker: Arc<Mutex<dyn PiecePicker>> = Arc::new(Mutex::new(picker));
+        let mut fp: Box<dyn FileProv> = Box::new(MockFileProv::new());
+
+        let result = handle(P2PEvent::CheckRequestTimeouts, &mut state, &mut fp, &picker).await.unwrap();
+
+        assert_eq!(state.ongoing_requests.keys().collect::<Vec<&Block>>(), vec![&recent]);
+        assert_eq!(result.messages_for_peer, vec![Message::Cancel(timed_out)]);
+    }
+
+    #[tokio::test]
+    async fn peer_snubbed_when_no_blocks_received_test() {
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
+        state.awaiting_blocks_since = Some(Instant::now() - SNUB_TIMEOUT);
+        let (picker, mut fp) = prepare_mocks();
+
+        let result = handle(P2PEvent::CheckRequestTimeouts, &mut state, &mut fp, &picker).await.unwrap();
+
+        assert!(state.is_snubbed);
+        assert_eq!(result.internal_events, vec![InternalEvent::PeerSnubbed(0, true)]);
+    }
+
+    #[tokio::test]
+    async fn peer_unsnubbed_when_block_received_test() {
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
+        state.is_snubbed = true;
+        state.ongoing_requests.insert(Block::new(0, 0, 1), Instant::now() - Duration::from_secs(2));
+        let (picker, mut fp) = prepare_mocks();
+
+        let msg = P2PEvent::PeerMessageReceived(Ok(Message::Piece(DataBlock::new(0, 0, vec![0]))));
+        let result = handle(msg, &mut state, &mut fp, &picker).await.unwrap();
+
+        assert!(!state.is_snubbed);
+        assert!(state.awaiting_blocks_since.is_none());
+        assert!(state.avg_request_response_time.unwrap() >= Duration::from_secs(2));
+        assert!(result.internal_events.contains(&InternalEvent::PeerSnubbed(0, false)));
+    }
+
+    #[tokio::test]
+    async fn peer_snub_timer_restarted_while_requests_remain_test() {
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
+        state.awaiting_blocks_since = Some(Instant::now() - SNUB_TIMEOUT);
+        state.ongoing_requests.insert(Block::new(0, 0, 1), Instant::now() - SNUB_TIMEOUT);
+        state.ongoing_requests.insert(Block::new(0, 1, 1), Instant::now() - SNUB_TIMEOUT);
+        let (picker, mut fp) = prepare_mocks();
+
+        let msg = P2PEvent::PeerMessageReceived(Ok(Message::Piece(DataBlock::new(0, 0, vec![0]))));
+        handle(msg, &mut state, &mut fp, &picker).await.unwrap();
+
+        assert!(state.awaiting_blocks_since.unwrap().elapsed() < SNUB_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn snubbed_peer_gets_single_request_test() {
+        let mut result = HandlerResult::new();
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
+        state.client_is_interested = true;
+        state.client_is_choked = false;
+        state.is_snubbed = true;
+        let mut picker = MockPiecePicker::new();
+        picker.expect_pick()
+            .withf(|_, _, num_of_blocks| *num_of_blocks == 1)
+            .returning(|_, _, _| vec![Block::new(0, 0, 1)]);
+        let picker: Arc<Mutex<dyn PiecePicker>> = Arc::new(Mutex::new(picker));
+
+        pick_blocks(&mut state, &mut result, &picker).await;
+
+        assert_eq!(state.ongoing_requests.len(), 1);
+        assert!(state.awaiting_blocks_since.is_some());
+    }
+
+    #[test]
+    fn queue_depth_before_measurements_test() {
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
+        assert_eq!(queue_depth(&mut state), INITIAL_QUEUE_DEPTH);
+    }
+
+    #[test]
+    fn queue_depth_follows_download_rate_test() {
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
+        state.min_request_response_time = Some(Duration::from_millis(500));
+        // 10 MiB/s over a 500ms round trip needs 640 blocks of 16KiB in flight
+        state.download_rate.record(10 * 1024 * 1024);
+        state.peer_max_requests = Some(300);
+        assert_eq!(queue_depth(&mut state), 300);
+
+        let mut slow_state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
+        slow_state.min_request_response_time = Some(Duration::from_millis(500));
+        slow_state.download_rate.record(config::BLOCK_SIZE_BYTES);
+        assert_eq!(queue_depth(&mut slow_state), MIN_QUEUE_DEPTH);
+    }
+
+    #[tokio::test]
+    async fn handle_extended_handshake_test() {
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
+        let (picker, mut fp) = prepare_mocks();
+        let handshake = ExtendedHandshake { v: Some("Peer 1.0".to_string()), reqq: Some(42), ..Default::default() };
+
+        let msg = P2PEvent::PeerMessageReceived(Ok(Message::Extended(0, handshake.to_bytes())));
+        let result = handle(msg, &mut state, &mut fp, &picker).await.unwrap();
+
+        assert_eq!(state.peer_max_requests, Some(42));
+        assert_eq!(state.status().client.as_deref(), Some("Peer 1.0"));
+        assert!(state.extended_handshake_sent);
+        assert!(matches!(result.messages_for_peer[..], [Message::Extended(0, _)]));
+    }
+
+    #[tokio::test]
+    async fn extended_handshake_of_blocked_client_refused_test() {
+        let filter = ClientFilter { blocked: ClientFilter::parse_rules("Xunlei").unwrap(), allowed: vec![] };
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2)).with_client_filter(filter);
+        let (picker, mut fp) = prepare_mocks();
+        let handshake = ExtendedHandshake { v: Some("Xunlei 0.0.1".to_string()), ..Default::default() };
+
+        let msg = P2PEvent::PeerMessageReceived(Ok(Message::Extended(0, handshake.to_bytes())));
+        let result = handle(msg, &mut state, &mut fp, &picker).await;
+
+        assert_eq!(result.err(), Some(P2PError::ClientRefused("Xunlei 0.0.1".to_string())));
+        assert_eq!(state.peer_client, None);
+    }
+
+    #[tokio::test]
+    async fn pause_releases_requests_and_chokes_peer_test() {
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
+        state.peer_bitfield.piece_acquired(0);
+        state.client_is_interested = true;
+        state.peer_is_choked = false;
+        state.ongoing_requests.insert(Block::new(0, 0, 1), Instant::now());
+        let mut picker = MockPiecePicker::new();
+        picker.expect_unpick_blocks().times(1).returning(|_| ());
+        let picker: Arc<Mutex<dyn PiecePicker>> = Arc::new(Mutex::new(picker));
+        let (_, mut fp) = prepare_mocks();
+
+        let result = handle(P2PEvent::Pause, &mut state, &mut fp, &picker).await.unwrap();
+
+        assert!(state.is_paused);
+        assert!(state.ongoing_requests.is_empty());
+        assert!(!state.client_is_interested);
+        assert!(state.peer_is_choked);
+        assert!(matches!(result.messages_for_peer[..], [Message::NotInterested, Message::Choke]));
+    }
+
+    #[tokio::test]
+    async fn close_releases_requests_test() {
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
+        state.ongoing_requests.insert(Block::new(0, 0, 1), Instant::now());
+        let mut picker = MockPiecePicker::new();
+        picker.expect_unpick_blocks().times(1).returning(|_| ());
+        let picker: Arc<Mutex<dyn PiecePicker>> = Arc::new(Mutex::new(picker));
+        let (_, mut fp) = prepare_mocks();
+
+        let result = handle(P2PEvent::Close, &mut state, &mut fp, &picker).await.unwrap();
+
+        assert!(state.is_closing);
+        assert!(state.ongoing_requests.is_empty());
+        assert!(result.messages_for_peer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn paused_transfer_ignores_unchoke_test() {
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
+        state.is_paused = true;
+        let (picker, mut fp) = prepare_mocks();
+
+        let result = handle(P2PEvent::UnchokePeer, &mut state, &mut fp, &picker).await.unwrap();
+
+        assert!(state.peer_is_choked);
+        assert!(result.messages_for_peer.is_empty());
+    }
+
+    #[tokio::test]
+    async fn resume_restores_interest_test() {
+        let mut state = P2PState::new(0, Bitfield::init(5), generate_mock_layout(5, 2, 2));
+        state.peer_bitfield.piece_acquired(0);
+        state.is_paused = true;
+        let (picker, mut fp) = prepare_mocks();
+
+        let result = handle(P2PEvent::Resume, &mut state, &mut fp, &picker).await.unwrap();
+
+        assert!(!state.is_paused);
+        assert!(state.client_is_interested);
+        assert!(matches!(result.messages_for_peer[..], [Message::Interested]));
+    }
+
     fn prepare_mocks() -> (Arc<Mutex<dyn PiecePicker>>, Box<dyn FileProv>) {
         let mut picker = MockPiecePicker::new();
         picker.expect_increase_availability_for_pieces().returning(|_| ());
-        picker.expect_pick().returning(move |_, _| vec![]);
+        picker.expect_pick().returning(move |_, _, _| vec![]);
         picker.expect_increase_availability_for_piece().returning(|_| ());
+        picker.expect_is_piece_wanted().returning(|_| true);
 
         let mut fp = MockFileProv::new();
-        fp.expect_read_block().returning(|_| vec![]);
+        fp.expect_read_block().returning(|_| Ok(vec![]));
 
         return (Arc::new(Mutex::new(picker)), Box::new(fp));
     }
@@ -1,41 +1,184 @@
-use std::collections::HashSet;
-use crate::core_models::entities::{Bitfield, Block, Message};
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use log::info;
+use crate::config;
+use crate::core_models::entities::{Bitfield, Block, Message, MessageError, PeerStatus, TorrentLayout};
+use crate::p2p::peer_client::{ClientFilter, PeerClient};
+use crate::transfer_rate::TransferRate;
+
+// Time window over which the download rate from a peer is measured
+const DOWNLOAD_RATE_WINDOW_SECS: u64 = 20;
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct P2PState {
     pub transfer_idx: usize,
+    pub layout: TorrentLayout,
     pub client_bitfield: Bitfield,
     pub peer_bitfield: Bitfield,
     pub client_is_choked: bool,
     pub peer_is_choked: bool,
     pub client_is_interested: bool,
     pub peer_is_interested: bool,
-    pub ongoing_requests: HashSet<Block>,
+    // requested blocks, along with the time they were requested at
+    pub ongoing_requests: HashMap<Block, Instant>,
+    // since when the client has been waiting for the peer to send a block; reset whenever a block arrives
+    pub awaiting_blocks_since: Option<Instant>,
+    // smoothed time it takes the peer to respond to a request
+    pub avg_request_response_time: Option<Duration>,
+    // fastest response to a request; approximates the round trip time to the peer
+    pub min_request_response_time: Option<Duration>,
+    pub download_rate: TransferRate,
+    // maximum number of outstanding requests, as advertised by the peer in the extension handshake
+    pub peer_max_requests: Option<usize>,
+    pub extended_handshake_sent: bool,
+    // id the peer sent in its handshake
+    pub peer_id: Option<Vec<u8>>,
+    // client software of the peer, from its peer id and later its extension handshake
+    pub peer_client: Option<PeerClient>,
+    pub client_filter: ClientFilter,
+    pub is_snubbed: bool,
+    // a paused transfer neither requests nor serves blocks
+    pub is_paused: bool,
+    // the transfer is ending; the messages already queued are still sent to the peer
+    pub is_closing: bool,
+    // upper bound of the outstanding requests, also advertised to the peer
+    pub max_queue_depth: usize,
 }
 
 impl P2PState {
-    pub fn new(transfer_idx: usize, client_bitfield: Bitfield, num_of_pieces: usize) -> Self {
+    pub fn new(transfer_idx: usize, client_bitfield: Bitfield, layout: TorrentLayout) -> Self {
         return P2PState {
             transfer_idx,
             client_bitfield,
-            peer_bitfield: Bitfield::init(num_of_pieces),
+            peer_bitfield: Bitfield::init(layout.pieces),
+            layout,
             client_is_choked: true,
             peer_is_choked: true,
             client_is_interested: false,
             peer_is_interested: false,
-            ongoing_requests: HashSet::new(),
+            ongoing_requests: HashMap::new(),
+            awaiting_blocks_since: None,
+            avg_request_response_time: None,
+            min_request_response_time: None,
+            download_rate: TransferRate::new(Duration::from_secs(DOWNLOAD_RATE_WINDOW_SECS)),
+            peer_max_requests: None,
+            extended_handshake_sent: false,
+            peer_id: None,
+            peer_client: None,
+            client_filter: ClientFilter::default(),
+            is_snubbed: false,
+            is_paused: false,
+            is_closing: false,
+            max_queue_depth: config::DEFAULT_MAX_QUEUE_DEPTH,
         };
     }
+
+    pub fn with_max_queue_depth(mut self, max_queue_depth: usize) -> Self {
+        self.max_queue_depth = max_queue_depth;
+        return self;
+    }
+
+    pub fn with_client_filter(mut self, client_filter: ClientFilter) -> Self {
+        self.client_filter = client_filter;
+        return self;
+    }
+
+    // Records the client of the peer, unless the filter refuses it
+    pub fn client_identified(&mut self, client: PeerClient) -> Result<(), P2PError> {
+        if !self.client_filter.allows(&client) {
+            return Err(P2PError::ClientRefused(client.to_string()));
+        }
+        info!("Peer of transfer {} runs {}", self.transfer_idx, client);
+        self.peer_client = Some(client);
+        return Ok(());
+    }
+
+    pub fn status(&self) -> PeerStatus {
+        return PeerStatus {
+            client: self.peer_client.as_ref().map(|client| client.to_string()),
+            client_is_choked: self.client_is_choked,
+            peer_is_choked: self.peer_is_choked,
+            client_is_interested: self.client_is_interested,
+            peer_is_interested: self.peer_is_interested,
+            is_snubbed: self.is_snubbed,
+            outstanding_requests: self.ongoing_requests.len(),
+        };
+    }
+}
+
+// Peer ids of the peers a torrent is connected to, so that a second connection to the same peer
+// can be turned down
+#[derive(Clone, Default)]
+pub struct ConnectedPeers {
+    peer_ids: Arc<Mutex<HashSet<Vec<u8>>>>,
+}
+
+impl ConnectedPeers {
+    // The peer counts as connected for as long as the returned registration lives; none is returned
+    // when the peer is connected already
+    pub fn register(&self, peer_id: &[u8]) -> Option<PeerRegistration> {
+        if !self.peer_ids.lock().unwrap().insert(peer_id.to_vec()) {
+            return None;
+        }
+        return Some(PeerRegistration { peers: self.clone(), peer_id: peer_id.to_vec() });
+    }
+}
+
+pub struct PeerRegistration {
+    peers: ConnectedPeers,
+    peer_id: Vec<u8>,
 }
 
-#[derive(Clone, Debug)]
+impl Drop for PeerRegistration {
+    fn drop(&mut self) {
+        self.peers.peer_ids.lock().unwrap().remove(&self.peer_id);
+    }
+}
+
+// Addresses of the peers banned for sending corrupt data, which are not connected to again for the
+// rest of the session
+#[derive(Clone, Default)]
+pub struct BannedPeers {
+    ips: Arc<Mutex<HashSet<Ipv4Addr>>>,
+}
+
+impl BannedPeers {
+    pub fn ban(&self, ip: Ipv4Addr) {
+        self.ips.lock().unwrap().insert(ip);
+    }
+
+    pub fn is_banned(&self, ip: &Ipv4Addr) -> bool {
+        return self.ips.lock().unwrap().contains(ip);
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum P2PError {
     TCPConnectionNotEstablished,
     HandshakeFailed,
+    UnsupportedProtocol,
+    InfoHashMismatch,
+    // the peer id in the handshake differs from the one the tracker gave
+    PeerIdMismatch,
+    ConnectedToSelf,
+    // the torrent is already connected to a peer with the same peer id
+    DuplicateConnection,
+    // the client of the peer is blocked, or not among the allowed ones
+    ClientRefused(String),
+    // the peer sent corrupt data
+    PeerBanned,
+    // the peer requested a block outside of the pieces of the torrent
+    InvalidRequest(Block),
     SocketClosed,
     IO(String),
-    UnknownMessageReceived,
+    MalformedMessage(MessageError),
+    // the length prefix of a message exceeds the largest message the peer may send
+    MessageTooLong(usize),
     MessageDeliveryFailed(String),
+    // the coordinator stopped listening, so the transfer is no longer needed
+    ChannelClosed,
 }
 
 // Events that can be received by a p2p transfer task
@@ -44,8 +187,33 @@ pub enum P2PEvent {
     BlockStored(Block),
     PieceStored(usize),
     SendKeepAlive,
+    CheckRequestTimeouts,
     ChokePeer,
     UnchokePeer,
+    Pause,
+    Resume,
+    Ban,
+    // the wanted pieces changed, so the interest in the peer may have as well
+    PrioritiesChanged,
+    Close,
     PeerMessageReceived(Result<Message, P2PError>),
 }
 
+
+#[cfg(test)]
+mod tests {
+    use crate::p2p::models::ConnectedPeers;
+
+    #[test]
+    fn test_peer_registered_once() {
+        let peers = ConnectedPeers::default();
+
+        let registration = peers.register(&[1; 20]);
+        assert!(registration.is_some());
+        assert!(peers.register(&[1; 20]).is_none());
+        assert!(peers.register(&[2; 20]).is_some());
+
+        drop(registration);
+        assert!(peers.register(&[1; 20]).is_some());
+    }
+}
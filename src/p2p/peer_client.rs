@@ -0,0 +1,325 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+// Two letter codes of the clients using Azureus style peer ids, e.g. `-qB4500-`
+const AZUREUS_CLIENTS: [(&str, &str); 20] = [
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BN", "Baidu Netdisk"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "rTorrent"),
+    ("qB", "qBittorrent"),
+    ("QD", "QQDownload"),
+    ("RC", "rust_torrent_client"),
+    ("SD", "Thunder"),
+    ("TR", "Transmission"),
+    ("TT", "TuoTu"),
+    ("UM", "µTorrent Mac"),
+    ("UT", "µTorrent"),
+    ("UW", "µTorrent Web"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+];
+
+// Leading characters of the clients using Shadow style peer ids, e.g. `S58B-----`
+const SHADOW_CLIENTS: [(u8, &str); 7] = [
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+// Client software of a peer, identified by its peer id or its extension handshake
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PeerClient {
+    pub name: String,
+    pub version: Option<Version>,
+}
+
+impl PeerClient {
+    fn new(name: &str, version: Option<Version>) -> Self {
+        return PeerClient { name: name.to_string(), version };
+    }
+
+    // Decodes Azureus, Shadow and Mainline style peer ids
+    pub fn from_peer_id(peer_id: &[u8]) -> Option<Self> {
+        if peer_id.len() < 9 {
+            return None;
+        }
+        return azureus_style(peer_id)
+            .or_else(|| shadow_style(peer_id))
+            .or_else(|| mainline_style(peer_id));
+    }
+
+    // Parses the `v` key of the extension handshake, which usually looks like `qBittorrent/4.5.0`
+    // or `µTorrent 3.5.5`
+    pub fn from_extension_version(v: &str) -> Self {
+        let v = v.trim();
+        let parsed = v.rsplit_once([' ', '/'])
+            .and_then(|(name, version)| Some((name, version.trim_start_matches('v').parse().ok()?)));
+        return match parsed {
+            Some((name, version)) => PeerClient::new(name.trim(), Some(version)),
+            None => PeerClient::new(v, None),
+        };
+    }
+}
+
+impl fmt::Display for PeerClient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        return match &self.version {
+            Some(version) => write!(f, "{} {}", self.name, version),
+            None => write!(f, "{}", self.name),
+        };
+    }
+}
+
+fn azureus_style(peer_id: &[u8]) -> Option<PeerClient> {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' || !peer_id[1..7].iter().all(u8::is_ascii_alphanumeric) {
+        return None;
+    }
+    let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+    let name = AZUREUS_CLIENTS.iter()
+        .find(|(client_code, _)| *client_code == code)
+        .map_or(code, |(_, name)| *name);
+    let mut parts: Vec<u32> = peer_id[3..7].iter().map(|c| (*c as char).to_digit(36).unwrap_or(0)).collect();
+    // the fourth digit is usually a build number, left out when zero
+    if parts[3] == 0 {
+        parts.pop();
+    }
+
+    return Some(PeerClient::new(name, Some(Version(parts))));
+}
+
+fn shadow_style(peer_id: &[u8]) -> Option<PeerClient> {
+    let (_, name) = SHADOW_CLIENTS.iter().find(|(letter, _)| *letter == peer_id[0])?;
+    if &peer_id[6..9] != b"---" {
+        return None;
+    }
+    let parts: Option<Vec<u32>> = peer_id[1..6].iter()
+        .take_while(|c| **c != b'-')
+        .map(|c| shadow_digit(*c))
+        .collect();
+
+    return Some(PeerClient::new(name, parts.filter(|parts| !parts.is_empty()).map(Version)));
+}
+
+fn shadow_digit(c: u8) -> Option<u32> {
+    return match c {
+        b'0'..=b'9' => Some((c - b'0') as u32),
+        b'A'..=b'Z' => Some((c - b'A') as u32 + 10),
+        b'a'..=b'z' => Some((c - b'a') as u32 + 36),
+        b'.' => Some(62),
+        _ => None,
+    };
+}
+
+// e.g. `M4-3-6--` or `M4-20-8-`
+fn mainline_style(peer_id: &[u8]) -> Option<PeerClient> {
+    let name = match peer_id[0] {
+        b'M' => "Mainline",
+        b'Q' => "Queen Bee",
+        _ => return None,
+    };
+    let version = std::str::from_utf8(&peer_id[1..8]).ok()?;
+    let parts: Vec<u32> = version.split('-')
+        .filter(|part| !part.is_empty())
+        .map(|part| part.parse().ok())
+        .collect::<Option<Vec<u32>>>()?;
+    if parts.len() != 3 || !version.ends_with('-') {
+        return None;
+    }
+
+    return Some(PeerClient::new(name, Some(Version(parts))));
+}
+
+// Dotted version number, compared part by part with missing parts counting as zero
+#[derive(Clone, Debug, Eq)]
+pub struct Version(pub Vec<u32>);
+
+impl Version {
+    fn part(&self, idx: usize) -> u32 {
+        return self.0.get(idx).copied().unwrap_or(0);
+    }
+}
+
+impl PartialEq for Version {
+    fn eq(&self, other: &Self) -> bool {
+        return self.cmp(other) == Ordering::Equal;
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        return Some(self.cmp(other));
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let parts = self.0.len().max(other.0.len());
+        return (0..parts)
+            .map(|idx| self.part(idx).cmp(&other.part(idx)))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal);
+    }
+}
+
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<u32> = s.split('.')
+            .map(|part| part.parse().map_err(|_| format!("invalid version {}", s)))
+            .collect::<Result<Vec<u32>, String>>()?;
+        return Ok(Version(parts));
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let parts: Vec<String> = self.0.iter().map(|part| part.to_string()).collect();
+        return write!(f, "{}", parts.join("."));
+    }
+}
+
+// Matches clients by name, case insensitively, and optionally by an inclusive version range, written
+// as `<name>`, `<name> <version>`, `<name> <min>-<max>`, `<name> <min>-` or `<name> -<max>`
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ClientRule {
+    name: String,
+    min_version: Option<Version>,
+    max_version: Option<Version>,
+}
+
+impl ClientRule {
+    pub fn matches(&self, client: &PeerClient) -> bool {
+        if !client.name.eq_ignore_ascii_case(&self.name) {
+            return false;
+        }
+        if self.min_version.is_none() && self.max_version.is_none() {
+            return true;
+        }
+        let Some(version) = &client.version else {
+            return false;
+        };
+
+        return self.min_version.as_ref().is_none_or(|min| version >= min)
+            && self.max_version.as_ref().is_none_or(|max| version <= max);
+    }
+}
+
+impl FromStr for ClientRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err("empty client rule".to_string());
+        }
+        let (name, range) = match s.rsplit_once(' ') {
+            Some((name, range)) if range.starts_with(|c: char| c.is_ascii_digit() || c == '-') => (name, range),
+            _ => (s, ""),
+        };
+        let parse = |version: &str| if version.is_empty() { Ok(None) } else { version.parse().map(Some) };
+        let (min_version, max_version) = match range.split_once('-') {
+            Some((min, max)) => (parse(min)?, parse(max)?),
+            None => (parse(range)?, parse(range)?),
+        };
+
+        return Ok(ClientRule { name: name.trim().to_string(), min_version, max_version });
+    }
+}
+
+// Decides which peers to talk to by their client. Blocked clients are always refused; when clients
+// are allowed explicitly, all the others are refused. Peers whose client is not known are let through.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ClientFilter {
+    pub blocked: Vec<ClientRule>,
+    pub allowed: Vec<ClientRule>,
+}
+
+impl ClientFilter {
+    pub fn allows(&self, client: &PeerClient) -> bool {
+        if self.blocked.iter().any(|rule| rule.matches(client)) {
+            return false;
+        }
+        return self.allowed.is_empty() || self.allowed.iter().any(|rule| rule.matches(client));
+    }
+
+    // Parses a comma separated list of rules
+    pub fn parse_rules(rules: &str) -> Result<Vec<ClientRule>, String> {
+        return rules.split(',')
+            .filter(|rule| !rule.trim().is_empty())
+            .map(|rule| rule.parse())
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::p2p::peer_client::{ClientFilter, ClientRule, PeerClient, Version};
+
+    fn client(name: &str, version: &str) -> PeerClient {
+        return PeerClient { name: name.to_string(), version: Some(version.parse().unwrap()) };
+    }
+
+    #[test]
+    fn test_azureus_style_peer_id_decoded() {
+        assert_eq!(PeerClient::from_peer_id(b"-qB4500-abcdefghijkl"), Some(client("qBittorrent", "4.5.0")));
+        assert_eq!(PeerClient::from_peer_id(b"-TR2940-abcdefghijkl"), Some(client("Transmission", "2.9.4")));
+        assert_eq!(PeerClient::from_peer_id(b"-ZZ1234-abcdefghijkl"), Some(client("ZZ", "1.2.3.4")));
+    }
+
+    #[test]
+    fn test_shadow_and_mainline_style_peer_ids_decoded() {
+        assert_eq!(PeerClient::from_peer_id(b"S58B-----abcdefghijk"), Some(client("Shadow", "5.8.11")));
+        assert_eq!(PeerClient::from_peer_id(b"M4-3-6--abcdefghijkl"), Some(client("Mainline", "4.3.6")));
+        assert_eq!(PeerClient::from_peer_id(b"M4-20-8-abcdefghijkl"), Some(client("Mainline", "4.20.8")));
+        assert_eq!(PeerClient::from_peer_id(&[0u8; 20]), None);
+    }
+
+    #[test]
+    fn test_extension_version_parsed() {
+        assert_eq!(PeerClient::from_extension_version("qBittorrent/4.5.0"), client("qBittorrent", "4.5.0"));
+        assert_eq!(PeerClient::from_extension_version("µTorrent 3.5.5"), client("µTorrent", "3.5.5"));
+        assert_eq!(PeerClient::from_extension_version("Peer"), PeerClient { name: "Peer".to_string(), version: None });
+        assert_eq!(client("Deluge", "2.1").to_string(), "Deluge 2.1");
+    }
+
+    #[test]
+    fn test_versions_compared_by_part() {
+        assert!("4.10".parse::<Version>().unwrap() > "4.9.9".parse().unwrap());
+        assert_eq!("4.5".parse::<Version>().unwrap(), "4.5.0".parse().unwrap());
+    }
+
+    #[test]
+    fn test_clients_filtered_by_name_and_version() {
+        let filter = ClientFilter {
+            blocked: ClientFilter::parse_rules("Xunlei, qBittorrent -4.1.9").unwrap(),
+            allowed: vec![],
+        };
+
+        assert!(!filter.allows(&client("xunlei", "0.0.1")));
+        assert!(!filter.allows(&client("qBittorrent", "4.1.5")));
+        assert!(filter.allows(&client("qBittorrent", "4.2")));
+        assert!(filter.allows(&client("Deluge", "2.1")));
+    }
+
+    #[test]
+    fn test_only_allowed_clients_let_through() {
+        let filter = ClientFilter { blocked: vec![], allowed: ClientFilter::parse_rules("Transmission 3.0-4.0").unwrap() };
+
+        assert!(filter.allows(&client("Transmission", "3.0")));
+        assert!(!filter.allows(&client("Transmission", "2.94")));
+        assert!(!filter.allows(&client("Deluge", "2.1")));
+        assert!("Deluge 2.1".parse::<ClientRule>().unwrap().matches(&client("Deluge", "2.1.0")));
+    }
+}
@@ -10,25 +10,58 @@ use crate::{p2p};
 use crate::core_models::entities::{Bitfield, Peer};
 use crate::core_models::events::InternalEvent;
 use crate::dependency_provider::TransferDeps;
-use crate::p2p::conn::{PeerReceiver, PeerSender};
+use crate::file_provider::FileProv;
+use crate::metrics::{GaugeGuard, Metrics, OVERHEAD};
+use crate::core_models::entities::Message;
+use crate::p2p::conn;
+use crate::p2p::conn::{EstablishedConnection, IncomingConnection, PeerReceiver, PeerSender, HANDSHAKE_LEN};
 use crate::p2p::models::{P2PEvent, P2PState, P2PError};
+use crate::p2p::peer_client::PeerClient;
+use crate::rate_limiter::{Direction, PeerRateLimiter};
+
+const REQUEST_TIMEOUTS_CHECK_INTERVAL_SECS: u64 = 5;
 
 pub fn spawn(peer: Peer,
                    transfer_idx: usize,
                    client_bitfield: Bitfield,
                    deps: Arc<dyn TransferDeps>,
 ) -> (JoinHandle<Result<(), P2PError>>, Sender<P2PEvent>) {
-    let (tx_to_self, rx) = mpsc::channel::<P2PEvent>(8192);
-    let state = P2PState::new(transfer_idx, client_bitfield, deps.torrent_layout().pieces);
+    return spawn_transfer(PeerConnection::Outgoing(peer), transfer_idx, client_bitfield, deps);
+}
+
+// Spawns a transfer over a connection the peer opened to the client
+pub fn spawn_incoming(connection: IncomingConnection,
+                      transfer_idx: usize,
+                      client_bitfield: Bitfield,
+                      deps: Arc<dyn TransferDeps>,
+) -> (JoinHandle<Result<(), P2PError>>, Sender<P2PEvent>) {
+    return spawn_transfer(PeerConnection::Incoming(connection), transfer_idx, client_bitfield, deps);
+}
+
+enum PeerConnection {
+    Outgoing(Peer),
+    Incoming(IncomingConnection),
+}
+
+fn spawn_transfer(connection: PeerConnection,
+                  transfer_idx: usize,
+                  client_bitfield: Bitfield,
+                  deps: Arc<dyn TransferDeps>,
+) -> (JoinHandle<Result<(), P2PError>>, Sender<P2PEvent>) {
+    let config = deps.client_config();
+    let (tx_to_self, rx) = mpsc::channel::<P2PEvent>(config.peer_channel_capacity);
+    let state = P2PState::new(transfer_idx, client_bitfield, deps.torrent_layout())
+        .with_max_queue_depth(config.max_queue_depth)
+        .with_client_filter(config.client_filter);
     let tx_to_self_clone = tx_to_self.clone();
     let handle = tokio::spawn(async move {
-        return run(peer, deps, state, rx, tx_to_self_clone).await;
+        return run(connection, deps, state, rx, tx_to_self_clone).await;
     });
 
     return (handle, tx_to_self);
 }
 
-async fn run(peer: Peer,
+async fn run(connection: PeerConnection,
              deps: Arc<dyn TransferDeps>,
              mut state: P2PState,
              mut rx: Receiver<P2PEvent>,
@@ -37,74 +70,214 @@ async fn run(peer: Peer,
     let output_tx = deps.output_tx();
     let picker = deps.piece_picker();
     let mut file_provider = deps.file_provider();
+    let metrics = deps.metrics();
 
-    let (read_conn, mut write_conn) = match connect_to_peer(&deps, peer).await {
-        Ok((read, write)) => { (read, write) }
+    // the connection counts against the session wide budget for as long as the transfer runs
+    let _permit = deps.connection_budget().acquire_owned().await;
+    let connection = match connection {
+        PeerConnection::Outgoing(peer) => {
+            let _half_open = GaugeGuard::raise(&metrics.half_open_peers);
+            connect_to_peer(&deps, peer).await
+        }
+        PeerConnection::Incoming(incoming) => Ok(EstablishedConnection {
+            peer_id: incoming.peer_id,
+            receiver: incoming.receiver,
+            sender: incoming.sender,
+        }),
+    };
+    // the peer stays registered until the transfer ends, so that other connections to it are refused
+    let connection = connection.and_then(|connection| {
+        if let Some(client) = PeerClient::from_peer_id(&connection.peer_id) {
+            state.client_identified(client)?;
+        }
+        let registration = deps.connected_peers().register(&connection.peer_id)
+            .ok_or(P2PError::DuplicateConnection)?;
+        return Ok((connection, registration));
+    });
+    let (mut read_conn, write_conn, _registration) = match connection {
+        Ok((connection, registration)) => {
+            state.peer_id = Some(connection.peer_id);
+            (connection.receiver, connection.sender, registration)
+        }
         Err(err) => {
             warn!("P2P Transfer {} terminated due to {:?}", state.transfer_idx, err);
-            output_tx.send(InternalEvent::P2PTransferTerminated(state.transfer_idx)).await.unwrap();
+            let _ = output_tx.send(InternalEvent::P2PTransferTerminated(state.transfer_idx)).await;
             return Err(err);
         }
     };
 
-    output_tx.send(InternalEvent::PeerConnectionEstablished(state.transfer_idx)).await.unwrap();
-    file_provider.open_read_only_instance().await;
+    output_tx.send(InternalEvent::PeerConnectionEstablished(state.transfer_idx)).await
+        .map_err(|_| P2PError::ChannelClosed)?;
+    let _connected = GaugeGuard::raise(&metrics.connected_peers);
+    metrics.downloaded_bytes.with_label_values(&[OVERHEAD]).inc_by(HANDSHAKE_LEN as u64);
+    metrics.uploaded_bytes.with_label_values(&[OVERHEAD]).inc_by(HANDSHAKE_LEN as u64);
+    if let Err(err) = file_provider.open_read_only_instance().await {
+        // the peer can still be downloaded from; its requests fail until the file can be read
+        output_tx.send(InternalEvent::StorageFailed(format!("{:?}", err))).await
+            .map_err(|_| P2PError::ChannelClosed)?;
+    }
 
-    let peer_msg_handler = tokio::spawn(recv_peer_messages(read_conn, tx_to_self.clone()));
-    let keep_alive_handler = tokio::spawn(keep_alive_event_scheduler(tx_to_self));
+    read_conn.set_max_message_len(conn::max_message_len(deps.torrent_layout().pieces));
+    let config = deps.client_config();
+    let rate_limiter = PeerRateLimiter::new(deps.rate_limiter(), config.peer_rate_limits);
+    let (control_tx, control_rx) = mpsc::channel::<Message>(config.channel_capacity);
+    let (pieces_tx, pieces_rx) = mpsc::channel::<Message>(config.channel_capacity);
+    let peer_msg_handler = tokio::spawn(
+        recv_peer_messages(read_conn, tx_to_self.clone(), rate_limiter.clone(), metrics.clone()),
+    );
+    let peer_msg_sender = tokio::spawn(
+        send_peer_messages(write_conn, control_rx, pieces_rx, tx_to_self.clone(), rate_limiter, metrics),
+    );
+    let keep_alive_handler = tokio::spawn(keep_alive_event_scheduler(tx_to_self.clone(), config.keep_alive_interval_secs));
+    let request_timeouts_handler = tokio::spawn(request_timeouts_scheduler(tx_to_self));
 
-    while let Some(data) = rx.recv().await {
-        let handler_result = p2p::handlers::handle(
-            data, &mut state, &mut file_provider, &picker,
-        ).await;
-        match handler_result {
-            Ok(result) => {
-                for message in result.messages_for_peer {
-                    write_conn.send(message).await.unwrap();
-                }
-                for event in result.internal_events {
-                    output_tx.send(event).await.unwrap();
-                }
-            }
-            Err(err) => {
-                warn!("P2P Transfer {} terminated due to {:?}", state.transfer_idx, err);
-                output_tx.send(InternalEvent::P2PTransferTerminated(state.transfer_idx)).await.unwrap();
-                keep_alive_handler.abort();
-                return Err(err);
-            }
+    let result = handle_events(&mut state, &mut rx, &mut file_provider, &deps, &control_tx, &pieces_tx).await;
+
+    if result.is_ok() {
+        // once the messages already queued are sent, the sender ends and the connection is closed
+        drop(control_tx);
+        drop(pieces_tx);
+        let sender_abort_handle = peer_msg_sender.abort_handle();
+        if timeout(Duration::from_secs(config.shutdown_timeout_secs), peer_msg_sender).await.is_err() {
+            sender_abort_handle.abort();
         }
+    } else {
+        peer_msg_sender.abort();
     }
-
     peer_msg_handler.abort();
     keep_alive_handler.abort();
+    request_timeouts_handler.abort();
+    if let Err(err) = &result {
+        warn!("P2P Transfer {} terminated due to {:?}", state.transfer_idx, err);
+        p2p::handlers::release_all_requests(&mut state, &picker).await;
+        let _ = output_tx.send(InternalEvent::P2PTransferTerminated(state.transfer_idx)).await;
+    }
+
+    return result;
+}
+
+async fn handle_events(state: &mut P2PState,
+                       rx: &mut Receiver<P2PEvent>,
+                       file_provider: &mut Box<dyn FileProv>,
+                       deps: &Arc<dyn TransferDeps>,
+                       control_tx: &Sender<Message>,
+                       pieces_tx: &Sender<Message>,
+) -> Result<(), P2PError> {
+    let output_tx = deps.output_tx();
+    let picker = deps.piece_picker();
+    let mut reported_status = state.status();
+
+    while let Some(data) = rx.recv().await {
+        let result = p2p::handlers::handle(data, state, file_provider, &picker).await?;
+        for message in result.messages_for_peer {
+            // blocks are throttled separately so that they never hold back control messages
+            let outgoing_tx = if let Message::Piece(_) = message { pieces_tx } else { control_tx };
+            let _ = outgoing_tx.send(message).await;
+        }
+        for event in result.internal_events {
+            output_tx.send(event).await.map_err(|_| P2PError::ChannelClosed)?;
+        }
+        if state.is_closing {
+            break;
+        }
+        let status = state.status();
+        if status != reported_status {
+            reported_status = status.clone();
+            output_tx.send(InternalEvent::PeerStatusChanged(state.transfer_idx, status)).await
+                .map_err(|_| P2PError::ChannelClosed)?;
+        }
+    }
 
     return Ok(());
 }
 
-async fn recv_peer_messages(mut conn: Box<dyn PeerReceiver>, tx: Sender<P2PEvent>) {
+async fn recv_peer_messages(mut conn: Box<dyn PeerReceiver>,
+                            tx: Sender<P2PEvent>,
+                            rate_limiter: PeerRateLimiter,
+                            metrics: Arc<Metrics>) {
+    let overhead = metrics.downloaded_bytes.with_label_values(&[OVERHEAD]);
     loop {
         let message = conn.receive().await;
         let is_err = message.is_err();
-        tx.send(P2PEvent::PeerMessageReceived(message)).await.unwrap();
-        if is_err {
+        if let Ok(message) = &message {
+            overhead.inc_by(message.overhead_len() as u64);
+        }
+        // delaying the next read lets TCP flow control slow down the peer
+        if let Ok(Message::Piece(data_block)) = &message {
+            rate_limiter.acquire(Direction::Download, data_block.data.len()).await;
+        }
+        if tx.send(P2PEvent::PeerMessageReceived(message)).await.is_err() || is_err {
+            break;
+        }
+    }
+}
+
+async fn send_peer_messages(mut conn: Box<dyn PeerSender>,
+                            mut control_rx: Receiver<Message>,
+                            mut pieces_rx: Receiver<Message>,
+                            tx: Sender<P2PEvent>,
+                            rate_limiter: PeerRateLimiter,
+                            metrics: Arc<Metrics>) {
+    let overhead = metrics.uploaded_bytes.with_label_values(&[OVERHEAD]);
+    loop {
+        let message = tokio::select! {
+            biased;
+            Some(message) = control_rx.recv() => message,
+            Some(message) = pieces_rx.recv() => {
+                if let Message::Piece(data_block) = &message {
+                    let mut wait = Box::pin(rate_limiter.acquire(Direction::Upload, data_block.data.len()));
+                    // keep sending control messages while the block waits for bandwidth
+                    loop {
+                        tokio::select! {
+                            biased;
+                            _ = &mut wait => break,
+                            Some(control_message) = control_rx.recv() => {
+                                overhead.inc_by(control_message.overhead_len() as u64);
+                                if let Err(err) = conn.send(control_message).await {
+                                    let _ = tx.send(P2PEvent::PeerMessageReceived(Err(err))).await;
+                                    return;
+                                }
+                            }
+                        }
+                    }
+                }
+                message
+            }
+            else => break,
+        };
+        overhead.inc_by(message.overhead_len() as u64);
+        if let Err(err) = conn.send(message).await {
+            let _ = tx.send(P2PEvent::PeerMessageReceived(Err(err))).await;
+            break;
+        }
+    }
+}
+
+async fn keep_alive_event_scheduler(tx: Sender<P2PEvent>, interval_secs: u64) {
+    let mut interval = time::interval(Duration::from_secs(interval_secs));
+    loop {
+        interval.tick().await;
+        if tx.send(P2PEvent::SendKeepAlive).await.is_err() {
             break;
         }
     }
 }
 
-async fn keep_alive_event_scheduler(tx: Sender<P2PEvent>) {
-    let mut interval = time::interval(Duration::from_secs(50));
+async fn request_timeouts_scheduler(tx: Sender<P2PEvent>) {
+    let mut interval = time::interval(Duration::from_secs(REQUEST_TIMEOUTS_CHECK_INTERVAL_SECS));
     loop {
         interval.tick().await;
-        tx.send(P2PEvent::SendKeepAlive).await.unwrap();
+        if tx.send(P2PEvent::CheckRequestTimeouts).await.is_err() {
+            break;
+        }
     }
 }
 
-async fn connect_to_peer(deps: &Arc<dyn TransferDeps>, peer: Peer)
-                         -> Result<(Box<dyn PeerReceiver>, Box<dyn PeerSender>), P2PError> {
+async fn connect_to_peer(deps: &Arc<dyn TransferDeps>, peer: Peer) -> Result<EstablishedConnection, P2PError> {
+    let config = deps.client_config();
     let connection = timeout(
-        Duration::from_secs(10),
-        deps.peer_connector().connect_to(peer, deps.info_hash(), deps.client_config().client_id),
+        Duration::from_secs(config.connect_timeout_secs),
+        deps.peer_connector().connect_to(peer, deps.info_hash(), config.client_id),
     ).await;
 
     return match connection {
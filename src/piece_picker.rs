@@ -1,26 +1,38 @@
 use std::collections::{HashMap, HashSet};
-use rand::prelude::IteratorRandom;
 use crate::core_models::entities::{Block, TorrentLayout};
 use crate::core_models::entities::Bitfield;
+use crate::selection::Priority;
 use mockall::automock;
 
 // Penalty applied to pieces with all blocks picked
-const ALL_BLOCKS_PICKED_PENALTY: i32 = 5000;
-// Penalty applied to pieces with all the blocks removed
+const ALL_BLOCKS_PICKED_PENALTY: i32 = 20000;
+// Penalty applied to pieces with all the blocks removed; also applied to skipped pieces, so they are never picked
 const ALL_BLOCKS_REMOVED_PENALTY: i32 = 50000;
+// Score difference between two consecutive priority levels; outweighs rarity and piece completion
+const PRIORITY_LEVEL_STEP: i32 = 5000;
 // Starting score for all pieces
 const PIECE_BASE_SCORE: i32 = 1000;
 // Bonus applied to pieces with some blocks picked; used to prioritize piece completion
 const SOME_BLOCKS_PICKED_BONUS: i32 = -1000;
+// Endgame starts once at most this many blocks are left to download, or once all the remaining blocks are picked
+pub const ENDGAME_THRESHOLD_BLOCKS: usize = 32;
 
-enum PickEvent {
-    FirstPickedBlocks,
-    AllBlocksPicked,
+// Default number of pieces picked in order when downloading sequentially
+pub const DEFAULT_SEQUENTIAL_WINDOW: usize = 20;
+
+#[derive(Debug, PartialEq)]
+pub enum PickerError {
+    // a priority must be given for every piece of the torrent
+    PrioritiesLengthMismatch { expected: usize, len: usize },
 }
 
-struct PickResult {
-    picked_blocks: Vec<Block>,
-    pick_event: Option<PickEvent>,
+// Order in which pieces are picked
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PickingMode {
+    RarestFirst,
+    // pieces inside a window of `window` pieces, starting at the first piece with unpicked blocks,
+    // are picked in order; the first and last pieces are picked before everything else
+    Sequential { window: usize },
 }
 
 struct PieceDownloadState {
@@ -49,19 +61,53 @@ impl PieceDownloadState {
         return piece;
     }
 
+    fn has_unpicked_blocks(&self) -> bool {
+        return !self.blocks_unpicked.is_empty();
+    }
+
     fn all_blocks_removed(&self) -> bool {
         return self.blocks_picked.is_empty() && self.blocks_unpicked.is_empty();
     }
+
+    fn remaining_blocks(&self) -> usize {
+        return self.blocks_picked.len() + self.blocks_unpicked.len();
+    }
+
+    // score adjustment corresponding to how far along the piece's download is
+    fn score(&self) -> i32 {
+        if self.all_blocks_removed() {
+            return ALL_BLOCKS_PICKED_PENALTY + ALL_BLOCKS_REMOVED_PENALTY;
+        }
+        if self.blocks_unpicked.is_empty() {
+            return ALL_BLOCKS_PICKED_PENALTY;
+        }
+        if self.had_blocks_picked_from_it {
+            return SOME_BLOCKS_PICKED_BONUS;
+        }
+        return 0;
+    }
 }
 
 #[automock]
 pub trait PiecePicker: Send {
-    fn pick(&mut self, peer_bitfield: &Bitfield, num_of_blocks: usize) -> Vec<Block>;
+    // picks at most `num_of_blocks` blocks owned by the peer; in endgame, blocks already picked for other
+    // peers are also returned, as long as they are not in `requested_from_peer`
+    fn pick(&mut self, peer_bitfield: &Bitfield, requested_from_peer: &HashSet<Block>, num_of_blocks: usize) -> Vec<Block>;
     fn increase_availability_for_piece(&mut self, piece_idx: usize);
     fn increase_availability_for_pieces(&mut self, piece_idxs: Vec<usize>);
     fn decrease_availability_for_pieces(&mut self, piece_idxs: Vec<usize>);
     fn remove_block(&mut self, block: &Block);
+    // returns picked blocks which will not be delivered, so that they can be picked again
+    fn unpick_blocks(&mut self, blocks: &[Block]);
     fn reinsert_piece(&mut self, piece_idx: usize);
+    // drops a piece which is already stored, so that none of its blocks get picked
+    fn remove_piece(&mut self, piece_idx: usize);
+    fn set_picking_mode(&mut self, mode: PickingMode);
+    fn set_piece_priorities(&mut self, priorities: Vec<Priority>) -> Result<(), PickerError>;
+    fn is_piece_wanted(&self, piece_idx: usize) -> bool;
+    fn is_in_endgame(&self) -> bool;
+    // all the blocks of the wanted pieces are stored
+    fn is_selection_complete(&self) -> bool;
 }
 
 pub struct RarestPiecePicker {
@@ -72,7 +118,13 @@ pub struct RarestPiecePicker {
     piece_lookup_table: HashMap<usize, usize>,
     // download state for each piece
     piece_download_state: HashMap<usize, PieceDownloadState>,
-
+    mode: PickingMode,
+    piece_priorities: Vec<Priority>,
+    // first piece which may still have unpicked blocks; only used in sequential mode
+    sequential_cursor: usize,
+    // blocks of wanted pieces which were not yet stored, and how many of them were not yet picked
+    remaining_blocks: usize,
+    unpicked_blocks: usize,
 }
 
 impl RarestPiecePicker {
@@ -85,44 +137,117 @@ impl RarestPiecePicker {
             piece_download_state.insert(piece_idx, piece);
         }
 
-        return RarestPiecePicker { layout, priority_score_sorted_pieces: priority_sorted_pieces, piece_lookup_table, piece_download_state };
+        let pieces = layout.pieces;
+        let remaining_blocks = piece_download_state.values().map(|piece| piece.remaining_blocks()).sum();
+        return RarestPiecePicker {
+            layout,
+            priority_score_sorted_pieces: priority_sorted_pieces,
+            piece_lookup_table,
+            piece_download_state,
+            mode: PickingMode::RarestFirst,
+            piece_priorities: vec![Priority::Normal; pieces],
+            sequential_cursor: 0,
+            remaining_blocks,
+            unpicked_blocks: remaining_blocks,
+        };
     }
 
-    fn pick_blocks(&mut self, piece_idx: usize, num_of_blocks: usize) -> PickResult {
-        let piece = self.piece_download_state.get_mut(&piece_idx).unwrap();
+    pub fn with_mode(mut self, mode: PickingMode) -> Self {
+        self.mode = mode;
+        return self;
+    }
+
+    fn rarest_piece(&self, peer_bitfield: &Bitfield) -> Option<usize> {
+        // find the first owned piece with available blocks
+        return self.priority_score_sorted_pieces
+            .iter()
+            .find(|(piece_idx, score)| {
+                score < &ALL_BLOCKS_REMOVED_PENALTY
+                    && peer_bitfield.has_piece(*piece_idx)
+                    && self.is_piece_wanted(*piece_idx)
+                    && self.piece_download_state[piece_idx].has_unpicked_blocks()
+            })
+            .map(|(piece_idx, _score)| *piece_idx);
+    }
 
-        // when a piece has no unpicked blocks, we re-pick an already picked block at random
-        if piece.blocks_unpicked.is_empty() {
-            let block = piece.blocks_picked.iter()
-                .choose(&mut rand::thread_rng())
-                .unwrap();
-            return PickResult { picked_blocks: vec![Block::new(piece_idx, block.0, block.1)], pick_event: None };
+    fn sequential_piece(&mut self, peer_bitfield: &Bitfield, window: usize) -> Option<usize> {
+        // skipped pieces keep their unpicked blocks, but must not hold the window back
+        while self.sequential_cursor < self.layout.pieces
+            && (!self.piece_download_state[&self.sequential_cursor].has_unpicked_blocks()
+                || !self.is_piece_wanted(self.sequential_cursor)) {
+            self.sequential_cursor += 1;
         }
 
-        // for pieces with unpicked blocks, pick at most num_of_blocks
-        let blocks: Vec<(usize, usize)> = piece.blocks_unpicked.iter()
-            .take(num_of_blocks)
-            .map(|(offset, length)| (*offset, *length))
-            .collect();
-        blocks.iter().for_each(|block| {
-            piece.blocks_unpicked.remove(block);
-            piece.blocks_picked.insert(*block);
+        // a torrent without pieces has nothing to pick
+        let last_piece = self.layout.pieces.checked_sub(1)?;
+        let window_end = (self.sequential_cursor + window).min(self.layout.pieces);
+        return [0, last_piece].into_iter()
+            .chain(self.sequential_cursor..window_end)
+            .find(|piece_idx| {
+                peer_bitfield.has_piece(*piece_idx)
+                    && self.is_piece_wanted(*piece_idx)
+                    && self.piece_download_state[piece_idx].has_unpicked_blocks()
+            });
+    }
+
+    fn priority_score(priority: Priority) -> i32 {
+        return match priority {
+            Priority::Skip => ALL_BLOCKS_REMOVED_PENALTY,
+            Priority::Low => PRIORITY_LEVEL_STEP,
+            Priority::Normal => 0,
+            Priority::High => -PRIORITY_LEVEL_STEP,
+        };
+    }
+
+    fn pick_blocks(&mut self, piece_idx: usize, num_of_blocks: usize) -> Vec<Block> {
+        return self.update_piece_state(piece_idx, |piece| {
+            let blocks: Vec<(usize, usize)> = piece.blocks_unpicked.iter()
+                .take(num_of_blocks)
+                .map(|(offset, length)| (*offset, *length))
+                .collect();
+            blocks.iter().for_each(|block| {
+                piece.blocks_unpicked.remove(block);
+                piece.blocks_picked.insert(*block);
+            });
+            piece.had_blocks_picked_from_it = true;
+
+            return blocks.iter()
+                .map(|(offset, length)| Block::new(piece_idx, *offset, *length))
+                .collect();
         });
+    }
 
-        let blocks = blocks.iter()
-            .map(|(offset, length)| Block::new(piece_idx, *offset, *length))
+    // in endgame, blocks which are already requested from other peers get requested from this peer as well
+    fn pick_endgame_blocks(&self, peer_bitfield: &Bitfield, requested_from_peer: &HashSet<Block>, num_of_blocks: usize) -> Vec<Block> {
+        return self.priority_score_sorted_pieces
+            .iter()
+            .take_while(|(_piece_idx, score)| score < &ALL_BLOCKS_REMOVED_PENALTY)
+            .filter(|(piece_idx, _score)| peer_bitfield.has_piece(*piece_idx) && self.is_piece_wanted(*piece_idx))
+            .flat_map(|(piece_idx, _score)| {
+                self.piece_download_state[piece_idx].blocks_picked.iter()
+                    .map(|(offset, length)| Block::new(*piece_idx, *offset, *length))
+            })
+            .filter(|block| !requested_from_peer.contains(block))
+            .take(num_of_blocks)
             .collect();
+    }
 
-        if !piece.had_blocks_picked_from_it {
-            piece.had_blocks_picked_from_it = true;
-            return PickResult { picked_blocks: blocks, pick_event: Some(PickEvent::FirstPickedBlocks) };
-        }
+    // applies `update` to the piece's download state, then updates the piece's score and the block counters
+    fn update_piece_state<T>(&mut self, piece_idx: usize, update: impl FnOnce(&mut PieceDownloadState) -> T) -> T {
+        let piece = self.piece_download_state.get_mut(&piece_idx).unwrap();
+        let (score, remaining, unpicked) = (piece.score(), piece.remaining_blocks(), piece.blocks_unpicked.len());
+        let result = update(piece);
+        let (new_score, new_remaining, new_unpicked) = (piece.score(), piece.remaining_blocks(), piece.blocks_unpicked.len());
 
-        if piece.blocks_unpicked.is_empty() {
-            return PickResult { picked_blocks: blocks, pick_event: Some(PickEvent::AllBlocksPicked) };
+        if self.is_piece_wanted(piece_idx) {
+            self.remaining_blocks = self.remaining_blocks + new_remaining - remaining;
+            self.unpicked_blocks = self.unpicked_blocks + new_unpicked - unpicked;
+        }
+        if new_score != score {
+            self.update_priority(piece_idx, new_score - score);
         }
 
-        return PickResult { picked_blocks: blocks, pick_event: None };
+        return result;
     }
 
     fn update_priority(&mut self, piece_idx: usize, update: i32) {
@@ -153,27 +278,19 @@ impl RarestPiecePicker {
 }
 
 impl PiecePicker for RarestPiecePicker {
-    fn pick(&mut self, peer_bitfield: &Bitfield, num_of_blocks: usize) -> Vec<Block> {
-        // find the first owned piece with available blocks
-        let piece_idx = self.priority_score_sorted_pieces
-            .iter()
-            .find(|(piece_idx, score)| {
-                score < &ALL_BLOCKS_REMOVED_PENALTY && peer_bitfield.has_piece(*piece_idx)
-            })
-            .map(|(piece_idx, _score)| *piece_idx);
-
-        let blocks = piece_idx.map_or(vec![], |piece_idx| {
-            let pick_result = self.pick_blocks(piece_idx, num_of_blocks);
-            if let Some(event) = pick_result.pick_event {
-                match event {
-                    PickEvent::FirstPickedBlocks => {
-                        self.update_priority(piece_idx, SOME_BLOCKS_PICKED_BONUS)
-                    }
-                    PickEvent::AllBlocksPicked => self.update_priority(piece_idx, ALL_BLOCKS_PICKED_PENALTY - SOME_BLOCKS_PICKED_BONUS),
-                }
-            }
-            pick_result.picked_blocks
-        });
+    fn pick(&mut self, peer_bitfield: &Bitfield, requested_from_peer: &HashSet<Block>, num_of_blocks: usize) -> Vec<Block> {
+        // outside the sequential window, pieces are still picked rarest first
+        let piece_idx = match self.mode {
+            PickingMode::RarestFirst => None,
+            PickingMode::Sequential { window } => self.sequential_piece(peer_bitfield, window),
+        }.or_else(|| self.rarest_piece(peer_bitfield));
+
+        let mut blocks = piece_idx.map_or(vec![], |piece_idx| self.pick_blocks(piece_idx, num_of_blocks));
+        if blocks.len() < num_of_blocks && self.is_in_endgame() {
+            let mut requested = requested_from_peer.clone();
+            requested.extend(blocks.iter().cloned());
+            blocks.extend(self.pick_endgame_blocks(peer_bitfield, &requested, num_of_blocks - blocks.len()));
+        }
 
         return blocks;
     }
@@ -195,33 +312,95 @@ impl PiecePicker for RarestPiecePicker {
     }
 
     fn remove_block(&mut self, block: &Block) {
-        let piece_state = self.piece_download_state.get_mut(&block.piece_idx).unwrap();
-        piece_state.blocks_picked.remove(&(block.offset, block.length));
-        if piece_state.all_blocks_removed() {
-            self.update_priority(block.piece_idx, ALL_BLOCKS_REMOVED_PENALTY);
+        // a block may be stored without being picked, when it was requested before its piece got reinserted
+        self.update_piece_state(block.piece_idx, |piece| {
+            piece.blocks_picked.remove(&(block.offset, block.length));
+            piece.blocks_unpicked.remove(&(block.offset, block.length));
+        });
+    }
+
+    fn unpick_blocks(&mut self, blocks: &[Block]) {
+        for block in blocks {
+            self.update_piece_state(block.piece_idx, |piece| {
+                if piece.blocks_picked.remove(&(block.offset, block.length)) {
+                    piece.blocks_unpicked.insert((block.offset, block.length));
+                }
+            });
+            self.sequential_cursor = self.sequential_cursor.min(block.piece_idx);
         }
     }
 
     fn reinsert_piece(&mut self, piece_idx: usize) {
         let fresh_state = PieceDownloadState::init(piece_idx, &self.layout);
-        self.piece_download_state.insert(piece_idx, fresh_state);
-        self.update_priority(piece_idx, -ALL_BLOCKS_REMOVED_PENALTY + PIECE_BASE_SCORE);
+        self.update_piece_state(piece_idx, |piece| *piece = fresh_state);
+        self.sequential_cursor = self.sequential_cursor.min(piece_idx);
+    }
+
+    fn remove_piece(&mut self, piece_idx: usize) {
+        self.update_piece_state(piece_idx, |piece| {
+            piece.blocks_unpicked.clear();
+            piece.blocks_picked.clear();
+        });
+    }
+
+    fn set_picking_mode(&mut self, mode: PickingMode) {
+        self.mode = mode;
+        self.sequential_cursor = 0;
+    }
+
+    fn set_piece_priorities(&mut self, priorities: Vec<Priority>) -> Result<(), PickerError> {
+        if priorities.len() != self.layout.pieces {
+            return Err(PickerError::PrioritiesLengthMismatch { expected: self.layout.pieces, len: priorities.len() });
+        }
+        for (piece_idx, priority) in priorities.into_iter().enumerate() {
+            let previous = self.piece_priorities[piece_idx];
+            if previous != priority {
+                let remaining = self.piece_download_state[&piece_idx].remaining_blocks();
+                let unpicked = self.piece_download_state[&piece_idx].blocks_unpicked.len();
+                if previous == Priority::Skip {
+                    self.remaining_blocks += remaining;
+                    self.unpicked_blocks += unpicked;
+                } else if priority == Priority::Skip {
+                    self.remaining_blocks -= remaining;
+                    self.unpicked_blocks -= unpicked;
+                }
+                self.piece_priorities[piece_idx] = priority;
+                self.update_priority(piece_idx, Self::priority_score(priority) - Self::priority_score(previous));
+            }
+        }
+        self.sequential_cursor = 0;
+        return Ok(());
+    }
+
+    fn is_piece_wanted(&self, piece_idx: usize) -> bool {
+        return self.piece_priorities[piece_idx] != Priority::Skip;
+    }
+
+    fn is_in_endgame(&self) -> bool {
+        return self.remaining_blocks > 0
+            && (self.unpicked_blocks == 0 || self.remaining_blocks <= ENDGAME_THRESHOLD_BLOCKS);
+    }
+
+    fn is_selection_complete(&self) -> bool {
+        return self.remaining_blocks == 0;
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use crate::piece_picker::{PiecePicker, RarestPiecePicker};
-    use crate::core_models::entities::{Bitfield};
-    use crate::{mocks};
+    use std::collections::HashSet;
+    use crate::piece_picker::{ENDGAME_THRESHOLD_BLOCKS, PickerError, PickingMode, PiecePicker, RarestPiecePicker};
+    use crate::core_models::entities::{Bitfield, Block};
+    use crate::{config, mocks};
+    use crate::selection::Priority;
 
     #[test]
     fn test_piece_pick_no_pieces_available() {
         let layout = mocks::generate_mock_layout(2, 2, 2);
         let mut piece_picker = RarestPiecePicker::init(layout);
         let peer_bitfield = Bitfield::init(2);
-        let blocks = piece_picker.pick(&peer_bitfield, 2);
+        let blocks = piece_picker.pick(&peer_bitfield, &HashSet::new(), 2);
 
         assert!(blocks.is_empty());
     }
@@ -233,24 +412,57 @@ mod tests {
         let mut peer_bitfield = Bitfield::init(2);
 
         peer_bitfield.piece_acquired(0);
-        let blocks = piece_picker.pick(&peer_bitfield, 2);
+        let blocks = piece_picker.pick(&peer_bitfield, &HashSet::new(), 2);
 
         assert_eq!(blocks.len(), 2);
         assert_eq!(blocks[0].piece_idx, blocks[1].piece_idx);
     }
 
     #[test]
-    fn test_pick_from_already_picked_blocks() {
+    fn test_endgame_picks_blocks_requested_from_other_peers() {
         let layout = mocks::generate_mock_layout(2, 2, 2);
         let mut piece_picker = RarestPiecePicker::init(layout);
         let mut peer_bitfield = Bitfield::init(2);
 
         peer_bitfield.piece_acquired(0);
-        piece_picker.pick(&peer_bitfield, 2);
+        let requested: HashSet<Block> = piece_picker.pick(&peer_bitfield, &HashSet::new(), 2).into_iter().collect();
+        assert!(piece_picker.is_in_endgame());
+
+        // the blocks are not requested twice from the same peer
+        let blocks = piece_picker.pick(&peer_bitfield, &requested, 2);
+        assert!(blocks.is_empty());
 
-        let blocks = piece_picker.pick(&peer_bitfield, 2);
-        assert_eq!(blocks.len(), 1);
-        assert_eq!(blocks[0].piece_idx, 0);
+        // but they are requested from other peers
+        let blocks: HashSet<Block> = piece_picker.pick(&peer_bitfield, &HashSet::new(), 2).into_iter().collect();
+        assert_eq!(blocks, requested);
+    }
+
+    #[test]
+    fn test_endgame_entered_on_threshold() {
+        let layout = mocks::generate_mock_layout(2, ENDGAME_THRESHOLD_BLOCKS, 1);
+        let mut piece_picker = RarestPiecePicker::init(layout);
+        let mut peer_bitfield = Bitfield::init(2);
+        peer_bitfield.piece_acquired(0);
+        assert!(!piece_picker.is_in_endgame());
+
+        let block = piece_picker.pick(&peer_bitfield, &HashSet::new(), 1).remove(0);
+        piece_picker.remove_block(&block);
+
+        assert!(piece_picker.is_in_endgame());
+    }
+
+    #[test]
+    fn test_no_endgame_once_all_blocks_stored() {
+        let layout = mocks::generate_mock_layout(1, 2, 2);
+        let mut piece_picker = RarestPiecePicker::init(layout);
+        let mut peer_bitfield = Bitfield::init(1);
+        peer_bitfield.piece_acquired(0);
+
+        let blocks = piece_picker.pick(&peer_bitfield, &HashSet::new(), 2);
+        blocks.iter().for_each(|block| piece_picker.remove_block(block));
+
+        assert!(!piece_picker.is_in_endgame());
+        assert!(piece_picker.pick(&peer_bitfield, &HashSet::new(), 2).is_empty());
     }
 
     #[test]
@@ -262,7 +474,7 @@ mod tests {
         peer_bitfield.piece_acquired(0);
         peer_bitfield.piece_acquired(1);
         piece_picker.increase_availability_for_piece(0);
-        let blocks = piece_picker.pick(&peer_bitfield, 2);
+        let blocks = piece_picker.pick(&peer_bitfield, &HashSet::new(), 2);
 
         assert_eq!(blocks.len(), 2);
         assert_eq!(blocks[0].piece_idx, 1);
@@ -278,13 +490,13 @@ mod tests {
         peer_bitfield.piece_acquired(0);
         peer_bitfield.piece_acquired(1);
         // pick blocks from either piece
-        let piece_idx = piece_picker.pick(&peer_bitfield, 2)[0].piece_idx;
+        let piece_idx = piece_picker.pick(&peer_bitfield, &HashSet::new(), 2)[0].piece_idx;
 
         // increase availability for the previously picked piece
         piece_picker.increase_availability_for_piece(piece_idx);
 
         // on the second pick, the previously picked piece should be again picked, even though it is less rare
-        let blocks = piece_picker.pick(&peer_bitfield, 2);
+        let blocks = piece_picker.pick(&peer_bitfield, &HashSet::new(), 2);
         assert_eq!(blocks.len(), 2);
         assert_eq!(blocks[0].piece_idx, piece_idx);
         assert_eq!(blocks[1].piece_idx, piece_idx);
@@ -297,13 +509,60 @@ mod tests {
         let mut peer = Bitfield::init(2);
 
         peer.piece_acquired(0);
-        let blocks = piece_picker.pick(&peer, 2);
+        let blocks = piece_picker.pick(&peer, &HashSet::new(), 2);
         blocks.iter().for_each(|block| piece_picker.remove_block(block));
 
-        let blocks = piece_picker.pick(&peer, 2);
+        let blocks = piece_picker.pick(&peer, &HashSet::new(), 2);
         assert!(blocks.is_empty());
     }
 
+    #[test]
+    fn test_unpick_blocks() {
+        let layout = mocks::generate_mock_layout(2, 2, 2);
+        let mut piece_picker = RarestPiecePicker::init(layout);
+        let mut peer = Bitfield::init(2);
+        peer.piece_acquired(0);
+        peer.piece_acquired(1);
+
+        let blocks = piece_picker.pick(&peer, &HashSet::new(), 2);
+        piece_picker.unpick_blocks(&blocks);
+
+        // the partially downloaded piece is picked again before the other one
+        let picked_again = piece_picker.pick(&peer, &HashSet::new(), 2);
+        assert_eq!(picked_again.len(), 2);
+        assert_eq!(picked_again[0].piece_idx, blocks[0].piece_idx);
+        assert_eq!(picked_again[1].piece_idx, blocks[0].piece_idx);
+    }
+
+    #[test]
+    fn test_unpick_stored_block_ignored() {
+        let layout = mocks::generate_mock_layout(1, 1, 1);
+        let mut piece_picker = RarestPiecePicker::init(layout);
+        let mut peer = Bitfield::init(1);
+        peer.piece_acquired(0);
+
+        let blocks = piece_picker.pick(&peer, &HashSet::new(), 1);
+        piece_picker.remove_block(&blocks[0]);
+        piece_picker.unpick_blocks(&blocks);
+
+        assert!(piece_picker.pick(&peer, &HashSet::new(), 1).is_empty());
+    }
+
+    #[test]
+    fn test_removed_piece_not_picked() {
+        let layout = mocks::generate_mock_layout(2, 2, 2);
+        let mut piece_picker = RarestPiecePicker::init(layout);
+        let mut peer = Bitfield::init(2);
+        peer.piece_acquired(0);
+        peer.piece_acquired(1);
+
+        piece_picker.remove_piece(0);
+        let blocks = piece_picker.pick(&peer, &HashSet::new(), 4);
+
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks.iter().all(|block| block.piece_idx == 1));
+    }
+
     #[test]
     fn test_reinsert_piece() {
         let layout = mocks::generate_mock_layout(2, 2, 2);
@@ -311,12 +570,187 @@ mod tests {
         let mut peer = Bitfield::init(2);
 
         peer.piece_acquired(0);
-        let blocks = piece_picker.pick(&peer, 2);
+        let blocks = piece_picker.pick(&peer, &HashSet::new(), 2);
         piece_picker.remove_block(&blocks[0]);
         piece_picker.remove_block(&blocks[1]);
 
         piece_picker.reinsert_piece(0);
-        let blocks = piece_picker.pick(&peer, 2);
+        let blocks = piece_picker.pick(&peer, &HashSet::new(), 2);
         assert_eq!(blocks.len(), 2);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_sequential_pick_first_and_last_pieces_first() {
+        let layout = mocks::generate_mock_layout(6, 1, 1);
+        let mut piece_picker = RarestPiecePicker::init(layout).with_mode(PickingMode::Sequential { window: 3 });
+        let mut peer = Bitfield::init(6);
+        (0..6).for_each(|piece_idx| peer.piece_acquired(piece_idx));
+
+        let picked: Vec<usize> = (0..6).map(|_| piece_picker.pick(&peer, &HashSet::new(), 1)[0].piece_idx).collect();
+
+        assert_eq!(picked, vec![0, 5, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_sequential_pick_falls_back_to_rarest_outside_window() {
+        let layout = mocks::generate_mock_layout(6, 1, 1);
+        let mut piece_picker = RarestPiecePicker::init(layout).with_mode(PickingMode::Sequential { window: 2 });
+        let mut peer = Bitfield::init(6);
+        peer.piece_acquired(3);
+        peer.piece_acquired(4);
+        piece_picker.increase_availability_for_piece(3);
+
+        let blocks = piece_picker.pick(&peer, &HashSet::new(), 1);
+
+        assert_eq!(blocks[0].piece_idx, 4);
+    }
+
+    #[test]
+    fn test_sequential_window_moves_back_on_reinsert() {
+        let layout = mocks::generate_mock_layout(4, 1, 1);
+        let mut piece_picker = RarestPiecePicker::init(layout).with_mode(PickingMode::Sequential { window: 1 });
+        let mut peer = Bitfield::init(4);
+        (0..4).for_each(|piece_idx| peer.piece_acquired(piece_idx));
+
+        let blocks: Vec<Block> = (0..3).map(|_| piece_picker.pick(&peer, &HashSet::new(), 1).remove(0)).collect();
+        piece_picker.remove_block(&blocks[2]);
+        piece_picker.reinsert_piece(1);
+        let blocks = piece_picker.pick(&peer, &HashSet::new(), 1);
+
+        assert_eq!(blocks[0].piece_idx, 1);
+    }
+
+    #[test]
+    fn test_skipped_pieces_never_picked() {
+        let layout = mocks::generate_mock_layout(2, 2, 2);
+        let mut piece_picker = RarestPiecePicker::init(layout);
+        let mut peer = Bitfield::init(2);
+        peer.piece_acquired(0);
+        piece_picker.set_piece_priorities(vec![Priority::Skip, Priority::Normal]).unwrap();
+
+        let blocks = piece_picker.pick(&peer, &HashSet::new(), 2);
+
+        assert!(blocks.is_empty());
+        assert!(!piece_picker.is_piece_wanted(0));
+        assert!(piece_picker.is_piece_wanted(1));
+    }
+
+    #[test]
+    fn test_higher_priority_picked_before_rarer_pieces() {
+        let layout = mocks::generate_mock_layout(3, 2, 2);
+        let mut piece_picker = RarestPiecePicker::init(layout);
+        let mut peer = Bitfield::init(3);
+        (0..3).for_each(|piece_idx| peer.piece_acquired(piece_idx));
+        piece_picker.increase_availability_for_pieces(vec![2, 2, 2]);
+        piece_picker.set_piece_priorities(vec![Priority::Low, Priority::Normal, Priority::High]).unwrap();
+
+        let picked: Vec<usize> = (0..3).map(|_| piece_picker.pick(&peer, &HashSet::new(), 2)[0].piece_idx).collect();
+
+        assert_eq!(picked, vec![2, 1, 0]);
+    }
+
+    #[test]
+    fn test_unskipped_piece_picked_again() {
+        let layout = mocks::generate_mock_layout(1, 2, 2);
+        let mut piece_picker = RarestPiecePicker::init(layout);
+        let mut peer = Bitfield::init(1);
+        peer.piece_acquired(0);
+
+        piece_picker.set_piece_priorities(vec![Priority::Skip]).unwrap();
+        piece_picker.set_piece_priorities(vec![Priority::Normal]).unwrap();
+
+        assert_eq!(piece_picker.pick(&peer, &HashSet::new(), 2).len(), 2);
+    }
+
+    #[test]
+    fn test_sequential_pick_ignores_skipped_pieces() {
+        let layout = mocks::generate_mock_layout(4, 1, 1);
+        let mut piece_picker = RarestPiecePicker::init(layout).with_mode(PickingMode::Sequential { window: 4 });
+        let mut peer = Bitfield::init(4);
+        (0..4).for_each(|piece_idx| peer.piece_acquired(piece_idx));
+        piece_picker.set_piece_priorities(vec![Priority::Skip, Priority::Normal, Priority::Normal, Priority::Skip]).unwrap();
+
+        let blocks = piece_picker.pick(&peer, &HashSet::new(), 1);
+
+        assert_eq!(blocks[0].piece_idx, 1);
+    }
+
+    #[test]
+    fn test_partially_picked_skipped_piece_not_picked() {
+        let layout = mocks::generate_mock_layout(2, 4, 4);
+        let mut piece_picker = RarestPiecePicker::init(layout);
+        let mut peer = Bitfield::init(2);
+        peer.piece_acquired(0);
+        piece_picker.pick(&peer, &HashSet::new(), 1);
+
+        piece_picker.set_piece_priorities(vec![Priority::Skip, Priority::Normal]).unwrap();
+
+        assert!(piece_picker.pick(&peer, &HashSet::new(), 1).is_empty());
+    }
+
+    #[test]
+    fn test_selection_complete_once_wanted_pieces_stored() {
+        let layout = mocks::generate_mock_layout(2, 1, 1);
+        let mut piece_picker = RarestPiecePicker::init(layout);
+        let mut peer = Bitfield::init(2);
+        peer.piece_acquired(1);
+        let block = piece_picker.pick(&peer, &HashSet::new(), 1).remove(0);
+        assert!(!piece_picker.is_selection_complete());
+
+        piece_picker.remove_block(&block);
+        assert!(!piece_picker.is_selection_complete());
+        piece_picker.set_piece_priorities(vec![Priority::Skip, Priority::Normal]).unwrap();
+
+        assert!(piece_picker.is_selection_complete());
+    }
+
+    #[test]
+    fn test_block_stored_without_being_picked_removed() {
+        let layout = mocks::generate_mock_layout(1, 1, 1);
+        let mut piece_picker = RarestPiecePicker::init(layout);
+        let mut peer = Bitfield::init(1);
+        peer.piece_acquired(0);
+
+        piece_picker.remove_block(&Block::new(0, 0, config::BLOCK_SIZE_BYTES));
+
+        assert!(piece_picker.pick(&peer, &HashSet::new(), 1).is_empty());
+        assert!(piece_picker.is_selection_complete());
+    }
+
+    #[test]
+    fn test_sequential_window_moves_past_skipped_pieces() {
+        let layout = mocks::generate_mock_layout(6, 1, 1);
+        let mut piece_picker = RarestPiecePicker::init(layout).with_mode(PickingMode::Sequential { window: 2 });
+        let mut peer = Bitfield::init(6);
+        (0..5).for_each(|piece_idx| peer.piece_acquired(piece_idx));
+        let mut priorities = vec![Priority::Skip; 3];
+        priorities.extend([Priority::Normal; 3]);
+        piece_picker.set_piece_priorities(priorities).unwrap();
+        // rarest first would pick piece 4
+        piece_picker.increase_availability_for_piece(3);
+
+        let blocks = piece_picker.pick(&peer, &HashSet::new(), 1);
+
+        assert_eq!(blocks[0].piece_idx, 3);
+    }
+
+    #[test]
+    fn test_sequential_pick_without_pieces_picks_nothing() {
+        let mut layout = mocks::generate_mock_layout(1, 1, 1);
+        layout.pieces = 0;
+        let mut piece_picker = RarestPiecePicker::init(layout).with_mode(PickingMode::Sequential { window: 4 });
+
+        assert!(piece_picker.pick(&Bitfield::init(0), &HashSet::new(), 1).is_empty());
+    }
+
+    #[test]
+    fn test_priorities_not_matching_piece_count_refused() {
+        let layout = mocks::generate_mock_layout(2, 2, 2);
+        let mut piece_picker = RarestPiecePicker::init(layout);
+
+        let result = piece_picker.set_piece_priorities(vec![Priority::Skip]);
+
+        assert_eq!(result, Err(PickerError::PrioritiesLengthMismatch { expected: 2, len: 1 }));
+        assert!(piece_picker.is_piece_wanted(0));
+    }
+}
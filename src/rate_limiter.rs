@@ -0,0 +1,160 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Direction {
+    Download,
+    Upload,
+}
+
+// Bandwidth caps in bytes per second; `None` means unlimited
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RateLimits {
+    pub download_bytes_per_sec: Option<u64>,
+    pub upload_bytes_per_sec: Option<u64>,
+}
+
+// Token bucket which can hold at most one second worth of tokens. Transfers reserve tokens upfront,
+// and the bucket goes into debt when there are not enough of them; the debt is the time the transfer has to wait.
+#[derive(Debug)]
+struct TokenBucket {
+    bytes_per_sec: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(bytes_per_sec: u64) -> Self {
+        return TokenBucket { bytes_per_sec, tokens: bytes_per_sec as f64, last_refill: Instant::now() };
+    }
+
+    fn reserve(&mut self, bytes: usize, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.bytes_per_sec as f64).min(self.bytes_per_sec as f64);
+        self.last_refill = now;
+        self.tokens -= bytes as f64;
+
+        return if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.bytes_per_sec.max(1) as f64)
+        };
+    }
+}
+
+// Limits the download and upload rates of whatever transfers share it
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    download: Mutex<Option<TokenBucket>>,
+    upload: Mutex<Option<TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        let limiter = RateLimiter::default();
+        limiter.set_limits(limits);
+        return limiter;
+    }
+
+    pub fn set_limits(&self, limits: RateLimits) {
+        *self.bucket(Direction::Download).lock().unwrap() = limits.download_bytes_per_sec.map(TokenBucket::new);
+        *self.bucket(Direction::Upload).lock().unwrap() = limits.upload_bytes_per_sec.map(TokenBucket::new);
+    }
+
+    pub fn limits(&self) -> RateLimits {
+        let limit = |direction| self.bucket(direction).lock().unwrap().as_ref().map(|bucket| bucket.bytes_per_sec);
+        return RateLimits {
+            download_bytes_per_sec: limit(Direction::Download),
+            upload_bytes_per_sec: limit(Direction::Upload),
+        };
+    }
+
+    // reserves bandwidth for `bytes` and returns how long to wait before transferring them
+    pub fn reserve(&self, direction: Direction, bytes: usize) -> Duration {
+        return self.bucket(direction).lock().unwrap()
+            .as_mut()
+            .map_or(Duration::ZERO, |bucket| bucket.reserve(bytes, Instant::now()));
+    }
+
+    fn bucket(&self, direction: Direction) -> &Mutex<Option<TokenBucket>> {
+        return match direction {
+            Direction::Download => &self.download,
+            Direction::Upload => &self.upload,
+        };
+    }
+}
+
+// Rate limiting for a single peer connection: the global limits apply, along with the optional per peer ones
+#[derive(Clone, Debug)]
+pub struct PeerRateLimiter {
+    global: Arc<RateLimiter>,
+    peer: Arc<RateLimiter>,
+}
+
+impl PeerRateLimiter {
+    pub fn new(global: Arc<RateLimiter>, peer_limits: RateLimits) -> Self {
+        return PeerRateLimiter { global, peer: Arc::new(RateLimiter::new(peer_limits)) };
+    }
+
+    pub fn reserve(&self, direction: Direction, bytes: usize) -> Duration {
+        let global_wait = self.global.reserve(direction, bytes);
+        let peer_wait = self.peer.reserve(direction, bytes);
+        return global_wait.max(peer_wait);
+    }
+
+    pub async fn acquire(&self, direction: Direction, bytes: usize) {
+        let wait = self.reserve(direction, bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use crate::rate_limiter::{Direction, PeerRateLimiter, RateLimiter, RateLimits, TokenBucket};
+
+    #[test]
+    fn test_bucket_allows_burst_of_one_second() {
+        let mut bucket = TokenBucket::new(1000);
+        let now = Instant::now();
+        assert_eq!(bucket.reserve(1000, now), Duration::ZERO);
+        assert_eq!(bucket.reserve(500, now), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_bucket_refills_over_time() {
+        let mut bucket = TokenBucket::new(1000);
+        let now = Instant::now();
+        bucket.reserve(1000, now);
+        assert_eq!(bucket.reserve(500, now + Duration::from_millis(500)), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_unlimited_direction_never_waits() {
+        let limiter = RateLimiter::new(RateLimits { download_bytes_per_sec: Some(1), upload_bytes_per_sec: None });
+        assert_eq!(limiter.reserve(Direction::Upload, 1_000_000), Duration::ZERO);
+        assert!(limiter.reserve(Direction::Download, 1_000_000) > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_peer_limit_applies_under_global_limit() {
+        let global = Arc::new(RateLimiter::new(RateLimits::default()));
+        let peer_limits = RateLimits { download_bytes_per_sec: Some(1000), upload_bytes_per_sec: None };
+        let limiter = PeerRateLimiter::new(global, peer_limits);
+
+        limiter.reserve(Direction::Download, 1000);
+        assert!(limiter.reserve(Direction::Download, 1000) >= Duration::from_millis(999));
+        assert_eq!(limiter.reserve(Direction::Upload, 1_000_000), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_set_limits() {
+        let limiter = RateLimiter::new(RateLimits::default());
+        let limits = RateLimits { download_bytes_per_sec: Some(10), upload_bytes_per_sec: Some(20) };
+        limiter.set_limits(limits);
+        assert_eq!(limiter.limits(), limits);
+    }
+}
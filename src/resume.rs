@@ -0,0 +1,80 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use serde_derive::{Deserialize, Serialize};
+use crate::coordinator::stats::{PieceState, TorrentStats};
+use crate::core_models::entities::Bitfield;
+
+const RESUME_FILE_EXTENSION: &str = "resume";
+
+// State of a torrent saved when its transfer stops, bencoded next to the output file
+#[derive(Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct ResumeData {
+    #[serde(with = "serde_bytes")]
+    pub info_hash: Vec<u8>,
+    // the stored pieces, laid out like the payload of a BITFIELD message
+    #[serde(with = "serde_bytes")]
+    pub pieces: Vec<u8>,
+    pub downloaded: u64,
+    pub uploaded: u64,
+}
+
+impl ResumeData {
+    pub fn from_stats(stats: &TorrentStats) -> Self {
+        let mut pieces = Bitfield::init(stats.pieces);
+        stats.piece_states.iter()
+            .enumerate()
+            .filter(|(_, state)| **state == PieceState::Stored)
+            .for_each(|(piece_idx, _)| pieces.piece_acquired(piece_idx));
+
+        return ResumeData {
+            info_hash: stats.info_hash.clone(),
+            pieces: pieces.content,
+            downloaded: stats.downloaded_bytes,
+            uploaded: stats.uploaded_bytes,
+        };
+    }
+
+    // Writes to a temporary file first, so that an interrupted save leaves the previous data intact
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let bytes = serde_bencode::to_bytes(self).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let temp_path = path.with_extension(format!("{}.tmp", RESUME_FILE_EXTENSION));
+        std::fs::write(&temp_path, bytes)?;
+        return std::fs::rename(temp_path, path);
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let bytes = std::fs::read(path)?;
+        return serde_bencode::from_bytes(&bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err));
+    }
+}
+
+pub fn resume_file_path(output_file_path: &str) -> PathBuf {
+    return PathBuf::from(format!("{}.{}", output_file_path, RESUME_FILE_EXTENSION));
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use crate::coordinator::stats::TorrentStats;
+    use crate::core_models::entities::Bitfield;
+    use crate::mocks::generate_mock_layout;
+    use crate::resume::{resume_file_path, ResumeData};
+
+    #[test]
+    fn test_resume_data_saved_and_loaded() {
+        let dir = TempDir::new().unwrap();
+        let path = resume_file_path(dir.path().join("torrent.bin").to_str().unwrap());
+        let mut stats = TorrentStats::new("torrent".to_string(), vec![1; 20], &generate_mock_layout(10, 2, 1));
+        stats.piece_stored(0);
+        stats.piece_stored(9);
+        stats.downloaded_bytes = 1024;
+
+        ResumeData::from_stats(&stats).save(&path).unwrap();
+        let resume_data = ResumeData::load(&path).unwrap();
+
+        let pieces = Bitfield::new(resume_data.pieces.clone());
+        assert!(pieces.has_piece(0) && pieces.has_piece(9) && !pieces.has_piece(1));
+        assert_eq!(resume_data, ResumeData { info_hash: vec![1; 20], pieces: vec![0b1000_0000, 0b0100_0000], downloaded: 1024, uploaded: 0 });
+        assert!(path.to_str().unwrap().ends_with("torrent.bin.resume"));
+    }
+}
@@ -0,0 +1,373 @@
+use hyper::{Body, Request, Response, StatusCode};
+use hyper::header::CONTENT_TYPE;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::coordinator::stats::{PeerStats, TorrentStats, TorrentStatus};
+use crate::rate_limiter::RateLimits;
+use crate::rpc::server::{json_response, status_response};
+use crate::rpc::transmission::session_id_conflict;
+use crate::session::{Session, SessionError};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Debug, Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    #[serde(default)]
+    id: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    id: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: &str) -> Self {
+        return RpcError { code, message: message.to_string() };
+    }
+}
+
+impl From<SessionError> for RpcError {
+    fn from(err: SessionError) -> Self {
+        return RpcError { code: SERVER_ERROR, message: format!("{:?}", err) };
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AddParams {
+    path: Option<String>,
+    magnet: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TorrentParams {
+    info_hash: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct LimitsParams {
+    download_bytes_per_sec: Option<u64>,
+    upload_bytes_per_sec: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct TorrentInfo {
+    info_hash: String,
+    name: String,
+    status: String,
+    progress: f64,
+    total_length: usize,
+    downloaded_bytes: u64,
+    uploaded_bytes: u64,
+    download_rate: f64,
+    upload_rate: f64,
+    connected_peers: usize,
+}
+
+impl TorrentInfo {
+    fn from_stats(mut stats: TorrentStats) -> Self {
+        return TorrentInfo {
+            info_hash: encode_info_hash(&stats.info_hash),
+            status: status_name(&stats.status),
+            progress: stats.progress(),
+            total_length: stats.total_length,
+            downloaded_bytes: stats.downloaded_bytes,
+            uploaded_bytes: stats.uploaded_bytes,
+            download_rate: stats.download_rate.bytes_per_sec(),
+            upload_rate: stats.upload_rate.bytes_per_sec(),
+            connected_peers: stats.connected_peers(),
+            name: stats.name,
+        };
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct PeerInfo {
+    address: String,
+    source: String,
+    client: Option<String>,
+    downloaded_bytes: u64,
+    uploaded_bytes: u64,
+    download_rate: f64,
+    upload_rate: f64,
+}
+
+impl PeerInfo {
+    fn from_stats(mut stats: PeerStats) -> Self {
+        return PeerInfo {
+            address: format!("{}:{}", stats.peer.ip, stats.peer.port),
+            source: format!("{:?}", stats.peer.source),
+            client: stats.status.client.clone(),
+            downloaded_bytes: stats.downloaded_bytes,
+            uploaded_bytes: stats.uploaded_bytes,
+            download_rate: stats.download_rate.bytes_per_sec(),
+            upload_rate: stats.upload_rate.bytes_per_sec(),
+        };
+    }
+}
+
+// Serves a JSON-RPC request over HTTP; requiring a JSON content type and the session id keeps
+// plain cross-origin form posts from reaching the API
+pub async fn handle_request(request: Request<Body>, session: &Session, session_id: &str) -> Response<Body> {
+    let is_json = request.headers().get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"));
+    if !is_json {
+        return status_response(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+    if let Some(response) = session_id_conflict(&request, session_id) {
+        return response;
+    }
+
+    return match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => json_response(handle(&body, session).await),
+        Err(_) => status_response(StatusCode::BAD_REQUEST),
+    };
+}
+
+// Handles a JSON-RPC 2.0 request body and returns the serialized response
+pub async fn handle(body: &[u8], session: &Session) -> Vec<u8> {
+    let response = match serde_json::from_slice::<Value>(body) {
+        Err(_) => error_response(Value::Null, RpcError::new(PARSE_ERROR, "Parse error")),
+        Ok(value) => match serde_json::from_value::<RpcRequest>(value) {
+            Ok(request) if request.jsonrpc == "2.0" => {
+                match call(&request.method, request.params, session).await {
+                    Ok(result) => RpcResponse { jsonrpc: "2.0", result: Some(result), error: None, id: request.id },
+                    Err(err) => error_response(request.id, err),
+                }
+            }
+            _ => error_response(Value::Null, RpcError::new(INVALID_REQUEST, "Invalid request")),
+        },
+    };
+
+    return serde_json::to_vec(&response).unwrap();
+}
+
+async fn call(method: &str, params: Value, session: &Session) -> Result<Value, RpcError> {
+    return match method {
+        "torrent.add" => {
+            let params: AddParams = parse_params(params)?;
+            let torrent = match (params.path, params.magnet) {
+                (Some(path), _) => session.add_torrent_file(&path).await?,
+                (None, Some(magnet)) => session.add_magnet(&magnet).await?,
+                (None, None) => return Err(RpcError::new(INVALID_PARAMS, "Missing path or magnet")),
+            };
+            Ok(json!({ "info_hash": encode_info_hash(torrent.info_hash()) }))
+        }
+        "torrent.remove" => {
+            session.remove_torrent(&torrent_param(params)?).await?;
+            Ok(Value::Null)
+        }
+        "torrent.pause" => {
+            session.pause_torrent(&torrent_param(params)?).await?;
+            Ok(Value::Null)
+        }
+        "torrent.resume" => {
+            session.resume_torrent(&torrent_param(params)?).await?;
+            Ok(Value::Null)
+        }
+        "torrent.list" => {
+            let torrents: Vec<TorrentInfo> = session.torrents().await.into_iter()
+                .map(TorrentInfo::from_stats)
+                .collect();
+            Ok(serde_json::to_value(torrents).unwrap())
+        }
+        "torrent.peers" => {
+            let stats = session.torrent(&torrent_param(params)?).await.ok_or(SessionError::TorrentNotFound)?;
+            let peers: Vec<PeerInfo> = stats.peers.into_values()
+                .filter(|peer| peer.is_connected)
+                .map(PeerInfo::from_stats)
+                .collect();
+            Ok(serde_json::to_value(peers).unwrap())
+        }
+        "session.set_limits" => {
+            let params: LimitsParams = parse_params(params)?;
+            session.set_rate_limits(RateLimits {
+                download_bytes_per_sec: params.download_bytes_per_sec,
+                upload_bytes_per_sec: params.upload_bytes_per_sec,
+            });
+            Ok(Value::Null)
+        }
+        _ => Err(RpcError::new(METHOD_NOT_FOUND, "Method not found")),
+    };
+}
+
+fn parse_params<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, RpcError> {
+    return serde_json::from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, &err.to_string()));
+}
+
+fn torrent_param(params: Value) -> Result<Vec<u8>, RpcError> {
+    let params: TorrentParams = parse_params(params)?;
+    return decode_info_hash(&params.info_hash).ok_or(RpcError::new(INVALID_PARAMS, "Invalid info hash"));
+}
+
+fn error_response(id: Value, error: RpcError) -> RpcResponse {
+    return RpcResponse { jsonrpc: "2.0", result: None, error: Some(error), id };
+}
+
+pub(crate) fn status_name(status: &TorrentStatus) -> String {
+    return match status {
+        TorrentStatus::Failed(_) => "failed".to_string(),
+        status => format!("{:?}", status).to_lowercase(),
+    };
+}
+
+pub(crate) fn encode_info_hash(info_hash: &[u8]) -> String {
+    return info_hash.iter().map(|byte| format!("{:02x}", byte)).collect();
+}
+
+pub(crate) fn decode_info_hash(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() != 40 || !hex.is_ascii() {
+        return None;
+    }
+    return (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Body, Request, StatusCode};
+    use hyper::header::CONTENT_TYPE;
+    use serde_json::{json, Value};
+    use crate::config::Config;
+    use crate::rate_limiter::RateLimits;
+    use crate::rpc::json_rpc::{decode_info_hash, encode_info_hash, handle, handle_request};
+    use crate::rpc::transmission::SESSION_ID_HEADER;
+    use crate::session::Session;
+
+    const SESSION_ID: &str = "abc";
+
+    async fn start_session() -> Session {
+        return Session::start(Config { listening_port: 0, ..Config::default() }).await.unwrap();
+    }
+
+    async fn request(session: &Session, body: &str) -> Value {
+        return serde_json::from_slice(&handle(body.as_bytes(), session).await).unwrap();
+    }
+
+    async fn http_request(session: &Session, content_type: &str, session_id: Option<&str>) -> (StatusCode, Option<String>) {
+        let mut builder = Request::post("/jsonrpc").header(CONTENT_TYPE, content_type);
+        if let Some(session_id) = session_id {
+            builder = builder.header(SESSION_ID_HEADER, session_id);
+        }
+        let body = Body::from(r#"{"jsonrpc":"2.0","method":"torrent.list","id":1}"#);
+        let response = handle_request(builder.body(body).unwrap(), session, SESSION_ID).await;
+        let header = response.headers().get(SESSION_ID_HEADER).map(|value| value.to_str().unwrap().to_string());
+        return (response.status(), header);
+    }
+
+    #[tokio::test]
+    async fn test_request_without_json_content_type_refused() {
+        let session = start_session().await;
+
+        let (status, _) = http_request(&session, "text/plain", Some(SESSION_ID)).await;
+
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[tokio::test]
+    async fn test_request_without_session_id_answered_with_session_id() {
+        let session = start_session().await;
+
+        let (missing, header) = http_request(&session, "application/json", None).await;
+        let (wrong, _) = http_request(&session, "application/json", Some("xyz")).await;
+        let (valid, _) = http_request(&session, "application/json; charset=utf-8", Some(SESSION_ID)).await;
+
+        assert_eq!(missing, StatusCode::CONFLICT);
+        assert_eq!(header.as_deref(), Some(SESSION_ID));
+        assert_eq!(wrong, StatusCode::CONFLICT);
+        assert_eq!(valid, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_body_is_parse_error() {
+        let session = start_session().await;
+
+        let response = request(&session, "{not json").await;
+
+        assert_eq!(response["error"]["code"], json!(-32700));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_method_not_found() {
+        let session = start_session().await;
+
+        let response = request(&session, r#"{"jsonrpc":"2.0","method":"torrent.seed","id":3}"#).await;
+
+        assert_eq!(response["error"]["code"], json!(-32601));
+        assert_eq!(response["id"], json!(3));
+    }
+
+    #[tokio::test]
+    async fn test_list_of_empty_session() {
+        let session = start_session().await;
+
+        let response = request(&session, r#"{"jsonrpc":"2.0","method":"torrent.list","id":1}"#).await;
+
+        assert_eq!(response["result"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn test_magnet_link_without_peers_not_added() {
+        let session = start_session().await;
+        let invalid = json!({"jsonrpc": "2.0", "method": "torrent.add", "params": {"magnet": "magnet:?xt=urn:btih:00"}, "id": 1});
+        let trackerless = json!({"jsonrpc": "2.0", "method": "torrent.add", "params": {"magnet": format!("magnet:?xt=urn:btih:{}", "ab".repeat(20))}, "id": 2});
+
+        let invalid = request(&session, &invalid.to_string()).await;
+        let trackerless = request(&session, &trackerless.to_string()).await;
+
+        assert_eq!(invalid["error"]["code"], json!(-32000));
+        assert!(invalid["error"]["message"].as_str().unwrap().starts_with("InvalidTorrent"));
+        assert_eq!(trackerless["error"]["message"], json!("MetadataNotFetched(\"NoTrackers\")"));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_torrent_paused() {
+        let session = start_session().await;
+        let body = json!({"jsonrpc": "2.0", "method": "torrent.pause", "params": {"info_hash": encode_info_hash(&[1; 20])}, "id": 1});
+
+        let response = request(&session, &body.to_string()).await;
+
+        assert_eq!(response["error"]["message"], json!("TorrentNotFound"));
+    }
+
+    #[tokio::test]
+    async fn test_limits_set() {
+        let session = start_session().await;
+
+        let response = request(&session, r#"{"jsonrpc":"2.0","method":"session.set_limits","params":{"download_bytes_per_sec":1024},"id":1}"#).await;
+
+        assert_eq!(response["result"], Value::Null);
+        assert_eq!(session.rate_limits(), RateLimits { download_bytes_per_sec: Some(1024), upload_bytes_per_sec: None });
+    }
+
+    #[test]
+    fn test_info_hash_hex_round_trip() {
+        let info_hash: Vec<u8> = (0..20).collect();
+
+        assert_eq!(decode_info_hash(&encode_info_hash(&info_hash)), Some(info_hash));
+        assert_eq!(decode_info_hash("zz"), None);
+    }
+}
@@ -0,0 +1,54 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use crate::rpc::{json_rpc, transmission};
+use crate::session::Session;
+
+pub const JSON_RPC_PATH: &str = "/jsonrpc";
+pub const TRANSMISSION_RPC_PATH: &str = "/transmission/rpc";
+
+// Serves the control API of the session over HTTP
+pub async fn serve(addr: SocketAddr, session: Arc<Session>) -> Result<(), hyper::Error> {
+    let session_id = Arc::new(transmission::generate_session_id());
+    let make_service = make_service_fn(move |_conn| {
+        let session = session.clone();
+        let session_id = session_id.clone();
+        async move {
+            return Ok::<_, Infallible>(service_fn(move |request| {
+                handle_request(request, session.clone(), session_id.clone())
+            }));
+        }
+    });
+
+    return Server::bind(&addr).serve(make_service).await;
+}
+
+async fn handle_request(request: Request<Body>,
+                        session: Arc<Session>,
+                        session_id: Arc<String>) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::POST, JSON_RPC_PATH) => json_rpc::handle_request(request, &session, &session_id).await,
+        (&Method::POST, TRANSMISSION_RPC_PATH) => {
+            transmission::handle_request(request, &session, &session_id).await
+        }
+        (_, JSON_RPC_PATH | TRANSMISSION_RPC_PATH) => status_response(StatusCode::METHOD_NOT_ALLOWED),
+        _ => status_response(StatusCode::NOT_FOUND),
+    };
+
+    return Ok(response);
+}
+
+pub(crate) fn json_response(body: Vec<u8>) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    response.headers_mut().insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    return response;
+}
+
+pub(crate) fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    return response;
+}
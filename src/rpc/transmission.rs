@@ -0,0 +1,420 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hyper::{Body, Request, Response, StatusCode};
+use hyper::header::HeaderValue;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use crate::coordinator::stats::{TorrentStats, TorrentStatus};
+use crate::rpc::json_rpc::{decode_info_hash, encode_info_hash};
+use crate::rpc::server::{json_response, status_response};
+use crate::session::{Session, SessionError};
+
+pub const SESSION_ID_HEADER: &str = "X-Transmission-Session-Id";
+const RPC_VERSION: u32 = 17;
+const RPC_VERSION_MINIMUM: u32 = 14;
+// transmission reports speeds and speed limits in kB/s
+const SPEED_UNIT_BYTES: u64 = 1000;
+
+// torrent status codes of the transmission protocol
+const STATUS_STOPPED: u8 = 0;
+const STATUS_DOWNLOAD_WAIT: u8 = 3;
+const STATUS_DOWNLOAD: u8 = 4;
+const STATUS_SEED: u8 = 6;
+
+// error codes of a torrent, 3 is a local error
+const ERROR_NONE: u8 = 0;
+const ERROR_LOCAL: u8 = 3;
+
+#[derive(Debug, Deserialize)]
+struct TransmissionRequest {
+    method: String,
+    #[serde(default)]
+    arguments: Value,
+    tag: Option<Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct TransmissionResponse {
+    result: String,
+    arguments: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tag: Option<Value>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct AddArguments {
+    filename: Option<String>,
+    metainfo: Option<String>,
+    #[serde(default)]
+    paused: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct GetArguments {
+    #[serde(default)]
+    ids: Option<Value>,
+    #[serde(default)]
+    fields: Vec<String>,
+}
+
+pub fn generate_session_id() -> String {
+    return rand::thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(48)
+        .map(char::from)
+        .collect();
+}
+
+// Serves a request of the transmission protocol; requests which do not carry the current session id
+// are answered with 409 and the id, which the client is expected to repeat the request with
+pub async fn handle_request(request: Request<Body>, session: &Session, session_id: &str) -> Response<Body> {
+    if let Some(response) = session_id_conflict(&request, session_id) {
+        return response;
+    }
+
+    let body = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => body,
+        Err(_) => return status_response(StatusCode::BAD_REQUEST),
+    };
+    let request: TransmissionRequest = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(_) => return status_response(StatusCode::BAD_REQUEST),
+    };
+    let (result, arguments) = match call(&request.method, request.arguments, session).await {
+        Ok(arguments) => ("success".to_string(), arguments),
+        Err(err) => (err, json!({})),
+    };
+
+    let response = TransmissionResponse { result, arguments, tag: request.tag };
+    return json_response(serde_json::to_vec(&response).unwrap());
+}
+
+// Answers requests lacking the current session id with 409 and the id, so that a page of another
+// origin which cannot read the response is unable to issue requests on behalf of the user
+pub(crate) fn session_id_conflict(request: &Request<Body>, session_id: &str) -> Option<Response<Body>> {
+    let has_session_id = request.headers().get(SESSION_ID_HEADER)
+        .is_some_and(|value| value.as_bytes() == session_id.as_bytes());
+    if has_session_id {
+        return None;
+    }
+
+    let mut response = status_response(StatusCode::CONFLICT);
+    response.headers_mut().insert(SESSION_ID_HEADER, HeaderValue::from_str(session_id).unwrap());
+    return Some(response);
+}
+
+async fn call(method: &str, arguments: Value, session: &Session) -> Result<Value, String> {
+    return match method {
+        "torrent-add" => add_torrent(parse_arguments(arguments)?, session).await,
+        "torrent-get" => {
+            let arguments: GetArguments = parse_arguments(arguments)?;
+            let torrents: Vec<Value> = select_torrents(session, arguments.ids.as_ref()).await?.into_iter()
+                .map(|stats| torrent_fields(stats, &arguments.fields))
+                .collect();
+            Ok(json!({ "torrents": torrents }))
+        }
+        "torrent-start" | "torrent-start-now" | "torrent-stop" | "torrent-remove" => {
+            let arguments: GetArguments = parse_arguments(arguments)?;
+            for stats in select_torrents(session, arguments.ids.as_ref()).await? {
+                // the downloaded data is kept on removal, `delete-local-data` is not supported
+                let result = match method {
+                    "torrent-stop" => session.pause_torrent(&stats.info_hash).await,
+                    "torrent-remove" => session.remove_torrent(&stats.info_hash).await,
+                    _ => session.resume_torrent(&stats.info_hash).await,
+                };
+                result.map_err(|err| format!("{:?}", err))?;
+            }
+            Ok(json!({}))
+        }
+        "session-get" => Ok(session_fields(session)),
+        "session-stats" => Ok(session_stats(session).await),
+        _ => Err("method name not recognized".to_string()),
+    };
+}
+
+async fn add_torrent(arguments: AddArguments, session: &Session) -> Result<Value, String> {
+    let result = if let Some(metainfo) = arguments.metainfo {
+        let bytes = BASE64.decode(metainfo.trim()).map_err(|_| "invalid or corrupt torrent file".to_string())?;
+        session.add_torrent_bytes(&bytes).await
+    } else if let Some(filename) = arguments.filename {
+        if filename.starts_with("magnet:") {
+            session.add_magnet(&filename).await
+        } else if filename.starts_with("http://") || filename.starts_with("https://") {
+            let bytes = download_torrent(&filename).await?;
+            session.add_torrent_bytes(&bytes).await
+        } else {
+            session.add_torrent_file(&filename).await
+        }
+    } else {
+        return Err("no filename or metainfo specified".to_string());
+    };
+
+    let torrent = match result {
+        Ok(torrent) => torrent,
+        Err(SessionError::InvalidTorrent(_)) => return Err("invalid or corrupt torrent file".to_string()),
+        Err(err) => return Err(format!("{:?}", err)),
+    };
+    if arguments.paused {
+        let _ = torrent.pause().await;
+    }
+    let stats = torrent.status();
+    return Ok(json!({
+        "torrent-added": { "id": stats.id, "name": stats.name, "hashString": encode_info_hash(&stats.info_hash) }
+    }));
+}
+
+async fn download_torrent(url: &str) -> Result<Vec<u8>, String> {
+    let response = reqwest::get(url).await.map_err(|err| err.to_string())?;
+    let bytes = response.error_for_status().map_err(|err| err.to_string())?
+        .bytes().await.map_err(|err| err.to_string())?;
+    return Ok(bytes.to_vec());
+}
+
+fn parse_arguments<T: serde::de::DeserializeOwned + Default>(arguments: Value) -> Result<T, String> {
+    if arguments.is_null() {
+        return Ok(T::default());
+    }
+    return serde_json::from_value(arguments).map_err(|err| err.to_string());
+}
+
+// Selects the torrents given by `ids`, which is either a single id, a list of ids and hash strings,
+// or "recently-active"; all the torrents are selected when there are no ids
+async fn select_torrents(session: &Session, ids: Option<&Value>) -> Result<Vec<TorrentStats>, String> {
+    let mut torrents = session.torrents().await;
+    torrents.sort_by_key(|stats| stats.id);
+    let ids: Vec<Value> = match ids {
+        None => return Ok(torrents),
+        Some(Value::String(ids)) if ids == "recently-active" => return Ok(torrents),
+        Some(Value::Array(ids)) => ids.clone(),
+        Some(id) => vec![id.clone()],
+    };
+
+    let mut selected = Vec::new();
+    for id in ids {
+        let matches: Box<dyn Fn(&TorrentStats) -> bool> = match &id {
+            Value::Number(id) => {
+                let id = id.as_u64().ok_or("invalid torrent id".to_string())? as usize;
+                Box::new(move |stats| stats.id == id)
+            }
+            Value::String(hash) => {
+                let info_hash = decode_info_hash(hash).ok_or("invalid torrent hash".to_string())?;
+                Box::new(move |stats| stats.info_hash == info_hash)
+            }
+            _ => return Err("invalid torrent id".to_string()),
+        };
+        selected.extend(torrents.iter().filter(|stats| matches(stats)).cloned());
+    }
+    return Ok(selected);
+}
+
+fn torrent_fields(mut stats: TorrentStats, fields: &[String]) -> Value {
+    let left_until_done = stats.remaining_bytes();
+    let download_rate = stats.download_rate.bytes_per_sec() as u64;
+    let upload_rate = stats.upload_rate.bytes_per_sec() as u64;
+    let (status, error, error_string) = match &stats.status {
+        TorrentStatus::Starting => (STATUS_DOWNLOAD_WAIT, ERROR_NONE, String::new()),
+        TorrentStatus::Downloading => (STATUS_DOWNLOAD, ERROR_NONE, String::new()),
+        TorrentStatus::Seeding => (STATUS_SEED, ERROR_NONE, String::new()),
+        TorrentStatus::Paused | TorrentStatus::Completed | TorrentStatus::Stopped => (STATUS_STOPPED, ERROR_NONE, String::new()),
+        TorrentStatus::Failed(reason) => (STATUS_STOPPED, ERROR_LOCAL, reason.clone()),
+    };
+    let eta: i64 = stats.eta().map_or(-1, |eta| eta.as_secs() as i64);
+    let upload_ratio = if stats.downloaded_bytes == 0 { -1.0 } else { stats.uploaded_bytes as f64 / stats.downloaded_bytes as f64 };
+
+    let all_fields = json!({
+        "id": stats.id,
+        "name": stats.name,
+        "hashString": encode_info_hash(&stats.info_hash),
+        "status": status,
+        "error": error,
+        "errorString": error_string,
+        "percentDone": stats.progress(),
+        "totalSize": stats.total_length,
+        "sizeWhenDone": stats.total_length,
+        "leftUntilDone": left_until_done,
+        "haveValid": stats.total_length - left_until_done,
+        "isFinished": stats.status == TorrentStatus::Completed,
+        "rateDownload": download_rate,
+        "rateUpload": upload_rate,
+        "downloadedEver": stats.downloaded_bytes,
+        "uploadedEver": stats.uploaded_bytes,
+        "uploadRatio": upload_ratio,
+        "eta": eta,
+        "peersConnected": stats.connected_peers(),
+    });
+    let Value::Object(all_fields) = all_fields else { unreachable!() };
+    let selected: Map<String, Value> = all_fields.into_iter()
+        .filter(|(name, _)| fields.contains(name))
+        .collect();
+    return Value::Object(selected);
+}
+
+fn session_fields(session: &Session) -> Value {
+    let config = session.config();
+    let limits = session.rate_limits();
+    return json!({
+        "version": format!("{} (rust_torrent_client)", env!("CARGO_PKG_VERSION")),
+        "rpc-version": RPC_VERSION,
+        "rpc-version-minimum": RPC_VERSION_MINIMUM,
+        "session-id": Value::Null,
+        "peer-port": config.listening_port,
+        "peer-limit-global": config.max_connections,
+        "speed-limit-down": limits.download_bytes_per_sec.unwrap_or(0) / SPEED_UNIT_BYTES,
+        "speed-limit-down-enabled": limits.download_bytes_per_sec.is_some(),
+        "speed-limit-up": limits.upload_bytes_per_sec.unwrap_or(0) / SPEED_UNIT_BYTES,
+        "speed-limit-up-enabled": limits.upload_bytes_per_sec.is_some(),
+        "units": {
+            "speed-units": ["kB/s", "MB/s", "GB/s", "TB/s"],
+            "speed-bytes": SPEED_UNIT_BYTES,
+            "size-units": ["kB", "MB", "GB", "TB"],
+            "size-bytes": 1000,
+            "memory-units": ["KiB", "MiB", "GiB", "TiB"],
+            "memory-bytes": 1024,
+        },
+    });
+}
+
+async fn session_stats(session: &Session) -> Value {
+    let torrents = session.torrents().await;
+    let mut download_speed = 0.0;
+    let mut upload_speed = 0.0;
+    let mut downloaded_bytes = 0;
+    let mut uploaded_bytes = 0;
+    let mut active_torrents = 0;
+    for mut stats in torrents.iter().cloned() {
+        download_speed += stats.download_rate.bytes_per_sec();
+        upload_speed += stats.upload_rate.bytes_per_sec();
+        downloaded_bytes += stats.downloaded_bytes;
+        uploaded_bytes += stats.uploaded_bytes;
+        if matches!(stats.status, TorrentStatus::Starting | TorrentStatus::Downloading | TorrentStatus::Seeding) {
+            active_torrents += 1;
+        }
+    }
+    // nothing is persisted between runs, so the cumulative stats are the ones of this session
+    let current_stats = json!({
+        "uploadedBytes": uploaded_bytes,
+        "downloadedBytes": downloaded_bytes,
+        "filesAdded": torrents.len(),
+        "sessionCount": 1,
+        "secondsActive": session.uptime().as_secs(),
+    });
+
+    return json!({
+        "activeTorrentCount": active_torrents,
+        "pausedTorrentCount": torrents.len() - active_torrents,
+        "torrentCount": torrents.len(),
+        "downloadSpeed": download_speed as u64,
+        "uploadSpeed": upload_speed as u64,
+        "cumulative-stats": current_stats.clone(),
+        "current-stats": current_stats,
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Body, Request, StatusCode};
+    use serde_json::{json, Value};
+    use crate::config::Config;
+    use crate::coordinator::stats::{TorrentStats, TorrentStatus};
+    use crate::mocks::MockTorrent;
+    use crate::rpc::transmission::{handle_request, torrent_fields, SESSION_ID_HEADER};
+    use crate::session::Session;
+
+    const SESSION_ID: &str = "abc";
+
+    async fn start_session() -> Session {
+        return Session::start(Config { listening_port: 0, ..Config::default() }).await.unwrap();
+    }
+
+    async fn request(session: &Session, session_id: Option<&str>, body: Value) -> (StatusCode, Option<String>, Value) {
+        let mut builder = Request::post("/transmission/rpc");
+        if let Some(session_id) = session_id {
+            builder = builder.header(SESSION_ID_HEADER, session_id);
+        }
+        let response = handle_request(builder.body(Body::from(body.to_string())).unwrap(), session, SESSION_ID).await;
+        let status = response.status();
+        let header = response.headers().get(SESSION_ID_HEADER).map(|value| value.to_str().unwrap().to_string());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = if body.is_empty() { Value::Null } else { serde_json::from_slice(&body).unwrap() };
+        return (status, header, body);
+    }
+
+    #[tokio::test]
+    async fn test_request_without_session_id_rejected() {
+        let session = start_session().await;
+
+        let (status, header, _) = request(&session, None, json!({"method": "session-get"})).await;
+
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(header.as_deref(), Some(SESSION_ID));
+    }
+
+    #[tokio::test]
+    async fn test_request_with_stale_session_id_rejected() {
+        let session = start_session().await;
+
+        let (status, _, _) = request(&session, Some("stale"), json!({"method": "session-get"})).await;
+
+        assert_eq!(status, StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn test_session_get() {
+        let session = start_session().await;
+
+        let (status, _, body) = request(&session, Some(SESSION_ID), json!({"method": "session-get", "tag": 7})).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["result"], json!("success"));
+        assert_eq!(body["tag"], json!(7));
+        assert_eq!(body["arguments"]["rpc-version"], json!(17));
+        assert_eq!(body["arguments"]["speed-limit-down-enabled"], json!(false));
+    }
+
+    #[tokio::test]
+    async fn test_session_stats_of_empty_session() {
+        let session = start_session().await;
+
+        let (_, _, body) = request(&session, Some(SESSION_ID), json!({"method": "session-stats"})).await;
+
+        assert_eq!(body["arguments"]["torrentCount"], json!(0));
+        assert_eq!(body["arguments"]["current-stats"]["sessionCount"], json!(1));
+    }
+
+    #[tokio::test]
+    async fn test_magnet_link_without_peers_not_added() {
+        let session = start_session().await;
+        let invalid = json!({"method": "torrent-add", "arguments": {"filename": "magnet:?xt=urn:btih:00"}});
+        let trackerless = json!({"method": "torrent-add", "arguments": {"filename": format!("magnet:?xt=urn:btih:{}", "ab".repeat(20))}});
+
+        let (_, _, invalid) = request(&session, Some(SESSION_ID), invalid).await;
+        let (_, _, trackerless) = request(&session, Some(SESSION_ID), trackerless).await;
+
+        assert_eq!(invalid["result"], json!("invalid or corrupt torrent file"));
+        assert_eq!(trackerless["result"], json!("MetadataNotFetched(\"NoTrackers\")"));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_method() {
+        let session = start_session().await;
+
+        let (_, _, body) = request(&session, Some(SESSION_ID), json!({"method": "blocklist-update"})).await;
+
+        assert_eq!(body["result"], json!("method name not recognized"));
+    }
+
+    #[test]
+    fn test_only_requested_fields_returned() {
+        let layout = MockTorrent::generate(4, 2, 2).layout;
+        let mut stats = TorrentStats::new("mock".to_string(), vec![1; 20], &layout);
+        stats.id = 3;
+        stats.stored_pieces = 4;
+        stats.status = TorrentStatus::Completed;
+
+        let fields = torrent_fields(stats, &["id".to_string(), "percentDone".to_string(), "leftUntilDone".to_string(), "isFinished".to_string()]);
+
+        assert_eq!(fields, json!({"id": 3, "percentDone": 1.0, "leftUntilDone": 0, "isFinished": true}));
+    }
+}
@@ -0,0 +1,133 @@
+use std::ops::Range;
+use std::str::FromStr;
+use crate::core_models::entities::{Info, TorrentLayout};
+
+// Download priority of a file or of a byte range of the torrent
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Priority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl FromStr for Priority {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        return match value.to_lowercase().as_str() {
+            "skip" => Ok(Priority::Skip),
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => Err(format!("unknown priority {}", value)),
+        };
+    }
+}
+
+// Byte ranges occupied by each file, in the order they appear in the metainfo
+pub fn file_byte_ranges(info: &Info) -> Vec<Range<usize>> {
+    return match &info.files {
+        None => vec![Range { start: 0, end: info.length.unwrap_or(0) as usize }],
+        Some(files) => {
+            let mut start = 0;
+            files.iter()
+                .map(|file| {
+                    let range = start..(start + file.length as usize);
+                    start = range.end;
+                    range
+                })
+                .collect()
+        }
+    };
+}
+
+// Computes the priority of every piece, given priorities for byte ranges of the torrent.
+// Ranges set later override the earlier ones, bytes not covered by any range have `Normal` priority
+// and each piece gets the highest priority of the data it covers, so a piece is only skipped
+// when all of its data is skipped.
+pub fn piece_priorities(layout: &TorrentLayout, ranges: &[(Range<usize>, Priority)]) -> Vec<Priority> {
+    return (0..layout.pieces)
+        .map(|piece_idx| {
+            let piece_start = piece_idx * layout.head_pieces_length;
+            let piece = piece_start..(piece_start + layout.piece_length(piece_idx));
+            segments(&piece, ranges).into_iter()
+                .map(|segment| priority_of_segment(&segment, ranges))
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+}
+
+// splits `piece` at every range boundary that falls inside it
+fn segments(piece: &Range<usize>, ranges: &[(Range<usize>, Priority)]) -> Vec<Range<usize>> {
+    let mut boundaries: Vec<usize> = ranges.iter()
+        .flat_map(|(range, _)| [range.start, range.end])
+        .filter(|boundary| piece.contains(boundary))
+        .chain([piece.start, piece.end])
+        .collect();
+    boundaries.sort();
+    boundaries.dedup();
+
+    return boundaries.windows(2).map(|pair| pair[0]..pair[1]).collect();
+}
+
+fn priority_of_segment(segment: &Range<usize>, ranges: &[(Range<usize>, Priority)]) -> Priority {
+    return ranges.iter()
+        .rev()
+        .find(|(range, _)| range.start <= segment.start && segment.end <= range.end)
+        .map_or(Priority::Normal, |(_, priority)| *priority);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::mocks;
+    use crate::selection::{piece_priorities, Priority};
+
+    #[test]
+    fn test_uncovered_pieces_have_normal_priority() {
+        let layout = mocks::generate_mock_layout(3, 1, 1);
+        let priorities = piece_priorities(&layout, &[]);
+        assert_eq!(priorities, vec![Priority::Normal; 3]);
+    }
+
+    #[test]
+    fn test_piece_skipped_only_when_all_data_skipped() {
+        let layout = mocks::generate_mock_layout(3, 2, 2);
+        let piece_len = layout.head_pieces_length;
+        // skip the first piece and a half
+        let ranges = vec![(0..(piece_len + piece_len / 2), Priority::Skip)];
+
+        let priorities = piece_priorities(&layout, &ranges);
+
+        assert_eq!(priorities, vec![Priority::Skip, Priority::Normal, Priority::Normal]);
+    }
+
+    #[test]
+    fn test_piece_gets_highest_priority_of_its_data() {
+        let layout = mocks::generate_mock_layout(3, 2, 2);
+        let piece_len = layout.head_pieces_length;
+        let ranges = vec![
+            (0..layout.output_file_length, Priority::Low),
+            ((piece_len + 1)..(piece_len + 2), Priority::High),
+        ];
+
+        let priorities = piece_priorities(&layout, &ranges);
+
+        assert_eq!(priorities, vec![Priority::Low, Priority::High, Priority::Low]);
+    }
+
+    #[test]
+    fn test_later_ranges_override_earlier_ones() {
+        let layout = mocks::generate_mock_layout(2, 1, 1);
+        let ranges = vec![
+            (0..layout.output_file_length, Priority::High),
+            (0..layout.output_file_length, Priority::Skip),
+        ];
+
+        let priorities = piece_priorities(&layout, &ranges);
+
+        assert_eq!(priorities, vec![Priority::Skip, Priority::Skip]);
+    }
+}
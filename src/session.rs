@@ -0,0 +1,786 @@
+//! Runs torrents in-process.
+//!
+//! ```no_run
+//! # async fn download() -> Result<(), rust_torrent_client::session::SessionError> {
+//! use rust_torrent_client::session::Session;
+//!
+//! let session = Session::builder().with_download_dir("/tmp/artifacts").start().await?;
+//! let torrent = session.add_torrent_file("artifact.torrent").await?;
+//! torrent.wait_for_completion().await?;
+//! # return Ok(());
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fs::OpenOptions;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use log::{info, warn};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch, Mutex, RwLock};
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+use crate::alerts::{Alert, AlertCategory, AlertStream};
+use crate::config::Config;
+use crate::{coordinator, metadata, torrent_parser};
+use crate::coordinator::ipc::set_status;
+use crate::coordinator::stats::{TorrentStats, TorrentStatus};
+use crate::coordinator::task::TransferError;
+use crate::core_models::entities::{Peer, PeerSource, Torrent, TorrentLayout};
+use crate::core_models::events::InternalEvent;
+use crate::ip_filter;
+use crate::dependency_provider::{DependencyProvider, SessionResources, TransferDeps};
+use crate::metadata::{MagnetLink, MetadataError};
+use crate::metrics::Metrics;
+use crate::p2p::conn;
+use crate::p2p::conn::IncomingConnection;
+use crate::piece_picker::PickingMode;
+use crate::rate_limiter::RateLimits;
+use crate::selection::{piece_priorities, Priority};
+
+#[derive(Debug)]
+pub enum SessionError {
+    ListenerNotStarted(String),
+    InvalidTorrent(String),
+    OutputFileNotCreated(String),
+    DuplicateTorrent,
+    TorrentNotFound,
+    // the transfer of the torrent is over, it can no longer be controlled
+    TransferEnded,
+    TransferStopped,
+    TransferFailed(String),
+    IpFilterNotLoaded(String),
+    // the metadata of a magnet link could not be fetched from peers
+    MetadataNotFetched(String),
+    InvalidPriorities(String),
+}
+
+// How often the blocklist file is checked for changes
+const IP_FILTER_CHECK_INTERVAL_SECS: u64 = 30;
+
+// Incoming connections are routed to the torrents by info hash
+type TorrentRoutes = Arc<RwLock<HashMap<Vec<u8>, Sender<IncomingConnection>>>>;
+
+#[derive(Clone)]
+struct TorrentEntry {
+    control_tx: Sender<InternalEvent>,
+    stats_rx: watch::Receiver<TorrentStats>,
+    deps: Arc<dyn TransferDeps>,
+}
+
+// Torrents of the session, shared with their handles
+struct Registry {
+    routes: TorrentRoutes,
+    torrents: RwLock<HashMap<Vec<u8>, TorrentEntry>>,
+}
+
+impl Registry {
+    async fn remove(&self, info_hash: &Vec<u8>) -> Result<TorrentEntry, SessionError> {
+        let entry = self.torrents.write().await.remove(info_hash).ok_or(SessionError::TorrentNotFound)?;
+        self.routes.write().await.remove(info_hash);
+        return Ok(entry);
+    }
+}
+
+/// Configures a [`Session`] before starting it.
+pub struct SessionBuilder {
+    config: Config,
+}
+
+impl SessionBuilder {
+    /// Starts from the given configuration instead of the default one.
+    pub fn with_config(mut self, config: Config) -> Self {
+        self.config = config;
+        return self;
+    }
+
+    /// Port of the listener shared by all torrents; 0 picks a free one.
+    pub fn with_listening_port(mut self, port: u16) -> Self {
+        self.config.listening_port = port;
+        return self;
+    }
+
+    /// Directory the downloaded files are written to.
+    pub fn with_download_dir(mut self, download_dir: impl Into<PathBuf>) -> Self {
+        self.config.download_dir = download_dir.into();
+        return self;
+    }
+
+    /// Limits the transfer rates of the whole session.
+    pub fn with_rate_limits(mut self, limits: RateLimits) -> Self {
+        self.config.rate_limits = limits;
+        return self;
+    }
+
+    /// Limits the transfer rates of every single peer.
+    pub fn with_peer_rate_limits(mut self, limits: RateLimits) -> Self {
+        self.config.peer_rate_limits = limits;
+        return self;
+    }
+
+    /// Maximum number of peer connections across all torrents.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.config.max_connections = max_connections;
+        return self;
+    }
+
+    /// Blocklist of the IP ranges peers are refused from; reloaded whenever the file changes.
+    pub fn with_ip_filter(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.ip_filter_path = Some(path.into());
+        return self;
+    }
+
+    /// Binds the listener and starts the session; must be called within a tokio runtime.
+    pub async fn start(self) -> Result<Session, SessionError> {
+        return Session::start(self.config).await;
+    }
+}
+
+/// Runs any number of torrents, each with its own coordinator, piece picker and storage, which share
+/// a single listener, rate limiter and connection budget.
+pub struct Session {
+    config: Config,
+    resources: SessionResources,
+    registry: Arc<Registry>,
+    transfers: Mutex<Vec<JoinHandle<Result<(), TransferError>>>>,
+    listener_handle: JoinHandle<()>,
+    ip_filter_handle: Option<JoinHandle<()>>,
+    next_torrent_id: AtomicUsize,
+    started_at: Instant,
+}
+
+impl Session {
+    pub fn builder() -> SessionBuilder {
+        return SessionBuilder { config: Config::default() };
+    }
+
+    pub async fn start(config: Config) -> Result<Self, SessionError> {
+        let resources = SessionResources::new(&config);
+        // no peer is let through before the blocklist is in place
+        let ip_filter_handle = match &config.ip_filter_path {
+            None => None,
+            Some(path) => {
+                let check_interval = Duration::from_secs(IP_FILTER_CHECK_INTERVAL_SECS);
+                let handle = ip_filter::load_and_watch(resources.ip_filter.clone(), path.clone(), check_interval).await
+                    .map_err(|err| SessionError::IpFilterNotLoaded(format!("{}: {}", path.display(), err)))?;
+                Some(handle)
+            }
+        };
+        let listener = TcpListener::bind(("0.0.0.0", config.listening_port)).await
+            .map_err(|err| SessionError::ListenerNotStarted(err.to_string()))?;
+        let registry = Arc::new(Registry {
+            routes: Arc::new(RwLock::new(HashMap::new())),
+            torrents: RwLock::new(HashMap::new()),
+        });
+        let listener_handle = tokio::spawn(accept_connections(
+            listener, registry.routes.clone(), config.clone(), resources.clone(),
+        ));
+
+        return Ok(Session {
+            config,
+            resources,
+            registry,
+            transfers: Mutex::new(vec![]),
+            listener_handle,
+            ip_filter_handle,
+            next_torrent_id: AtomicUsize::new(1),
+            started_at: Instant::now(),
+        });
+    }
+
+    /// Prepares the output file of the torrent in the download directory and starts transferring it.
+    pub async fn add_torrent(&self,
+                             torrent: Torrent,
+                             picking_mode: PickingMode,
+                             priorities: Vec<Priority>) -> Result<TorrentHandle, SessionError> {
+        let info_hash = torrent.info_hash.clone();
+        let mut torrents = self.registry.torrents.write().await;
+        if torrents.contains_key(&info_hash) {
+            return Err(SessionError::DuplicateTorrent);
+        }
+
+        if torrent.info.length.is_none() {
+            return Err(SessionError::InvalidTorrent("only single file torrents are supported".to_string()));
+        }
+        if !is_safe_file_name(&torrent.info.name) {
+            return Err(SessionError::InvalidTorrent(format!("unsafe name -> {:?}", torrent.info.name)));
+        }
+        let mut layout = TorrentLayout::from_torrent(&torrent);
+        layout.output_file_path = self.config.download_dir.join(&layout.output_file_path).to_string_lossy().to_string();
+        let (coordinator_tx, coordinator_rx) = mpsc::channel(self.config.channel_capacity);
+        let (incoming_tx, incoming_rx) = mpsc::channel(64);
+        let deps = Arc::new(DependencyProvider::init(
+            self.config.clone(), self.resources.clone(), torrent, layout.clone(), picking_mode, coordinator_tx.clone(),
+        ));
+        deps.piece_picker().lock().await.set_piece_priorities(priorities)
+            .map_err(|err| SessionError::InvalidPriorities(format!("{:?}", err)))?;
+        // completes the transfer right away when every wanted piece is skipped or restored
+        let _ = coordinator_tx.send(InternalEvent::PrioritiesChanged).await;
+        if let Err(err) = create_output_files(&layout) {
+            let reason = format!("{:?}", err);
+            self.resources.alerts.send(Alert::StorageError { info_hash, reason });
+            return Err(err);
+        }
+
+        let torrent_id = self.next_torrent_id.fetch_add(1, Ordering::Relaxed);
+        deps.stats_tx().send_modify(|stats| stats.id = torrent_id);
+        let entry = TorrentEntry { control_tx: coordinator_tx, stats_rx: deps.stats_tx().subscribe(), deps: deps.clone() };
+        torrents.insert(info_hash.clone(), entry.clone());
+        self.registry.routes.write().await.insert(info_hash.clone(), incoming_tx);
+
+        let routes = self.registry.routes.clone();
+        let route_key = info_hash.clone();
+        self.transfers.lock().await.push(tokio::spawn(async move {
+            let result = coordinator::task::run(deps.clone(), coordinator_rx, incoming_rx).await;
+            routes.write().await.remove(&route_key);
+            if let Err(err) = &result {
+                let status = TorrentStatus::Failed(format!("{:?}", err));
+                set_status(&deps.stats_tx(), &deps.alerts(), &route_key, status);
+            }
+            return result;
+        }));
+
+        return Ok(TorrentHandle { info_hash, entry, registry: self.registry.clone() });
+    }
+
+    /// Adds the torrent described by a .torrent file.
+    pub async fn add_torrent_file(&self, path: &str) -> Result<TorrentHandle, SessionError> {
+        let torrent = torrent_parser::parse_torrent(path)
+            .map_err(|err| SessionError::InvalidTorrent(err.to_string()))?;
+        return self.add_parsed_torrent(torrent).await;
+    }
+
+    /// Adds the torrent described by the contents of a .torrent file.
+    pub async fn add_torrent_bytes(&self, bytes: &[u8]) -> Result<TorrentHandle, SessionError> {
+        let torrent = torrent_parser::parse_torrent_bytes(bytes)
+            .map_err(|err| SessionError::InvalidTorrent(err.to_string()))?;
+        return self.add_parsed_torrent(torrent).await;
+    }
+
+    /// Adds the torrent of a magnet link, once its metadata is fetched from the peers the trackers
+    /// of the link know of.
+    pub async fn add_magnet(&self, link: &str) -> Result<TorrentHandle, SessionError> {
+        let magnet = MagnetLink::parse(link).map_err(|err| SessionError::InvalidTorrent(format!("{:?}", err)))?;
+        if self.registry.torrents.read().await.contains_key(&magnet.info_hash) {
+            return Err(SessionError::DuplicateTorrent);
+        }
+
+        let not_fetched = |err: MetadataError| SessionError::MetadataNotFetched(format!("{:?}", err));
+        let peers = metadata::announce(&magnet, &self.config).await.map_err(not_fetched)?;
+        let peers = peers.into_iter()
+            .filter(|peer| !self.resources.ip_filter.is_blocked(&peer.ip) && !self.resources.banned_peers.is_banned(&peer.ip))
+            .collect();
+        let info_bytes = metadata::fetch_from_peers(peers, &magnet.info_hash, &self.config).await.map_err(not_fetched)?;
+        let torrent = torrent_parser::torrent_from_info_bytes(&info_bytes, &magnet.trackers)
+            .map_err(|err| SessionError::InvalidTorrent(err.to_string()))?;
+        if torrent.info_hash != magnet.info_hash {
+            return Err(SessionError::InvalidTorrent("info hash of the metadata differs from the magnet link".to_string()));
+        }
+        return self.add_parsed_torrent(torrent).await;
+    }
+
+    /// Handle of a torrent of the session.
+    pub async fn torrent_handle(&self, info_hash: &Vec<u8>) -> Option<TorrentHandle> {
+        let entry = self.registry.torrents.read().await.get(info_hash).cloned()?;
+        return Some(TorrentHandle { info_hash: info_hash.clone(), entry, registry: self.registry.clone() });
+    }
+
+    /// Stops transferring the torrent; the downloaded data is kept.
+    pub async fn remove_torrent(&self, info_hash: &Vec<u8>) -> Result<(), SessionError> {
+        let entry = self.registry.remove(info_hash).await?;
+        let _ = entry.control_tx.send(InternalEvent::StopTransfer).await;
+        return Ok(());
+    }
+
+    pub async fn pause_torrent(&self, info_hash: &Vec<u8>) -> Result<(), SessionError> {
+        return self.torrent_handle(info_hash).await.ok_or(SessionError::TorrentNotFound)?.pause().await;
+    }
+
+    pub async fn resume_torrent(&self, info_hash: &Vec<u8>) -> Result<(), SessionError> {
+        return self.torrent_handle(info_hash).await.ok_or(SessionError::TorrentNotFound)?.resume().await;
+    }
+
+    /// Current state of every torrent of the session.
+    pub async fn torrents(&self) -> Vec<TorrentStats> {
+        return self.registry.torrents.read().await.values()
+            .map(|entry| entry.stats_rx.borrow().clone())
+            .collect();
+    }
+
+    pub async fn torrent(&self, info_hash: &Vec<u8>) -> Option<TorrentStats> {
+        return self.registry.torrents.read().await.get(info_hash)
+            .map(|entry| entry.stats_rx.borrow().clone());
+    }
+
+    /// Receives the alerts of the given categories raised by any torrent of the session from now on.
+    pub fn subscribe_alerts(&self, categories: &[AlertCategory]) -> AlertStream {
+        return self.resources.alerts.subscribe(categories);
+    }
+
+    /// Metrics of all the torrents of the session.
+    pub fn metrics(&self) -> Arc<Metrics> {
+        return self.resources.metrics.clone();
+    }
+
+    pub fn config(&self) -> &Config {
+        return &self.config;
+    }
+
+    pub fn uptime(&self) -> Duration {
+        return self.started_at.elapsed();
+    }
+
+    pub fn rate_limits(&self) -> RateLimits {
+        return self.resources.rate_limiter.limits();
+    }
+
+    /// Changes the session wide rate limits, which applies to the running transfers right away.
+    pub fn set_rate_limits(&self, limits: RateLimits) {
+        self.resources.rate_limiter.set_limits(limits);
+    }
+
+    /// Waits until all the torrents of the session are done transferring, then stops accepting
+    /// connections.
+    pub async fn wait(&self) {
+        let mut transfers = self.transfers.lock().await;
+        // a transfer is only dropped once it ended, so that waiting can be given up and started again
+        while let Some(transfer) = transfers.first_mut() {
+            let result = transfer.await;
+            transfers.remove(0);
+            if let Ok(Err(err)) = result {
+                warn!("Transfer failed due to {:?}", err);
+            }
+        }
+        self.listener_handle.abort();
+        if let Some(handle) = &self.ip_filter_handle {
+            handle.abort();
+        }
+    }
+
+    /// Stops every torrent and waits for them to wind down: the received data is written and
+    /// flushed, the trackers are told and the resume data is saved.
+    pub async fn shutdown(&self) {
+        let entries: Vec<TorrentEntry> = self.registry.torrents.read().await.values().cloned().collect();
+        for entry in entries {
+            let _ = entry.control_tx.send(InternalEvent::StopTransfer).await;
+        }
+        self.wait().await;
+    }
+
+    async fn add_parsed_torrent(&self, torrent: Torrent) -> Result<TorrentHandle, SessionError> {
+        let priorities = vec![Priority::Normal; torrent.piece_hashes.len()];
+        return self.add_torrent(torrent, PickingMode::RarestFirst, priorities).await;
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.listener_handle.abort();
+    }
+}
+
+/// Controls a torrent of a [`Session`]; cloning it is cheap.
+#[derive(Clone)]
+pub struct TorrentHandle {
+    info_hash: Vec<u8>,
+    entry: TorrentEntry,
+    registry: Arc<Registry>,
+}
+
+impl TorrentHandle {
+    pub fn info_hash(&self) -> &Vec<u8> {
+        return &self.info_hash;
+    }
+
+    /// Current state of the transfer.
+    pub fn status(&self) -> TorrentStats {
+        return self.entry.stats_rx.borrow().clone();
+    }
+
+    /// Receives the state of the transfer whenever it changes.
+    pub fn subscribe(&self) -> watch::Receiver<TorrentStats> {
+        return self.entry.stats_rx.clone();
+    }
+
+    /// Stops requesting and serving blocks, the peers stay connected.
+    pub async fn pause(&self) -> Result<(), SessionError> {
+        return self.send_control_event(InternalEvent::PauseTransfer).await;
+    }
+
+    pub async fn resume(&self) -> Result<(), SessionError> {
+        return self.send_control_event(InternalEvent::ResumeTransfer).await;
+    }
+
+    /// Stops transferring the torrent and removes it from the session; the downloaded data is kept.
+    pub async fn remove(self) -> Result<(), SessionError> {
+        self.registry.remove(&self.info_hash).await?;
+        let _ = self.entry.control_tx.send(InternalEvent::StopTransfer).await;
+        return Ok(());
+    }
+
+    /// Sets the priorities of byte ranges of the torrent; the rest of it gets normal priority.
+    pub async fn set_priorities(&self, ranges: &[(Range<usize>, Priority)]) -> Result<(), SessionError> {
+        let priorities = piece_priorities(&self.entry.deps.torrent_layout(), ranges);
+        self.entry.deps.piece_picker().lock().await.set_piece_priorities(priorities)
+            .map_err(|err| SessionError::InvalidPriorities(format!("{:?}", err)))?;
+        let _ = self.entry.control_tx.send(InternalEvent::PrioritiesChanged).await;
+        return Ok(());
+    }
+
+    /// Waits until the download completes, after which the torrent may keep seeding; fails if the
+    /// transfer is stopped or fails before.
+    pub async fn wait_for_completion(&self) -> Result<TorrentStats, SessionError> {
+        let mut stats_rx = self.entry.stats_rx.clone();
+        let stats = stats_rx
+            .wait_for(|stats| matches!(stats.status, TorrentStatus::Seeding | TorrentStatus::Completed | TorrentStatus::Stopped | TorrentStatus::Failed(_)))
+            .await
+            .map_err(|_| SessionError::TransferEnded)?
+            .clone();
+
+        return match stats.status {
+            TorrentStatus::Seeding | TorrentStatus::Completed => Ok(stats),
+            TorrentStatus::Failed(reason) => Err(SessionError::TransferFailed(reason)),
+            _ => Err(SessionError::TransferStopped),
+        };
+    }
+
+    async fn send_control_event(&self, event: InternalEvent) -> Result<(), SessionError> {
+        return self.entry.control_tx.send(event).await.map_err(|_| SessionError::TransferEnded);
+    }
+}
+
+// The name of the torrent comes from its author, and must not lead the output file out of the
+// download directory, nor point at the directory itself
+fn is_safe_file_name(name: &str) -> bool {
+    return !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\', '\0'])
+        && !Path::new(name).is_absolute();
+}
+
+// Data already in the file is kept, so that the pieces stored by an earlier transfer can be resumed
+fn create_output_files(layout: &TorrentLayout) -> Result<(), SessionError> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(layout.output_file_path.as_str())
+        .map_err(|err| SessionError::OutputFileNotCreated(err.to_string()))?;
+    file.set_len(layout.output_file_length as u64)
+        .map_err(|err| SessionError::OutputFileNotCreated(err.to_string()))?;
+    return Ok(());
+}
+
+async fn accept_connections(listener: TcpListener,
+                            routes: TorrentRoutes,
+                            config: Config,
+                            resources: SessionResources) {
+    let handshake_timeout = Duration::from_secs(config.connect_timeout_secs);
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(err) => {
+                warn!("Failed to accept connection: {}", err);
+                continue;
+            }
+        };
+        if matches!(addr.ip(), IpAddr::V4(ip) if resources.ip_filter.is_blocked(&ip)) {
+            resources.metrics.blocked_peers.with_label_values(&[PeerSource::Incoming.label()]).inc();
+            info!("Refused connection from {}, blocked by the IP filter", addr);
+            continue;
+        }
+        if resources.connection_budget.available_permits() == 0 {
+            info!("Refused connection from {}, connection limit reached", addr);
+            continue;
+        }
+        tokio::spawn(route_connection(stream, addr, routes.clone(), config.client_id.clone(), handshake_timeout));
+    }
+}
+
+// Hands the connection over to the torrent the peer asks for in its handshake
+async fn route_connection(mut stream: TcpStream,
+                          addr: SocketAddr,
+                          routes: TorrentRoutes,
+                          client_id: String,
+                          handshake_timeout: Duration) {
+    let IpAddr::V4(ip) = addr.ip() else {
+        return;
+    };
+    let handshake = timeout(handshake_timeout, conn::receive_handshake(&mut stream)).await;
+    let Ok(Ok(handshake)) = handshake else {
+        info!("Dropped connection from {}, handshake not received", addr);
+        return;
+    };
+    let Some(torrent_tx) = routes.read().await.get(&handshake.info_hash).cloned() else {
+        info!("Dropped connection from {}, unknown info hash", addr);
+        return;
+    };
+    if let Err(err) = handshake.validate(&handshake.info_hash, &client_id, None) {
+        info!("Dropped connection from {} due to {:?}", addr, err);
+        return;
+    }
+
+    let peer = Peer { ip, port: addr.port(), source: PeerSource::Incoming, peer_id: None };
+    match conn::accept_connection(stream, peer, handshake, &client_id).await {
+        Ok(connection) => { let _ = torrent_tx.send(connection).await; }
+        Err(err) => info!("Dropped connection from {} due to {:?}", addr, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::io::Read;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use hyper::{Body, Request, Response, Server};
+    use hyper::service::{make_service_fn, service_fn};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use tempfile::TempDir;
+    use tokio::sync::{mpsc, RwLock};
+    use crate::alerts::{Alert, AlertCategory};
+    use crate::coordinator::stats::TorrentStatus;
+    use crate::core_models::entities::{PeerSource, Torrent};
+    use crate::piece_picker::PickingMode;
+    use crate::selection::Priority;
+    use crate::session::{route_connection, Session, SessionError, TorrentRoutes};
+    use crate::torrent_parser::parse_torrent;
+
+    const INFO_HASH: [u8; 20] = [7; 20];
+    const CLIENT_ID: &str = "-XX0001-abcdefghijkl";
+
+    fn handshake(info_hash: &[u8]) -> Vec<u8> {
+        let mut handshake = vec![19];
+        handshake.extend(b"BitTorrent protocol");
+        handshake.extend([0u8; 8]);
+        handshake.extend(info_hash);
+        handshake.extend([1u8; 20]);
+        return handshake;
+    }
+
+    // a tracker without any peers, which records the query of every announce
+    fn start_tracker() -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        let queries = Arc::new(std::sync::Mutex::new(vec![]));
+        let recorded = queries.clone();
+        let make_service = make_service_fn(move |_conn| {
+            let recorded = recorded.clone();
+            async move {
+                return Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    recorded.lock().unwrap().push(request.uri().query().unwrap_or_default().to_string());
+                    async move { return Ok::<_, Infallible>(Response::new(Body::from("d8:intervali1800e5:peers0:e"))); }
+                }));
+            }
+        });
+        let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_service);
+        let announce_url = format!("http://{}/announce", server.local_addr());
+        tokio::spawn(server);
+
+        return (announce_url, queries);
+    }
+
+    // the tracker of the torrent refuses connections, so its transfer fails right away
+    fn unreachable_torrent() -> Torrent {
+        let mut torrent = parse_torrent("test_resources/ubuntu-18.04.6-desktop-amd64.iso.torrent").unwrap();
+        torrent.announce = "http://127.0.0.1:1/announce".to_string();
+        return torrent;
+    }
+
+    async fn start_session(download_dir: &TempDir) -> Session {
+        return Session::builder()
+            .with_listening_port(0)
+            .with_download_dir(download_dir.path())
+            .start().await.unwrap();
+    }
+
+    async fn connect(routes: TorrentRoutes) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, addr) = listener.accept().await.unwrap();
+            route_connection(stream, addr, routes, CLIENT_ID.to_string(), Duration::from_secs(10)).await;
+        });
+
+        return TcpStream::connect(local_addr).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_connection_routed_by_info_hash() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let routes: TorrentRoutes = Arc::new(RwLock::new(HashMap::from([(INFO_HASH.to_vec(), tx)])));
+        let mut stream = connect(routes).await;
+
+        stream.write_all(&handshake(&INFO_HASH)).await.unwrap();
+        let mut response = vec![0u8; 68];
+        stream.read_exact(&mut response).await.unwrap();
+
+        assert_eq!(&response[28..48], &INFO_HASH);
+        assert_eq!(&response[48..68], CLIENT_ID.as_bytes());
+        let connection = rx.recv().await.unwrap();
+        assert_eq!(connection.peer.source, PeerSource::Incoming);
+    }
+
+    #[tokio::test]
+    async fn test_connection_for_unknown_torrent_dropped() {
+        let routes: TorrentRoutes = Arc::new(RwLock::new(HashMap::new()));
+        let mut stream = connect(routes).await;
+
+        stream.write_all(&handshake(&INFO_HASH)).await.unwrap();
+        let mut response = vec![];
+        let bytes_read = stream.read_to_end(&mut response).await.unwrap();
+
+        assert_eq!(bytes_read, 0);
+    }
+
+    #[tokio::test]
+    async fn test_connection_to_self_dropped() {
+        let (tx, mut rx) = mpsc::channel(1);
+        let routes: TorrentRoutes = Arc::new(RwLock::new(HashMap::from([(INFO_HASH.to_vec(), tx)])));
+        let mut stream = connect(routes).await;
+        let mut own_handshake = handshake(&INFO_HASH);
+        own_handshake[48..].copy_from_slice(CLIENT_ID.as_bytes());
+
+        stream.write_all(&own_handshake).await.unwrap();
+        let mut response = vec![];
+        let bytes_read = stream.read_to_end(&mut response).await.unwrap();
+
+        assert_eq!(bytes_read, 0);
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_session_not_started_without_blocklist() {
+        let download_dir = TempDir::new().unwrap();
+
+        let session = Session::builder()
+            .with_listening_port(0)
+            .with_ip_filter(download_dir.path().join("missing.dat"))
+            .start().await;
+
+        assert!(matches!(session, Err(SessionError::IpFilterNotLoaded(_))));
+    }
+
+    #[tokio::test]
+    async fn test_torrent_added_to_download_dir() {
+        let download_dir = TempDir::new().unwrap();
+        let session = start_session(&download_dir).await;
+        let torrent = unreachable_torrent();
+        let priorities = vec![Priority::Normal; torrent.piece_hashes.len()];
+
+        let handle = session.add_torrent(torrent.clone(), PickingMode::RarestFirst, priorities.clone()).await.unwrap();
+        let duplicate = session.add_torrent(torrent.clone(), PickingMode::RarestFirst, priorities).await;
+
+        assert!(download_dir.path().join(&torrent.info.name).exists());
+        assert_eq!(handle.status().id, 1);
+        assert!(matches!(duplicate, Err(SessionError::DuplicateTorrent)));
+        assert!(matches!(handle.wait_for_completion().await, Err(SessionError::TransferFailed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_existing_data_kept_when_torrent_added() {
+        let download_dir = TempDir::new().unwrap();
+        let session = start_session(&download_dir).await;
+        let torrent = unreachable_torrent();
+        let output_path = download_dir.path().join(&torrent.info.name);
+        std::fs::write(&output_path, [7u8; 1024]).unwrap();
+        let priorities = vec![Priority::Normal; torrent.piece_hashes.len()];
+
+        session.add_torrent(torrent.clone(), PickingMode::RarestFirst, priorities).await.unwrap();
+
+        let mut data = [0u8; 1024];
+        let mut file = std::fs::File::open(&output_path).unwrap();
+        file.read_exact(&mut data).unwrap();
+        assert_eq!(file.metadata().unwrap().len(), torrent.info.length.unwrap());
+        assert!(data.iter().all(|byte| *byte == 7));
+    }
+
+    #[tokio::test]
+    async fn test_torrent_with_unsafe_name_refused() {
+        let download_dir = TempDir::new().unwrap();
+        let session = start_session(&download_dir).await;
+
+        for name in ["", ".", "..", "../escaped", "/tmp/absolute", "nested/name", "..\\escaped", "C:\\windows"] {
+            let mut torrent = unreachable_torrent();
+            torrent.info.name = name.to_string();
+            let priorities = vec![Priority::Normal; torrent.piece_hashes.len()];
+
+            let result = session.add_torrent(torrent, PickingMode::RarestFirst, priorities).await;
+
+            assert!(matches!(result, Err(SessionError::InvalidTorrent(_))), "name accepted -> {:?}", name);
+        }
+        assert!(session.torrents().await.is_empty());
+        assert!(!download_dir.path().parent().unwrap().join("escaped").exists());
+    }
+
+    #[tokio::test]
+    async fn test_priorities_not_matching_piece_count_refused() {
+        let download_dir = TempDir::new().unwrap();
+        let session = start_session(&download_dir).await;
+        let torrent = unreachable_torrent();
+        let priorities = vec![Priority::Normal; torrent.piece_hashes.len() + 1];
+        let output_file = download_dir.path().join(&torrent.info.name);
+
+        let result = session.add_torrent(torrent, PickingMode::RarestFirst, priorities).await;
+
+        assert!(matches!(result, Err(SessionError::InvalidPriorities(_))));
+        assert!(session.torrents().await.is_empty());
+        assert!(!output_file.exists());
+    }
+
+    #[tokio::test]
+    async fn test_removed_torrent_forgotten() {
+        let download_dir = TempDir::new().unwrap();
+        let session = start_session(&download_dir).await;
+        let torrent = unreachable_torrent();
+        let priorities = vec![Priority::Normal; torrent.piece_hashes.len()];
+        let handle = session.add_torrent(torrent.clone(), PickingMode::RarestFirst, priorities).await.unwrap();
+
+        handle.remove().await.unwrap();
+
+        assert!(session.torrents().await.is_empty());
+        assert!(session.torrent_handle(&torrent.info_hash).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_failed_announce_alerted() {
+        let download_dir = TempDir::new().unwrap();
+        let session = start_session(&download_dir).await;
+        let mut alerts = session.subscribe_alerts(&[AlertCategory::Tracker, AlertCategory::Status]);
+        let torrent = unreachable_torrent();
+        let priorities = vec![Priority::Normal; torrent.piece_hashes.len()];
+
+        session.add_torrent(torrent.clone(), PickingMode::RarestFirst, priorities).await.unwrap();
+
+        assert!(matches!(alerts.recv().await, Ok(Alert::TrackerError { info_hash, .. }) if info_hash == torrent.info_hash));
+        assert!(matches!(alerts.recv().await, Ok(Alert::TorrentStateChanged { status: TorrentStatus::Failed(_), .. })));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_announces_stop_and_saves_resume_data() {
+        let (announce_url, announces) = start_tracker();
+        let download_dir = TempDir::new().unwrap();
+        let session = start_session(&download_dir).await;
+        let mut alerts = session.subscribe_alerts(&[AlertCategory::Status]);
+        let mut torrent = unreachable_torrent();
+        torrent.announce = announce_url;
+        let priorities = vec![Priority::Normal; torrent.piece_hashes.len()];
+        let handle = session.add_torrent(torrent.clone(), PickingMode::RarestFirst, priorities).await.unwrap();
+        assert!(matches!(alerts.recv().await, Ok(Alert::TorrentStateChanged { status: TorrentStatus::Downloading, .. })));
+
+        session.shutdown().await;
+
+        let announces = announces.lock().unwrap().clone();
+        assert!(announces.first().unwrap().contains("event=started"));
+        assert!(announces.last().unwrap().contains("event=stopped"));
+        assert_eq!(handle.status().status, TorrentStatus::Stopped);
+        assert!(download_dir.path().join(format!("{}.resume", torrent.info.name)).exists());
+    }
+}
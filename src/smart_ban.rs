@@ -0,0 +1,164 @@
+use std::collections::{HashMap, HashSet};
+use std::net::Ipv4Addr;
+use sha1::{Digest, Sha1};
+use crate::core_models::entities::Block;
+
+// Trust lost by each peer which sent blocks of a piece that failed the hash check, and gained back
+// for each piece that passed
+const HASH_FAILURE_PENALTY: i32 = 2;
+const PIECE_PASSED_REWARD: i32 = 1;
+// peers whose trust drops below this are banned, even if their blocks were never singled out
+const MIN_TRUST: i32 = -7;
+
+// the peers which sent a block of a failed piece, along with the hashes of the data they sent
+type BlockSenders = HashMap<Block, Vec<(Ipv4Addr, Vec<u8>)>>;
+
+// A stored block, along with the address of the peer which sent it and its data as read back from the file
+pub struct StoredBlock<'a> {
+    pub block: &'a Block,
+    pub sender: Ipv4Addr,
+    pub data: &'a [u8],
+}
+
+// Attributes hash failures to the peers which sent the corrupt blocks. The blocks of a failed piece
+// are hashed one by one; once the piece is downloaded again and passes, the peers whose blocks differ
+// from the good ones are the culprits. Peers are told apart by address, so that they are still
+// known once they reconnected, or banned after they disconnected.
+#[derive(Default)]
+pub struct SmartBan {
+    failed_blocks: HashMap<usize, BlockSenders>,
+    trust: HashMap<Ipv4Addr, i32>,
+    banned: HashSet<Ipv4Addr>,
+}
+
+impl SmartBan {
+    // Remembers who sent what for a piece which failed the hash check; returns the peers to ban
+    // because they were involved in too many failures
+    pub fn piece_failed(&mut self, piece_idx: usize, blocks: &[StoredBlock]) -> Vec<Ipv4Addr> {
+        // a peer sending the same corrupt data again is only remembered once, so repeated failures of a piece
+        // do not grow its entries
+        let failed_blocks = self.failed_blocks.entry(piece_idx).or_default();
+        for stored in blocks {
+            let sent = (stored.sender, hash(stored.data));
+            let senders = failed_blocks.entry(stored.block.clone()).or_default();
+            if !senders.contains(&sent) {
+                senders.push(sent);
+            }
+        }
+
+        let mut to_ban = Vec::new();
+        for sender in senders(blocks) {
+            let trust = self.trust.entry(sender).or_default();
+            *trust -= HASH_FAILURE_PENALTY;
+            if *trust < MIN_TRUST {
+                to_ban.push(sender);
+            }
+        }
+
+        return self.ban(to_ban);
+    }
+
+    // Compares the blocks of a piece which passed the hash check with the ones it failed with
+    // earlier; returns the peers to ban because they sent corrupt blocks
+    pub fn piece_passed(&mut self, piece_idx: usize, blocks: &[StoredBlock]) -> Vec<Ipv4Addr> {
+        for sender in senders(blocks) {
+            *self.trust.entry(sender).or_default() += PIECE_PASSED_REWARD;
+        }
+        // the entries of the piece are no longer needed once it verified
+        let Some(failed_blocks) = self.failed_blocks.remove(&piece_idx) else {
+            return vec![];
+        };
+
+        let mut to_ban = Vec::new();
+        for stored in blocks {
+            let good_hash = hash(stored.data);
+            let senders = failed_blocks.get(stored.block).into_iter().flatten();
+            senders.filter(|(_, block_hash)| *block_hash != good_hash)
+                .for_each(|(sender, _)| to_ban.push(*sender));
+        }
+
+        return self.ban(to_ban);
+    }
+
+    // peers are only reported once
+    fn ban(&mut self, senders: Vec<Ipv4Addr>) -> Vec<Ipv4Addr> {
+        let mut banned: Vec<Ipv4Addr> = senders.into_iter()
+            .filter(|sender| self.banned.insert(*sender))
+            .collect();
+        banned.sort();
+        return banned;
+    }
+}
+
+fn senders(blocks: &[StoredBlock]) -> HashSet<Ipv4Addr> {
+    return blocks.iter().map(|stored| stored.sender).collect();
+}
+
+fn hash(data: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    hasher.update(data);
+    return hasher.finalize().into_iter().collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use crate::core_models::entities::Block;
+    use crate::smart_ban::{SmartBan, StoredBlock};
+
+    fn stored<'a>(block: &'a Block, sender: u8, data: &'a [u8]) -> StoredBlock<'a> {
+        return StoredBlock { block, sender: Ipv4Addr::new(10, 0, 0, sender), data };
+    }
+
+    #[test]
+    fn test_peer_which_sent_corrupt_block_banned() {
+        let mut smart_ban = SmartBan::default();
+        let (first, second) = (Block::new(0, 0, 2), Block::new(0, 2, 2));
+
+        let banned = smart_ban.piece_failed(0, &[stored(&first, 1, &[1, 2]), stored(&second, 2, &[0, 0])]);
+        assert!(banned.is_empty());
+
+        let banned = smart_ban.piece_passed(0, &[stored(&first, 1, &[1, 2]), stored(&second, 3, &[3, 4])]);
+        assert_eq!(banned, vec![Ipv4Addr::new(10, 0, 0, 2)]);
+        assert!(smart_ban.piece_passed(0, &[stored(&first, 1, &[1, 2]), stored(&second, 3, &[3, 4])]).is_empty());
+    }
+
+    #[test]
+    fn test_peer_involved_in_repeated_failures_banned() {
+        let mut smart_ban = SmartBan::default();
+        let block = Block::new(0, 0, 2);
+
+        let banned: Vec<Ipv4Addr> = (0..4)
+            .flat_map(|_| smart_ban.piece_failed(0, &[stored(&block, 1, &[0, 0])]))
+            .collect();
+
+        assert_eq!(banned, vec![Ipv4Addr::new(10, 0, 0, 1)]);
+    }
+
+    #[test]
+    fn test_failed_blocks_cleared_once_piece_passes() {
+        let mut smart_ban = SmartBan::default();
+        let block = Block::new(0, 0, 2);
+
+        smart_ban.piece_failed(0, &[stored(&block, 1, &[0, 0])]);
+        smart_ban.piece_failed(0, &[stored(&block, 1, &[0, 0])]);
+        smart_ban.piece_failed(1, &[stored(&block, 2, &[0, 0])]);
+        assert_eq!(smart_ban.failed_blocks[&0][&block].len(), 1);
+
+        smart_ban.piece_passed(0, &[stored(&block, 3, &[1, 2])]);
+        assert!(!smart_ban.failed_blocks.contains_key(&0));
+        assert!(smart_ban.failed_blocks.contains_key(&1));
+    }
+
+    #[test]
+    fn test_passed_pieces_restore_trust() {
+        let mut smart_ban = SmartBan::default();
+        let block = Block::new(0, 0, 2);
+
+        for piece_idx in 0..4 {
+            smart_ban.piece_passed(piece_idx, &[stored(&block, 1, &[1, 2])]);
+            smart_ban.piece_passed(piece_idx, &[stored(&block, 1, &[1, 2])]);
+            assert!(smart_ban.piece_failed(piece_idx, &[stored(&block, 1, &[0, 0])]).is_empty());
+        }
+    }
+}
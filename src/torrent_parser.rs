@@ -1,20 +1,98 @@
 use std::fs;
+use std::ops::Range;
 use sha1::{Digest, Sha1};
 use crate::core_models::entities::Torrent;
 
 pub fn parse_torrent(file_path: &str) -> Result<Torrent, Box<dyn std::error::Error>> {
     let file = fs::read(file_path)?;
-    let mut torrent = serde_bencode::de::from_bytes::<Torrent>(&file)?;
+    return parse_torrent_bytes(&file);
+}
+
+pub fn parse_torrent_bytes(bytes: &[u8]) -> Result<Torrent, Box<dyn std::error::Error>> {
+    let mut torrent = serde_bencode::de::from_bytes::<Torrent>(bytes)?;
+    let info = info_dict_range(bytes).ok_or("info dictionary not found")?;
+    torrent.info_bytes = bytes[info].to_vec();
     let mut hasher = Sha1::new();
-    hasher.update(serde_bencode::ser::to_bytes(&torrent.info)?);
+    hasher.update(&torrent.info_bytes);
     torrent.info_hash = hasher.finalize().into_iter().collect();
     torrent.piece_hashes = torrent.info.pieces.as_ref().chunks(20).map(|array| array.to_owned()).collect();
     return Ok(torrent);
 }
 
+// Builds the torrent of a magnet link out of the info dictionary fetched from peers, announced to
+// the trackers of the link
+pub fn torrent_from_info_bytes(info_bytes: &[u8], trackers: &[String]) -> Result<Torrent, Box<dyn std::error::Error>> {
+    let mut bytes = b"d".to_vec();
+    if let Some(announce) = trackers.first() {
+        bytes.extend(b"8:announce");
+        bytes.extend(serde_bencode::ser::to_bytes(announce)?);
+    }
+    if trackers.len() > 1 {
+        let tiers: Vec<Vec<String>> = trackers.iter().map(|tracker| vec![tracker.clone()]).collect();
+        bytes.extend(b"13:announce-list");
+        bytes.extend(serde_bencode::ser::to_bytes(&tiers)?);
+    }
+    bytes.extend(b"4:info");
+    bytes.extend(info_bytes);
+    bytes.push(b'e');
+    return parse_torrent_bytes(&bytes);
+}
+
+// Writes the metainfo back to disk; the `info` dictionary is written exactly as it was read, including
+// keys the client does not know about, so the info hash of the saved torrent stays the same
+pub fn save_torrent(torrent: &Torrent, file_path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let mut bytes = serde_bencode::ser::to_bytes(torrent)?;
+    if !torrent.info_bytes.is_empty() {
+        let info = info_dict_range(&bytes).ok_or("info dictionary not found")?;
+        bytes.splice(info, torrent.info_bytes.iter().copied());
+    }
+    fs::write(file_path, bytes)?;
+    return Ok(());
+}
+
+// Position of the value of the `info` key in the bencoded metainfo dictionary
+fn info_dict_range(bytes: &[u8]) -> Option<Range<usize>> {
+    if bytes.first() != Some(&b'd') {
+        return None;
+    }
+    let mut pos = 1;
+    while *bytes.get(pos)? != b'e' {
+        let key_len = bencoded_len(&bytes[pos..])?;
+        let key = &bytes[pos..pos + key_len];
+        pos += key_len;
+        let value_len = bencoded_len(&bytes[pos..])?;
+        if key == b"4:info" {
+            return Some(pos..pos + value_len);
+        }
+        pos += value_len;
+    }
+    return None;
+}
+
+// Length of the bencoded value the bytes start with
+pub(crate) fn bencoded_len(bytes: &[u8]) -> Option<usize> {
+    return match bytes.first()? {
+        b'i' => Some(bytes.iter().position(|byte| *byte == b'e')? + 1),
+        b'l' | b'd' => {
+            let mut len = 1;
+            while *bytes.get(len)? != b'e' {
+                len += bencoded_len(&bytes[len..])?;
+            }
+            Some(len + 1)
+        }
+        b'0'..=b'9' => {
+            let colon = bytes.iter().position(|byte| *byte == b':')?;
+            let string_len: usize = std::str::from_utf8(&bytes[..colon]).ok()?.parse().ok()?;
+            colon.checked_add(1 + string_len).filter(|len| *len <= bytes.len())
+        }
+        _ => None,
+    };
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::torrent_parser::parse_torrent;
+    use sha1::Digest;
+    use crate::torrent_parser::{bencoded_len, parse_torrent, parse_torrent_bytes, save_torrent, torrent_from_info_bytes};
 
     #[test]
     pub fn test_torrent_parse() {
@@ -28,5 +106,61 @@ mod tests {
         assert_eq!(metadata.announce, "https://torrent.ubuntu.com/announce");
         assert_eq!(metadata.announce_list, Some(expected_trackers));
         assert_eq!(metadata.piece_hashes.len(), 9591);
+        assert!(!metadata.is_private());
+    }
+
+    #[test]
+    pub fn test_torrent_built_from_info_bytes() {
+        let info_bytes = b"d6:lengthi1024e4:name4:test12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaae";
+        let trackers = vec!["http://a/announce".to_string(), "http://b/announce".to_string()];
+
+        let torrent = torrent_from_info_bytes(info_bytes, &trackers).unwrap();
+
+        assert_eq!(torrent.info.name, "test");
+        assert_eq!(torrent.info_hash, sha1::Sha1::digest(info_bytes).to_vec());
+        assert_eq!(torrent.announce, "http://a/announce");
+        assert_eq!(torrent.announce_list, Some(vec![vec![trackers[0].clone()], vec![trackers[1].clone()]]));
+        assert_eq!(torrent.piece_hashes, vec![vec![b'a'; 20]]);
+    }
+
+    #[test]
+    pub fn test_save_torrent_keeps_info_hash() {
+        let metadata = parse_torrent("test_resources/debian-12.0.0-amd64-netinst.iso.torrent").unwrap();
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_path = output_dir.path().join("saved.torrent");
+        let output_path = output_path.to_str().unwrap();
+
+        save_torrent(&metadata, output_path).unwrap();
+        let saved = parse_torrent(output_path).unwrap();
+
+        assert_eq!(saved.info_hash, metadata.info_hash);
+        assert_eq!(saved.announce, metadata.announce);
+    }
+
+    #[test]
+    pub fn test_private_flag_and_unknown_info_keys_kept_on_save() {
+        let info_bytes = b"d6:lengthi1024e4:name4:test12:piece lengthi16384e6:pieces20:aaaaaaaaaaaaaaaaaaaa7:privatei1e6:source3:abce";
+        let mut bytes = b"d8:announce17:http://a/announce4:info".to_vec();
+        bytes.extend(info_bytes);
+        bytes.push(b'e');
+        let metadata = parse_torrent_bytes(&bytes).unwrap();
+        let output_dir = tempfile::tempdir().unwrap();
+        let output_path = output_dir.path().join("private.torrent");
+        let output_path = output_path.to_str().unwrap();
+
+        save_torrent(&metadata, output_path).unwrap();
+        let saved = parse_torrent(output_path).unwrap();
+
+        assert!(saved.is_private());
+        assert_eq!(saved.info_bytes, info_bytes.to_vec());
+        assert_eq!(saved.info_hash, sha1::Sha1::digest(info_bytes).to_vec());
+    }
+
+    #[test]
+    pub fn test_bencoded_values_measured() {
+        assert_eq!(bencoded_len(b"d8:msg_typei1e5:piecei0eeDATA"), Some(25));
+        assert_eq!(bencoded_len(b"li1e3:abce"), Some(10));
+        assert_eq!(bencoded_len(b"d8:msg_typei1e"), None);
+        assert_eq!(bencoded_len(b"99:abc"), None);
     }
 }
@@ -1,6 +1,6 @@
 use std::error::Error;
 use crate::config::Config;
-use crate::core_models::entities::{Peer, Torrent};
+use crate::core_models::entities::{Peer, PeerSource, Torrent};
 use form_urlencoded::byte_serialize;
 use serde::Deserialize;
 use serde_derive::Deserialize;
@@ -18,6 +18,7 @@ pub enum TrackerRequestEvent {
     Started,
     Regular(u64, u64),
     Completed(u64, u64),
+    Stopped(u64, u64),
 }
 
 impl TrackerRequestEvent {
@@ -25,7 +26,8 @@ impl TrackerRequestEvent {
         return match self {
             TrackerRequestEvent::Started => "started".to_string(),
             TrackerRequestEvent::Regular(_, _) => "".to_string(),
-            TrackerRequestEvent::Completed(_, _) => "completed".to_string()
+            TrackerRequestEvent::Completed(_, _) => "completed".to_string(),
+            TrackerRequestEvent::Stopped(_, _) => "stopped".to_string()
         };
     }
 
@@ -33,7 +35,8 @@ impl TrackerRequestEvent {
         return match self {
             TrackerRequestEvent::Started => "0".to_string(),
             TrackerRequestEvent::Regular(downloaded, _) => downloaded.to_string(),
-            TrackerRequestEvent::Completed(downloaded, _) => downloaded.to_string()
+            TrackerRequestEvent::Completed(downloaded, _) => downloaded.to_string(),
+            TrackerRequestEvent::Stopped(downloaded, _) => downloaded.to_string()
         };
     }
 
@@ -41,7 +44,8 @@ impl TrackerRequestEvent {
         return match self {
             TrackerRequestEvent::Started => "0".to_string(),
             TrackerRequestEvent::Regular(_, uploaded) => uploaded.to_string(),
-            TrackerRequestEvent::Completed(_, uploaded) => uploaded.to_string()
+            TrackerRequestEvent::Completed(_, uploaded) => uploaded.to_string(),
+            TrackerRequestEvent::Stopped(_, uploaded) => uploaded.to_string()
         };
     }
 }
@@ -60,16 +64,46 @@ pub struct TrackerResponse {
     pub tracker_id: Option<String>,
 }
 
+// Trackers send the peers either compactly, in 6 bytes each, or as dictionaries which may also carry
+// the peer ids
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PeersModel {
+    Compact(serde_bytes::ByteBuf),
+    Dictionaries(Vec<DictionaryPeer>),
+}
+
+#[derive(Deserialize)]
+struct DictionaryPeer {
+    ip: String,
+    port: u16,
+    #[serde(default, rename = "peer id", with = "serde_bytes")]
+    peer_id: Option<Vec<u8>>,
+}
+
 fn deserialize_peers<'de, D>(deserializer: D) -> Result<Vec<Peer>, D::Error>
     where D: serde::Deserializer<'de>,
 {
-    let bytes = serde_bytes::ByteBuf::deserialize(deserializer)?;
+    let bytes = match PeersModel::deserialize(deserializer)? {
+        PeersModel::Compact(bytes) => bytes,
+        PeersModel::Dictionaries(peers) => {
+            // only IPv4 peers are supported
+            return Ok(peers.into_iter()
+                .filter_map(|peer| Some(Peer {
+                    ip: peer.ip.parse().ok()?,
+                    port: peer.port,
+                    source: PeerSource::Tracker,
+                    peer_id: peer.peer_id,
+                }))
+                .collect());
+        }
+    };
     let mut iter = bytes.as_ref().chunks_exact(6);
     let mut peers = Vec::new();
     while let Some(chunk) = iter.next() {
         let ip = Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3]);
         let port = u16::from_be_bytes([chunk[4], chunk[5]]);
-        peers.push(Peer { ip, port });
+        peers.push(Peer { ip, port, source: PeerSource::Tracker, peer_id: None });
     }
 
     return Ok(peers);
@@ -99,7 +133,7 @@ impl TorrentTrackerClient {
             ("event", event.name()),
             ("downloaded", event.downloaded()),
             ("uploaded", event.uploaded()),
-            ("numwant", "300".to_string())
+            ("numwant", self.client_config.numwant.to_string())
         ];
         let query_params = query_params.into_iter()
             .filter(|(_key, value)| !value.is_empty())
@@ -125,4 +159,31 @@ impl TrackerClient for TorrentTrackerClient {
         let response = serde_bencode::de::from_bytes::<TrackerResponse>(&*response)?;
         return Ok(response);
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use crate::tracker::client::TrackerResponse;
+
+    #[test]
+    fn test_compact_peers_parsed() {
+        let response = b"d8:intervali1800e5:peers6:\x7f\x00\x00\x01\x1a\xe1e";
+
+        let response = serde_bencode::from_bytes::<TrackerResponse>(response).unwrap();
+
+        assert_eq!(response.peers[0].ip, Ipv4Addr::LOCALHOST);
+        assert_eq!(response.peers[0].port, 6881);
+        assert_eq!(response.peers[0].peer_id, None);
+    }
+
+    #[test]
+    fn test_dictionary_peers_parsed_with_peer_ids() {
+        let response = b"d8:intervali1800e5:peersld2:ip9:127.0.0.17:peer id20:-XX0001-abcdefghijkl4:porti6881eed2:ip3:::14:porti6881eeee";
+
+        let response = serde_bencode::from_bytes::<TrackerResponse>(response).unwrap();
+
+        assert_eq!(response.peers.len(), 1);
+        assert_eq!(response.peers[0].ip, Ipv4Addr::LOCALHOST);
+        assert_eq!(response.peers[0].peer_id, Some(b"-XX0001-abcdefghijkl".to_vec()));
+    }
+}
@@ -1,30 +1,35 @@
+use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::mpsc;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::task::JoinHandle;
 use tokio::time;
-use crate::tracker::client::{TrackerClient, TrackerRequestEvent};
+use crate::alerts::Alert;
+use crate::dependency_provider::TransferDeps;
+use crate::tracker::client::{TrackerClient, TrackerRequestEvent, TrackerResponse};
 
 pub enum TrackerEvent {
     Downloaded(u64),
     Uploaded(u64),
     RegularAnnounce,
     CompletedAnnounce,
+    // the transfer is being stopped; the tracker is told and the task ends
+    StoppedAnnounce,
 }
 
-pub fn spawn(client: Box<dyn TrackerClient>, interval: u64)
+pub fn spawn(client: Box<dyn TrackerClient>, interval: u64, deps: Arc<dyn TransferDeps>)
                    -> (JoinHandle<()>, Sender<TrackerEvent>) {
-    let (tx_to_self, rx) = mpsc::channel::<TrackerEvent>(1024);
+    let (tx_to_self, rx) = mpsc::channel::<TrackerEvent>(deps.client_config().channel_capacity);
     let tx_to_self_clone = tx_to_self.clone();
     let handle = tokio::spawn(async move {
-        return run(tx_to_self_clone, rx, interval, client).await;
+        return run(tx_to_self_clone, rx, interval, client, deps).await;
     });
 
     return (handle, tx_to_self);
 }
 
 async fn run(tx_to_self: Sender<TrackerEvent>, mut rx: Receiver<TrackerEvent>,
-             interval: u64, client: Box<dyn TrackerClient>) {
+             interval: u64, client: Box<dyn TrackerClient>, deps: Arc<dyn TransferDeps>) {
     let mut downloaded: u64 = 0;
     let mut uploaded: u64 = 0;
 
@@ -35,22 +40,53 @@ async fn run(tx_to_self: Sender<TrackerEvent>, mut rx: Receiver<TrackerEvent>,
             TrackerEvent::Downloaded(size) => downloaded += size,
             TrackerEvent::Uploaded(size) => uploaded += size,
             TrackerEvent::RegularAnnounce => {
-                let _ = client.announce(TrackerRequestEvent::Regular(downloaded, uploaded)).await;
+                let _ = announce(client.as_ref(), TrackerRequestEvent::Regular(downloaded, uploaded), deps.as_ref()).await;
             }
+            // the torrent keeps seeding, so the regular announces go on
             TrackerEvent::CompletedAnnounce => {
-                let _ = client.announce(TrackerRequestEvent::Completed(downloaded, uploaded)).await;
+                let _ = announce(client.as_ref(), TrackerRequestEvent::Completed(downloaded, uploaded), deps.as_ref()).await;
+            }
+            TrackerEvent::StoppedAnnounce => {
                 regular_announce_handle.abort();
+                let _ = announce(client.as_ref(), TrackerRequestEvent::Stopped(downloaded, uploaded), deps.as_ref()).await;
                 break;
             }
         }
     }
 }
 
+// Announces to the tracker, recording the result in the stats and metrics of the torrent and
+// alerting about it
+pub async fn announce(client: &dyn TrackerClient, event: TrackerRequestEvent, deps: &dyn TransferDeps)
+                      -> Result<TrackerResponse, String> {
+    let metrics = deps.metrics();
+    let timer = metrics.tracker_announce_seconds.start_timer();
+    let result = client.announce(event).await.map_err(|err| err.to_string());
+    timer.observe_duration();
+
+    let info_hash = deps.info_hash();
+    match &result {
+        Ok(response) => {
+            deps.stats_tx().send_modify(|stats| stats.tracker.announce_succeeded(response));
+            deps.alerts().send(Alert::TrackerReply { info_hash, peers: response.peers.len() });
+        }
+        Err(reason) => {
+            metrics.tracker_announce_errors.inc();
+            deps.stats_tx().send_modify(|stats| stats.tracker.announce_failed(reason.clone()));
+            deps.alerts().send(Alert::TrackerError { info_hash, reason: reason.clone() });
+        }
+    }
+
+    return result;
+}
+
 async fn regular_announce_scheduler(tx: Sender<TrackerEvent>, interval: u64) {
     let mut interval = time::interval(Duration::from_secs(interval));
     interval.tick().await;
     loop {
         interval.tick().await;
-        tx.send(TrackerEvent::RegularAnnounce).await.unwrap();
+        if tx.send(TrackerEvent::RegularAnnounce).await.is_err() {
+            break;
+        }
     }
 }
\ No newline at end of file
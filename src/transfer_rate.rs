@@ -0,0 +1,90 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+// Rolling estimate of a transfer rate, computed over the last `window` of transferred data
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TransferRate {
+    window: Duration,
+    started_at: Instant,
+    // (time of the transfer, bytes transferred)
+    samples: VecDeque<(Instant, usize)>,
+    bytes_in_window: usize,
+}
+
+impl TransferRate {
+    pub fn new(window: Duration) -> Self {
+        return TransferRate {
+            window,
+            started_at: Instant::now(),
+            samples: VecDeque::new(),
+            bytes_in_window: 0,
+        };
+    }
+
+    pub fn record(&mut self, bytes: usize) {
+        self.record_at(Instant::now(), bytes);
+    }
+
+    pub fn record_at(&mut self, at: Instant, bytes: usize) {
+        self.samples.push_back((at, bytes));
+        self.bytes_in_window += bytes;
+        self.drop_samples_older_than(at);
+    }
+
+    pub fn bytes_per_sec(&mut self) -> f64 {
+        return self.bytes_per_sec_at(Instant::now());
+    }
+
+    pub fn bytes_per_sec_at(&mut self, now: Instant) -> f64 {
+        self.drop_samples_older_than(now);
+        // until a full window has passed, the rate is computed over the time elapsed since the start
+        let elapsed = now.saturating_duration_since(self.started_at)
+            .clamp(Duration::from_secs(1), self.window.max(Duration::from_secs(1)));
+        return self.bytes_in_window as f64 / elapsed.as_secs_f64();
+    }
+
+    fn drop_samples_older_than(&mut self, now: Instant) {
+        while let Some((at, bytes)) = self.samples.front() {
+            if now.saturating_duration_since(*at) <= self.window {
+                break;
+            }
+            self.bytes_in_window -= bytes;
+            self.samples.pop_front();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::transfer_rate::TransferRate;
+
+    #[test]
+    fn test_rate_over_window() {
+        let mut rate = TransferRate::new(Duration::from_secs(10));
+        let start = Instant::now();
+        rate.record_at(start, 1000);
+        rate.record_at(start + Duration::from_secs(5), 1000);
+
+        assert_eq!(rate.bytes_per_sec_at(start + Duration::from_secs(10)), 200.0);
+    }
+
+    #[test]
+    fn test_old_samples_dropped() {
+        let mut rate = TransferRate::new(Duration::from_secs(10));
+        let start = Instant::now();
+        rate.record_at(start, 1000);
+        rate.record_at(start + Duration::from_secs(15), 500);
+
+        assert_eq!(rate.bytes_per_sec_at(start + Duration::from_secs(20)), 50.0);
+    }
+
+    #[test]
+    fn test_rate_before_full_window() {
+        let mut rate = TransferRate::new(Duration::from_secs(10));
+        let start = Instant::now();
+        rate.record_at(start, 1000);
+
+        assert!(rate.bytes_per_sec_at(start + Duration::from_millis(1)) >= 500.0);
+    }
+}
@@ -0,0 +1,179 @@
+use std::io;
+use std::io::Stdout;
+use std::sync::Arc;
+use std::time::Duration;
+use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::{event, execute};
+use crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::backend::CrosstermBackend;
+use ratatui::Terminal;
+use crate::coordinator::stats::TorrentStats;
+use crate::rate_limiter::RateLimits;
+use crate::session::Session;
+use crate::tui::logger::LogBuffer;
+use crate::tui::view;
+
+const REFRESH_INTERVAL_MILLIS: u64 = 200;
+const LIMIT_STEP_BYTES_PER_SEC: u64 = 100 * 1024;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    Quit,
+    SelectNext,
+    SelectPrevious,
+    Pause,
+    Resume,
+    ChangeDownloadLimit(i64),
+    ChangeUploadLimit(i64),
+}
+
+impl Action {
+    pub fn from_key(key: KeyEvent) -> Option<Action> {
+        if key.kind != KeyEventKind::Press {
+            return None;
+        }
+        let step = LIMIT_STEP_BYTES_PER_SEC as i64;
+        return match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => Some(Action::Quit),
+            KeyCode::Char('q') | KeyCode::Esc => Some(Action::Quit),
+            KeyCode::Down | KeyCode::Char('j') => Some(Action::SelectNext),
+            KeyCode::Up | KeyCode::Char('k') => Some(Action::SelectPrevious),
+            KeyCode::Char('p') => Some(Action::Pause),
+            KeyCode::Char('r') => Some(Action::Resume),
+            KeyCode::Char('+') | KeyCode::Char('=') => Some(Action::ChangeDownloadLimit(step)),
+            KeyCode::Char('-') => Some(Action::ChangeDownloadLimit(-step)),
+            KeyCode::Char(']') => Some(Action::ChangeUploadLimit(step)),
+            KeyCode::Char('[') => Some(Action::ChangeUploadLimit(-step)),
+            _ => None,
+        };
+    }
+}
+
+pub struct App {
+    pub torrents: Vec<TorrentStats>,
+    pub selected: usize,
+    pub limits: RateLimits,
+    pub logs: LogBuffer,
+}
+
+impl App {
+    pub fn new(logs: LogBuffer) -> Self {
+        return App {
+            torrents: vec![],
+            selected: 0,
+            limits: RateLimits::default(),
+            logs,
+        };
+    }
+
+    pub fn selected_torrent(&mut self) -> Option<&mut TorrentStats> {
+        return self.torrents.get_mut(self.selected);
+    }
+
+    async fn refresh(&mut self, session: &Session) {
+        let mut torrents = session.torrents().await;
+        torrents.sort_by_key(|stats| stats.id);
+        self.torrents = torrents;
+        self.selected = self.selected.min(self.torrents.len().saturating_sub(1));
+        self.limits = session.rate_limits();
+    }
+
+    // applies the action, returns false once the user asked to quit
+    async fn apply(&mut self, action: Action, session: &Session) -> bool {
+        let selected_hash = self.torrents.get(self.selected).map(|stats| stats.info_hash.clone());
+        match action {
+            Action::Quit => return false,
+            Action::SelectNext => self.selected = (self.selected + 1).min(self.torrents.len().saturating_sub(1)),
+            Action::SelectPrevious => self.selected = self.selected.saturating_sub(1),
+            Action::Pause => if let Some(info_hash) = selected_hash {
+                let _ = session.pause_torrent(&info_hash).await;
+            }
+            Action::Resume => if let Some(info_hash) = selected_hash {
+                let _ = session.resume_torrent(&info_hash).await;
+            }
+            Action::ChangeDownloadLimit(delta) => {
+                self.limits.download_bytes_per_sec = adjust_limit(self.limits.download_bytes_per_sec, delta);
+                session.set_rate_limits(self.limits);
+            }
+            Action::ChangeUploadLimit(delta) => {
+                self.limits.upload_bytes_per_sec = adjust_limit(self.limits.upload_bytes_per_sec, delta);
+                session.set_rate_limits(self.limits);
+            }
+        }
+
+        return true;
+    }
+}
+
+// Raising a limit starts from zero when unlimited; lowering it to zero removes the limit
+pub fn adjust_limit(limit: Option<u64>, delta: i64) -> Option<u64> {
+    let adjusted = limit.unwrap_or(0) as i64 + delta;
+    return match limit {
+        None if delta < 0 => None,
+        _ if adjusted <= 0 => None,
+        _ => Some(adjusted as u64),
+    };
+}
+
+// Runs the full screen interface until the user quits
+pub async fn run(session: Arc<Session>, logs: LogBuffer) -> io::Result<()> {
+    let mut terminal = TerminalGuard::enter()?;
+    let mut app = App::new(logs);
+    let mut refresh = tokio::time::interval(Duration::from_millis(REFRESH_INTERVAL_MILLIS));
+
+    loop {
+        refresh.tick().await;
+        app.refresh(&session).await;
+        terminal.0.draw(|frame| view::draw(frame, &mut app))?;
+
+        while event::poll(Duration::ZERO)? {
+            let Event::Key(key) = event::read()? else { continue; };
+            let Some(action) = Action::from_key(key) else { continue; };
+            if !app.apply(action, &session).await {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Restores the terminal when the interface exits, also on errors
+struct TerminalGuard(Terminal<CrosstermBackend<Stdout>>);
+
+impl TerminalGuard {
+    fn enter() -> io::Result<Self> {
+        enable_raw_mode()?;
+        execute!(io::stdout(), EnterAlternateScreen)?;
+        return Ok(TerminalGuard(Terminal::new(CrosstermBackend::new(io::stdout()))?));
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        let _ = disable_raw_mode();
+        let _ = execute!(self.0.backend_mut(), LeaveAlternateScreen);
+        let _ = self.0.show_cursor();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+    use crate::tui::app::{adjust_limit, Action};
+
+    #[test]
+    fn test_keys_mapped_to_actions() {
+        assert_eq!(Action::from_key(KeyEvent::from(KeyCode::Char('q'))), Some(Action::Quit));
+        assert_eq!(Action::from_key(KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL)), Some(Action::Quit));
+        assert_eq!(Action::from_key(KeyEvent::from(KeyCode::Char('p'))), Some(Action::Pause));
+        assert_eq!(Action::from_key(KeyEvent::from(KeyCode::Char('-'))), Some(Action::ChangeDownloadLimit(-102400)));
+        assert_eq!(Action::from_key(KeyEvent::from(KeyCode::Char('x'))), None);
+    }
+
+    #[test]
+    fn test_limit_adjusted() {
+        assert_eq!(adjust_limit(None, 100), Some(100));
+        assert_eq!(adjust_limit(None, -100), None);
+        assert_eq!(adjust_limit(Some(300), -100), Some(200));
+        assert_eq!(adjust_limit(Some(100), -100), None);
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+const MAX_LOG_LINES: usize = 500;
+
+// Most recent log lines, shown in the log pane instead of being written over the screen
+#[derive(Clone, Default)]
+pub struct LogBuffer {
+    lines: Arc<Mutex<VecDeque<String>>>,
+}
+
+impl LogBuffer {
+    pub fn push(&self, line: String) {
+        let mut lines = self.lines.lock().unwrap();
+        if lines.len() == MAX_LOG_LINES {
+            lines.pop_front();
+        }
+        lines.push_back(line);
+    }
+
+    // the last `count` lines, oldest first
+    pub fn tail(&self, count: usize) -> Vec<String> {
+        let lines = self.lines.lock().unwrap();
+        return lines.iter().skip(lines.len().saturating_sub(count)).cloned().collect();
+    }
+}
+
+struct BufferLogger {
+    buffer: LogBuffer,
+    level: LevelFilter,
+}
+
+impl Log for BufferLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        return metadata.level() <= self.level;
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            let time = chrono::Local::now().format("%H:%M:%S");
+            self.buffer.push(format!("{} {:<5} {}", time, record.level(), record.args()));
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// Installs a global logger which collects the log lines into the returned buffer
+pub fn init(level: LevelFilter) -> Result<LogBuffer, SetLoggerError> {
+    let buffer = LogBuffer::default();
+    log::set_boxed_logger(Box::new(BufferLogger { buffer: buffer.clone(), level }))?;
+    log::set_max_level(level);
+    return Ok(buffer);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tui::logger::{LogBuffer, MAX_LOG_LINES};
+
+    #[test]
+    fn test_oldest_lines_dropped() {
+        let buffer = LogBuffer::default();
+        for i in 0..MAX_LOG_LINES + 2 {
+            buffer.push(i.to_string());
+        }
+
+        assert_eq!(buffer.tail(2), vec![(MAX_LOG_LINES).to_string(), (MAX_LOG_LINES + 1).to_string()]);
+        assert_eq!(buffer.tail(MAX_LOG_LINES * 2).len(), MAX_LOG_LINES);
+    }
+}
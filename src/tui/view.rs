@@ -0,0 +1,304 @@
+use std::time::Duration;
+use ratatui::Frame;
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph, Row, Table, TableState};
+use crate::coordinator::stats::{PeerStats, PieceState, TorrentStats, TorrentStatus, TrackerStatus};
+use crate::core_models::entities::PeerSource;
+use crate::tui::app::App;
+
+const KEYS_HELP: &str = " q quit | ↑/↓ select | p pause | r resume | +/- download limit | ]/[ upload limit ";
+
+pub fn draw(frame: &mut Frame, app: &mut App) {
+    let [header, torrents, details, logs, footer] = Layout::vertical([
+        Constraint::Length(1),
+        Constraint::Length(app.torrents.len().clamp(1, 8) as u16 + 3),
+        Constraint::Min(8),
+        Constraint::Length(8),
+        Constraint::Length(1),
+    ]).areas(frame.size());
+    let [peers, side] = Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(details);
+    let [pieces, tracker] = Layout::vertical([Constraint::Min(3), Constraint::Length(6)]).areas(side);
+
+    draw_header(frame, header, app);
+    draw_torrents(frame, torrents, app);
+    match app.selected_torrent() {
+        Some(torrent) => {
+            draw_peers(frame, peers, torrent);
+            draw_pieces(frame, pieces, torrent);
+            draw_tracker(frame, tracker, torrent);
+        }
+        None => {
+            frame.render_widget(Block::bordered().title(" Peers "), peers);
+            frame.render_widget(Block::bordered().title(" Pieces "), pieces);
+            frame.render_widget(Block::bordered().title(" Tracker "), tracker);
+        }
+    }
+    draw_logs(frame, logs, app);
+    frame.render_widget(Paragraph::new(KEYS_HELP).style(Style::new().fg(Color::Black).bg(Color::Gray)), footer);
+}
+
+fn draw_header(frame: &mut Frame, area: Rect, app: &mut App) {
+    let (mut download_rate, mut upload_rate) = (0.0, 0.0);
+    let (mut downloaded, mut total) = (0, 0);
+    for torrent in app.torrents.iter_mut() {
+        download_rate += torrent.download_rate.bytes_per_sec();
+        upload_rate += torrent.upload_rate.bytes_per_sec();
+        downloaded += torrent.total_length - torrent.remaining_bytes();
+        total += torrent.total_length;
+    }
+    let progress = if total == 0 { 0.0 } else { downloaded as f64 / total as f64 * 100.0 };
+    let header = format!(
+        " {} torrents | {:.1}% | ↓ {} (limit {}) | ↑ {} (limit {})",
+        app.torrents.len(), progress,
+        format_rate(download_rate), format_limit(app.limits.download_bytes_per_sec),
+        format_rate(upload_rate), format_limit(app.limits.upload_bytes_per_sec),
+    );
+    frame.render_widget(Paragraph::new(header).style(Style::new().add_modifier(Modifier::BOLD)), area);
+}
+
+fn draw_torrents(frame: &mut Frame, area: Rect, app: &mut App) {
+    let rows: Vec<Row> = app.torrents.iter_mut().map(|torrent| Row::new(vec![
+        torrent.name.clone(),
+        status_name(&torrent.status),
+        format!("{:.1}%", torrent.progress() * 100.0),
+        format_rate(torrent.download_rate.bytes_per_sec()),
+        format_rate(torrent.upload_rate.bytes_per_sec()),
+        format_eta(torrent.eta()),
+        torrent.connected_peers().to_string(),
+    ])).collect();
+    let table = Table::new(rows, [
+        Constraint::Min(20),
+        Constraint::Length(11),
+        Constraint::Length(7),
+        Constraint::Length(11),
+        Constraint::Length(11),
+        Constraint::Length(9),
+        Constraint::Length(5),
+    ])
+        .header(Row::new(vec!["Name", "Status", "Done", "Down", "Up", "ETA", "Peers"]).style(header_style()))
+        .highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+        .block(Block::bordered().title(" Torrents "));
+    let mut state = TableState::default().with_selected(Some(app.selected));
+    frame.render_stateful_widget(table, area, &mut state);
+}
+
+fn draw_peers(frame: &mut Frame, area: Rect, torrent: &mut TorrentStats) {
+    let rows: Vec<Row> = torrent.peers.values_mut()
+        .filter(|peer| peer.is_connected)
+        .map(|peer| Row::new(vec![
+            format!("{}:{}", peer.peer.ip, peer.peer.port),
+            peer.status.client.clone().unwrap_or_default(),
+            peer_flags(peer),
+            format_rate(peer.download_rate.bytes_per_sec()),
+            format_rate(peer.upload_rate.bytes_per_sec()),
+            peer.status.outstanding_requests.to_string(),
+        ]))
+        .collect();
+    let table = Table::new(rows, [
+        Constraint::Length(21),
+        Constraint::Min(12),
+        Constraint::Length(6),
+        Constraint::Length(11),
+        Constraint::Length(11),
+        Constraint::Length(4),
+    ])
+        .header(Row::new(vec!["Address", "Client", "Flags", "Down", "Up", "Reqs"]).style(header_style()))
+        .block(Block::bordered().title(format!(" Peers ({}) ", torrent.connected_peers())));
+    frame.render_widget(table, area);
+}
+
+fn draw_pieces(frame: &mut Frame, area: Rect, torrent: &TorrentStats) {
+    let block = Block::bordered().title(format!(" Pieces {}/{} ", torrent.stored_pieces, torrent.pieces));
+    let inner = block.inner(area);
+    let cells = piece_map_cells(&torrent.piece_states, inner.width as usize * inner.height as usize);
+    let lines: Vec<Line> = cells.chunks(inner.width.max(1) as usize)
+        .map(|row| Line::from(row.iter().map(|state| Span::styled("█", piece_style(*state))).collect::<Vec<Span>>()))
+        .collect();
+    frame.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+fn draw_tracker(frame: &mut Frame, area: Rect, torrent: &TorrentStats) {
+    let tracker = &torrent.tracker;
+    let status = match &tracker.status {
+        TrackerStatus::NotContacted => "not contacted".to_string(),
+        TrackerStatus::Working => "working".to_string(),
+        TrackerStatus::Failed(reason) => format!("failed: {}", reason),
+    };
+    let last_announce = tracker.last_announce_at
+        .map_or("never".to_string(), |at| format!("{} ago", format_duration(at.elapsed())));
+    let lines = vec![
+        Line::from(format!("Status: {}", status)),
+        Line::from(format!("Last announce: {}, every {}", last_announce, format_duration(tracker.interval))),
+        Line::from(format!("Seeders: {} | Leechers: {} | Peers: {}", tracker.seeders, tracker.leechers, tracker.peers)),
+    ];
+    frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" Tracker ")), area);
+}
+
+fn draw_logs(frame: &mut Frame, area: Rect, app: &App) {
+    let lines: Vec<Line> = app.logs.tail(area.height.saturating_sub(2) as usize).into_iter()
+        .map(Line::from)
+        .collect();
+    frame.render_widget(Paragraph::new(lines).block(Block::bordered().title(" Log ")), area);
+}
+
+fn header_style() -> Style {
+    return Style::new().fg(Color::Yellow).add_modifier(Modifier::BOLD);
+}
+
+fn piece_style(state: PieceState) -> Style {
+    return match state {
+        PieceState::Stored => Style::new().fg(Color::Green),
+        PieceState::Downloading => Style::new().fg(Color::Yellow),
+        PieceState::Missing => Style::new().fg(Color::DarkGray),
+    };
+}
+
+fn status_name(status: &TorrentStatus) -> String {
+    return match status {
+        TorrentStatus::Failed(_) => "Failed".to_string(),
+        status => format!("{:?}", status),
+    };
+}
+
+// Flags of a peer, in the style of other clients:
+// D/d downloading from the peer or interested while choked, U/u uploading to the peer or it is
+// interested while choked, S snubbed, I incoming connection
+pub fn peer_flags(peer: &PeerStats) -> String {
+    let status = &peer.status;
+    let mut flags = String::new();
+    if status.client_is_interested {
+        flags.push(if status.client_is_choked { 'd' } else { 'D' });
+    }
+    if status.peer_is_interested {
+        flags.push(if status.peer_is_choked { 'u' } else { 'U' });
+    }
+    if status.is_snubbed {
+        flags.push('S');
+    }
+    if peer.peer.source == PeerSource::Incoming {
+        flags.push('I');
+    }
+    return flags;
+}
+
+// Squeezes the pieces into the given number of cells: a cell is stored once all its pieces are,
+// and downloading while any of them is in progress
+pub fn piece_map_cells(pieces: &[PieceState], cells: usize) -> Vec<PieceState> {
+    if pieces.is_empty() || cells == 0 {
+        return vec![];
+    }
+    let pieces_per_cell = pieces.len().div_ceil(cells);
+    return pieces.chunks(pieces_per_cell)
+        .map(|chunk| {
+            if chunk.iter().all(|state| *state == PieceState::Stored) {
+                PieceState::Stored
+            } else if chunk.iter().any(|state| *state != PieceState::Missing) {
+                PieceState::Downloading
+            } else {
+                PieceState::Missing
+            }
+        })
+        .collect();
+}
+
+pub fn format_rate(bytes_per_sec: f64) -> String {
+    return format!("{}/s", format_bytes(bytes_per_sec));
+}
+
+fn format_limit(limit: Option<u64>) -> String {
+    return limit.map_or("none".to_string(), |limit| format_rate(limit as f64));
+}
+
+fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    return if unit == 0 { format!("{:.0} {}", value, UNITS[unit]) } else { format!("{:.1} {}", value, UNITS[unit]) };
+}
+
+pub fn format_eta(eta: Option<Duration>) -> String {
+    return eta.map_or("∞".to_string(), format_duration);
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    return match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, secs % 3600 / 60),
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+    use crate::coordinator::stats::{PeerStats, PieceState};
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+    use crate::coordinator::stats::TorrentStats;
+    use crate::core_models::entities::{Peer, PeerSource};
+    use crate::mocks::MockTorrent;
+    use crate::tui::app::App;
+    use crate::tui::logger::LogBuffer;
+    use crate::tui::view::{draw, format_eta, format_rate, peer_flags, piece_map_cells};
+
+    #[test]
+    fn test_torrent_drawn() {
+        let layout = MockTorrent::generate(40, 2, 1).layout;
+        let mut torrent = TorrentStats::new("ubuntu.iso".to_string(), vec![1; 20], &layout);
+        torrent.piece_stored(0);
+        let mut peer = PeerStats::new(Peer { ip: Ipv4Addr::LOCALHOST, port: 6881, source: PeerSource::Tracker, peer_id: None });
+        peer.is_connected = true;
+        peer.status.client = Some("Peer 1.0".to_string());
+        torrent.peers.insert(0, peer);
+        let logs = LogBuffer::default();
+        logs.push("Transfer started".to_string());
+        let mut app = App::new(logs);
+        app.torrents.push(torrent);
+        let mut terminal = Terminal::new(TestBackend::new(100, 30)).unwrap();
+
+        terminal.draw(|frame| draw(frame, &mut app)).unwrap();
+
+        let screen: String = terminal.backend().buffer().content().iter().map(|cell| cell.symbol()).collect();
+        assert!(screen.contains("ubuntu.iso"));
+        assert!(screen.contains("Peer 1.0"));
+        assert!(screen.contains("Pieces 1/40"));
+        assert!(screen.contains("Transfer started"));
+    }
+
+    #[test]
+    fn test_pieces_squeezed_into_cells() {
+        let pieces = [PieceState::Stored, PieceState::Stored, PieceState::Stored, PieceState::Missing, PieceState::Missing];
+
+        let cells = piece_map_cells(&pieces, 3);
+
+        assert_eq!(cells, vec![PieceState::Stored, PieceState::Downloading, PieceState::Missing]);
+        assert_eq!(piece_map_cells(&pieces, 10).len(), 5);
+    }
+
+    #[test]
+    fn test_peer_flags() {
+        let mut peer = PeerStats::new(Peer { ip: Ipv4Addr::LOCALHOST, port: 6881, source: PeerSource::Incoming, peer_id: None });
+        peer.status.client_is_interested = true;
+        peer.status.client_is_choked = false;
+        peer.status.peer_is_interested = true;
+        peer.status.peer_is_choked = true;
+
+        assert_eq!(peer_flags(&peer), "DuI");
+    }
+
+    #[test]
+    fn test_formatting() {
+        assert_eq!(format_rate(1536.0), "1.5 KiB/s");
+        assert_eq!(format_rate(10.0), "10 B/s");
+        assert_eq!(format_eta(Some(Duration::from_secs(3725))), "1h 02m");
+        assert_eq!(format_eta(None), "∞");
+    }
+}
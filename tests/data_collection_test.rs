@@ -1,8 +1,16 @@
+use std::net::Ipv4Addr;
 use std::sync::Arc;
 use tokio::sync::mpsc::channel;
+use rust_torrent_client::alerts::{Alert, AlertCategory};
 use rust_torrent_client::core_models::events::InternalEvent;
 use rust_torrent_client::data_collector;
+use rust_torrent_client::dependency_provider::TransferDeps;
 use rust_torrent_client::mocks::{MockDepsProvider, MockTorrent};
+use rust_torrent_client::resume::ResumeData;
+use rust_torrent_client::selection::Priority;
+
+const HONEST_PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const CORRUPT_PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
 
 #[tokio::test]
 async fn test_data_collection() {
@@ -12,26 +20,26 @@ async fn test_data_collection() {
     // spawn data collector task
     let (output_tx, mut output_rx) = channel::<InternalEvent>(64);
     let torrent = MockTorrent::generate(2, blocks_in_piece_1, blocks_in_piece_2);
-    let deps = MockDepsProvider::new(torrent.clone(), output_tx.clone());
-    let (_handle, tx) = data_collector::spawn(Arc::new(deps));
+    let deps: Arc<dyn TransferDeps> = Arc::new(MockDepsProvider::new(torrent.clone(), output_tx.clone()));
+    let (_handle, tx) = data_collector::spawn(deps.clone());
 
     // send blocks for first piece
     // we should get back `blocks_in_piece_1 - 1` BlockStored events
     for block_idx in 0..blocks_in_piece_1 {
         let data_block = torrent.data_block(0, block_idx);
-        tx.send(data_block).await.unwrap();
+        tx.send((HONEST_PEER, data_block)).await.unwrap();
         let event = output_rx.recv().await.unwrap();
         assert!(event.is_block_stored());
     }
     let event = output_rx.recv().await.unwrap();
     assert!(event.is_piece_stored());
 
-    // for the second piece, send corrupt blocks
+    // for the second piece, another peer sends corrupt blocks
     // we should get back `blocks_in_piece_2 - 2` BlockStored events
     for block_idx in 0..blocks_in_piece_2 {
         let mut data_block = torrent.data_block(0, block_idx);
         data_block.piece_idx = 1;
-        tx.send(data_block).await.unwrap();
+        tx.send((CORRUPT_PEER, data_block)).await.unwrap();
         if block_idx < blocks_in_piece_2 - 1 {
             let event = output_rx.recv().await.unwrap();
             assert!(event.is_block_stored());
@@ -42,7 +50,46 @@ async fn test_data_collection() {
     // we should get back `blocks_in_piece_2 - 1` BlockStored events
     for block_idx in 0..blocks_in_piece_2 {
         let data_block = torrent.data_block(1, block_idx);
-        tx.send(data_block).await.unwrap();
+        tx.send((HONEST_PEER, data_block)).await.unwrap();
+        let event = output_rx.recv().await.unwrap();
+        assert!(event.is_block_stored());
+    }
+    let event = output_rx.recv().await.unwrap();
+    assert!(event.is_piece_stored());
+    // the blocks of the peer which sent the corrupt data differ from the good ones
+    let event = output_rx.recv().await.unwrap();
+    assert_eq!(event, InternalEvent::BanPeer(CORRUPT_PEER));
+    // the address is banned in the session, whether or not the peer is still connected
+    assert!(deps.banned_peers().is_banned(&CORRUPT_PEER));
+    assert!(!deps.banned_peers().is_banned(&HONEST_PEER));
+    let event = output_rx.recv().await.unwrap();
+    assert!(event.is_download_complete());
+}
+
+#[tokio::test]
+async fn test_data_collection_completes_on_selected_pieces() {
+    let (output_tx, mut output_rx) = channel::<InternalEvent>(64);
+    let torrent = MockTorrent::generate(3, 2, 2);
+    let deps = MockDepsProvider::new(torrent.clone(), output_tx.clone());
+    let picker = deps.piece_picker();
+    picker.lock().await.set_piece_priorities(vec![Priority::Skip, Priority::Normal, Priority::Skip]).unwrap();
+    let (_handle, tx) = data_collector::spawn(Arc::new(deps));
+
+    // only the second piece is selected, so storing it completes the download
+    for block_idx in 0..2 {
+        tx.send((HONEST_PEER, torrent.data_block(1, block_idx))).await.unwrap();
+        let event = output_rx.recv().await.unwrap();
+        assert!(event.is_block_stored());
+    }
+    let event = output_rx.recv().await.unwrap();
+    assert!(event.is_piece_stored());
+    let event = output_rx.recv().await.unwrap();
+    assert!(event.is_download_complete());
+
+    // a skipped piece wanted later on is still stored
+    picker.lock().await.set_piece_priorities(vec![Priority::Normal, Priority::Normal, Priority::Skip]).unwrap();
+    for block_idx in 0..2 {
+        tx.send((HONEST_PEER, torrent.data_block(0, block_idx))).await.unwrap();
         let event = output_rx.recv().await.unwrap();
         assert!(event.is_block_stored());
     }
@@ -51,3 +98,71 @@ async fn test_data_collection() {
     let event = output_rx.recv().await.unwrap();
     assert!(event.is_download_complete());
 }
+
+#[tokio::test]
+async fn test_pieces_stored_while_draining_recorded() {
+    let (output_tx, output_rx) = channel::<InternalEvent>(64);
+    let torrent = MockTorrent::generate(2, 2, 2);
+    let deps: Arc<dyn TransferDeps> = Arc::new(MockDepsProvider::new(torrent.clone(), output_tx));
+    let (handle, tx) = data_collector::spawn(deps.clone());
+
+    // the coordinator no longer listens once the transfer stops, while the blocks it sent are still stored
+    drop(output_rx);
+    for block_idx in 0..2 {
+        tx.send((HONEST_PEER, torrent.data_block(1, block_idx))).await.unwrap();
+    }
+    drop(tx);
+    handle.await.unwrap();
+
+    let resume_data = ResumeData::from_stats(&deps.stats_tx().borrow());
+    assert_eq!(resume_data.pieces, vec![0b0100_0000]);
+}
+
+#[tokio::test]
+async fn test_data_collection_alerts_and_metrics() {
+    let (output_tx, mut output_rx) = channel::<InternalEvent>(64);
+    let torrent = MockTorrent::generate(1, 2, 2);
+    let deps = MockDepsProvider::new(torrent.clone(), output_tx.clone());
+    let info_hash = deps.info_hash();
+    let mut alerts = deps.alerts().subscribe(&[AlertCategory::Piece]);
+    let metrics = deps.metrics();
+    let (_handle, tx) = data_collector::spawn(Arc::new(deps));
+
+    // the first attempt at the piece is corrupt
+    let mut corrupt_block = torrent.data_block(0, 0);
+    corrupt_block.data = corrupt_block.data.iter().map(|byte| !byte).collect();
+    tx.send((CORRUPT_PEER, corrupt_block)).await.unwrap();
+    tx.send((HONEST_PEER, torrent.data_block(0, 1))).await.unwrap();
+    for block_idx in 0..2 {
+        tx.send((HONEST_PEER, torrent.data_block(0, block_idx))).await.unwrap();
+    }
+    while !output_rx.recv().await.unwrap().is_download_complete() {}
+
+    assert_eq!(alerts.recv().await, Ok(Alert::HashFailed { info_hash: info_hash.clone(), piece_idx: 0 }));
+    assert_eq!(alerts.recv().await, Ok(Alert::PieceFinished { info_hash: info_hash.clone(), piece_idx: 0 }));
+    assert_eq!(alerts.recv().await, Ok(Alert::TorrentFinished { info_hash }));
+    assert_eq!(metrics.hash_failures.get(), 1);
+    assert_eq!(metrics.pieces_stored.get(), 1);
+}
+
+#[tokio::test]
+async fn test_data_collection_reports_storage_failure() {
+    let (output_tx, mut output_rx) = channel::<InternalEvent>(64);
+    let torrent = MockTorrent::generate(2, 2, 2);
+    let deps = MockDepsProvider::new(torrent.clone(), output_tx.clone());
+    let layout = deps.torrent_layout();
+    std::fs::remove_file(&layout.output_file_path).unwrap();
+    let (_handle, tx) = data_collector::spawn(Arc::new(deps));
+
+    // the output file is gone, so the block can not be stored
+    tx.send((HONEST_PEER, torrent.data_block(0, 0))).await.unwrap();
+    let event = output_rx.recv().await.unwrap();
+    assert!(matches!(event, InternalEvent::StorageFailed(_)));
+
+    // once the file is back, the same block is stored
+    let file = std::fs::File::create(&layout.output_file_path).unwrap();
+    file.set_len(layout.output_file_length as u64).unwrap();
+    tx.send((HONEST_PEER, torrent.data_block(0, 0))).await.unwrap();
+    let event = output_rx.recv().await.unwrap();
+    assert!(event.is_block_stored());
+}